@@ -0,0 +1,162 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use const_oid::db::rfc5280::{ID_CE_BASIC_CONSTRAINTS, ID_CE_KEY_USAGE};
+use const_oid::db::rfc8410::ID_ED_25519;
+use const_oid::ObjectIdentifier;
+use der::Decode;
+use ed25519_dalek::{PublicKey, Signature, PUBLIC_KEY_LENGTH};
+use rice::x509::certificate::Certificate;
+
+use crate::der_tlv::{
+    read_tagged, read_tlv, TAG_BIT_STRING, TAG_BOOLEAN, TAG_INTEGER, TAG_SEQUENCE,
+};
+use crate::{Error, Result};
+
+// The keyCertSign bit of the KeyUsage bit string first byte (bit 5).
+const KEY_USAGE_KEY_CERT_SIGN: u8 = 0x04;
+
+/// Splits a certificate chain into its DER encoded certificates.
+pub fn split_chain(chain: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut certs = Vec::new();
+    let mut rest = chain;
+    while !rest.is_empty() {
+        let (cert, next) = read_tagged(rest, TAG_SEQUENCE)?;
+        certs.push(cert.raw);
+        rest = next;
+    }
+    Ok(certs)
+}
+
+// Returns the signed part of the `cert` DER certificate, i.e. its encoded TBSCertificate, along
+// with its signature.
+fn signed_data(cert: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (cert, _) = read_tagged(cert, TAG_SEQUENCE)?;
+    let (tbs_certificate, rest) = read_tagged(cert.value, TAG_SEQUENCE)?;
+    let (_signature_algorithm, rest) = read_tagged(rest, TAG_SEQUENCE)?;
+    let (signature, _) = read_tagged(rest, TAG_BIT_STRING)?;
+    // Ed25519 signatures have no unused bits.
+    match signature.value.split_first() {
+        Some((0, signature)) => Ok((tbs_certificate.raw, signature)),
+        _ => Err(Error::MalformedDer),
+    }
+}
+
+// Returns the value of the `oid` extension of `cert`, if any.
+fn extension<'a>(cert: &Certificate<'a>, oid: ObjectIdentifier) -> Option<&'a [u8]> {
+    cert.tbs_certificate
+        .extensions
+        .as_ref()
+        .and_then(|extensions| extensions.iter().find(|e| e.extn_id == oid))
+        .map(|e| e.extn_value)
+}
+
+// Returns the `cert` BasicConstraints path length constraint if `cert` is a certificate
+// authority, i.e. if its BasicConstraints cA is TRUE.
+fn ca_path_len(cert: &Certificate) -> Result<Option<Option<usize>>> {
+    let extn_value = match extension(cert, ID_CE_BASIC_CONSTRAINTS) {
+        Some(extn_value) => extn_value,
+        None => return Ok(None),
+    };
+    let (basic_constraints, _) = read_tagged(extn_value, TAG_SEQUENCE)?;
+    let mut rest = basic_constraints.value;
+    let mut ca = false;
+    let mut path_len = None;
+    while !rest.is_empty() {
+        let (tlv, next) = read_tlv(rest)?;
+        match (tlv.tag, tlv.value) {
+            (TAG_BOOLEAN, [value]) if path_len.is_none() => ca = *value != 0,
+            (TAG_INTEGER, value) if !value.is_empty() && value.len() <= 4 => {
+                // pathLenConstraint is non negative.
+                if value[0] & 0x80 != 0 {
+                    return Err(Error::MalformedDer);
+                }
+                path_len = Some(value.iter().fold(0usize, |len, &b| (len << 8) | b as usize));
+            }
+            _ => return Err(Error::MalformedDer),
+        }
+        rest = next;
+    }
+    Ok(ca.then_some(path_len))
+}
+
+// Returns true if the `cert` KeyUsage allows it to sign certificates.
+fn can_sign_certificates(cert: &Certificate) -> Result<bool> {
+    let extn_value = match extension(cert, ID_CE_KEY_USAGE) {
+        Some(extn_value) => extn_value,
+        None => return Ok(false),
+    };
+    let (key_usage, _) = read_tagged(extn_value, TAG_BIT_STRING)?;
+    // Skip the unused bits count.
+    Ok(key_usage
+        .value
+        .get(1)
+        .map(|bits| bits & KEY_USAGE_KEY_CERT_SIGN != 0)
+        .unwrap_or(false))
+}
+
+/// Verifies the `chain` certificate chain and returns its last certificate.
+///
+/// The first certificate must be signed by `trusted_root`, and every following certificate must
+/// be issued and signed by the subject of the certificate that precedes it. Every issuing
+/// certificate must be a certificate authority (BasicConstraints cA set) allowed to sign
+/// certificates (KeyUsage keyCertSign set), and the issuers' path length constraints must hold.
+/// This prevents the holder of the evidence certificate key from extending the chain with
+/// certificates of its own.
+pub fn verify_chain<'a>(
+    chain: &'a [u8],
+    trusted_root: &[u8; PUBLIC_KEY_LENGTH],
+) -> Result<Certificate<'a>> {
+    let mut issuer: Option<Certificate<'a>> = None;
+    // The number of intermediate certificates that can still follow, as limited by the issuers
+    // path length constraints.
+    let mut max_path_len: Option<usize> = None;
+    for (i, der) in split_chain(chain)?.into_iter().enumerate() {
+        let cert = Certificate::from_der(der).map_err(|e| Error::CertificateParsing(i, e))?;
+        if cert.signature_algorithm.oid != ID_ED_25519 {
+            return Err(Error::UnsupportedSignatureAlgorithm(
+                i,
+                cert.signature_algorithm.oid,
+            ));
+        }
+
+        let issuer_key = match issuer.as_ref() {
+            Some(parent) => {
+                let path_len = ca_path_len(parent)?.ok_or(Error::IssuerNotCa(i))?;
+                if !can_sign_certificates(parent)? {
+                    return Err(Error::IssuerNotCa(i));
+                }
+                // The parent is an intermediate certificate, unless it's self-issued.
+                if parent.tbs_certificate.subject != parent.tbs_certificate.issuer {
+                    max_path_len = match max_path_len {
+                        Some(0) => return Err(Error::PathLengthExceeded(i)),
+                        Some(len) => Some(len - 1),
+                        None => None,
+                    };
+                }
+                if let Some(path_len) = path_len {
+                    max_path_len = Some(max_path_len.map_or(path_len, |len| len.min(path_len)));
+                }
+                if parent.tbs_certificate.subject != cert.tbs_certificate.issuer {
+                    return Err(Error::IssuerMismatch(i));
+                }
+                parent
+                    .tbs_certificate
+                    .subject_public_key_info
+                    .subject_public_key
+            }
+            None => trusted_root.as_slice(),
+        };
+        let public_key =
+            PublicKey::from_bytes(issuer_key).map_err(|_| Error::InvalidPublicKey(i))?;
+        let (tbs_certificate, signature) = signed_data(der)?;
+        let signature = Signature::from_bytes(signature).map_err(|_| Error::InvalidSignature(i))?;
+        public_key
+            .verify_strict(tbs_certificate, &signature)
+            .map_err(|_| Error::InvalidSignature(i))?;
+
+        issuer = Some(cert);
+    }
+    issuer.ok_or(Error::EmptyChain)
+}
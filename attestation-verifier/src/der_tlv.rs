@@ -0,0 +1,94 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{Error, Result};
+
+// DER SEQUENCE tag.
+pub(crate) const TAG_SEQUENCE: u8 = 0x30;
+// DER BIT STRING tag.
+pub(crate) const TAG_BIT_STRING: u8 = 0x03;
+// DER BOOLEAN tag.
+pub(crate) const TAG_BOOLEAN: u8 = 0x01;
+// DER INTEGER tag.
+pub(crate) const TAG_INTEGER: u8 = 0x02;
+
+/// A DER encoded tag-length-value.
+pub(crate) struct Tlv<'a> {
+    /// The TLV tag.
+    pub tag: u8,
+    /// The whole TLV encoding.
+    pub raw: &'a [u8],
+    /// The TLV value.
+    pub value: &'a [u8],
+}
+
+/// Reads the TLV at the start of `bytes`, and returns it along with the bytes that follow it.
+/// Only single byte tags and definite lengths of up to 4 bytes are supported, which covers the
+/// certificates Salus issues.
+pub(crate) fn read_tlv(bytes: &[u8]) -> Result<(Tlv, &[u8])> {
+    let (&tag, rest) = bytes.split_first().ok_or(Error::MalformedDer)?;
+    let (&first, rest) = rest.split_first().ok_or(Error::MalformedDer)?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let num_bytes = (first & 0x7f) as usize;
+        if num_bytes == 0 || num_bytes > 4 || rest.len() < num_bytes {
+            return Err(Error::MalformedDer);
+        }
+        let len = rest[..num_bytes]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[num_bytes..])
+    };
+    if rest.len() < len {
+        return Err(Error::MalformedDer);
+    }
+    let header_len = bytes.len() - rest.len();
+    let tlv = Tlv {
+        tag,
+        raw: &bytes[..header_len + len],
+        value: &rest[..len],
+    };
+    Ok((tlv, &rest[len..]))
+}
+
+/// Reads the TLV at the start of `bytes`, checking its tag.
+pub(crate) fn read_tagged(bytes: &[u8], tag: u8) -> Result<(Tlv, &[u8])> {
+    let (tlv, rest) = read_tlv(bytes)?;
+    if tlv.tag != tag {
+        return Err(Error::MalformedDer);
+    }
+    Ok((tlv, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_length() {
+        let bytes = [0x30, 0x02, 0x05, 0x00, 0xff];
+        let (tlv, rest) = read_tlv(&bytes).unwrap();
+        assert_eq!(tlv.tag, TAG_SEQUENCE);
+        assert_eq!(tlv.raw, &bytes[..4]);
+        assert_eq!(tlv.value, &[0x05, 0x00]);
+        assert_eq!(rest, &[0xff]);
+    }
+
+    #[test]
+    fn long_length() {
+        let mut bytes = vec![0x03, 0x82, 0x01, 0x00];
+        bytes.extend_from_slice(&[0u8; 0x100]);
+        let (tlv, rest) = read_tagged(&bytes, TAG_BIT_STRING).unwrap();
+        assert_eq!(tlv.value.len(), 0x100);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(read_tlv(&[0x30, 0x03, 0x05, 0x00]).is_err());
+        assert!(read_tlv(&[0x30, 0x82, 0x01]).is_err());
+        assert!(read_tagged(&[0x30, 0x00], TAG_BIT_STRING).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use attestation::event_log::{
+    CEL_TLV_HEADER_LEN, CEL_T_DIGESTS, CEL_T_PCR, CEL_T_RECNUM, CEL_T_SALUS_EVENT,
+    SALUS_EVENT_ADDRESS, SALUS_EVENT_DESCRIPTION, SALUS_EVENT_DROPPED, SALUS_EVENT_LENGTH,
+    TPM_ALG_SHA256, TPM_ALG_SHA384, TPM_ALG_SHA512,
+};
+use attestation::{extended_digest, TcgPcrIndex, MSMT_REGISTERS};
+use const_oid::db::rfc5912::{ID_SHA_256, ID_SHA_384, ID_SHA_512};
+use digest::{Digest, Output};
+
+use crate::evidence::Measurements;
+use crate::{Error, Result};
+
+// A single Salus event log record.
+struct Record<'a> {
+    pcr: TcgPcrIndex,
+    digest: &'a [u8],
+    address: Option<u64>,
+}
+
+// Reads the CEL TLV at the start of `bytes`, and returns its type and value along with the
+// bytes that follow it.
+fn read_cel_tlv(bytes: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    if bytes.len() < CEL_TLV_HEADER_LEN {
+        return Err(Error::MalformedEventLog);
+    }
+    let (header, rest) = bytes.split_at(CEL_TLV_HEADER_LEN);
+    // Unwrap ok: the length field is 4 bytes long.
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(Error::MalformedEventLog);
+    }
+    let (value, rest) = rest.split_at(len);
+    Ok((header[0], value, rest))
+}
+
+fn read_u64(value: &[u8]) -> Result<u64> {
+    value
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| Error::MalformedEventLog)
+}
+
+// Reads the `recnum` record at the start of `bytes`, whose digests are `alg_id` digests of
+// `digest_len` bytes. Returns the record along with the bytes that follow it.
+fn read_record(
+    bytes: &[u8],
+    recnum: u64,
+    alg_id: u8,
+    digest_len: usize,
+) -> Result<(Record, &[u8])> {
+    let (tlv_type, value, mut rest) = read_cel_tlv(bytes)?;
+    if tlv_type != CEL_T_RECNUM || read_u64(value)? != recnum {
+        return Err(Error::MalformedEventLog);
+    }
+
+    // The Salus event content is the last field of a record.
+    let mut pcr = None;
+    let mut digest = None;
+    let content = loop {
+        let (tlv_type, value, next) = read_cel_tlv(rest)?;
+        rest = next;
+        match tlv_type {
+            CEL_T_PCR => match value {
+                [index] => pcr = Some(*index),
+                _ => return Err(Error::MalformedEventLog),
+            },
+            CEL_T_DIGESTS => match read_cel_tlv(value)? {
+                (id, d, []) if id == alg_id && d.len() == digest_len => digest = Some(d),
+                _ => return Err(Error::MalformedEventLog),
+            },
+            CEL_T_SALUS_EVENT => break value,
+            _ => return Err(Error::MalformedEventLog),
+        }
+    };
+
+    let mut address = None;
+    let mut fields = content;
+    while !fields.is_empty() {
+        let (tlv_type, value, next) = read_cel_tlv(fields)?;
+        fields = next;
+        match tlv_type {
+            SALUS_EVENT_ADDRESS => address = Some(read_u64(value)?),
+            SALUS_EVENT_LENGTH | SALUS_EVENT_DESCRIPTION => {}
+            // The extensions that were not recorded can't be replayed.
+            SALUS_EVENT_DROPPED => return Err(Error::DroppedEvents(read_u64(value)?)),
+            _ => return Err(Error::MalformedEventLog),
+        }
+    }
+
+    let record = match (pcr, digest) {
+        (Some(pcr), Some(digest)) => Record {
+            pcr: pcr.try_into().map_err(|_| Error::MalformedEventLog)?,
+            digest,
+            address,
+        },
+        _ => return Err(Error::MalformedEventLog),
+    };
+    Ok((record, rest))
+}
+
+fn replay<D: Digest>(log: &[u8], measurements: &Measurements, alg_id: u8) -> Result<()> {
+    let log_digest = measurements
+        .event_log_digest()
+        .ok_or(Error::MissingEventLogDigest)?;
+    if D::digest(log).as_slice() != log_digest {
+        return Err(Error::EventLogDigestMismatch);
+    }
+
+    // Replay every extension from zeroed registers, in FWID order.
+    let mut registers = vec![Output::<D>::default(); MSMT_REGISTERS];
+    let mut rest = log;
+    let mut recnum = 0;
+    while !rest.is_empty() {
+        let (record, next) = read_record(rest, recnum, alg_id, <D as Digest>::output_size())?;
+        let register = &mut registers[record.pcr.fwid_index()];
+        *register = extended_digest::<D>(register, record.digest, record.address);
+        rest = next;
+        recnum += 1;
+    }
+
+    for pcr in (0..=u8::MAX).filter_map(|i| TcgPcrIndex::try_from(i).ok()) {
+        if registers[pcr.fwid_index()].as_slice() != measurements.register(pcr) {
+            return Err(Error::MeasurementMismatch(pcr));
+        }
+    }
+    Ok(())
+}
+
+/// Replays `log`, a TVM event log as returned by the event log guest call, against the
+/// `measurements` of the TVM evidence. The log digest must be the evidence event log digest, and
+/// replaying the log extensions must give every evidence measurement register, the TVM pages and
+/// configuration ones included. Their expected values are checked with a `Policy`.
+///
+/// Logs that dropped events can't be replayed, and are rejected.
+pub fn replay_event_log(log: &[u8], measurements: &Measurements) -> Result<()> {
+    let hash_algorithm = measurements.hash_algorithm();
+    if hash_algorithm == ID_SHA_256 {
+        replay::<sha2::Sha256>(log, measurements, TPM_ALG_SHA256)
+    } else if hash_algorithm == ID_SHA_384 {
+        replay::<sha2::Sha384>(log, measurements, TPM_ALG_SHA384)
+    } else if hash_algorithm == ID_SHA_512 {
+        replay::<sha2::Sha512>(log, measurements, TPM_ALG_SHA512)
+    } else {
+        Err(Error::UnsupportedHashAlgorithm(hash_algorithm))
+    }
+}
@@ -0,0 +1,71 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use attestation::{TcgPcrIndex, MSMT_REGISTERS};
+use const_oid::ObjectIdentifier;
+use der::Decode;
+use rice::x509::certificate::Certificate;
+use rice::x509::extensions::dice::tcbinfo::{DiceTcbInfo, TCG_DICE_TCB_INFO};
+
+use crate::{Error, Result};
+
+/// The TVM measurements reported by a certificate DICE TcbInfo extension.
+#[derive(Clone, Debug)]
+pub struct Measurements {
+    hash_algorithm: ObjectIdentifier,
+    // Measurement registers, in FWID order.
+    registers: Vec<Vec<u8>>,
+    // Event log digest. Its FWID follows the measurement registers ones.
+    event_log_digest: Option<Vec<u8>>,
+}
+
+impl Measurements {
+    /// Extracts the measurements from the `cert` DICE TcbInfo extension.
+    pub fn from_certificate(cert: &Certificate) -> Result<Self> {
+        let extn = cert
+            .tbs_certificate
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.iter().find(|e| e.extn_id == TCG_DICE_TCB_INFO))
+            .ok_or(Error::MissingTcbInfo)?;
+        let tcb_info = DiceTcbInfo::from_der(extn.extn_value).map_err(Error::TcbInfoParsing)?;
+        let fwids = tcb_info.fwids.as_ref().ok_or(Error::MissingFwids)?;
+
+        let mut digests = Vec::new();
+        let mut hash_algorithm = None;
+        for fwid in fwids.iter() {
+            if *hash_algorithm.get_or_insert(fwid.hash_alg) != fwid.hash_alg {
+                return Err(Error::InconsistentHashAlgorithm);
+            }
+            digests.push(fwid.digest.as_bytes().to_vec());
+        }
+
+        let event_log_digest = match digests.len() {
+            MSMT_REGISTERS => None,
+            n if n == MSMT_REGISTERS + 1 => digests.pop(),
+            n => return Err(Error::InvalidFwidCount(n)),
+        };
+        Ok(Self {
+            // Unwrap ok: there is at least one FWID.
+            hash_algorithm: hash_algorithm.unwrap(),
+            registers: digests,
+            event_log_digest,
+        })
+    }
+
+    /// Returns the measurement hash algorithm.
+    pub fn hash_algorithm(&self) -> ObjectIdentifier {
+        self.hash_algorithm
+    }
+
+    /// Returns the `pcr` measurement register value.
+    pub fn register(&self, pcr: TcgPcrIndex) -> &[u8] {
+        &self.registers[pcr.fwid_index()]
+    }
+
+    /// Returns the event log digest, if the evidence reports one.
+    pub fn event_log_digest(&self) -> Option<&[u8]> {
+        self.event_log_digest.as_deref()
+    }
+}
@@ -0,0 +1,591 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host side verification of the attestation evidence issued by Salus.
+//!
+//! Salus attestation evidence is an X.509 certificate chain. The chain starts from the platform
+//! device identity, goes through the Salus (TSM) layer and the TVM attestation CDI certificates,
+//! and ends with the evidence certificate issued for the TVM CSR. Every TVM layer certificate
+//! carries the TVM measurement registers in a DICE TcbInfo extension.
+//!
+//! This crate checks the chain signatures against a trusted root public key, extracts the
+//! measurement registers from the evidence certificate and compares them against a policy of
+//! expected values. It can also replay the TVM event log against the evidence measurements.
+
+use attestation::TcgPcrIndex;
+use const_oid::ObjectIdentifier;
+
+/// DICE certificate chain parsing and signature verification.
+pub mod chain;
+// Minimal DER TLV parsing.
+mod der_tlv;
+/// Event log replay.
+pub mod event_log;
+/// Measurement registers extraction.
+pub mod evidence;
+/// Verification policy.
+pub mod policy;
+
+pub use chain::verify_chain;
+pub use event_log::replay_event_log;
+pub use evidence::Measurements;
+pub use policy::Policy;
+
+/// Evidence verification errors.
+#[derive(Debug)]
+pub enum Error {
+    /// Malformed DER encoding.
+    MalformedDer,
+
+    /// The certificate chain is empty.
+    EmptyChain,
+
+    /// Certificate parsing failed.
+    CertificateParsing(usize, der::Error),
+
+    /// The certificate issuer does not match its parent certificate subject.
+    IssuerMismatch(usize),
+
+    /// The certificate parent is not a certificate authority allowed to sign certificates.
+    IssuerNotCa(usize),
+
+    /// The certificate is beyond its issuers path length constraints.
+    PathLengthExceeded(usize),
+
+    /// The certificate chain does not have the length the policy expects.
+    UnexpectedChainLength(usize),
+
+    /// The certificate is not signed with Ed25519.
+    UnsupportedSignatureAlgorithm(usize, ObjectIdentifier),
+
+    /// The certificate issuer public key is invalid.
+    InvalidPublicKey(usize),
+
+    /// The certificate signature does not verify.
+    InvalidSignature(usize),
+
+    /// The certificate has no DICE TcbInfo extension.
+    MissingTcbInfo,
+
+    /// The DICE TcbInfo extension is malformed.
+    TcbInfoParsing(der::Error),
+
+    /// The DICE TcbInfo extension has no FWIDs.
+    MissingFwids,
+
+    /// The DICE TcbInfo extension does not have one FWID per measurement register.
+    InvalidFwidCount(usize),
+
+    /// The FWIDs do not all use the same hash algorithm.
+    InconsistentHashAlgorithm,
+
+    /// The measurement hash algorithm does not match the policy.
+    UnexpectedHashAlgorithm(ObjectIdentifier),
+
+    /// A measurement register does not match the policy, or the replayed event log.
+    MeasurementMismatch(TcgPcrIndex),
+
+    /// The measurement hash algorithm is not supported.
+    UnsupportedHashAlgorithm(ObjectIdentifier),
+
+    /// The evidence does not report an event log digest.
+    MissingEventLogDigest,
+
+    /// The event log digest does not match the evidence.
+    EventLogDigestMismatch,
+
+    /// The event log is malformed.
+    MalformedEventLog,
+
+    /// The event log dropped events, and can't be replayed.
+    DroppedEvents(u64),
+}
+
+/// Custom verifier result.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Verifies the `chain` certificate chain against `policy`, and returns the measurements
+/// reported by its last certificate.
+///
+/// `chain` is the concatenation of DER encoded certificates, starting from the one issued by
+/// the policy trusted root and ending with the evidence certificate. This is the TVM DICE chain
+/// followed by the TVM evidence certificate.
+pub fn verify(chain: &[u8], policy: &Policy) -> Result<Measurements> {
+    policy.check_chain_length(chain::split_chain(chain)?.len())?;
+    let leaf = verify_chain(chain, policy.trusted_root())?;
+    let measurements = Measurements::from_certificate(&leaf)?;
+    policy.check(&measurements)?;
+    Ok(measurements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use attestation::event_log::MAX_RUNTIME_EVENTS;
+    use attestation::{extended_digest, AttestationManager};
+    use const_oid::db::rfc5912::{ID_SHA_256, ID_SHA_384, ID_SHA_512};
+    use der::Decode;
+    use digest::Digest;
+    use ed25519_dalek::{
+        Keypair, PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH,
+    };
+    use generic_array::GenericArray;
+    use rice::cdi::{CompoundDeviceIdentifier, CDI_ID_LEN};
+    use rice::layer::Layer;
+    use rice::x509::extensions::dice::tcbinfo::DiceTcbInfo;
+    use rice::x509::request::CertReq;
+    use signature::{Error as SignatureError, Signer};
+    use zeroize::Zeroize;
+
+    // The attestation manager current CDI, to issue certificates with a rice `Layer`, and the CDIs
+    // derived from it.
+    enum ManagerCdi<'a, C> {
+        Current(&'a C),
+        Next(C),
+    }
+
+    impl<C: Zeroize> Zeroize for ManagerCdi<'_, C> {
+        fn zeroize(&mut self) {
+            match self {
+                // The current CDI belongs to the attestation manager.
+                Self::Current(_) => {}
+                Self::Next(cdi) => cdi.zeroize(),
+            }
+        }
+    }
+
+    impl<'a, C> CompoundDeviceIdentifier<PUBLIC_KEY_LENGTH, Signature> for ManagerCdi<'a, C>
+    where
+        C: CompoundDeviceIdentifier<PUBLIC_KEY_LENGTH, Signature> + Zeroize,
+    {
+        fn id(&self) -> core::result::Result<[u8; CDI_ID_LEN], rice::Error> {
+            match self {
+                Self::Current(cdi) => cdi.id(),
+                Self::Next(cdi) => cdi.id(),
+            }
+        }
+
+        fn next(
+            &self,
+            info: Option<&[u8]>,
+            next_tci: Option<&[u8]>,
+        ) -> core::result::Result<Self, rice::Error> {
+            Ok(match self {
+                Self::Current(cdi) => Self::Next(cdi.next(info, next_tci)?),
+                Self::Next(cdi) => Self::Next(cdi.next(info, next_tci)?),
+            })
+        }
+
+        fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+            match self {
+                Self::Current(cdi) => cdi.public_key(),
+                Self::Next(cdi) => cdi.public_key(),
+            }
+        }
+    }
+
+    impl<C: Signer<Signature>> Signer<Signature> for ManagerCdi<'_, C> {
+        fn try_sign(&self, msg: &[u8]) -> core::result::Result<Signature, SignatureError> {
+            match self {
+                Self::Current(cdi) => cdi.try_sign(msg),
+                Self::Next(cdi) => cdi.try_sign(msg),
+            }
+        }
+    }
+
+    // The secret key of the evidence CSR.
+    const EVIDENCE_CSR_SECRET_KEY: [u8; SECRET_KEY_LENGTH] = [
+        0xf7, 0x25, 0xc4, 0x15, 0xac, 0xf7, 0x9b, 0x94, 0x73, 0x17, 0xba, 0xc6, 0x03, 0xb3, 0x1d,
+        0x61, 0xec, 0xb6, 0xf3, 0x29, 0xb9, 0xd5, 0x40, 0xfb, 0x2f, 0x1c, 0xd0, 0xc0, 0x3c, 0xb6,
+        0xd3, 0xf1,
+    ];
+
+    // An Ed25519 CSR, for the `EVIDENCE_CSR_SECRET_KEY` key.
+    const EVIDENCE_CSR: &[u8] = include_bytes!("../testdata/evidence-csr.der");
+
+    // A CDI holding a plain Ed25519 key, e.g. a CSR key.
+    struct KeyCdi {
+        secret_key: [u8; SECRET_KEY_LENGTH],
+    }
+
+    impl KeyCdi {
+        fn keypair(&self) -> Keypair {
+            // Unwrap ok: any 32 bytes are a valid Ed25519 secret key.
+            let secret = SecretKey::from_bytes(&self.secret_key).unwrap();
+            let public = PublicKey::from(&secret);
+            Keypair { secret, public }
+        }
+    }
+
+    impl Zeroize for KeyCdi {
+        fn zeroize(&mut self) {
+            self.secret_key.zeroize();
+        }
+    }
+
+    impl CompoundDeviceIdentifier<PUBLIC_KEY_LENGTH, Signature> for KeyCdi {
+        fn id(&self) -> core::result::Result<[u8; CDI_ID_LEN], rice::Error> {
+            let mut id = [0u8; CDI_ID_LEN];
+            id.copy_from_slice(&sha2::Sha256::digest(self.public_key())[..CDI_ID_LEN]);
+            Ok(id)
+        }
+
+        fn next(
+            &self,
+            info: Option<&[u8]>,
+            next_tci: Option<&[u8]>,
+        ) -> core::result::Result<Self, rice::Error> {
+            let mut hasher = sha2::Sha256::new_with_prefix(self.secret_key);
+            hasher.update(info.unwrap_or_default());
+            hasher.update(next_tci.unwrap_or_default());
+            Ok(Self {
+                secret_key: hasher.finalize().into(),
+            })
+        }
+
+        fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+            self.keypair().public.to_bytes()
+        }
+    }
+
+    impl Signer<Signature> for KeyCdi {
+        fn try_sign(&self, msg: &[u8]) -> core::result::Result<Signature, SignatureError> {
+            self.keypair().try_sign(msg)
+        }
+    }
+
+    // Builds a finalized SHA-384 attestation manager, with measured TVM pages.
+    fn attestation_manager() -> AttestationManager<sha2::Sha384> {
+        attestation_manager_with(ID_SHA_384)
+    }
+
+    // Builds a finalized attestation manager for the `D` measurement hash algorithm, with
+    // measured TVM pages.
+    fn attestation_manager_with<D: Digest>(
+        hash_algorithm: ObjectIdentifier,
+    ) -> AttestationManager<D> {
+        let dice_cdis: Option<(&[u8], &[u8])> = Some((&[1u8; 32], &[2u8; 32]));
+        let mgr = AttestationManager::<D>::new(dice_cdis, 1, hash_algorithm).unwrap();
+        mgr.extend_tvm_page(&[0xa5u8; 4096], 0x8020_0000).unwrap();
+        mgr.extend_tvm_page(&[0x5au8; 4096], 0x8020_1000).unwrap();
+        mgr.set_epc(0x8020_0000);
+        mgr.set_arg(0);
+        mgr.finalize().unwrap();
+        mgr
+    }
+
+    // Returns the TVM attestation CDI certificate, as the DICE chain guest call does.
+    fn tvm_certificate<D: Digest>(mgr: &AttestationManager<D>) -> Vec<u8> {
+        let mut cert = [0u8; 4096];
+        let len = mgr.tvm_certificate(&mut cert).unwrap();
+        cert[..len].to_vec()
+    }
+
+    // Encodes the `mgr` measurement registers and event log digest as a DICE TcbInfo extension.
+    fn tcb_info_extension<D: Digest>(
+        mgr: &AttestationManager<D>,
+        hash_algorithm: ObjectIdentifier,
+    ) -> Vec<u8> {
+        let mut tcb_info = DiceTcbInfo::new();
+        let msmt_regs = mgr.measurement_registers().unwrap();
+        let event_log_digest = mgr.event_log_digest().unwrap();
+        for m in msmt_regs.iter().chain(core::iter::once(&event_log_digest)) {
+            tcb_info
+                .add_fwid::<D>(hash_algorithm, GenericArray::from_slice(m.as_slice()))
+                .unwrap();
+        }
+        let mut tcb_info_bytes = [0u8; 4096];
+        tcb_info.to_extension(&mut tcb_info_bytes).unwrap().to_vec()
+    }
+
+    // Issues the evidence certificate for `EVIDENCE_CSR` the same way U-mode does: the TVM
+    // attestation CDI certifies the CSR key, with the TVM measurements as TcbInfo FWIDs.
+    fn evidence_certificate(mgr: &AttestationManager<sha2::Sha384>) -> Vec<u8> {
+        let tcb_info_extn = tcb_info_extension(mgr, ID_SHA_384);
+        let extensions: [&[u8]; 1] = [&tcb_info_extn];
+
+        let csr = CertReq::from_der(EVIDENCE_CSR).unwrap();
+        let layer: Layer<PUBLIC_KEY_LENGTH, Signature, _, sha2::Sha384> = Layer::new(
+            ManagerCdi::Current(mgr.attestation_current_cdi()),
+            Some(ManagerCdi::Current(mgr.attestation_current_cdi())),
+        );
+        layer
+            .csr_certificate(&csr, Some(&extensions))
+            .unwrap()
+            .to_vec()
+    }
+
+    // Returns the TVM DICE chain, followed by the evidence certificate.
+    fn evidence_chain(mgr: &AttestationManager<sha2::Sha384>) -> Vec<u8> {
+        let mut chain = tvm_certificate(mgr);
+        chain.extend_from_slice(&evidence_certificate(mgr));
+        chain
+    }
+
+    fn trusted_root<D: Digest>(mgr: &AttestationManager<D>) -> [u8; PUBLIC_KEY_LENGTH] {
+        mgr.tsm_public_key()
+    }
+
+    #[test]
+    fn verify_tvm_certificate() {
+        let mgr = attestation_manager();
+        let cert = tvm_certificate(&mgr);
+        let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+        let policy = Policy::new(trusted_root(&mgr))
+            .hash_algorithm(ID_SHA_384)
+            .expect_measurement(TcgPcrIndex::TvmPage, tvm_pages.as_slice());
+
+        let measurements = verify(&cert, &policy).unwrap();
+        assert_eq!(measurements.hash_algorithm(), ID_SHA_384);
+        assert_eq!(
+            measurements.register(TcgPcrIndex::TvmPage),
+            tvm_pages.as_slice()
+        );
+        assert_eq!(
+            measurements.event_log_digest(),
+            Some(mgr.event_log_digest().unwrap().as_slice())
+        );
+    }
+
+    #[test]
+    fn untrusted_root() {
+        let mgr = attestation_manager();
+        let cert = tvm_certificate(&mgr);
+        let policy = Policy::new([0x42u8; PUBLIC_KEY_LENGTH]);
+        assert!(verify(&cert, &policy).is_err());
+    }
+
+    #[test]
+    fn tampered_certificate() {
+        let mgr = attestation_manager();
+        let mut cert = tvm_certificate(&mgr);
+        // Flip a bit in the TVM pages measurement.
+        let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+        let pos = cert
+            .windows(tvm_pages.len())
+            .position(|w| w == tvm_pages.as_slice())
+            .unwrap();
+        cert[pos] ^= 1;
+        let policy = Policy::new(trusted_root(&mgr));
+        assert!(matches!(
+            verify(&cert, &policy),
+            Err(Error::InvalidSignature(0))
+        ));
+    }
+
+    #[test]
+    fn measurement_mismatch() {
+        let mgr = attestation_manager();
+        let cert = tvm_certificate(&mgr);
+        let policy = Policy::new(trusted_root(&mgr))
+            .expect_measurement(TcgPcrIndex::TvmConfiguration, &[0u8; 48]);
+        assert!(matches!(
+            verify(&cert, &policy),
+            Err(Error::MeasurementMismatch(TcgPcrIndex::TvmConfiguration))
+        ));
+    }
+
+    #[test]
+    fn unexpected_hash_algorithm() {
+        let mgr = attestation_manager();
+        let cert = tvm_certificate(&mgr);
+        let policy = Policy::new(trusted_root(&mgr)).hash_algorithm(ID_SHA_256);
+        assert!(matches!(
+            verify(&cert, &policy),
+            Err(Error::UnexpectedHashAlgorithm(ID_SHA_384))
+        ));
+    }
+
+    #[test]
+    fn chain_of_two() {
+        // Issue the same TVM certificate twice: the second one is not issued by the first
+        // certificate subject.
+        let mgr = attestation_manager();
+        let cert = tvm_certificate(&mgr);
+        let mut chain = cert.clone();
+        chain.extend_from_slice(&cert);
+        let policy = Policy::new(trusted_root(&mgr));
+        assert!(matches!(
+            verify(&chain, &policy),
+            Err(Error::IssuerMismatch(1))
+        ));
+    }
+
+    // Checks the evidence of a TVM created with a `D` measurement hash algorithm.
+    fn check_tvm_evidence<D: Digest>(hash_algorithm: ObjectIdentifier) {
+        let mgr = attestation_manager_with::<D>(hash_algorithm);
+        let cert = tvm_certificate(&mgr);
+        let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+        assert_eq!(tvm_pages.len(), <D as Digest>::output_size());
+        let policy = Policy::new(trusted_root(&mgr))
+            .hash_algorithm(hash_algorithm)
+            .expect_measurement(TcgPcrIndex::TvmPage, tvm_pages.as_slice());
+
+        let measurements = verify(&cert, &policy).unwrap();
+        assert_eq!(measurements.hash_algorithm(), hash_algorithm);
+        assert_eq!(
+            measurements.register(TcgPcrIndex::TvmPage),
+            tvm_pages.as_slice()
+        );
+        assert_eq!(
+            measurements.event_log_digest(),
+            Some(mgr.event_log_digest().unwrap().as_slice())
+        );
+        // The same pages measured with SHA-384 give a different measurement.
+        let sha384_pages = attestation_manager()
+            .read_msmt_register(TcgPcrIndex::TvmPage)
+            .unwrap();
+        assert_ne!(tvm_pages.as_slice(), sha384_pages.as_slice());
+    }
+
+    #[test]
+    fn sha256_tvm_evidence() {
+        check_tvm_evidence::<sha2::Sha256>(ID_SHA_256);
+    }
+
+    #[test]
+    fn sha512_tvm_evidence() {
+        check_tvm_evidence::<sha2::Sha512>(ID_SHA_512);
+    }
+
+    #[test]
+    fn verify_csr_evidence() {
+        let mgr = attestation_manager();
+        let chain = evidence_chain(&mgr);
+        let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+        let policy = Policy::new(trusted_root(&mgr))
+            .hash_algorithm(ID_SHA_384)
+            .chain_length(2)
+            .expect_measurement(TcgPcrIndex::TvmPage, tvm_pages.as_slice());
+
+        let measurements = verify(&chain, &policy).unwrap();
+        assert_eq!(
+            measurements.register(TcgPcrIndex::TvmPage),
+            tvm_pages.as_slice()
+        );
+        assert_eq!(
+            measurements.event_log_digest(),
+            Some(mgr.event_log_digest().unwrap().as_slice())
+        );
+    }
+
+    #[test]
+    fn self_issued_leaf() {
+        // The CSR key holder issues itself a certificate with made up measurements, and appends
+        // it to its evidence chain.
+        let mgr = attestation_manager();
+        let mut chain = evidence_chain(&mgr);
+        let forged_mgr = attestation_manager_with::<sha2::Sha384>(ID_SHA_384);
+        forged_mgr
+            .extend_msmt_register(TcgPcrIndex::RuntimePcr0, &[0x42; 48], None, None)
+            .unwrap();
+        let tcb_info_extn = tcb_info_extension(&forged_mgr, ID_SHA_384);
+        let extensions: [&[u8]; 1] = [&tcb_info_extn];
+        let layer: Layer<PUBLIC_KEY_LENGTH, Signature, _, sha2::Sha384> = Layer::new(
+            KeyCdi {
+                secret_key: EVIDENCE_CSR_SECRET_KEY,
+            },
+            Some(KeyCdi {
+                secret_key: EVIDENCE_CSR_SECRET_KEY,
+            }),
+        );
+        chain.extend_from_slice(&layer.next_certificate(Some(&extensions)).unwrap());
+
+        let policy = Policy::new(trusted_root(&mgr));
+        assert!(matches!(
+            verify(&chain, &policy),
+            Err(Error::IssuerNotCa(2))
+        ));
+    }
+
+    #[test]
+    fn unexpected_chain_length() {
+        let mgr = attestation_manager();
+        let chain = evidence_chain(&mgr);
+        let policy = Policy::new(trusted_root(&mgr)).chain_length(3);
+        assert!(matches!(
+            verify(&chain, &policy),
+            Err(Error::UnexpectedChainLength(2))
+        ));
+    }
+
+    // Returns the encoded event log of `mgr`, as the event log guest call does.
+    fn event_log<D: Digest>(mgr: &AttestationManager<D>) -> Vec<u8> {
+        let mut log = Vec::new();
+        mgr.for_each_event_log_record(usize::MAX, |record| -> attestation::Result<()> {
+            log.extend_from_slice(record);
+            Ok(())
+        })
+        .unwrap();
+        log
+    }
+
+    #[test]
+    fn replay_tvm_event_log() {
+        let mgr = attestation_manager();
+        mgr.extend_msmt_register(TcgPcrIndex::RuntimePcr0, &[0x42; 48], None, Some("runtime"))
+            .unwrap();
+        let cert = tvm_certificate(&mgr);
+        let measurements = verify(&cert, &Policy::new(trusted_root(&mgr))).unwrap();
+        replay_event_log(&event_log(&mgr), &measurements).unwrap();
+
+        // The log now holds an extension the evidence doesn't report.
+        mgr.extend_msmt_register(TcgPcrIndex::RuntimePcr1, &[0x42; 48], None, None)
+            .unwrap();
+        assert!(matches!(
+            replay_event_log(&event_log(&mgr), &measurements),
+            Err(Error::EventLogDigestMismatch)
+        ));
+    }
+
+    #[test]
+    fn replay_tvm_pages_and_configuration() {
+        let mgr = attestation_manager();
+        let cert = tvm_certificate(&mgr);
+
+        // Reference values computed from the TVM layout alone: a single region of two pages, and
+        // the entry PC and argument.
+        let mut image = sha2::Sha384::new();
+        image.update([0xa5u8; 4096]);
+        image.update([0x5au8; 4096]);
+        let tvm_pages =
+            extended_digest::<sha2::Sha384>(&[0u8; 48], &image.finalize(), Some(0x8020_0000));
+        let entry_pc = sha2::Sha384::digest(0x8020_0000u64.to_le_bytes());
+        let entry_arg = sha2::Sha384::digest(0u64.to_le_bytes());
+        let tvm_config = extended_digest::<sha2::Sha384>(
+            &extended_digest::<sha2::Sha384>(&[0u8; 48], &entry_pc, None),
+            &entry_arg,
+            None,
+        );
+
+        let policy = Policy::new(trusted_root(&mgr))
+            .expect_measurement(TcgPcrIndex::TvmPage, tvm_pages.as_slice())
+            .expect_measurement(TcgPcrIndex::TvmConfiguration, tvm_config.as_slice());
+        let measurements = verify(&cert, &policy).unwrap();
+        replay_event_log(&event_log(&mgr), &measurements).unwrap();
+
+        // The log of a TVM with another configuration doesn't replay to these measurements.
+        let other = AttestationManager::<sha2::Sha384>::new(None, 1, ID_SHA_384).unwrap();
+        other.extend_tvm_page(&[0xa5u8; 4096], 0x8020_0000).unwrap();
+        other.extend_tvm_page(&[0x5au8; 4096], 0x8020_1000).unwrap();
+        other.set_epc(0x8020_1000);
+        other.finalize().unwrap();
+        assert!(replay_event_log(&event_log(&other), &measurements).is_err());
+    }
+
+    #[test]
+    fn replay_dropped_events() {
+        let mgr = attestation_manager();
+        for _ in 0..MAX_RUNTIME_EVENTS + 1 {
+            mgr.extend_msmt_register(TcgPcrIndex::RuntimePcr0, &[0x42; 48], None, None)
+                .unwrap();
+        }
+        let cert = tvm_certificate(&mgr);
+        let measurements = verify(&cert, &Policy::new(trusted_root(&mgr))).unwrap();
+        assert!(matches!(
+            replay_event_log(&event_log(&mgr), &measurements),
+            Err(Error::DroppedEvents(1))
+        ));
+    }
+}
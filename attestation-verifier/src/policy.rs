@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use attestation::TcgPcrIndex;
+use const_oid::ObjectIdentifier;
+use ed25519_dalek::PUBLIC_KEY_LENGTH;
+
+use crate::evidence::Measurements;
+use crate::{Error, Result};
+
+/// An evidence verification policy: the public key the certificate chains must be rooted at,
+/// and the expected measurements.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    trusted_root: [u8; PUBLIC_KEY_LENGTH],
+    hash_algorithm: Option<ObjectIdentifier>,
+    chain_length: Option<usize>,
+    expected: Vec<(TcgPcrIndex, Vec<u8>)>,
+}
+
+impl Policy {
+    /// Creates a policy for chains rooted at the `trusted_root` Ed25519 public key, and with no
+    /// expected measurements.
+    pub fn new(trusted_root: [u8; PUBLIC_KEY_LENGTH]) -> Self {
+        Self {
+            trusted_root,
+            hash_algorithm: None,
+            chain_length: None,
+            expected: Vec::new(),
+        }
+    }
+
+    /// Requires the measurements to use `hash_algorithm`.
+    pub fn hash_algorithm(mut self, hash_algorithm: ObjectIdentifier) -> Self {
+        self.hash_algorithm = Some(hash_algorithm);
+        self
+    }
+
+    /// Requires the certificate chains to hold exactly `chain_length` certificates, i.e. pins
+    /// the DICE layers between the trusted root and the evidence certificate.
+    pub fn chain_length(mut self, chain_length: usize) -> Self {
+        self.chain_length = Some(chain_length);
+        self
+    }
+
+    /// Requires the `pcr` measurement register to be `digest`.
+    pub fn expect_measurement(mut self, pcr: TcgPcrIndex, digest: &[u8]) -> Self {
+        self.expected.push((pcr, digest.to_vec()));
+        self
+    }
+
+    /// Returns the trusted root public key.
+    pub fn trusted_root(&self) -> &[u8; PUBLIC_KEY_LENGTH] {
+        &self.trusted_root
+    }
+
+    /// Checks a certificate chain length against the policy.
+    pub fn check_chain_length(&self, chain_length: usize) -> Result<()> {
+        match self.chain_length {
+            Some(expected) if expected != chain_length => {
+                Err(Error::UnexpectedChainLength(chain_length))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `measurements` against the policy.
+    pub fn check(&self, measurements: &Measurements) -> Result<()> {
+        if let Some(hash_algorithm) = self.hash_algorithm {
+            if measurements.hash_algorithm() != hash_algorithm {
+                return Err(Error::UnexpectedHashAlgorithm(
+                    measurements.hash_algorithm(),
+                ));
+            }
+        }
+        for (pcr, digest) in self.expected.iter() {
+            if measurements.register(*pcr) != digest.as_slice() {
+                return Err(Error::MeasurementMismatch(*pcr));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use arrayvec::ArrayVec;
+use digest::{Digest, OutputSizeUser};
+
+use crate::measurement::MeasurementRegisterDigest;
+use crate::{Error, Result};
+
+/// Maximum number of events recorded in a TVM event log.
+pub const MAX_EVENTS: usize = 256;
+
+/// Maximum number of runtime events, i.e. extensions of the dynamic measurement registers,
+/// recorded in a TVM event log. The rest of the log is reserved for the static measurement
+/// registers, so that a TVM can't evict its boot-time measurements by extending its runtime
+/// registers.
+pub const MAX_RUNTIME_EVENTS: usize = 128;
+
+/// Maximum length of an event description.
+pub const MAX_EVENT_DESCRIPTION_LEN: usize = 32;
+
+/// Maximum length of an encoded event log record.
+pub const MAX_RECORD_LEN: usize = 256;
+
+/// Length of a TCG Canonical Event Log (CEL) TLV header. Every field is encoded as a 1 byte type,
+/// a 4 bytes big endian length and the value.
+pub const CEL_TLV_HEADER_LEN: usize = 5;
+/// CEL record number field type.
+pub const CEL_T_RECNUM: u8 = 0;
+/// CEL PCR index field type.
+pub const CEL_T_PCR: u8 = 1;
+/// CEL digests field type.
+pub const CEL_T_DIGESTS: u8 = 3;
+/// Salus event content field type, in the CEL vendor range.
+pub const CEL_T_SALUS_EVENT: u8 = 0xc0;
+
+/// Salus event measured data address field type.
+pub const SALUS_EVENT_ADDRESS: u8 = 0;
+/// Salus event description field type.
+pub const SALUS_EVENT_DESCRIPTION: u8 = 1;
+/// Salus dropped events count field type.
+pub const SALUS_EVENT_DROPPED: u8 = 2;
+/// Salus event measured data length field type.
+pub const SALUS_EVENT_LENGTH: u8 = 3;
+
+/// TPM_ALG_ID of SHA-256 digests. The supported TPM_ALG_IDs all fit in the CEL digest type byte.
+pub const TPM_ALG_SHA256: u8 = 0x0b;
+/// TPM_ALG_ID of SHA-384 digests.
+pub const TPM_ALG_SHA384: u8 = 0x0c;
+/// TPM_ALG_ID of SHA-512 digests.
+pub const TPM_ALG_SHA512: u8 = 0x0d;
+
+/// A measurement event, i.e. a single measurement register extension.
+pub struct MeasurementEvent<D: Digest> {
+    // TCG PCR index of the extended register.
+    pcr_index: u8,
+
+    // The digest the register was extended with.
+    digest: MeasurementRegisterDigest<D>,
+
+    // The measured data address, if any. The register was extended with it, along with the
+    // digest. Always set for `TvmPage` events, to the region start address.
+    address: Option<u64>,
+
+    // The measured data length, if any. Only set for `TvmPage` events, that measure a whole
+    // region of contiguous pages.
+    length: Option<u64>,
+
+    // Optional event description.
+    description: ArrayVec<u8, MAX_EVENT_DESCRIPTION_LEN>,
+}
+
+/// A bounded event log, recording the TVM measurement register extensions.
+///
+/// The log is exported as a TCG Canonical Event Log, in its TLV encoding. Each record carries
+/// the record number, the PCR index, the extended digest and a Salus specific content with the
+/// event address, length and description.
+///
+/// Static register extensions are never dropped: once their part of the log is full, they can't
+/// be recorded and the extension must fail. Runtime events beyond `MAX_RUNTIME_EVENTS` are dropped
+/// and counted, and a last record then reports the number of dropped events: such a log can not
+/// be replayed.
+pub struct EventLog<D: Digest> {
+    events: ArrayVec<MeasurementEvent<D>, MAX_EVENTS>,
+    runtime_events: usize,
+    dropped: u64,
+}
+
+impl<D: Digest> Default for EventLog<D> {
+    fn default() -> Self {
+        Self {
+            events: ArrayVec::new(),
+            runtime_events: 0,
+            dropped: 0,
+        }
+    }
+}
+
+// A CEL TLV writer over a record buffer.
+struct TlvWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> TlvWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self
+            .pos
+            .checked_add(bytes.len())
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(Error::EventLogRecordTooLarge)?;
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn header(&mut self, tlv_type: u8, len: usize) -> Result<()> {
+        self.put(&[tlv_type])?;
+        self.put(&(len as u32).to_be_bytes())
+    }
+
+    fn tlv(&mut self, tlv_type: u8, value: &[u8]) -> Result<()> {
+        self.header(tlv_type, value.len())?;
+        self.put(value)
+    }
+
+    fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+fn tpm_alg_id<D: Digest>() -> Result<u8> {
+    match <D as OutputSizeUser>::output_size() {
+        32 => Ok(TPM_ALG_SHA256),
+        48 => Ok(TPM_ALG_SHA384),
+        64 => Ok(TPM_ALG_SHA512),
+        size => Err(Error::UnsupportedHashAlgorithm(size)),
+    }
+}
+
+impl<D: Digest> EventLog<D> {
+    /// Returns an error if a static register extension can't be recorded because the part of the
+    /// log reserved for those is full.
+    pub fn check_static_room(&self) -> Result<()> {
+        if self.events.len() - self.runtime_events >= MAX_EVENTS - MAX_RUNTIME_EVENTS {
+            return Err(Error::EventLogFull);
+        }
+        Ok(())
+    }
+
+    /// Records a measurement event. `runtime` events are dropped if `MAX_RUNTIME_EVENTS` of them
+    /// are already recorded. Static register events must have been checked for room with
+    /// `check_static_room`.
+    pub fn record(
+        &mut self,
+        runtime: bool,
+        pcr_index: u8,
+        digest: &[u8],
+        address: Option<u64>,
+        length: Option<u64>,
+        description: Option<&str>,
+    ) {
+        let mut desc = ArrayVec::new();
+        if let Some(d) = description {
+            let len = d.len().min(MAX_EVENT_DESCRIPTION_LEN);
+            // Unwrap ok: `len` is at most the description capacity.
+            desc.try_extend_from_slice(&d.as_bytes()[..len]).unwrap();
+        }
+        let event = MeasurementEvent {
+            pcr_index,
+            digest: MeasurementRegisterDigest::<D>::clone_from_slice(digest),
+            address,
+            length,
+            description: desc,
+        };
+        if runtime {
+            if self.runtime_events >= MAX_RUNTIME_EVENTS {
+                self.dropped += 1;
+                return;
+            }
+            self.runtime_events += 1;
+        }
+        // Unwrap ok: runtime events are capped above and static events were checked for room, so
+        // the log can't be full.
+        self.events.try_push(event).unwrap();
+    }
+
+    /// Returns the number of runtime events that were dropped because the log was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Encodes the `recnum` event into `buf` and returns the record length.
+    fn encode_event(recnum: u64, event: &MeasurementEvent<D>, buf: &mut [u8]) -> Result<usize> {
+        let digest_len = <D as OutputSizeUser>::output_size();
+        let mut content_len = 0;
+        if event.address.is_some() {
+            content_len += CEL_TLV_HEADER_LEN + 8;
+        }
+        if event.length.is_some() {
+            content_len += CEL_TLV_HEADER_LEN + 8;
+        }
+        if !event.description.is_empty() {
+            content_len += CEL_TLV_HEADER_LEN + event.description.len();
+        }
+
+        let mut w = TlvWriter::new(buf);
+        w.tlv(CEL_T_RECNUM, &recnum.to_be_bytes())?;
+        w.tlv(CEL_T_PCR, &[event.pcr_index])?;
+        w.header(CEL_T_DIGESTS, CEL_TLV_HEADER_LEN + digest_len)?;
+        w.tlv(tpm_alg_id::<D>()?, &event.digest)?;
+        w.header(CEL_T_SALUS_EVENT, content_len)?;
+        if let Some(address) = event.address {
+            w.tlv(SALUS_EVENT_ADDRESS, &address.to_be_bytes())?;
+        }
+        if let Some(length) = event.length {
+            w.tlv(SALUS_EVENT_LENGTH, &length.to_be_bytes())?;
+        }
+        if !event.description.is_empty() {
+            w.tlv(SALUS_EVENT_DESCRIPTION, &event.description)?;
+        }
+        Ok(w.len())
+    }
+
+    // Encodes the dropped events record into `buf` and returns the record length.
+    fn encode_dropped(&self, recnum: u64, buf: &mut [u8]) -> Result<usize> {
+        let mut w = TlvWriter::new(buf);
+        w.tlv(CEL_T_RECNUM, &recnum.to_be_bytes())?;
+        w.header(CEL_T_SALUS_EVENT, CEL_TLV_HEADER_LEN + 8)?;
+        w.tlv(SALUS_EVENT_DROPPED, &self.dropped.to_be_bytes())?;
+        Ok(w.len())
+    }
+
+    /// Encodes the log as a TCG Canonical Event Log and calls `f` on each encoded record, in
+    /// order. Stops at the first error returned by `f`.
+    pub fn for_each_record<E, F>(&self, mut f: F) -> core::result::Result<(), E>
+    where
+        E: From<Error>,
+        F: FnMut(&[u8]) -> core::result::Result<(), E>,
+    {
+        let mut record = [0u8; MAX_RECORD_LEN];
+        for (recnum, event) in self.events.iter().enumerate() {
+            let len = Self::encode_event(recnum as u64, event, &mut record)?;
+            f(&record[..len])?;
+        }
+        if self.dropped > 0 {
+            let len = self.encode_dropped(self.events.len() as u64, &mut record)?;
+            f(&record[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the encoded log.
+    pub fn encoded_len(&self) -> Result<usize> {
+        let mut len = 0;
+        self.for_each_record(|r| -> Result<()> {
+            len += r.len();
+            Ok(())
+        })?;
+        Ok(len)
+    }
+
+    /// Returns the digest of the encoded log.
+    pub fn digest(&self) -> Result<MeasurementRegisterDigest<D>> {
+        let mut hasher = D::new();
+        self.for_each_record(|r| -> Result<()> {
+            hasher.update(r);
+            Ok(())
+        })?;
+        Ok(hasher.finalize())
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use sync::Once;
+use zeroize::Zeroize;
+
+use crate::{Error, Result};
+
+/// Length of the CDIs passed by the previous layer.
+pub const HANDOFF_CDI_LEN: usize = 32;
+
+// "DICE", little endian.
+const HANDOFF_MAGIC: u32 = 0x4543_4944;
+const HANDOFF_VERSION: u32 = 1;
+
+// Header layout, all fields are little endian:
+//
+// +--------+---------+----------------+----------+-----------------+-------------+------------+
+// | magic  | version | cert_chain_len | reserved | attestation_cdi | sealing_cdi | cert_chain |
+// | u32    | u32     | u32            | u32      | [u8; 32]        | [u8; 32]    | [u8]       |
+// +--------+---------+----------------+----------+-----------------+-------------+------------+
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 4;
+const CERT_CHAIN_LEN_OFFSET: usize = 8;
+const ATTESTATION_CDI_OFFSET: usize = 16;
+const SEALING_CDI_OFFSET: usize = ATTESTATION_CDI_OFFSET + HANDOFF_CDI_LEN;
+const CERT_CHAIN_OFFSET: usize = SEALING_CDI_OFFSET + HANDOFF_CDI_LEN;
+
+// The DICE handoff passed by firmware. Only set once at boot.
+static DICE_HANDOFF: Once<DiceHandoff<'static>> = Once::new();
+
+/// The DICE data handed off by the previous boot layer (i.e. the platform firmware) to Salus.
+///
+/// It contains the Salus layer CDIs and the DER-encoded certificate chain linking the Salus
+/// layer to the platform device identity. Certificates are concatenated, starting from the one
+/// closest to the root of trust and ending with the Salus layer certificate.
+///
+/// The CDIs are copied out of the handoff blob, which is then wiped of them.
+pub struct DiceHandoff<'a> {
+    attestation_cdi: [u8; HANDOFF_CDI_LEN],
+    sealing_cdi: [u8; HANDOFF_CDI_LEN],
+    cert_chain: &'a [u8],
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    let field = bytes
+        .get(offset..offset + 4)
+        .ok_or(Error::InvalidDiceHandoff)?;
+    // Unwrap ok: `field` is 4 bytes long.
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+impl<'a> DiceHandoff<'a> {
+    /// Parses a DICE handoff blob, and zeroes the CDIs it carries once they're copied out.
+    pub fn from_bytes(bytes: &'a mut [u8]) -> Result<Self> {
+        if read_u32(bytes, MAGIC_OFFSET)? != HANDOFF_MAGIC
+            || read_u32(bytes, VERSION_OFFSET)? != HANDOFF_VERSION
+        {
+            return Err(Error::InvalidDiceHandoff);
+        }
+        let cert_chain_len = read_u32(bytes, CERT_CHAIN_LEN_OFFSET)? as usize;
+        if bytes.len() < CERT_CHAIN_OFFSET + cert_chain_len {
+            return Err(Error::InvalidDiceHandoff);
+        }
+        let (header, cert_chain) = bytes.split_at_mut(CERT_CHAIN_OFFSET);
+        let mut attestation_cdi = [0u8; HANDOFF_CDI_LEN];
+        attestation_cdi.copy_from_slice(&header[ATTESTATION_CDI_OFFSET..SEALING_CDI_OFFSET]);
+        let mut sealing_cdi = [0u8; HANDOFF_CDI_LEN];
+        sealing_cdi.copy_from_slice(&header[SEALING_CDI_OFFSET..]);
+        header[ATTESTATION_CDI_OFFSET..].zeroize();
+
+        Ok(Self {
+            attestation_cdi,
+            sealing_cdi,
+            cert_chain: &cert_chain[..cert_chain_len],
+        })
+    }
+
+    /// Returns the Salus layer attestation CDI.
+    pub fn attestation_cdi(&self) -> &[u8] {
+        &self.attestation_cdi
+    }
+
+    /// Returns the Salus layer sealing CDI.
+    pub fn sealing_cdi(&self) -> &[u8] {
+        &self.sealing_cdi
+    }
+
+    /// Returns the DER-encoded certificate chain, up to and including the Salus layer
+    /// certificate.
+    pub fn cert_chain(&self) -> &'a [u8] {
+        self.cert_chain
+    }
+}
+
+impl Drop for DiceHandoff<'_> {
+    fn drop(&mut self) {
+        self.attestation_cdi.zeroize();
+        self.sealing_cdi.zeroize();
+    }
+}
+
+impl DiceHandoff<'static> {
+    /// Parses and installs the DICE handoff passed by firmware. Must be called at most once,
+    /// before any `AttestationManager` is created. The CDIs are zeroed in `bytes`.
+    pub fn init(bytes: &'static mut [u8]) -> Result<()> {
+        let handoff = Self::from_bytes(bytes)?;
+        DICE_HANDOFF.call_once(|| handoff);
+        Ok(())
+    }
+
+    /// Returns the DICE handoff passed by firmware, if any.
+    pub fn get() -> Option<&'static DiceHandoff<'static>> {
+        DICE_HANDOFF.get()
+    }
+}
@@ -21,6 +21,12 @@ pub enum Error {
     /// The DICE engine failed to generate a TCB DICE extension.
     DiceTcbInfo(rice::Error),
 
+    /// The DICE engine failed to build a certificate.
+    DiceCertificate(rice::Error),
+
+    /// The certificate is larger than the caller buffer
+    CertificateBufferTooSmall(usize),
+
     /// Invalid measurement register descriptor index
     InvalidMeasurementRegisterDescIndex(usize),
 
@@ -33,8 +39,35 @@ pub enum Error {
     /// Derived Key is too short
     DerivedKeyTooShort,
 
+    /// The measurement digest length matches no supported hash algorithm
+    UnsupportedHashAlgorithm(usize),
+
     /// The DICE engined failed to retrieve the CDI ID.
     DiceCdiId(rice::Error),
+
+    /// The DICE handoff from the previous layer is malformed.
+    InvalidDiceHandoff,
+
+    /// Measurement digest length does not match the hash algorithm
+    InvalidMeasurementDigestLength(usize),
+
+    /// An event log record does not fit in the record buffer
+    EventLogRecordTooLarge,
+
+    /// The encoded event log is larger than the caller buffer
+    EventLogBufferTooSmall(usize),
+
+    /// The event log has no room left for static measurement register extensions
+    EventLogFull,
+
+    /// The sealing key derivation failed.
+    SealingKeyDerivation,
+
+    /// The previous layer handed off no sealing CDI to derive sealing keys from.
+    NoSealingCdi,
+
+    /// The measurement register mask selects registers that don't exist.
+    InvalidMeasurementRegisterMask(u64),
 }
 
 /// Custom attestation result.
@@ -177,10 +210,16 @@ macro_rules! impl_newtype {
     };
 }
 
+/// The measurement event log
+pub mod event_log;
+/// The DICE handoff from the previous boot layer
+pub mod handoff;
 /// The attesation manager
 pub mod manager;
 // TCB layer measurement module
 mod measurement;
 
 // Alias and be less mouthful.
+pub use handoff::DiceHandoff;
 pub use manager::AttestationManager;
+pub use measurement::extended_digest;
@@ -6,19 +6,25 @@ use arrayvec::ArrayVec;
 use const_oid::ObjectIdentifier;
 use core::marker::PhantomData;
 use digest::{Digest, OutputSizeUser};
-use ed25519::Signature;
-use ed25519_dalek::SECRET_KEY_LENGTH;
+use ed25519::{
+    signature::{Error as SignatureError, Signer},
+    Signature,
+};
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
 use generic_array::GenericArray;
 use hkdf::HmacImpl;
 use rice::{
-    cdi::{CdiType, CompoundDeviceIdentifier},
-    layer::LayerBase,
+    cdi::{CdiType, CompoundDeviceIdentifier, CDI_ID_LEN},
+    layer::{Layer, LayerBase},
     local_cdi::LocalCdi,
+    x509::extensions::dice::tcbinfo::DiceTcbInfo,
 };
 use sbi_rs::{AttestationCapabilities, EvidenceFormat, HashAlgorithm};
-use sync::RwLock;
+use sync::{Mutex, RwLock};
+use zeroize::Zeroize;
 
 use crate::{
+    event_log::EventLog,
     measurement::{MeasurementRegister, MeasurementRegisterDigest, TVM_MSMT_REGISTERS},
     Error, Result, TcgPcrIndex, DYNAMIC_MSMT_REGISTERS, MSMT_REGISTERS, STATIC_MSMT_REGISTERS,
 };
@@ -28,6 +34,18 @@ const TCB_SVN: u64 = 0xdeadbeef;
 
 const CDI_LEN: usize = 32;
 
+// Size of the buffer the TcbInfo extension of the TVM certificate is encoded into.
+const TCB_INFO_EXTENSION_LEN: usize = 4096;
+
+// The HKDF salt for sealing keys, separating them from any other key derived from the sealing CDI.
+// The TVM identifier changes across boots and is not part of the derivation, the TVM image is bound
+// through the sealing TCI instead.
+const SEALING_KEY_SALT: &[u8] = b"Salus TVM sealing key";
+
+// The attestation CDI used when the previous layer hands off no CDI. It is well known, so evidence
+// signed with it proves nothing and is reported as unendorsed.
+const UNENDORSED_ATTESTATION_CDI: &[u8] = b"Salus unendorsed attestation CDI";
+
 /// The TVM configuration data.
 /// This structure extends PCR3 when the TVM finalizes.
 #[derive(Clone, Default, Debug)]
@@ -49,20 +67,109 @@ impl TvmConfiguration {
     }
 }
 
+// A region of contiguous TVM pages, measured one after the other. The region is extended into the
+// TVM pages register and recorded in the event log as a single event, with the region start
+// address, length and the digest of all the region pages.
+struct TvmPagesRegion<D: Digest> {
+    // The region start address.
+    start: u64,
+
+    // The region length, in bytes.
+    length: u64,
+
+    // The running digest of the region pages.
+    hasher: D,
+}
+
+impl<D: Digest> TvmPagesRegion<D> {
+    fn new(bytes: &[u8], address: u64) -> Self {
+        Self {
+            start: address,
+            length: bytes.len() as u64,
+            hasher: D::new_with_prefix(bytes),
+        }
+    }
+
+    // Returns true if a page at `address` directly follows the region.
+    fn is_followed_by(&self, address: u64) -> bool {
+        self.start.checked_add(self.length) == Some(address)
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.length += bytes.len() as u64;
+    }
+}
+
+// A borrowed CDI, for issuing certificates from CDIs owned by the attestation manager.
+#[derive(Clone, Copy)]
+struct CdiRef<'a, C>(&'a C);
+
+impl<C> Zeroize for CdiRef<'_, C> {
+    fn zeroize(&mut self) {
+        // The CDI is zeroed by its owner.
+    }
+}
+
+impl<C: CompoundDeviceIdentifier<CDI_LEN, Signature>> CompoundDeviceIdentifier<CDI_LEN, Signature>
+    for CdiRef<'_, C>
+{
+    fn id(&self) -> core::result::Result<[u8; CDI_ID_LEN], rice::Error> {
+        self.0.id()
+    }
+
+    fn next(
+        &self,
+        _info: Option<&[u8]>,
+        _next_tci: Option<&[u8]>,
+    ) -> core::result::Result<Self, rice::Error> {
+        // Certificates are only issued for existing CDIs, layers are never rolled from a borrowed
+        // CDI.
+        unreachable!();
+    }
+
+    fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        self.0.public_key()
+    }
+}
+
+impl<C: Signer<Signature>> Signer<Signature> for CdiRef<'_, C> {
+    fn try_sign(&self, msg: &[u8]) -> core::result::Result<Signature, SignatureError> {
+        self.0.try_sign(msg)
+    }
+}
+
 /// The attestation manager.
 pub struct AttestationManager<D: Digest, H: HmacImpl<D> = hmac::Hmac<D>> {
     // Measurement registers
     measurements: RwLock<ArrayVec<MeasurementRegister<D>, MSMT_REGISTERS>>,
 
+    // Measurement registers extensions log
+    event_log: RwLock<EventLog<D>>,
+
+    // The TVM pages region being measured, not yet extended into the TVM pages register.
+    tvm_pages_region: Mutex<Option<TvmPagesRegion<D>>>,
+
+    // The TSM (Salus) layer attestation CDI, i.e. the parent of the TVM attestation CDI.
+    tsm_attestation_cdi: LocalCdi<CDI_LEN, D, H>,
+
     // The attestation DICE layer (Built from the attestation TCI)
     attestation_layer: LayerBase<CDI_LEN, Signature, LocalCdi<CDI_LEN, D, H>>,
 
-    // The sealing DICE layer (Built from the sealing TCI)
-    sealing_layer: LayerBase<CDI_LEN, Signature, LocalCdi<CDI_LEN, D, H>>,
+    // The sealing CDI secret, from the previous layer. Sealing keys are derived from it and from the
+    // sealing TCI. There are no sealing keys without a sealing CDI from the previous layer.
+    sealing_cdi: Option<[u8; CDI_LEN]>,
+
+    // Whether the attestation CDI comes from the previous layer, i.e. whether evidence signed with it
+    // can be verified up to the platform root of trust.
+    endorsed: bool,
 
     // TVM identifier
     vm_id: u64,
 
+    // The measurement hash algorithm.
+    hash_algorithm: ObjectIdentifier,
+
     // TVM configuration.
     // The data here goes into PCR3 when the TVM finalizes.
     tvm_config: RwLock<TvmConfiguration>,
@@ -71,10 +178,11 @@ pub struct AttestationManager<D: Digest, H: HmacImpl<D> = hmac::Hmac<D>> {
 }
 
 impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
-    /// Create a new attestation manager.
+    /// Create a new attestation manager, from the attestation and sealing CDIs handed off by the
+    /// previous layer. Without them, evidence is signed with a well-known CDI and reported as
+    /// unendorsed, and no sealing key can be derived.
     pub fn new(
-        attestation_cdi: &'a [u8],
-        sealing_cdi: &'a [u8],
+        dice_cdis: Option<(&'a [u8], &'a [u8])>,
         vm_id: u64,
         hash_algorithm: ObjectIdentifier,
     ) -> Result<Self> {
@@ -84,6 +192,8 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         if <D as OutputSizeUser>::output_size() < SECRET_KEY_LENGTH {
             return Err(Error::DerivedKeyTooShort);
         }
+        // The measurement registers are reported with their hash algorithm.
+        Self::hash_algorithm()?;
 
         let mut measurements = ArrayVec::<MeasurementRegister<D>, MSMT_REGISTERS>::new();
 
@@ -91,6 +201,7 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
             measurements.insert(idx, msmt.build(hash_algorithm));
         }
 
+        let attestation_cdi = dice_cdis.map_or(UNENDORSED_ATTESTATION_CDI, |(cdi, _)| cdi);
         // The CDIs must have the same length as CDI_LEN, so we extract them if
         // that's not the case.
         let mut tmp_a_cdi = [0u8; CDI_LEN];
@@ -103,44 +214,110 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         };
         let local_attestation_cdi = LocalCdi::new(extracted_attestation_cdi, CdiType::Attestation)
             .map_err(Error::DiceCdiBuild)?;
+        let tsm_attestation_cdi = LocalCdi::new(extracted_attestation_cdi, CdiType::Attestation)
+            .map_err(Error::DiceCdiBuild)?;
 
-        let mut tmp_s_cdi = [0u8; CDI_LEN];
-        let extracted_sealing_cdi = if sealing_cdi.len() != CDI_LEN {
-            rice::kdf::extract_cdi::<D, H>(sealing_cdi, &mut tmp_s_cdi)
-                .map_err(Error::DiceCdiExtraction)?;
-            &tmp_s_cdi
-        } else {
-            sealing_cdi
+        let local_sealing_cdi = match dice_cdis {
+            Some((_, sealing_cdi)) => {
+                let mut tmp_s_cdi = [0u8; CDI_LEN];
+                let extracted_sealing_cdi = if sealing_cdi.len() != CDI_LEN {
+                    rice::kdf::extract_cdi::<D, H>(sealing_cdi, &mut tmp_s_cdi)
+                        .map_err(Error::DiceCdiExtraction)?;
+                    &tmp_s_cdi
+                } else {
+                    sealing_cdi
+                };
+                let mut local_sealing_cdi = [0u8; CDI_LEN];
+                local_sealing_cdi.copy_from_slice(extracted_sealing_cdi);
+                tmp_s_cdi.zeroize();
+                Some(local_sealing_cdi)
+            }
+            None => None,
         };
-        let local_sealing_cdi = LocalCdi::new(extracted_sealing_cdi, CdiType::Attestation)
-            .map_err(Error::DiceCdiBuild)?;
 
         Ok(AttestationManager {
             measurements: RwLock::new(measurements),
+            event_log: RwLock::new(EventLog::default()),
+            tvm_pages_region: Mutex::new(None),
+            tsm_attestation_cdi,
             attestation_layer: LayerBase::new(local_attestation_cdi, None),
-            sealing_layer: LayerBase::new(local_sealing_cdi, None),
+            sealing_cdi: local_sealing_cdi,
+            endorsed: dice_cdis.is_some(),
             vm_id,
+            hash_algorithm,
             tvm_config: RwLock::new(Default::default()),
             _pd: PhantomData,
         })
     }
 
-    /// Extend one of the measurement registers.
+    /// Extend one of the measurement registers with a digest of the measured data.
     /// Optionally, this function takes the measured data physical address as
-    /// an argument. The register will then be extended with both the data and
+    /// an argument. The register will then be extended with both the digest and
     /// its address.
+    /// The extension is recorded in the event log, with an optional description.
     pub fn extend_msmt_register(
         &self,
         msmt_idx: TcgPcrIndex,
-        bytes: &[u8],
+        digest: &[u8],
         address: Option<u64>,
+        description: Option<&str>,
     ) -> Result<()> {
-        self.measurements
-            .write()
+        if digest.len() != <D as OutputSizeUser>::output_size() {
+            return Err(Error::InvalidMeasurementDigestLength(digest.len()));
+        }
+        let mut region = self.tvm_pages_region.lock();
+        self.flush_tvm_pages_region(&mut region)?;
+        self.extend_and_record(msmt_idx, digest, address, None, description)
+    }
+
+    // Extends a measurement register with `digest` and records the extension in the event log, so
+    // that every register can be replayed from the log.
+    fn extend_and_record(
+        &self,
+        msmt_idx: TcgPcrIndex,
+        digest: &[u8],
+        address: Option<u64>,
+        length: Option<u64>,
+        description: Option<&str>,
+    ) -> Result<()> {
+        let mut measurements = self.measurements.write();
+        let msmt = measurements
             .iter_mut()
             .find(|m| m.pcr_index == msmt_idx as u8)
-            .ok_or(Error::InvalidMeasurementRegisterIndex(msmt_idx as usize))?
-            .extend(bytes, address)
+            .ok_or(Error::InvalidMeasurementRegisterIndex(msmt_idx as usize))?;
+        // Record the event while still holding the measurements lock, so that the log order
+        // matches the extension order. Static register extensions are never dropped from the log,
+        // so fail before extending the register if there's no room left to record them.
+        let mut event_log = self.event_log.write();
+        if msmt.static_measurement {
+            event_log.check_static_room()?;
+        }
+        msmt.extend(digest, address)?;
+        event_log.record(
+            !msmt.static_measurement,
+            msmt_idx as u8,
+            digest,
+            address,
+            length,
+            description,
+        );
+        Ok(())
+    }
+
+    // Extends the TVM pages register with the pending TVM pages region, if any, and records it in
+    // the event log. The caller holds the region lock, so that regions are extended in the order
+    // they were measured in. Room for the region event was reserved when the region was started.
+    fn flush_tvm_pages_region(&self, region: &mut Option<TvmPagesRegion<D>>) -> Result<()> {
+        if let Some(r) = region.take() {
+            self.extend_and_record(
+                TcgPcrIndex::TvmPage,
+                &r.hasher.finalize(),
+                Some(r.start),
+                Some(r.length),
+                None,
+            )?;
+        }
+        Ok(())
     }
 
     /// Read a measurement register data.
@@ -148,6 +325,7 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         &self,
         msmt_idx: TcgPcrIndex,
     ) -> Result<GenericArray<u8, <D as OutputSizeUser>::OutputSize>> {
+        self.flush_tvm_pages_region(&mut self.tvm_pages_region.lock())?;
         Ok(self
             .measurements
             .read()
@@ -159,28 +337,99 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
     }
 
     /// Extend the TVM pages measurement.
-    /// This is a extend_msmt_register wrapper, where the address is not
-    /// optional, and the measurement register is fixed to TvmPage.
+    /// Pages measured at contiguous addresses, one after the other, form a region. The TVM pages
+    /// register is extended with the region start address and the digest of the region content,
+    /// and the region is recorded as a single event with its start address, length and digest.
+    /// The region is extended and recorded when a non contiguous page is measured, when another
+    /// register is extended, or when the measurements are read.
     pub fn extend_tvm_page(&self, bytes: &[u8], address: u64) -> Result<()> {
-        self.extend_msmt_register(TcgPcrIndex::TvmPage, bytes, Some(address))
+        let mut region = self.tvm_pages_region.lock();
+        {
+            // The register is only extended when the region ends: fail early if it's locked.
+            let measurements = self.measurements.read();
+            let msmt = measurements
+                .iter()
+                .find(|m| m.pcr_index == TcgPcrIndex::TvmPage as u8)
+                .ok_or(Error::InvalidMeasurementRegisterIndex(
+                    TcgPcrIndex::TvmPage as usize,
+                ))?;
+            if !msmt.extensible {
+                return Err(Error::LockedMeasurementRegister(msmt.fwid_index));
+            }
+        }
+        let continues_region = region.as_ref().map_or(false, |r| r.is_followed_by(address));
+        if !continues_region {
+            // Reserve room for the new region event before starting the region.
+            self.flush_tvm_pages_region(&mut region)?;
+            self.event_log.read().check_static_room()?;
+        }
+        match region.as_mut() {
+            Some(r) if continues_region => r.append(bytes),
+            _ => *region = Some(TvmPagesRegion::new(bytes, address)),
+        }
+        Ok(())
     }
 
     /// Extend the TVM configuration measurement.
-    /// This is a extend_msmt_register wrapper, where the address is not
-    /// optional, and the measurement register is fixed to TvmPage.
+    /// This is a extend_msmt_register wrapper, where the measurement register is
+    /// fixed to TvmConfiguration. The register is extended with the digests of the
+    /// entry PC and argument.
     pub fn extend_tvm_configuration(&self) -> Result<()> {
-        self.extend_msmt_register(
+        let mut region = self.tvm_pages_region.lock();
+        self.flush_tvm_pages_region(&mut region)?;
+        self.record_tvm_configuration()
+    }
+
+    // Extends the TVM configuration register. The caller holds the TVM pages region lock.
+    fn record_tvm_configuration(&self) -> Result<()> {
+        let entry_pc = self.tvm_config.read().entry_pc;
+        let entry_arg = self.tvm_config.read().entry_arg;
+        self.extend_and_record(
             TcgPcrIndex::TvmConfiguration,
-            &self.tvm_config.read().entry_pc.to_le_bytes(),
+            &D::digest(entry_pc.to_le_bytes()),
+            None,
             None,
+            Some("TVM entry PC"),
         )?;
-        self.extend_msmt_register(
+        self.extend_and_record(
             TcgPcrIndex::TvmConfiguration,
-            &self.tvm_config.read().entry_arg.to_le_bytes(),
+            &D::digest(entry_arg.to_le_bytes()),
             None,
+            None,
+            Some("TVM entry argument"),
         )
     }
 
+    /// Encodes the event log as a TCG Canonical Event Log and calls `f` on each
+    /// encoded record, in order. Stops at the first error returned by `f`.
+    /// The log is encoded from a single snapshot: if the encoded log is larger than `max_len`,
+    /// `f` is never called and `EventLogBufferTooSmall` is returned. Returns the encoded log
+    /// length.
+    pub fn for_each_event_log_record<E, F>(
+        &self,
+        max_len: usize,
+        f: F,
+    ) -> core::result::Result<usize, E>
+    where
+        E: From<Error>,
+        F: FnMut(&[u8]) -> core::result::Result<(), E>,
+    {
+        self.flush_tvm_pages_region(&mut self.tvm_pages_region.lock())?;
+        let event_log = self.event_log.read();
+        let len = event_log.encoded_len()?;
+        if len > max_len {
+            return Err(Error::EventLogBufferTooSmall(len).into());
+        }
+        event_log.for_each_record(f)?;
+        Ok(len)
+    }
+
+    /// Returns the digest of the encoded event log.
+    pub fn event_log_digest(&self) -> Result<MeasurementRegisterDigest<D>> {
+        self.flush_tvm_pages_region(&mut self.tvm_pages_region.lock())?;
+        self.event_log.read().digest()
+    }
+
     fn attestation_tci(&self) -> GenericArray<u8, <D as OutputSizeUser>::OutputSize> {
         // The attestation TCI only includes the static measurements.
         let mut hasher = D::new();
@@ -194,12 +443,19 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
     }
 
     fn sealing_tci(&self) -> GenericArray<u8, <D as OutputSizeUser>::OutputSize> {
-        // The sealing TCI includes all stable measurements.
+        // The sealing TCI only includes the static TVM measurements, i.e. the TVM image and
+        // configuration. Those are the same for every boot of a given TVM, but differ between TVMs,
+        // so that a TVM can't derive the sealing keys of another one by replaying its runtime
+        // measurements. The platform measurements are already bound to the sealing CDI we get from
+        // the previous layer.
         let mut hasher = D::new();
         self.measurements
             .read()
             .iter()
-            .filter(|m| m.stable)
+            .filter(|m| {
+                m.pcr_index == TcgPcrIndex::TvmPage as u8
+                    || m.pcr_index == TcgPcrIndex::TvmConfiguration as u8
+            })
             .for_each(|m| hasher.update(m.digest.clone()));
 
         hasher.finalize()
@@ -209,10 +465,15 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
     /// extended. This should be called after the platform boot process is
     /// finished in order to only allow for dynamic measurements.
     pub fn finalize(&self) -> Result<()> {
-        // Extend the TVM configuration PCR.
-        self.extend_tvm_configuration()?;
-        for m in self.measurements.write().iter_mut() {
-            m.finalize()
+        // Extend the last TVM pages region and the TVM configuration PCR, and lock the registers.
+        // The region lock is held throughout, so that no page is measured in between.
+        {
+            let mut region = self.tvm_pages_region.lock();
+            self.flush_tvm_pages_region(&mut region)?;
+            self.record_tvm_configuration()?;
+            for m in self.measurements.write().iter_mut() {
+                m.finalize()
+            }
         }
 
         // Build the next attestation DICE layer.
@@ -223,18 +484,51 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
             )
             .map_err(Error::DiceRoll)?;
 
-        // Build the next sealing DICE layer.
-        self.sealing_layer
-            .roll(Some(&self.vm_id.to_le_bytes()), Some(&self.sealing_tci()))
-            .map_err(Error::DiceRoll)?;
-
         Ok(())
     }
 
+    // Returns the digest of the `msmt_mask` selection of measurement registers: the mask, followed
+    // by the current value of every selected register, in index order.
+    fn selected_measurements(
+        &self,
+        msmt_mask: u64,
+    ) -> Result<GenericArray<u8, <D as OutputSizeUser>::OutputSize>> {
+        if msmt_mask >> MSMT_REGISTERS != 0 {
+            return Err(Error::InvalidMeasurementRegisterMask(msmt_mask));
+        }
+        let mut hasher = D::new_with_prefix(msmt_mask.to_le_bytes());
+        for (i, digest) in self.measurement_registers()?.iter().enumerate() {
+            if msmt_mask & (1 << i) != 0 {
+                hasher.update(digest);
+            }
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Derives a sealing key from the sealing CDI, the TVM image and configuration, the current
+    /// value of the measurement registers selected by `msmt_mask`, and the `info` derivation
+    /// context, and writes it to `key`. The key length is the length of `key`.
+    /// This must only be called once the manager is finalized.
+    pub fn derive_sealing_key(&self, msmt_mask: u64, info: &[u8], key: &mut [u8]) -> Result<()> {
+        let sealing_cdi = self.sealing_cdi.as_ref().ok_or(Error::NoSealingCdi)?;
+        let tci = self.sealing_tci();
+        let selected = self.selected_measurements(msmt_mask)?;
+        hkdf::Hkdf::<D, H>::new(Some(SEALING_KEY_SALT), sealing_cdi)
+            .expand_multi_info(&[tci.as_slice(), selected.as_slice(), info], key)
+            .map_err(|_| Error::SealingKeyDerivation)
+    }
+
+    /// Returns true if the attestation CDI comes from the previous layer, and false if evidence
+    /// is signed with a well-known CDI, in which case it must not be trusted.
+    pub fn endorsed(&self) -> bool {
+        self.endorsed
+    }
+
     /// Extract data from attestation layer for U-mode operation.
     pub fn measurement_registers(
         &self,
     ) -> Result<ArrayVec<MeasurementRegisterDigest<D>, MSMT_REGISTERS>> {
+        self.flush_tvm_pages_region(&mut self.tvm_pages_region.lock())?;
         Ok(self
             .measurements
             .read()
@@ -253,15 +547,31 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         self.tvm_config.write().set_arg(a1);
     }
 
+    /// Returns the measurement hash algorithm.
+    pub fn hash_algorithm() -> Result<HashAlgorithm> {
+        match <D as OutputSizeUser>::output_size() {
+            32 => Ok(HashAlgorithm::Sha256),
+            48 => Ok(HashAlgorithm::Sha384),
+            64 => Ok(HashAlgorithm::Sha512),
+            size => Err(Error::UnsupportedHashAlgorithm(size)),
+        }
+    }
+
     /// Build the attestation capabilities.
     pub fn capabilities(&self) -> Result<AttestationCapabilities> {
         let mut caps = AttestationCapabilities::new(
             TCB_SVN,
-            HashAlgorithm::Sha384,
+            Self::hash_algorithm()?,
             EvidenceFormat::DiceTcbInfo,
             STATIC_MSMT_REGISTERS as u8,
             DYNAMIC_MSMT_REGISTERS as u8,
         );
+        // Entity Attestation Tokens are generated alongside X.509 certificates.
+        caps.evidence_formats |= EvidenceFormat::Eat;
+        // The measurement hash algorithm is selected at TVM creation, `hash_algorithm` is the one
+        // this TVM uses.
+        caps.hash_algorithms |=
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha384 | HashAlgorithm::Sha512;
 
         for (idx, m) in self.measurements.read().iter().enumerate() {
             caps.add_measurement_register(m.to_sbi_descriptor(), idx)
@@ -271,8 +581,285 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         Ok(caps)
     }
 
+    /// Builds the TSM layer certificate for the TVM attestation CDI into `cert`, and returns the
+    /// certificate length. The certificate is issued by the TSM layer attestation CDI for the
+    /// current attestation CDI public key, and carries the measurement registers and the event
+    /// log digest as a TcbInfo extension.
+    ///
+    /// The TSM layer CDI is shared by all TVMs, so it only ever signs certificates built here,
+    /// from the TVM measurements.
+    pub fn tvm_certificate(&self, cert: &mut [u8]) -> Result<usize> {
+        let msmt_regs = self.measurement_registers()?;
+        let event_log_digest = self.event_log_digest()?;
+        let mut tcb_info = DiceTcbInfo::new();
+        for m in msmt_regs.iter().chain(core::iter::once(&event_log_digest)) {
+            tcb_info
+                .add_fwid::<D>(self.hash_algorithm, m)
+                .map_err(Error::DiceTcbInfo)?;
+        }
+        let mut tcb_info_bytes = [0u8; TCB_INFO_EXTENSION_LEN];
+        let tcb_info_extn = tcb_info
+            .to_extension(&mut tcb_info_bytes)
+            .map_err(Error::DiceTcbInfo)?;
+        let extensions: [&[u8]; 1] = [tcb_info_extn];
+
+        let layer: Layer<CDI_LEN, Signature, _, D> = Layer::new(
+            CdiRef(&self.tsm_attestation_cdi),
+            Some(CdiRef(self.attestation_layer.current_cdi())),
+        );
+        let cert_der = layer
+            .next_certificate(Some(&extensions))
+            .map_err(Error::DiceCertificate)?;
+        let cert_der_len = cert_der.len();
+        if cert.len() < cert_der_len {
+            return Err(Error::CertificateBufferTooSmall(cert_der_len));
+        }
+        cert[..cert_der_len].copy_from_slice(&cert_der);
+        Ok(cert_der_len)
+    }
+
+    /// Returns the public key of the TSM layer attestation CDI, the issuer of the TVM attestation
+    /// CDI certificate.
+    pub fn tsm_public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        self.tsm_attestation_cdi.public_key()
+    }
+
     /// Return a reference to the current CDI of the attestation layer.
     pub fn attestation_current_cdi(&self) -> &impl CompoundDeviceIdentifier<CDI_LEN, Signature> {
         self.attestation_layer.current_cdi()
     }
 }
+
+impl<D: Digest, H: HmacImpl<D>> Drop for AttestationManager<D, H> {
+    fn drop(&mut self) {
+        self.sealing_cdi.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_log::{MAX_EVENTS, MAX_RUNTIME_EVENTS};
+    use const_oid::db::rfc5912::ID_SHA_384;
+
+    const DICE_CDIS: Option<(&[u8], &[u8])> = Some((&[1u8; 32], &[2u8; 32]));
+
+    // Builds a finalized SHA-384 attestation manager for TVM `vm_id`, with a measured TVM page
+    // holding `page_byte`.
+    fn finalized_manager(vm_id: u64, page_byte: u8) -> AttestationManager<sha2::Sha384> {
+        let mgr = AttestationManager::<sha2::Sha384>::new(DICE_CDIS, vm_id, ID_SHA_384).unwrap();
+        mgr.extend_tvm_page(&[page_byte; 4096], 0x8020_0000)
+            .unwrap();
+        mgr.set_epc(0x8020_0000);
+        mgr.finalize().unwrap();
+        mgr
+    }
+
+    #[test]
+    fn sealing_key_is_bound_to_tvm_image() {
+        // Two boots of the same TVM get different VM identifiers, but the same sealing key.
+        let first = finalized_manager(1, 0xa5);
+        let second = finalized_manager(2, 0xa5);
+        let mut first_key = [0u8; 32];
+        let mut second_key = [0u8; 32];
+        first
+            .derive_sealing_key(0, b"policy", &mut first_key)
+            .unwrap();
+        second
+            .derive_sealing_key(0, b"policy", &mut second_key)
+            .unwrap();
+        assert_eq!(first_key, second_key);
+
+        // The derivation context separates the keys of a given TVM.
+        let mut context_key = [0u8; 32];
+        first
+            .derive_sealing_key(0, b"other", &mut context_key)
+            .unwrap();
+        assert_ne!(first_key, context_key);
+
+        // A TVM with a different image gets a different key, even when it uses the same
+        // derivation context, e.g. when the policy selects no measurement register.
+        let other = finalized_manager(3, 0x5a);
+        let mut other_key = [0u8; 32];
+        other
+            .derive_sealing_key(0, b"policy", &mut other_key)
+            .unwrap();
+        assert_ne!(first_key, other_key);
+
+        // So does a TVM with the same image and a different configuration.
+        let mgr = AttestationManager::<sha2::Sha384>::new(DICE_CDIS, 4, ID_SHA_384).unwrap();
+        mgr.extend_tvm_page(&[0xa5; 4096], 0x8020_0000).unwrap();
+        mgr.set_epc(0x8020_1000);
+        mgr.finalize().unwrap();
+        mgr.derive_sealing_key(0, b"policy", &mut other_key)
+            .unwrap();
+        assert_ne!(first_key, other_key);
+    }
+
+    #[test]
+    fn sealing_key_is_bound_to_selected_registers() {
+        let mgr = finalized_manager(1, 0xa5);
+        let runtime_mask = 1 << TcgPcrIndex::RuntimePcr0.fwid_index();
+        let mut unselected_key = [0u8; 32];
+        let mut selected_key = [0u8; 32];
+        mgr.derive_sealing_key(0, b"policy", &mut unselected_key)
+            .unwrap();
+        mgr.derive_sealing_key(runtime_mask, b"policy", &mut selected_key)
+            .unwrap();
+        assert_ne!(unselected_key, selected_key);
+
+        // Extending a register only changes the keys that select it.
+        mgr.extend_msmt_register(TcgPcrIndex::RuntimePcr0, &[0x42; 48], None, None)
+            .unwrap();
+        let mut key = [0u8; 32];
+        mgr.derive_sealing_key(0, b"policy", &mut key).unwrap();
+        assert_eq!(key, unselected_key);
+        mgr.derive_sealing_key(runtime_mask, b"policy", &mut key)
+            .unwrap();
+        assert_ne!(key, selected_key);
+
+        assert!(matches!(
+            mgr.derive_sealing_key(1 << MSMT_REGISTERS, b"policy", &mut key),
+            Err(Error::InvalidMeasurementRegisterMask(_))
+        ));
+    }
+
+    #[test]
+    fn no_sealing_key_without_dice_handoff() {
+        let mgr = AttestationManager::<sha2::Sha384>::new(None, 1, ID_SHA_384).unwrap();
+        mgr.set_epc(0x8020_0000);
+        mgr.finalize().unwrap();
+        assert!(!mgr.endorsed());
+        let mut key = [0u8; 32];
+        assert!(matches!(
+            mgr.derive_sealing_key(0, b"policy", &mut key),
+            Err(Error::NoSealingCdi)
+        ));
+        assert!(finalized_manager(1, 0xa5).endorsed());
+    }
+
+    // Returns the number of records and the length of the encoded event log of `mgr`.
+    fn event_log_records(mgr: &AttestationManager<sha2::Sha384>) -> (usize, usize) {
+        let mut records = 0;
+        let len = mgr
+            .for_each_event_log_record(usize::MAX, |_| -> Result<()> {
+                records += 1;
+                Ok(())
+            })
+            .unwrap();
+        (records, len)
+    }
+
+    #[test]
+    fn tvm_pages_are_recorded_per_region() {
+        let mgr = AttestationManager::<sha2::Sha384>::new(DICE_CDIS, 1, ID_SHA_384).unwrap();
+        // A 4 MiB image, and a page right after a gap: two regions.
+        for i in 0..1024u64 {
+            mgr.extend_tvm_page(&[i as u8; 4096], 0x8020_0000 + i * 4096)
+                .unwrap();
+        }
+        mgr.extend_tvm_page(&[0xa5; 4096], 0x9000_0000).unwrap();
+
+        // The register is extended with every region.
+        let mut expected =
+            TVM_MSMT_REGISTERS[TcgPcrIndex::TvmPage.fwid_index()].build::<sha2::Sha384>(ID_SHA_384);
+        let mut image = sha2::Sha384::new();
+        for i in 0..1024u64 {
+            image.update([i as u8; 4096]);
+        }
+        expected
+            .extend(&image.finalize(), Some(0x8020_0000))
+            .unwrap();
+        expected
+            .extend(&sha2::Sha384::digest([0xa5; 4096]), Some(0x9000_0000))
+            .unwrap();
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap(),
+            expected.digest
+        );
+        assert_eq!(event_log_records(&mgr).0, 2);
+        assert_eq!(mgr.event_log.read().dropped(), 0);
+    }
+
+    #[test]
+    fn tvm_pages_are_locked_once_finalized() {
+        let mgr = finalized_manager(1, 0xa5);
+        assert!(matches!(
+            mgr.extend_tvm_page(&[0xa5; 4096], 0x8020_1000),
+            Err(Error::LockedMeasurementRegister(_))
+        ));
+    }
+
+    #[test]
+    fn event_log_snapshot() {
+        let mgr = finalized_manager(1, 0xa5);
+        let (records, len) = event_log_records(&mgr);
+        // The region event and the two TVM configuration events.
+        assert_eq!(records, 3);
+        assert!(matches!(
+            mgr.for_each_event_log_record(len, |_| -> Result<()> { Ok(()) }),
+            Ok(l) if l == len
+        ));
+        let mut called = false;
+        assert!(matches!(
+            mgr.for_each_event_log_record(len - 1, |_| -> Result<()> {
+                called = true;
+                Ok(())
+            }),
+            Err(Error::EventLogBufferTooSmall(l)) if l == len
+        ));
+        assert!(!called);
+    }
+
+    #[test]
+    fn runtime_events_dont_evict_static_events() {
+        let mgr = AttestationManager::<sha2::Sha384>::new(DICE_CDIS, 1, ID_SHA_384).unwrap();
+        for _ in 0..MAX_RUNTIME_EVENTS + 4 {
+            mgr.extend_msmt_register(TcgPcrIndex::RuntimePcr0, &[0x42; 48], None, None)
+                .unwrap();
+        }
+        assert_eq!(mgr.event_log.read().dropped(), 4);
+
+        // Non contiguous pages are measured as one region each. Leave room for the two TVM
+        // configuration events.
+        let static_events = MAX_EVENTS - MAX_RUNTIME_EVENTS;
+        for i in 0..static_events as u64 - 2 {
+            mgr.extend_tvm_page(&[0xa5; 4096], 0x8020_0000 + i * 0x2000)
+                .unwrap();
+        }
+        mgr.finalize().unwrap();
+        // Every event is recorded, followed by the dropped events record.
+        assert_eq!(event_log_records(&mgr).0, MAX_EVENTS + 1);
+        assert_eq!(mgr.event_log.read().dropped(), 4);
+    }
+
+    #[test]
+    fn static_events_are_never_dropped() {
+        let mgr = AttestationManager::<sha2::Sha384>::new(DICE_CDIS, 1, ID_SHA_384).unwrap();
+        let static_events = MAX_EVENTS - MAX_RUNTIME_EVENTS;
+        for i in 0..static_events as u64 {
+            mgr.extend_tvm_page(&[0xa5; 4096], 0x8020_0000 + i * 0x2000)
+                .unwrap();
+        }
+        // The last region can be recorded, but not the TVM configuration.
+        let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+        assert!(matches!(mgr.finalize(), Err(Error::EventLogFull)));
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmConfiguration)
+                .unwrap(),
+            GenericArray::default()
+        );
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap(),
+            tvm_pages
+        );
+        assert_eq!(mgr.event_log.read().dropped(), 0);
+    }
+
+    #[test]
+    fn locked_tvm_pages() {
+        let mgr = finalized_manager(1, 0xa5);
+        assert!(mgr.extend_tvm_page(&[0x5a; 4096], 0x8020_1000).is_err());
+        assert!(mgr.read_msmt_register(TcgPcrIndex::TvmPage).is_ok());
+    }
+}
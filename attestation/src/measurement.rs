@@ -96,6 +96,18 @@ pub const TVM_MSMT_REGISTERS: [MeasurementRegisterBuilder; MSMT_REGISTERS] = [
     msmt_dynamic_reg!(7, TcgPcrIndex::RuntimePcr3, true),
 ];
 
+impl TcgPcrIndex {
+    /// Returns the index of the measurement register in the DiceTcbInfo FWIDs list.
+    pub fn fwid_index(self) -> usize {
+        // Unwrap ok: every TCG PCR index has a measurement register.
+        TVM_MSMT_REGISTERS
+            .iter()
+            .find(|m| m.pcr_index == self as u8)
+            .map(|m| m.fwid_index as usize)
+            .unwrap()
+    }
+}
+
 /// Type of the register measured data hash.
 pub type MeasurementRegisterDigest<D> = GenericArray<u8, <D as OutputSizeUser>::OutputSize>;
 
@@ -136,18 +148,28 @@ pub struct MeasurementRegister<D: Digest> {
     pub digest: MeasurementRegisterDigest<D>,
 }
 
+/// Returns the value of a measurement register holding `digest`, once extended with `bytes`
+/// measured at `address`, i.e. `H(digest || address || bytes)`. The address is little endian and
+/// is only hashed if present.
+pub fn extended_digest<D: Digest>(
+    digest: &[u8],
+    bytes: &[u8],
+    address: Option<u64>,
+) -> MeasurementRegisterDigest<D> {
+    let mut hasher = D::new_with_prefix(digest);
+    if let Some(address) = address {
+        hasher.update(address.to_le_bytes());
+    }
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
 impl<D: Digest> MeasurementRegister<D> {
     pub fn extend(&mut self, bytes: &[u8], address: Option<u64>) -> Result<()> {
-        let mut hasher = self
-            .extensible
-            .then_some(D::new_with_prefix(self.digest.clone()))
-            .ok_or(Error::LockedMeasurementRegister(self.fwid_index))?;
-
-        if let Some(address) = address {
-            hasher.update(address.to_le_bytes());
+        if !self.extensible {
+            return Err(Error::LockedMeasurementRegister(self.fwid_index));
         }
-        hasher.update(bytes);
-        self.digest = hasher.finalize();
+        self.digest = extended_digest::<D>(&self.digest, bytes, address);
 
         Ok(())
     }
@@ -71,13 +71,15 @@ impl DeviceTree {
     }
 
     /// Constructs a device-tree form the given flattened device-tree (FDT) blob.
+    ///
+    /// Fails if the structure block can't be parsed to the end or leaves nodes unterminated.
     pub fn from(fdt: &Fdt) -> DeviceTreeResult<Self> {
         let fdt = fdt.inner();
         let mut tree = Self::new();
         let mut iter = fdt.parse_iter();
 
         let mut parent = None;
-        while let Ok(Some(token)) = iter.next() {
+        while let Some(token) = iter.next()? {
             match token {
                 ParsedTok::BeginNode(n) => {
                     let id = tree.add_node(str::from_utf8(n.name)?, parent)?;
@@ -101,6 +103,9 @@ impl DeviceTree {
                 _ => {}
             }
         }
+        if parent.is_some() {
+            return Err(DeviceTreeError::MalformedFdt);
+        }
         Ok(tree)
     }
 
@@ -597,6 +602,8 @@ impl fmt::Display for DeviceTreeProp {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::DeviceTreeSerializer;
+    use alloc::vec;
 
     fn stub_tree() -> DeviceTree {
         let mut tree = DeviceTree::new();
@@ -696,4 +703,60 @@ mod tests {
             }
         }
     }
+
+    // FDT structure block tokens.
+    const FDT_END_NODE: u32 = 0x2;
+    const FDT_NOP: u32 = 0x4;
+
+    fn read_be32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write_be32(buf: &mut [u8], offset: usize, val: u32) {
+        buf[offset..offset + 4].copy_from_slice(&val.to_be_bytes());
+    }
+
+    // Serializes `stub_tree()` and returns the blob along with the offset of its last token.
+    fn stub_fdt() -> (Vec<u8>, usize) {
+        let tree = stub_tree();
+        let writer = DeviceTreeSerializer::new(&tree);
+        let mut buf = vec![0; writer.output_size()];
+        writer.write_to(&mut buf);
+        // `off_dt_struct` and `size_dt_struct` in the FDT header.
+        let struct_end = read_be32(&buf, 8) as usize + read_be32(&buf, 36) as usize;
+        (buf, struct_end - 4)
+    }
+
+    fn parse(buf: &[u8]) -> DeviceTreeResult<DeviceTree> {
+        let fdt = unsafe {
+            // Not safe, but it's just a test.
+            Fdt::new_from_raw_pointer(buf.as_ptr()).unwrap()
+        };
+        DeviceTree::from(&fdt)
+    }
+
+    #[test]
+    fn fdt_round_trip() {
+        let (buf, _) = stub_fdt();
+        let tree = parse(&buf).unwrap();
+        let names: Vec<&str> = tree.iter().map(|node| node.name()).collect();
+        assert_eq!(names, ["", "a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn unterminated_node() {
+        let (mut buf, end) = stub_fdt();
+        // Turn the root node's end into a no-op.
+        assert_eq!(read_be32(&buf, end - 4), FDT_END_NODE);
+        write_be32(&mut buf, end - 4, FDT_NOP);
+        assert!(matches!(parse(&buf), Err(DeviceTreeError::MalformedFdt)));
+    }
+
+    #[test]
+    fn bad_token() {
+        let (mut buf, end) = stub_fdt();
+        // Replace the root node's end with a token that doesn't exist.
+        write_be32(&mut buf, end - 4, 0xf);
+        assert!(matches!(parse(&buf), Err(DeviceTreeError::FdtError(_))));
+    }
 }
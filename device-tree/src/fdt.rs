@@ -5,6 +5,7 @@
 //! Wrapper for basic FDT interaction.
 
 use crate::DeviceTreeResult;
+use core::ops::Range;
 use core::str;
 use fdt_rs::base::iters::{DevTreeNodeIter, DevTreeReserveEntryIter};
 use fdt_rs::base::parse::ParsedTok;
@@ -82,6 +83,23 @@ impl<'a> Fdt<'a> {
             .map(|n| ImsicInfo { inner: n })
     }
 
+    /// Returns the location of the DICE handoff blob passed by firmware in the
+    /// 'salus,dice-handoff' property of the '/chosen' node, if present. The range is relative to
+    /// the base of the FDT.
+    pub fn dice_handoff_range(&self) -> Option<Range<usize>> {
+        let chosen = self
+            .inner
+            .nodes()
+            .find(|n| Ok(n.name()? == "chosen"))
+            .ok()??;
+        let prop = chosen
+            .props()
+            .find(|p| Ok(p.name()? == "salus,dice-handoff"))
+            .ok()??;
+        let start = prop.raw().as_ptr() as usize - self.base_addr() as usize;
+        Some(start..start + prop.raw().len())
+    }
+
     /// This returns the property buf for the first property with the give name
     pub fn get_property(&self, name: &str) -> Option<&str> {
         let mut iter = self.inner.parse_iter();
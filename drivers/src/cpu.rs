@@ -5,6 +5,7 @@
 use arrayvec::{ArrayString, ArrayVec};
 use core::fmt;
 use device_tree::{DeviceTree, DeviceTreeNode, DeviceTreeResult};
+use riscv_regs::{pause, sie, ReadWriteable, Readable, Writeable, CSR};
 use sync::Once;
 
 const MAX_ISA_STRING_LEN: usize = 256;
@@ -220,6 +221,11 @@ impl CpuInfo {
         self.has_vector
     }
 
+    /// Returns the frequency of the `time` CSR, in Hz.
+    pub fn timer_frequency(&self) -> u32 {
+        self.timer_frequency
+    }
+
     /// Returns the total number of CPUs.
     pub fn num_cpus(&self) -> usize {
         self.hart_ids.len()
@@ -295,3 +301,56 @@ impl CpuInfo {
         Ok(())
     }
 }
+
+/// Returns the value of the `time` CSR in milliseconds.
+pub fn time_ms() -> u64 {
+    CSR.hpmcounter[1].get_value() * 1000 / CpuInfo::get().timer_frequency() as u64
+}
+
+// Converts `ms` milliseconds to ticks of the `time` CSR.
+fn ms_to_ticks(ms: u64) -> u64 {
+    CpuInfo::get().timer_frequency() as u64 * ms / 1000
+}
+
+/// Busy-waits for at least `ms` milliseconds, as measured by the `time` CSR.
+pub fn delay_ms(ms: u64) {
+    let ticks = ms_to_ticks(ms);
+    let start = CSR.hpmcounter[1].get_value();
+    while CSR.hpmcounter[1].get_value().wrapping_sub(start) < ticks {
+        pause();
+    }
+}
+
+/// Arms the supervisor timer of this CPU to fire `ms` milliseconds from now, with supervisor
+/// timer interrupts enabled. The previous STIMECMP value and timer interrupt enable are restored
+/// when it's dropped. Requires Sstc.
+pub struct SupervisorTimerGuard {
+    saved_stimecmp: u64,
+    saved_stie: bool,
+}
+
+impl SupervisorTimerGuard {
+    /// Arms the timer to fire `ms` milliseconds from now.
+    pub fn arm_ms(ms: u64) -> Self {
+        let deadline = CSR.hpmcounter[1]
+            .get_value()
+            .saturating_add(ms_to_ticks(ms));
+        let saved_stimecmp = CSR.stimecmp.get();
+        CSR.stimecmp.set(deadline);
+        let saved_stie = CSR.sie.read(sie::stimer) != 0;
+        CSR.sie.read_and_set_field(sie::stimer);
+        Self {
+            saved_stimecmp,
+            saved_stie,
+        }
+    }
+}
+
+impl Drop for SupervisorTimerGuard {
+    fn drop(&mut self) {
+        if !self.saved_stie {
+            CSR.sie.read_and_clear_field(sie::stimer);
+        }
+        CSR.stimecmp.set(self.saved_stimecmp);
+    }
+}
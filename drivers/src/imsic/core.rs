@@ -24,12 +24,14 @@ const MAX_GUEST_FILES: usize = 7;
 const MAX_MMIO_REGIONS: usize = 8;
 
 /// IMSIC external interrupt IDs.
-/// For now, we only expect to handle IPIs at HS-level.
+/// For now, we only expect to handle IPIs and IOMMU wakeups at HS-level.
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ImsicInterruptId {
     /// Interrupt ID for inter-processer notifications.
     Ipi = 1,
+    /// Interrupt ID the IOMMU signals to wake up a CPU waiting for commands to complete.
+    IommuWakeup = 2,
 }
 
 impl ImsicInterruptId {
@@ -37,6 +39,7 @@ impl ImsicInterruptId {
     fn from_raw(id: u64) -> Option<Self> {
         match id {
             1 => Some(ImsicInterruptId::Ipi),
+            2 => Some(ImsicInterruptId::IommuWakeup),
             _ => None,
         }
     }
@@ -447,9 +450,11 @@ impl Imsic {
         // We don't care about prioritization, so just set EITHRESHOLD to 0.
         CSR.si_eithreshold.set(0);
 
-        // The only interrupts we handle right now are IPIs.
-        let (offset, bit) = ImsicInterruptId::Ipi.offset_and_bit();
-        CSR.si_eie[offset].read_and_set_bits(1 << bit);
+        // The only interrupts we handle right now are IPIs and IOMMU wakeups.
+        for id in [ImsicInterruptId::Ipi, ImsicInterruptId::IommuWakeup] {
+            let (offset, bit) = id.offset_and_bit();
+            CSR.si_eie[offset].read_and_set_bits(1 << bit);
+        }
     }
 
     /// Returns a reference to the global IMSIC state.
@@ -544,6 +549,17 @@ impl Imsic {
         self.send_ipi_raw(cpu, ImsicFileId::Supervisor, ImsicInterruptId::Ipi as u32)
     }
 
+    /// Returns the address and data of an MSI that raises `id` in the supervisor-level interrupt
+    /// file of the specified CPU, for devices that signal the CPU directly.
+    pub fn interrupt_msi(
+        &self,
+        cpu: CpuId,
+        id: ImsicInterruptId,
+    ) -> Result<(SupervisorPageAddr, u32)> {
+        let addr = self.phys_file_addr(cpu, ImsicFileId::Supervisor)?;
+        Ok((addr, id as u32))
+    }
+
     /// Claims and returns the ID of the next pending interrupt in this CPU's supervisor-level
     /// interrupt file, or `None` if no interrupt is pending.
     pub fn next_pending_interrupt() -> Option<ImsicInterruptId> {
@@ -551,6 +567,13 @@ impl Imsic {
         ImsicInterruptId::from_raw(raw_id)
     }
 
+    /// Claims interrupt `id` in this CPU's supervisor-level interrupt file, leaving any other
+    /// pending interrupts untouched. Returns if `id` was pending.
+    pub fn claim_interrupt(id: ImsicInterruptId) -> bool {
+        let (offset, bit) = id.offset_and_bit();
+        CSR.si_eip[offset].read_and_clear_bits(1 << bit) & (1 << bit) != 0
+    }
+
     // Returns the number EIE/EIP registers used by the IMSIC.
     fn num_ei_regs(&self) -> usize {
         (self.interrupt_ids + 63) / 64
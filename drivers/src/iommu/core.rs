@@ -2,11 +2,14 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use alloc::vec::Vec;
+use device_tree::DeviceTree;
 use riscv_page_tables::{GuestStagePageTable, GuestStagePagingMode};
 use riscv_pages::*;
-use riscv_regs::{mmio_wmb, pause};
+use riscv_regs::mmio_wmb;
 use sync::{Mutex, Once};
-use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::fields::FieldValue;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 use tock_registers::LocalRegisterCopy;
 
 use super::device_directory::*;
@@ -14,7 +17,7 @@ use super::error::{Error, Result};
 use super::msi_page_table::MsiPageTable;
 use super::queue::*;
 use super::registers::*;
-use crate::pci::{self, PciArenaId, PciDevice, PcieRoot};
+use crate::pci::{self, PciDevice, PcieRoot};
 
 // Tracks the state of an allocated global soft-context ID (GSCID).
 #[derive(Clone, Copy, Debug)]
@@ -30,13 +33,172 @@ struct GscIdState {
 // the time being.
 const MAX_GSCIDS: usize = 64;
 
+// The number of page request group indices a device can use.
+const PAGE_REQUEST_GROUPS: usize = 512;
+
+// A device with a valid device context.
+#[derive(Clone, Copy, Debug)]
+struct AttachedDevice {
+    id: DeviceId,
+    owner: PageOwnerId,
+    gscid: GscId,
+    ats: AtsMode,
+    // A bit for each page request group for which an invalid request from the device has been
+    // seen. The group is rejected once its last request arrives.
+    invalid_page_groups: [u64; PAGE_REQUEST_GROUPS / 64],
+}
+
+impl AttachedDevice {
+    // Sets whether an invalid request has been seen in the page request group `group`, returning
+    // if one had been before.
+    fn set_page_group_invalid(&mut self, group: u16, invalid: bool) -> bool {
+        let (index, bit) = (group as usize / 64, 1 << (group % 64));
+        let was_invalid = self.invalid_page_groups[index] & bit != 0;
+        if invalid {
+            self.invalid_page_groups[index] |= bit;
+        } else {
+            self.invalid_page_groups[index] &= !bit;
+        }
+        was_invalid
+    }
+}
+
+// The value of ddtp that turns the IOMMU off, blocking all DMA.
+const DDT_MODE_OFF: u64 = 0;
+
+// How many times we poll for posted commands to be processed before we start waiting in 1ms
+// increments. Most commands are processed long before then.
+const COMMAND_POLL_COUNT: u64 = 1000;
+
+// How long we wait for the IOMMU to process posted commands or turn the command queue on or off
+// before giving up on it.
+const COMMAND_TIMEOUT_MS: u64 = 100;
+
+// The MSI configuration table entry used for command queue interrupts.
+const COMMAND_QUEUE_VECTOR: usize = 0;
+
+/// Lets a CPU that waits for the IOMMU to complete commands sleep rather than poll. The IOMMU
+/// signals completion with an MSI that wakes the waiting CPU.
+pub trait CompletionWaiter: Sync {
+    /// Returns the address and data of an MSI that wakes the calling CPU from `wait()`.
+    fn wakeup_msi(&self) -> (SupervisorPageAddr, u32);
+
+    /// Halts the calling CPU until it's woken by an interrupt, or until `timeout_ms` have passed.
+    fn wait(&self, timeout_ms: u64);
+}
+
+// Gives the IOMMU time to act on the register writes made so far before its registers are polled
+// again: a brief pause, or `ms` milliseconds if polling hasn't paid off.
+#[cfg(not(test))]
+fn wait_for_iommu(ms: u64) {
+    if ms == 0 {
+        riscv_regs::pause();
+    } else {
+        crate::cpu::delay_ms(ms);
+    }
+}
+
+// Time doesn't pass in tests; let the software model of the IOMMU make progress instead.
+#[cfg(test)]
+fn wait_for_iommu(_ms: u64) {
+    super::model::step();
+}
+
+// Writes `ddtp` to the IOMMU's device directory pointer and waits for the IOMMU to switch over.
+fn set_directory_pointer(registers: &IommuRegisters, ddtp: u64) {
+    while registers.ddtp.is_set(DirectoryPointer::Busy) {
+        wait_for_iommu(0);
+    }
+    registers.ddtp.set(ddtp);
+    while registers.ddtp.is_set(DirectoryPointer::Busy) {
+        wait_for_iommu(0);
+    }
+}
+
+// Returns if the IOMMU supports device directories of `mode`, by pointing it at the empty directory
+// table at `root` and checking if the mode sticks. Turns translation off again before returning.
+//
+// The IOMMU must be off when this is called: ddtp can only be switched to a directory mode from Off
+// or Bare, and DMA must stay blocked while `root` is briefly the active directory.
+fn directory_mode_supported(
+    registers: &IommuRegisters,
+    mode: DirectoryMode,
+    root: SupervisorPfn,
+) -> bool {
+    let mut ddtp = LocalRegisterCopy::<u64, DirectoryPointer::Register>::new(0);
+    ddtp.modify(DirectoryPointer::Ppn.val(root.bits()));
+    ddtp.modify(DirectoryPointer::Mode.val(mode.iommu_mode()));
+    // Make sure the table is seen as empty.
+    mmio_wmb();
+    set_directory_pointer(registers, ddtp.get());
+    let supported = registers.ddtp.read(DirectoryPointer::Mode) == mode.iommu_mode();
+    set_directory_pointer(registers, DDT_MODE_OFF);
+    supported
+}
+
+// Returns the "#iommu-cells" of the device-tree node with `phandle`, that is the number of cells
+// following the phandle in an "iommus" entry referring to it.
+fn iommu_cells(dt: &DeviceTree, phandle: u32) -> Option<usize> {
+    let node = dt.iter().find(|n| {
+        n.props()
+            .any(|p| p.name() == "phandle" && p.value_u32().next() == Some(phandle))
+    })?;
+    node.props()
+        .find(|p| p.name() == "#iommu-cells")?
+        .value_u32()
+        .next()
+        .map(|cells| cells as usize)
+}
+
+// Returns the IDs of the enabled platform devices in `dt` that use the IOMMU with `phandle` for DMA.
+// Each entry of an "iommus" property is the phandle of an IOMMU followed by "#iommu-cells" cells;
+// ours has just one, the device ID.
+fn platform_device_ids(dt: &DeviceTree, phandle: Option<u32>) -> Result<Vec<DeviceId>> {
+    let mut ids = Vec::new();
+    // Without a phandle, no node can refer to the IOMMU.
+    let Some(phandle) = phandle else {
+        return Ok(ids);
+    };
+    for node in dt.iter().filter(|n| !n.disabled()) {
+        let Some(prop) = node.props().find(|p| p.name() == "iommus") else {
+            continue;
+        };
+        let mut cells = prop.value_u32();
+        while let Some(iommu) = cells.next() {
+            let num_cells = iommu_cells(dt, iommu).ok_or(Error::InvalidIommusProperty)?;
+            if iommu != phandle {
+                // Skip the entries for other IOMMUs.
+                if cells.by_ref().take(num_cells).count() != num_cells {
+                    return Err(Error::InvalidIommusProperty);
+                }
+                continue;
+            }
+            if num_cells != 1 {
+                return Err(Error::InvalidIommuCells);
+            }
+            let id = cells.next().ok_or(Error::InvalidIommusProperty)?;
+            let id = DeviceId::new(id).ok_or(Error::InvalidPlatformDeviceId(id))?;
+            if !ids.contains(&id) {
+                ids.try_reserve(1).map_err(|_| Error::AllocError)?;
+                ids.push(id);
+            }
+        }
+    }
+    Ok(ids)
+}
+
 /// IOMMU device. Responsible for managing address translation for PCI devices.
 pub struct Iommu {
-    _arena_id: PciArenaId,
-    registers: &'static mut IommuRegisters,
+    registers: &'static IommuRegisters,
     command_queue: Mutex<CommandQueue>,
-    ddt: DeviceDirectory<Ddt3Level>,
+    page_request_queue: Option<Mutex<PageRequestQueue>>,
+    ddt: DeviceDirectory,
     gscids: Mutex<[Option<GscIdState>; MAX_GSCIDS]>,
+    fault_queue: Mutex<FaultQueue>,
+    attached_devices: Mutex<Vec<AttachedDevice>>,
+    pci_devices: Vec<DeviceId>,
+    platform_devices: Vec<DeviceId>,
+    completion_waiter: Once<&'static dyn CompletionWaiter>,
 }
 
 // The global IOMMU singleton.
@@ -47,18 +209,29 @@ const IOMMU_VENDOR_ID: u16 = 0x1efd;
 const IOMMU_DEVICE_ID: u16 = 0xedf1;
 
 impl Iommu {
-    /// Probes for and initializes the IOMMU device on the given PCI root. Uses `get_page` to
-    /// allocate pages for IOMMU-internal structures.
+    /// Probes for and initializes the IOMMU device on the PCI roots. Uses `get_page` to allocate
+    /// pages for IOMMU-internal structures.
+    ///
+    /// DMA is blocked for all devices until they're attached. Besides the PCI devices, devices in
+    /// `dt` that name the device ID they use for DMA in their "iommus" property are added to the
+    /// device directory, and may be attached with `attach_platform_devices()`.
     pub fn probe_from(
-        pci: &PcieRoot,
+        dt: &DeviceTree,
         get_page: &mut dyn FnMut() -> Option<Page<InternalClean>>,
     ) -> Result<()> {
-        let arena_id = pci
-            .take_and_enable_hypervisor_device(
-                pci::VendorId::new(IOMMU_VENDOR_ID),
-                pci::DeviceId::new(IOMMU_DEVICE_ID),
-            )
-            .map_err(Error::ProbingIommu)?;
+        let mut probe_result = Err(pci::PciError::DeviceNotFound);
+        for root in PcieRoot::roots() {
+            probe_result = root
+                .take_and_enable_hypervisor_device(
+                    pci::VendorId::new(IOMMU_VENDOR_ID),
+                    pci::DeviceId::new(IOMMU_DEVICE_ID),
+                )
+                .map(|arena_id| (root, arena_id));
+            if !matches!(probe_result, Err(pci::PciError::DeviceNotFound)) {
+                break;
+            }
+        }
+        let (pci, arena_id) = probe_result.map_err(Error::ProbingIommu)?;
         let (iommu_addr, regs_base, regs_size) = {
             let dev = pci.get_device(arena_id).unwrap().lock();
             // IOMMU registers are in BAR0.
@@ -77,7 +250,65 @@ impl Iommu {
         }
         // Safety: We've taken unique ownership of the IOMMU PCI device and have verified that
         // BAR0 points to a suitably sized and aligned register set.
-        let registers = unsafe { (regs_base.bits() as *mut IommuRegisters).as_mut().unwrap() };
+        let registers = unsafe {
+            (regs_base.bits() as *const IommuRegisters)
+                .as_ref()
+                .unwrap()
+        };
+
+        // Device IDs include the PCI segment, so a single device directory covers the devices on
+        // all PCI roots. It must be able to hold the ID of any device that may appear below them.
+        let mut max_id = DeviceId::new(0).unwrap();
+        for root in PcieRoot::roots() {
+            let id = DeviceId::try_from(root.max_function_address())?;
+            if id.bits() > max_id.bits() {
+                max_id = id;
+            }
+        }
+        // DMA from a PCI function goes through this IOMMU if the 'iommu-map' of its root complex
+        // says so. Without an 'iommu-map', only the functions on the IOMMU's own root complex are
+        // taken to be behind it. Functions behind no IOMMU, or behind another one, are never
+        // attached, so they can't be assigned to VMs.
+        let phandle = pci.pci_iommu_phandle(iommu_addr);
+        let translates = |root: &PcieRoot, addr: pci::Address| {
+            if root.has_iommu_map() {
+                phandle.is_some() && root.iommu_phandle_for(addr) == phandle
+            } else {
+                root.segment() == iommu_addr.segment()
+            }
+        };
+        let mut pci_devices = Vec::new();
+        for root in PcieRoot::roots() {
+            for dev in root.devices() {
+                let addr = dev.lock().info().address();
+                // Skip the IOMMU itself, and the functions it doesn't translate.
+                if addr == iommu_addr || !translates(root, addr) {
+                    continue;
+                }
+                pci_devices.try_reserve(1).map_err(|_| Error::AllocError)?;
+                pci_devices.push(addr.try_into()?);
+            }
+        }
+
+        let iommu = Self::new(registers, max_id, &pci_devices, dt, phandle, get_page)?;
+        IOMMU.call_once(|| iommu);
+        Ok(())
+    }
+
+    // Initializes the IOMMU with the register set at `registers`. The device directory is made
+    // large enough for device IDs up to `max_id`, and holds `pci_devices` along with the platform
+    // devices in `dt` that refer to the IOMMU by `phandle`.
+    pub(super) fn new(
+        registers: &'static IommuRegisters,
+        max_id: DeviceId,
+        pci_devices: &[DeviceId],
+        dt: &DeviceTree,
+        phandle: Option<u32>,
+        get_page: &mut dyn FnMut() -> Option<Page<InternalClean>>,
+    ) -> Result<Self> {
+        // Firmware may have left the IOMMU in bare mode. Turn it off to block DMA from all devices
+        // while we set it up.
+        set_directory_pointer(registers, DDT_MODE_OFF);
 
         // We need support for Sv48x4 G-stage translation and MSI page-tables at minimum.
         if !registers.capabilities.is_set(Capabilities::Sv48x4) {
@@ -87,6 +318,19 @@ impl Iommu {
             return Err(Error::MissingMsiSupport);
         }
 
+        // Platform devices share the device ID space with PCI devices, so their IDs must not collide
+        // and the device directory must be able to hold them too.
+        let platform_devices = platform_device_ids(dt, phandle)?;
+        if let Some(&id) = platform_devices.iter().find(|id| pci_devices.contains(id)) {
+            return Err(Error::PlatformDeviceIdConflict(id));
+        }
+        let mut max_id = max_id;
+        for &id in platform_devices.iter() {
+            if id.bits() > max_id.bits() {
+                max_id = id;
+            }
+        }
+
         // Initialize the command queue.
         let command_queue = CommandQueue::new(get_page().ok_or(Error::OutOfPages)?);
         let mut cqb = LocalRegisterCopy::<u64, QueueBase::Register>::new(0);
@@ -95,37 +339,89 @@ impl Iommu {
         registers.cqb.set(cqb.get());
         registers.cqcsr.write(CqControl::Enable.val(1));
         while !registers.cqcsr.is_set(CqControl::On) {
-            pause();
+            wait_for_iommu(0);
         }
 
-        // TODO: Set up fault queue.
+        // Initialize the fault queue.
+        let fault_queue = FaultQueue::new(get_page().ok_or(Error::OutOfPages)?);
+        let mut fqb = LocalRegisterCopy::<u64, QueueBase::Register>::new(0);
+        fqb.modify(QueueBase::Log2SzMinus1.val(fault_queue.capacity().ilog2() as u64 - 1));
+        fqb.modify(QueueBase::Ppn.val(fault_queue.base_address().pfn().bits()));
+        registers.fqb.set(fqb.get());
+        registers.fqcsr.write(FqControl::Enable.val(1));
+        while !registers.fqcsr.is_set(FqControl::On) {
+            wait_for_iommu(0);
+        }
 
-        // Set up an initial device directory table.
-        let ddt = DeviceDirectory::new(get_page().ok_or(Error::OutOfPages)?);
-        for dev in pci.devices() {
-            let addr = dev.lock().info().address();
-            if addr == iommu_addr {
-                // Skip the IOMMU itself.
-                continue;
+        // Set up the page-request queue if we can let devices use ATS. Translated requests from
+        // devices are always treated as guest physical addresses, so T2GPA support is required.
+        let page_request_queue = if registers.capabilities.is_set(Capabilities::Ats)
+            && registers.capabilities.is_set(Capabilities::T2gpa)
+        {
+            let pq = PageRequestQueue::new(get_page().ok_or(Error::OutOfPages)?);
+            let mut pqb = LocalRegisterCopy::<u64, QueueBase::Register>::new(0);
+            pqb.modify(QueueBase::Log2SzMinus1.val(pq.capacity().ilog2() as u64 - 1));
+            pqb.modify(QueueBase::Ppn.val(pq.base_address().pfn().bits()));
+            registers.pqb.set(pqb.get());
+            registers.pqcsr.write(PqControl::Enable.val(1));
+            while !registers.pqcsr.is_set(PqControl::On) {
+                wait_for_iommu(0);
             }
-            ddt.add_device(addr.try_into()?, get_page)?;
+            Some(Mutex::new(pq))
+        } else {
+            None
+        };
+
+        // Set up an initial device directory table, using the smallest table the IOMMU supports
+        // that can hold `max_id`. The IOMMU uses extended-format device contexts if and only if it
+        // supports flat MSI page tables.
+        let format = if registers.capabilities.is_set(Capabilities::MsiFlat) {
+            DeviceContextFormat::Extended
+        } else {
+            DeviceContextFormat::Base
+        };
+        let ddt_root = get_page().ok_or(Error::OutOfPages)?;
+        let ddt_root_pfn = ddt_root.pfn();
+        let mode = [
+            DirectoryMode::OneLevel,
+            DirectoryMode::TwoLevel,
+            DirectoryMode::ThreeLevel,
+        ]
+        .into_iter()
+        .filter(|m| m.supports_device_id(max_id, format))
+        .find(|&m| directory_mode_supported(registers, m, ddt_root_pfn))
+        .ok_or(Error::UnsupportedDirectoryMode)?;
+        let ddt = DeviceDirectory::new(ddt_root, mode, format);
+        for &id in pci_devices {
+            ddt.add_device(id, get_page)?;
+        }
+        let mut pci_device_list = Vec::new();
+        pci_device_list
+            .try_reserve(pci_devices.len())
+            .map_err(|_| Error::AllocError)?;
+        pci_device_list.extend_from_slice(pci_devices);
+        for &id in platform_devices.iter() {
+            ddt.add_device(id, get_page)?;
         }
         let mut ddtp = LocalRegisterCopy::<u64, DirectoryPointer::Register>::new(0);
         ddtp.modify(DirectoryPointer::Ppn.val(ddt.base_address().pfn().bits()));
-        ddtp.modify(DirectoryPointer::Mode.val(Ddt3Level::IOMMU_MODE));
+        ddtp.modify(DirectoryPointer::Mode.val(mode.iommu_mode()));
         // Ensure writes to the DDT have completed before we point the IOMMU at it.
         mmio_wmb();
-        registers.ddtp.set(ddtp.get());
+        set_directory_pointer(registers, ddtp.get());
 
-        let iommu = Iommu {
-            _arena_id: arena_id,
+        Ok(Iommu {
             registers,
             command_queue: Mutex::new(command_queue),
+            page_request_queue,
             ddt,
             gscids: Mutex::new([None; MAX_GSCIDS]),
-        };
-        IOMMU.call_once(|| iommu);
-        Ok(())
+            fault_queue: Mutex::new(fault_queue),
+            attached_devices: Mutex::new(Vec::new()),
+            pci_devices: pci_device_list,
+            platform_devices,
+            completion_waiter: Once::new(),
+        })
     }
 
     /// Gets a reference to the `Iommu` singleton.
@@ -133,11 +429,45 @@ impl Iommu {
         IOMMU.get()
     }
 
+    /// Returns if DMA from the PCI device at `address` is translated by this IOMMU. Only such
+    /// devices may be attached.
+    pub fn translates_pci_device(&self, address: pci::Address) -> bool {
+        DeviceId::try_from(address).is_ok_and(|id| self.pci_devices.contains(&id))
+    }
+
     /// Returns the version of this IOMMU device.
     pub fn version(&self) -> u64 {
         self.registers.capabilities.read(Capabilities::Version)
     }
 
+    /// Has CPUs that wait for the IOMMU to complete commands sleep using `waiter` until the IOMMU
+    /// signals completion with an MSI, rather than poll.
+    pub fn enable_completion_interrupts(
+        &self,
+        waiter: &'static dyn CompletionWaiter,
+    ) -> Result<()> {
+        if self
+            .registers
+            .capabilities
+            .matches_all(Capabilities::Igs::Wsi)
+        {
+            return Err(Error::MissingMsiInterrupts);
+        }
+        // Make sure no one is waiting on commands while we switch over.
+        let _cq = self.command_queue.lock();
+        self.registers.fctrl.modify(FeatureControl::Wsi::CLEAR);
+        self.registers
+            .icvec
+            .write(InterruptVectors::Civ.val(COMMAND_QUEUE_VECTOR as u64));
+        // The vector is unmasked once the address of the CPU to wake up is known.
+        self.registers.msi_cfg_tbl[COMMAND_QUEUE_VECTOR]
+            .vector_control
+            .write(MsiVectorControl::Mask::SET);
+        self.completion_waiter.call_once(|| waiter);
+        self.registers.cqcsr.write(self.cq_enabled());
+        Ok(())
+    }
+
     /// Allocates a new GSCID for `owner`.
     pub fn alloc_gscid(&self, owner: PageOwnerId) -> Result<GscId> {
         let mut gscids = self.gscids.lock();
@@ -183,6 +513,53 @@ impl Iommu {
         gscid: GscId,
     ) -> Result<()> {
         let dev_id = DeviceId::try_from(dev.info().address())?;
+        if !self.pci_devices.contains(&dev_id) {
+            return Err(Error::DeviceNotTranslated(dev_id));
+        }
+        // Let the device cache translations if both it and the IOMMU support ATS.
+        let ats = if self.page_request_queue.is_none() || !dev.has_ats() {
+            AtsMode::Disabled
+        } else if dev.has_pri() {
+            AtsMode::EnabledWithPri
+        } else {
+            AtsMode::Enabled
+        };
+        self.attach_device(dev_id, dev.owner(), ats, pt, msi_pt, gscid)?;
+        dev.set_iommu_attached();
+        Ok(())
+    }
+
+    /// Enables DMA for the platform (non-PCI) devices found at probe time, using `pt` for
+    /// 2nd-stage and `msi_pt` for MSI translation. Platform devices are always owned by the host.
+    pub fn attach_platform_devices<T: GuestStagePagingMode>(
+        &self,
+        pt: &GuestStagePageTable<T>,
+        msi_pt: &MsiPageTable,
+        gscid: GscId,
+    ) -> Result<()> {
+        for &dev_id in self.platform_devices.iter() {
+            self.attach_device(
+                dev_id,
+                Some(PageOwnerId::host()),
+                AtsMode::Disabled,
+                pt,
+                msi_pt,
+                gscid,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Enables DMA for the device with `dev_id` that's owned by `dev_owner`.
+    fn attach_device<T: GuestStagePagingMode>(
+        &self,
+        dev_id: DeviceId,
+        dev_owner: Option<PageOwnerId>,
+        ats: AtsMode,
+        pt: &GuestStagePageTable<T>,
+        msi_pt: &MsiPageTable,
+        gscid: GscId,
+    ) -> Result<()> {
         // Make sure the GSCID is valid and that it matches up with the device and page table
         // owner.
         let mut gscids = self.gscids.lock();
@@ -192,12 +569,22 @@ impl Iommu {
             .ok_or(Error::InvalidGscId(gscid))?;
         if pt.page_owner_id() != state.owner
             || msi_pt.owner() != state.owner
-            || dev.owner() != Some(state.owner)
+            || dev_owner != Some(state.owner)
         {
             return Err(Error::OwnerMismatch);
         }
-        self.ddt.enable_device(dev_id, pt, msi_pt, gscid)?;
-        dev.set_iommu_attached();
+        let mut attached_devices = self.attached_devices.lock();
+        attached_devices
+            .try_reserve(1)
+            .map_err(|_| Error::AllocError)?;
+        self.ddt.enable_device(dev_id, pt, msi_pt, gscid, ats)?;
+        attached_devices.push(AttachedDevice {
+            id: dev_id,
+            owner: state.owner,
+            gscid,
+            ats,
+            invalid_page_groups: [0; PAGE_REQUEST_GROUPS / 64],
+        });
         state.ref_count += 1;
         Ok(())
     }
@@ -220,45 +607,395 @@ impl Iommu {
             // Drop our reference to the GSCID used for the device.
             state.ref_count -= 1;
         }
-        // Flush translation caches for the device we just disabled.
-        let commands = [Command::iodir_inval_ddt(Some(dev_id)), Command::iofence()];
-        // Unwrap ok: we must have room for 2 commands in the CQ since we synchronously wait on
-        // commands to finish.
-        self.submit_commands_sync(&commands).unwrap();
-        Ok(())
+        // Flush translation caches for the device we just disabled, including any first-stage
+        // translations it may have cached through its process directory.
+        let mut commands = Vec::new();
+        commands.push(Command::iodir_inval_ddt(Some(dev_id)));
+        commands.push(Command::iotinval_vma(Some(gscid), None));
+        // The device's own TLB must be flushed as well before it can be handed to someone else.
+        let mut attached_devices = self.attached_devices.lock();
+        if let Some(index) = attached_devices.iter().position(|d| d.id == dev_id) {
+            let dev = attached_devices.swap_remove(index);
+            if dev.ats != AtsMode::Disabled {
+                commands.push(Command::ats_inval(dev_id, None));
+            }
+        }
+        drop(attached_devices);
+        self.submit_commands_sync(&commands)
+    }
+
+    /// Returns if process directory tables of format `mode`, and therefore first-stage translation
+    /// of DMAs tagged with a process ID, are supported by this IOMMU.
+    pub fn supports_process_directory(&self, mode: ProcessDirectoryMode) -> bool {
+        let caps = &self.registers.capabilities;
+        // We only allow Sv39 and Sv48 page tables for first-stage translation.
+        if !caps.is_set(Capabilities::Sv39) && !caps.is_set(Capabilities::Sv48) {
+            return false;
+        }
+        use ProcessDirectoryMode::*;
+        match mode {
+            Pd8 => caps.is_set(Capabilities::Pd8),
+            Pd17 => caps.is_set(Capabilities::Pd17),
+            Pd20 => caps.is_set(Capabilities::Pd20),
+        }
+    }
+
+    /// Enables first-stage translation of DMAs tagged with a process ID (PASID) from the given PCI
+    /// device, using the process directory table of format `mode` at `root`. The process directory
+    /// table is in the guest physical address space of the device's owner, which manages the
+    /// process contexts in it. `gscid` must be the GSCID the device was attached with.
+    pub fn enable_process_directory(
+        &self,
+        dev: &PciDevice,
+        gscid: GscId,
+        mode: ProcessDirectoryMode,
+        root: GuestPageAddr,
+    ) -> Result<()> {
+        if !self.supports_process_directory(mode) {
+            return Err(Error::UnsupportedProcessDirectoryMode(mode));
+        }
+        let dev_id = DeviceId::try_from(dev.info().address())?;
+        self.check_gscid_owner(gscid, dev)?;
+        self.ddt.enable_process_directory(dev_id, mode, root)?;
+        // Flush the device context along with any process contexts and first-stage translations
+        // cached from a previous process directory.
+        let commands = [
+            Command::iodir_inval_ddt(Some(dev_id)),
+            Command::iotinval_vma(Some(gscid), None),
+        ];
+        self.submit_commands_sync(&commands)
+    }
+
+    /// Disables first-stage translation for the given PCI device.
+    pub fn disable_process_directory(&self, dev: &PciDevice, gscid: GscId) -> Result<()> {
+        let dev_id = DeviceId::try_from(dev.info().address())?;
+        self.check_gscid_owner(gscid, dev)?;
+        self.ddt.disable_process_directory(dev_id)?;
+        let commands = [
+            Command::iodir_inval_ddt(Some(dev_id)),
+            Command::iotinval_vma(Some(gscid), None),
+        ];
+        self.submit_commands_sync(&commands)
+    }
+
+    /// Synchronizes the IOMMU's process context cache with updates made to the context for process
+    /// `pid` in the process directory table of the given PCI device.
+    pub fn invalidate_process_context(
+        &self,
+        dev: &PciDevice,
+        gscid: GscId,
+        pid: ProcessId,
+    ) -> Result<()> {
+        let dev_id = DeviceId::try_from(dev.info().address())?;
+        self.check_gscid_owner(gscid, dev)?;
+        let mode = self
+            .ddt
+            .process_directory_mode(dev_id)?
+            .ok_or(Error::ProcessDirectoryNotEnabled(dev_id))?;
+        if !mode.supports_process_id(pid) {
+            return Err(Error::InvalidProcessId(pid));
+        }
+        let mut commands = Vec::new();
+        commands.push(Command::iodir_inval_pdt(dev_id, pid));
+        if self
+            .attached_devices
+            .lock()
+            .iter()
+            .any(|d| d.id == dev_id && d.ats != AtsMode::Disabled)
+        {
+            commands.push(Command::ats_inval(dev_id, Some(pid)));
+        }
+        self.submit_commands_sync(&commands)
+    }
+
+    /// Synchronizes the IOMMU's translation caches with updates made to the first-stage page
+    /// tables of the processes with `pscid` in the VM identified by `gscid`.
+    pub fn fence_process(&self, gscid: GscId, pscid: PscId) -> Result<()> {
+        let mut commands = Vec::new();
+        commands.push(Command::iotinval_vma(Some(gscid), Some(pscid)));
+        self.push_device_tlb_invalidations(gscid, &mut commands);
+        self.submit_commands_sync(&commands)
     }
 
     /// Synchronizes the IOMMU's translation caches with updates made to the 2nd-stage and MSI
     /// page tables identified by `gscid`. If `addr` is not `None`, only flushes translations
     /// for `addr`.
-    pub fn fence(&self, gscid: GscId, addr: Option<GuestPageAddr>) {
-        let commands = [
-            Command::iotinval_gvma(Some(gscid), addr),
-            Command::iofence(),
-        ];
-        // Unwrap ok: we must have room for 2 commands in the CQ since we synchronously wait on
-        // commands to finish.
-        self.submit_commands_sync(&commands).unwrap();
+    ///
+    /// Returns an error if the IOMMU failed to complete the invalidation, in which case stale
+    /// translations may remain cached.
+    pub fn fence(&self, gscid: GscId, addr: Option<GuestPageAddr>) -> Result<()> {
+        let mut commands = Vec::new();
+        commands.push(Command::iotinval_gvma(Some(gscid), addr));
+        // Devices using ATS may have cached translations for the pages being fenced. We don't
+        // know which addresses they used to reach them, so flush their TLBs entirely.
+        self.push_device_tlb_invalidations(gscid, &mut commands);
+        self.submit_commands_sync(&commands)
     }
 
-    // Posts the commands in `commands` to the CQ, synchronously waiting for their completion.
+    /// Completes the page requests that devices have posted to the page-request queue. We don't
+    /// page in memory for devices on demand, so a page request group from a device with PRI
+    /// enabled is completed successfully right away if `is_mapped` reports that every guest
+    /// physical page it requests is mapped in, and owned by, the device's owner. The device then
+    /// retries translation. Groups with any other page, and requests from any other device, are
+    /// rejected.
+    pub fn process_page_requests(
+        &self,
+        is_mapped: impl Fn(PageOwnerId, GuestPageAddr) -> bool,
+    ) -> Result<()> {
+        let Some(pq) = self.page_request_queue.as_ref() else {
+            return Ok(());
+        };
+        let mut pq = pq.lock();
+        if self.registers.pqcsr.is_set(PqControl::Overflow) {
+            // The IOMMU has already responded to the requests it had to drop. Clear the overflow
+            // so that new requests are queued again.
+            self.registers
+                .pqcsr
+                .write(PqControl::Enable.val(1) + PqControl::Overflow.val(1));
+        }
+        let tail = self.registers.pqt.get() as usize;
+        if pq.update_tail(tail).is_err() {
+            return Ok(());
+        }
+        let mut responses = Vec::new();
+        {
+            let mut attached_devices = self.attached_devices.lock();
+            while let Ok(req) = pq.pop() {
+                let dev_id = req.device_id();
+                // Requests from devices that aren't attached with PRI enabled are always rejected,
+                // so there's no need to keep track of their groups.
+                let Some(dev) = attached_devices
+                    .iter_mut()
+                    .find(|d| d.id == dev_id && d.ats == AtsMode::EnabledWithPri)
+                else {
+                    if req.is_last_in_group() {
+                        responses.push(Command::ats_prgr(
+                            dev_id,
+                            req.process_id(),
+                            req.group_index(),
+                            PageResponseCode::InvalidRequest,
+                        ));
+                    }
+                    continue;
+                };
+                // Requests tagged with a process ID are for addresses in the 1st-stage address
+                // space of the process, which is managed by the device's owner. Any translation
+                // the device gets on retry still goes through the owner's 2nd-stage page table.
+                let owner = dev.owner;
+                let valid = req.process_id().is_some()
+                    || PageAddr::new(RawAddr::guest(req.page_addr(), owner))
+                        .is_some_and(|addr| is_mapped(owner, addr));
+                if !req.is_last_in_group() {
+                    if !valid {
+                        dev.set_page_group_invalid(req.group_index(), true);
+                    }
+                    continue;
+                }
+                let seen_invalid = dev.set_page_group_invalid(req.group_index(), false);
+                let code = if valid && !seen_invalid {
+                    PageResponseCode::Success
+                } else {
+                    PageResponseCode::InvalidRequest
+                };
+                responses.push(Command::ats_prgr(
+                    dev_id,
+                    req.process_id(),
+                    req.group_index(),
+                    code,
+                ));
+            }
+        }
+        // Hand the consumed entries back to the IOMMU.
+        self.registers.pqh.set(pq.head() as u32);
+        if !responses.is_empty() {
+            self.submit_commands_sync(&responses)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the records of faults encountered by the IOMMU from the fault queue, passing each
+    /// one to `report` along with the owner of the faulting device, if it's attached.
+    pub fn process_faults(&self, mut report: impl FnMut(&FaultRecord, Option<PageOwnerId>)) {
+        let mut fq = self.fault_queue.lock();
+        if self.registers.fqcsr.is_set(FqControl::Overflow) {
+            // Faults were dropped while the queue was full. Clear the overflow so that new faults
+            // are recorded again.
+            self.registers
+                .fqcsr
+                .write(FqControl::Enable.val(1) + FqControl::Overflow.val(1));
+        }
+        let tail = self.registers.fqt.get() as usize;
+        if fq.update_tail(tail).is_err() {
+            return;
+        }
+        let attached_devices = self.attached_devices.lock();
+        while let Ok(fault) = fq.pop() {
+            let owner = attached_devices
+                .iter()
+                .find(|d| d.id == fault.device_id())
+                .map(|d| d.owner);
+            report(&fault, owner);
+        }
+        drop(attached_devices);
+        // Hand the consumed records back to the IOMMU.
+        self.registers.fqh.set(fq.head() as u32);
+    }
+
+    // Appends ATS.INVAL commands to `commands` for each device attached with `gscid` that may
+    // have translations cached in its TLB.
+    fn push_device_tlb_invalidations(&self, gscid: GscId, commands: &mut Vec<Command>) {
+        let attached_devices = self.attached_devices.lock();
+        for dev in attached_devices
+            .iter()
+            .filter(|d| d.gscid == gscid && d.ats != AtsMode::Disabled)
+        {
+            commands.push(Command::ats_inval(dev.id, None));
+        }
+    }
+
+    // Checks that `gscid` is allocated to the owner of `dev`.
+    fn check_gscid_owner(&self, gscid: GscId, dev: &PciDevice) -> Result<()> {
+        let gscids = self.gscids.lock();
+        let state = gscids
+            .get(gscid.bits() as usize)
+            .and_then(|g| g.as_ref())
+            .ok_or(Error::InvalidGscId(gscid))?;
+        if dev.owner() != Some(state.owner) {
+            return Err(Error::OwnerMismatch);
+        }
+        Ok(())
+    }
+
+    // Posts the commands in `commands` to the CQ followed by an IOFENCE.C, synchronously waiting
+    // for their completion. If the IOMMU fails to complete the commands, the CQ is reset so that
+    // later commands can be posted again, and the error is returned.
     fn submit_commands_sync(&self, commands: &[Command]) -> Result<()> {
         let mut cq = self.command_queue.lock();
-        for &cmd in commands.iter() {
-            cq.push(cmd)?;
+        let waiter = self.completion_waiter.get().copied();
+        for &cmd in commands.iter().chain(&[Command::iofence(waiter.is_some())]) {
+            if cq.is_full() {
+                // Let the IOMMU drain the commands we've posted so far.
+                self.wait_for_commands(&mut cq, None)?;
+            }
+            // Unwrap ok: the CQ was drained above if it was full.
+            cq.push(cmd).unwrap();
         }
+        let Some(waiter) = waiter else {
+            return self.wait_for_commands(&mut cq, None);
+        };
+        // Have the IOMMU wake up this CPU once the fence completes.
+        let (addr, data) = waiter.wakeup_msi();
+        let msi = &self.registers.msi_cfg_tbl[COMMAND_QUEUE_VECTOR];
+        msi.addr.set(addr.bits());
+        msi.data.set(data);
+        msi.vector_control.write(MsiVectorControl::Mask::CLEAR);
+        let result = self.wait_for_commands(&mut cq, Some(waiter));
+        // Clear the pending interrupt so that the next fence signals a new one.
+        self.registers
+            .cqcsr
+            .write(self.cq_enabled() + CqControl::FenceWaitInterruptPending::SET);
+        self.registers.ipsr.write(InterruptPending::Cip::SET);
+        result
+    }
+
+    // Makes the commands posted to `cq` visible to the IOMMU and waits for it to consume them,
+    // sleeping with `waiter` if polling doesn't pay off. The IOMMU must signal a command queue
+    // interrupt on completion if `waiter` is set.
+    fn wait_for_commands(
+        &self,
+        cq: &mut CommandQueue,
+        waiter: Option<&dyn CompletionWaiter>,
+    ) -> Result<()> {
         // Make sure writes to the CQ have completed before we make them visible to HW.
         mmio_wmb();
         let tail = cq.tail() as u32;
         self.registers.cqt.set(tail);
-        while self.registers.cqh.get() != tail {
-            // TODO: timeout?
-            pause();
+        let mut polls = 0;
+        let mut waited_ms = 0;
+        loop {
+            self.check_command_queue(cq)?;
+            if self.registers.cqh.get() == tail {
+                break;
+            }
+            if polls < COMMAND_POLL_COUNT {
+                polls += 1;
+                wait_for_iommu(0);
+            } else if waited_ms < COMMAND_TIMEOUT_MS {
+                match waiter {
+                    Some(w) => w.wait(1),
+                    None => wait_for_iommu(1),
+                }
+                waited_ms += 1;
+            } else {
+                // Don't let a wedged IOMMU hang this CPU. Drop the commands so that the CQ isn't
+                // left full of them.
+                self.reset_command_queue(cq)?;
+                return Err(Error::CommandQueueStalled);
+            }
         }
         // Unwrap ok since we're setting head == tail.
         cq.update_head(tail as usize).unwrap();
         Ok(())
     }
+
+    // Checks if the IOMMU stopped processing commands due to an error, in which case `cq` is
+    // reset and the error is returned.
+    fn check_command_queue(&self, cq: &mut CommandQueue) -> Result<()> {
+        let cqcsr = self.registers.cqcsr.extract();
+        let err = if cqcsr.is_set(CqControl::CommandIllegal) {
+            Error::IllegalCommand
+        } else if cqcsr.is_set(CqControl::CommandTimeout) {
+            Error::CommandTimeout
+        } else if cqcsr.is_set(CqControl::MemoryFault) {
+            Error::CommandQueueMemoryFault
+        } else {
+            return Ok(());
+        };
+        self.reset_command_queue(cq)?;
+        Err(err)
+    }
+
+    // Turns the command queue off and back on, discarding the commands in `cq` and clearing any
+    // errors reported by the IOMMU.
+    fn reset_command_queue(&self, cq: &mut CommandQueue) -> Result<()> {
+        self.registers.cqcsr.write(CqControl::Enable::CLEAR);
+        self.wait_for_command_queue_on(false)?;
+        // The IOMMU resets the head to 0 when the queue is turned back on.
+        cq.reset();
+        self.registers.cqt.set(0);
+        self.registers.cqcsr.write(
+            self.cq_enabled()
+                + CqControl::MemoryFault::SET
+                + CqControl::CommandTimeout::SET
+                + CqControl::CommandIllegal::SET
+                + CqControl::FenceWaitInterruptPending::SET,
+        );
+        self.wait_for_command_queue_on(true)
+    }
+
+    // Waits for the command queue to be turned on or off, giving up with an error after
+    // `COMMAND_TIMEOUT_MS`.
+    fn wait_for_command_queue_on(&self, on: bool) -> Result<()> {
+        let mut waited_ms = 0;
+        loop {
+            // Give the IOMMU a moment to act on the write to cqcsr before checking on it.
+            wait_for_iommu(0);
+            if self.registers.cqcsr.is_set(CqControl::On) == on {
+                return Ok(());
+            }
+            if waited_ms >= COMMAND_TIMEOUT_MS {
+                return Err(Error::CommandQueueUnresponsive);
+            }
+            wait_for_iommu(1);
+            waited_ms += 1;
+        }
+    }
+
+    // Returns the cqcsr value that keeps the command queue on.
+    fn cq_enabled(&self) -> FieldValue<u32, CqControl::Register> {
+        let interrupts = self.completion_waiter.is_completed();
+        CqControl::Enable::SET + CqControl::InterruptEnable.val(interrupts as u32)
+    }
 }
 
 // `Iommu` holds `UnsafeCell`s for register access. Access to these registers is guarded by the
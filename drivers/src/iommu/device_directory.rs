@@ -15,10 +15,15 @@ use crate::pci::Address;
 
 // Maximum number of device ID bits used by the IOMMU.
 const DEVICE_ID_BITS: usize = 24;
-// Number of bits used to index into the leaf table.
-const LEAF_INDEX_BITS: usize = 6;
+// Number of bits used to index into the leaf table, for each device context format.
+const BASE_LEAF_INDEX_BITS: usize = 7;
+const EXTENDED_LEAF_INDEX_BITS: usize = 6;
 // Number of bits used to index into intermediate tables.
 const NON_LEAF_INDEX_BITS: usize = 9;
+// Maximum number of process ID (PASID) bits used by the IOMMU.
+const PROCESS_ID_BITS: usize = 20;
+// Number of bits in a process soft-context ID.
+const PSCID_BITS: usize = 20;
 
 /// The device ID. Used to index into the device directory table. For PCI devices behind an IOMMU
 /// this is equivalent to the requester ID of the PCI device (i.e. the bits of the B/D/F).
@@ -40,12 +45,14 @@ impl DeviceId {
         self.0
     }
 
-    // Returns the bits from this `DeviceId` used to index at `level`.
-    fn level_index_bits(&self, level: usize) -> usize {
+    // Returns the bits from this `DeviceId` used to index at `level` in a directory holding
+    // device contexts of `format`.
+    fn level_index_bits(&self, level: usize, format: DeviceContextFormat) -> usize {
+        let leaf_bits = format.leaf_index_bits();
         if level == 0 {
-            (self.0 as usize) & ((1 << LEAF_INDEX_BITS) - 1)
+            (self.0 as usize) & ((1 << leaf_bits) - 1)
         } else {
-            let shift = LEAF_INDEX_BITS + NON_LEAF_INDEX_BITS * (level - 1);
+            let shift = leaf_bits + NON_LEAF_INDEX_BITS * (level - 1);
             ((self.0 as usize) >> shift) & ((1 << NON_LEAF_INDEX_BITS) - 1)
         }
     }
@@ -75,6 +82,137 @@ impl GscId {
     }
 }
 
+/// The process ID of a DMA transaction. For PCI devices this is the PASID (Process Address Space ID)
+/// the device tags the transaction with, and selects the process context used for first-stage
+/// translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessId(u32);
+
+impl ProcessId {
+    /// Creates a new `ProcessId` from the raw `val`.
+    pub fn new(val: u32) -> Option<ProcessId> {
+        if (val & !((1 << PROCESS_ID_BITS) - 1)) == 0 {
+            Some(Self(val))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the raw bits of this `ProcessId`.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Process Soft-Context ID. The equivalent of satp.ASID for first-stage translations. PSCIDs are
+/// programmed into process contexts by the owner of the process directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PscId(u32);
+
+impl PscId {
+    /// Creates a new `PscId` from the raw `val`.
+    pub fn new(val: u32) -> Option<PscId> {
+        if (val & !((1 << PSCID_BITS) - 1)) == 0 {
+            Some(Self(val))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the raw bits of this `PscId`.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+/// The format of a process directory table, which maps the process ID of a transaction to the
+/// process context holding its first-stage (Sv39 or Sv48) page table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessDirectoryMode {
+    /// A 1-level table supporting 8-bit process IDs.
+    Pd8,
+    /// A 2-level table supporting 17-bit process IDs.
+    Pd17,
+    /// A 3-level table supporting 20-bit process IDs.
+    Pd20,
+}
+
+impl ProcessDirectoryMode {
+    /// Returns the `ProcessDirectoryMode` corresponding to the DC.fsc.MODE value `mode`, if any.
+    pub fn from_iommu_mode(mode: u64) -> Option<Self> {
+        use ProcessDirectoryMode::*;
+        match mode {
+            1 => Some(Pd8),
+            2 => Some(Pd17),
+            3 => Some(Pd20),
+            _ => None,
+        }
+    }
+
+    /// Returns the value programmed into DC.fsc.MODE for this mode.
+    pub fn iommu_mode(&self) -> u64 {
+        use ProcessDirectoryMode::*;
+        match self {
+            Pd8 => 1,
+            Pd17 => 2,
+            Pd20 => 3,
+        }
+    }
+
+    /// Returns if `id` can be looked up in a process directory of this mode.
+    pub fn supports_process_id(&self, id: ProcessId) -> bool {
+        use ProcessDirectoryMode::*;
+        let bits = match self {
+            Pd8 => 8,
+            Pd17 => 17,
+            Pd20 => 20,
+        };
+        (id.bits() >> bits) == 0
+    }
+}
+
+/// Controls whether a device may use Address Translation Services (ATS) to cache translations in
+/// its own TLB. Translations are always returned as guest physical addresses, so requests the
+/// device marks as already translated still go through 2nd-stage translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtsMode {
+    /// Translation requests from the device are rejected.
+    Disabled,
+    /// The device may send translation requests.
+    Enabled,
+    /// The device may send translation requests as well as page requests.
+    EnabledWithPri,
+}
+
+/// The format of the device contexts held in the leaf tables of the device directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceContextFormat {
+    /// 32-byte device contexts, which don't support MSI translation.
+    Base,
+    /// 64-byte device contexts, which add the MSI page table and MSI address pattern.
+    Extended,
+}
+
+impl DeviceContextFormat {
+    // Returns the size of a device context in this format.
+    fn size(&self) -> usize {
+        use DeviceContextFormat::*;
+        match self {
+            Base => core::mem::size_of::<DeviceContext>(),
+            Extended => core::mem::size_of::<DeviceContext>() + core::mem::size_of::<MsiContext>(),
+        }
+    }
+
+    // Returns the number of device ID bits used to index into a leaf table.
+    fn leaf_index_bits(&self) -> usize {
+        use DeviceContextFormat::*;
+        match self {
+            Base => BASE_LEAF_INDEX_BITS,
+            Extended => EXTENDED_LEAF_INDEX_BITS,
+        }
+    }
+}
+
 // Defines the translation context for a device. A valid device context enables translation for
 // DMAs from the corresponding device according to the tables programmed into the device context.
 #[repr(C)]
@@ -83,14 +221,32 @@ struct DeviceContext {
     iohgatp: u64,
     fsc: u64,
     ta: u64,
+}
+
+// The MSI translation fields that follow the `DeviceContext` in extended-format device contexts.
+#[repr(C)]
+struct MsiContext {
     msiptp: u64,
     msi_addr_mask: u64,
     msi_addr_pattern: u64,
     _reserved: u64,
 }
 
-// There are a bunch of other bits in `tc` for ATS, etc. but we only care about V for now.
+// There are a bunch of other bits in `tc` for ATS, etc. but we only care about V and PDTV for now.
 const DC_VALID: u64 = 1 << 0;
+// Enables ATS translation requests and PRI page requests from the device.
+const DC_EN_ATS: u64 = 1 << 1;
+const DC_EN_PRI: u64 = 1 << 2;
+// Makes ATS return guest physical addresses, and translate translated requests through the
+// 2nd-stage page table.
+const DC_T2GPA: u64 = 1 << 3;
+// Set if `fsc` points to a process directory table rather than a first-stage page table.
+const DC_PDTV: u64 = 1 << 5;
+// The bits in `tc` that are preserved when `fsc` is updated.
+const DC_ATS_BITS: u64 = DC_EN_ATS | DC_EN_PRI | DC_T2GPA;
+
+const FSC_MODE_SHIFT: u64 = 60;
+const FSC_PPN_MASK: u64 = (1 << 44) - 1;
 
 // Set in invalidated device contexts to indicate that the device context corresponds to a real
 // device. Prevents enabling of device contexts that weren't explicitly added with `add_device()`.
@@ -103,9 +259,6 @@ impl DeviceContext {
         self.iohgatp = 0;
         self.fsc = 0;
         self.ta = 0;
-        self.msiptp = 0;
-        self.msi_addr_mask = 0;
-        self.msi_addr_pattern = 0;
     }
 
     // Returns if the device context corresponds to a present device.
@@ -118,38 +271,127 @@ impl DeviceContext {
         (self.tc & DC_VALID) != 0
     }
 
-    // Marks the device context as valid, using `pt` and `msi_pt` for translation.
+    // Marks the device context as valid, using `pt` for translation and allowing the device to use
+    // ATS according to `ats`.
     fn set<T: GuestStagePagingMode>(
         &mut self,
         pt: &GuestStagePageTable<T>,
-        msi_pt: &MsiPageTable,
         gscid: GscId,
+        ats: AtsMode,
     ) {
-        const MSI_MODE_FLAT: u64 = 0x1;
-        const MSI_MODE_SHIFT: u64 = 60;
-        self.msiptp = msi_pt.base_address().pfn().bits() | (MSI_MODE_FLAT << MSI_MODE_SHIFT);
-
-        let (addr, mask) = msi_pt.msi_address_pattern();
-        self.msi_addr_mask = mask >> PFN_SHIFT;
-        self.msi_addr_pattern = addr.pfn().bits();
-
         const GSCID_SHIFT: u64 = 44;
         const HGATP_MODE_SHIFT: u64 = 60;
         self.iohgatp = pt.get_root_address().pfn().bits()
             | ((gscid.bits() as u64) << GSCID_SHIFT)
             | (T::HGATP_MODE << HGATP_MODE_SHIFT);
 
+        // First-stage translation starts out bare, until the owner of the device sets up a process
+        // directory.
+        self.fsc = 0;
+
         // Ensure the writes to the other context fields are visible before we mark the context
         // as valid.
         dma_wmb();
 
-        self.tc = DC_VALID;
+        self.tc = DC_VALID
+            | match ats {
+                AtsMode::Disabled => 0,
+                AtsMode::Enabled => DC_EN_ATS | DC_T2GPA,
+                AtsMode::EnabledWithPri => DC_EN_ATS | DC_EN_PRI | DC_T2GPA,
+            };
     }
 
     // Marks the device context as invalid.
     fn invalidate(&mut self) {
         self.tc = DC_SW_INVALIDATED;
     }
+
+    // Returns the mode of the process directory table used by this device context, if any.
+    fn process_directory_mode(&self) -> Option<ProcessDirectoryMode> {
+        if (self.tc & DC_PDTV) != 0 {
+            ProcessDirectoryMode::from_iommu_mode(self.fsc >> FSC_MODE_SHIFT)
+        } else {
+            None
+        }
+    }
+
+    // Points the device context at the process directory table `pdt`, or makes first-stage
+    // translation bare if `pdt` is `None`. The device context must be valid.
+    fn set_process_directory(&mut self, pdt: Option<(ProcessDirectoryMode, GuestPageAddr)>) {
+        // `fsc` is interpreted differently depending on PDTV, so block DMA from the device while
+        // the two are out of sync. The caller is responsible for flushing the IOMMU's cached copy
+        // of the device context once we're done.
+        let ats_bits = self.tc & DC_ATS_BITS;
+        self.tc = DC_SW_INVALIDATED;
+        dma_wmb();
+        let tc = match pdt {
+            Some((mode, root)) => {
+                self.fsc =
+                    (root.pfn().bits() & FSC_PPN_MASK) | (mode.iommu_mode() << FSC_MODE_SHIFT);
+                DC_VALID | DC_PDTV
+            }
+            None => {
+                self.fsc = 0;
+                DC_VALID
+            }
+        };
+        dma_wmb();
+        self.tc = tc | ats_bits;
+    }
+}
+
+impl MsiContext {
+    // Clears the MSI translation fields.
+    fn init(&mut self) {
+        self.msiptp = 0;
+        self.msi_addr_mask = 0;
+        self.msi_addr_pattern = 0;
+    }
+
+    // Sets up MSI translation using `msi_pt`.
+    fn set(&mut self, msi_pt: &MsiPageTable) {
+        const MSI_MODE_FLAT: u64 = 0x1;
+        const MSI_MODE_SHIFT: u64 = 60;
+        self.msiptp = msi_pt.base_address().pfn().bits() | (MSI_MODE_FLAT << MSI_MODE_SHIFT);
+
+        let (addr, mask) = msi_pt.msi_address_pattern();
+        self.msi_addr_mask = mask >> PFN_SHIFT;
+        self.msi_addr_pattern = addr.pfn().bits();
+    }
+}
+
+// A device context in a leaf directory table, along with its MSI translation fields if the
+// directory holds extended-format device contexts.
+struct DeviceContextEntry<'a> {
+    dc: &'a mut DeviceContext,
+    msi: Option<&'a mut MsiContext>,
+}
+
+impl DeviceContextEntry<'_> {
+    // Clears the device context.
+    fn init(&mut self) {
+        self.dc.init();
+        if let Some(msi) = self.msi.as_mut() {
+            msi.init();
+        }
+    }
+
+    // Marks the device context as valid, using `pt` and `msi_pt` for translation and allowing the
+    // device to use ATS according to `ats`.
+    fn set<T: GuestStagePagingMode>(
+        &mut self,
+        pt: &GuestStagePageTable<T>,
+        msi_pt: &MsiPageTable,
+        gscid: GscId,
+        ats: AtsMode,
+    ) -> Result<()> {
+        // MSIs can only be translated with extended-format device contexts.
+        let msi = self.msi.as_mut().ok_or(Error::MissingMsiSupport)?;
+        msi.set(msi_pt);
+        // The MSI fields are made visible to the IOMMU before the context is marked valid.
+        self.dc.set(pt, gscid, ats);
+        Ok(())
+    }
 }
 
 // A non-leaf device directory table entry. If valid, a non-leaf entry must point to the next
@@ -181,17 +423,19 @@ impl NonLeafEntry {
 
 // Represents a single entry in the device directory hierarchy.
 enum DeviceDirectoryEntry<'a> {
-    PresentLeaf(&'a mut DeviceContext),
-    NotPresentLeaf(&'a mut DeviceContext),
+    PresentLeaf(DeviceContextEntry<'a>),
+    NotPresentLeaf(DeviceContextEntry<'a>),
     NextLevel(DeviceDirectoryTable<'a>),
     Invalid(&'a mut NonLeafEntry, usize),
 }
 
 // Represents a single device directory table. Intermediate DDTs (level > 0) are made up entirely
-// of non-leaf entries, while Leaf DDTs (level == 0) are made up entirely of `DeviceContext`s.
+// of non-leaf entries, while Leaf DDTs (level == 0) are made up entirely of device contexts in
+// `format`.
 struct DeviceDirectoryTable<'a> {
     table_addr: SupervisorPageAddr,
     level: usize,
+    format: DeviceContextFormat,
     phantom: PhantomData<&'a mut DeviceDirectoryInner>,
 }
 
@@ -200,7 +444,8 @@ impl<'a> DeviceDirectoryTable<'a> {
     fn from_root(owner: &'a mut DeviceDirectoryInner) -> Self {
         Self {
             table_addr: owner.root.addr(),
-            level: owner.num_levels - 1,
+            level: owner.mode.levels() - 1,
+            format: owner.format,
             phantom: PhantomData,
         }
     }
@@ -212,11 +457,16 @@ impl<'a> DeviceDirectoryTable<'a> {
     //
     // If `nle` is valid, the PFN it contains must point to a next-level directory table page
     // that is uniquely owned by this device directory.
-    unsafe fn from_non_leaf_entry(nle: &'a mut NonLeafEntry, level: usize) -> Option<Self> {
+    unsafe fn from_non_leaf_entry(
+        nle: &'a mut NonLeafEntry,
+        level: usize,
+        format: DeviceContextFormat,
+    ) -> Option<Self> {
         if nle.valid() {
             Some(Self {
                 table_addr: nle.pfn().into(),
                 level,
+                format,
                 phantom: PhantomData,
             })
         } else {
@@ -226,21 +476,30 @@ impl<'a> DeviceDirectoryTable<'a> {
 
     // Returns the `DeviceDirectoryEntry` for `id` in this table.
     fn entry_for_id(&mut self, id: DeviceId) -> DeviceDirectoryEntry<'a> {
-        let index = id.level_index_bits(self.level);
+        let index = id.level_index_bits(self.level, self.format);
         use DeviceDirectoryEntry::*;
         if self.is_leaf() {
-            // Safety: self.table_addr is properly aligned and must point to an array of
-            // `DeviceContext`s if this is a leaf table. Further, `index` is guaranteed
-            // to be within in the bounds of the table.
-            let dc = unsafe {
-                let ptr = (self.table_addr.bits() as *mut DeviceContext).add(index);
-                // Pointer must be non-NULL.
-                ptr.as_mut().unwrap()
+            // Safety: self.table_addr is properly aligned and must point to an array of device
+            // contexts in `self.format` if this is a leaf table. Further, `index` is guaranteed
+            // to be within in the bounds of the table, and extended-format device contexts hold
+            // an `MsiContext` immediately after the `DeviceContext`.
+            let entry = unsafe {
+                let ptr = (self.table_addr.bits() as *mut u8).add(index * self.format.size());
+                // Pointers must be non-NULL.
+                let dc = (ptr as *mut DeviceContext).as_mut().unwrap();
+                let msi = match self.format {
+                    DeviceContextFormat::Base => None,
+                    DeviceContextFormat::Extended => {
+                        let ptr = ptr.add(core::mem::size_of::<DeviceContext>());
+                        Some((ptr as *mut MsiContext).as_mut().unwrap())
+                    }
+                };
+                DeviceContextEntry { dc, msi }
             };
-            if dc.present() {
-                PresentLeaf(dc)
+            if entry.dc.present() {
+                PresentLeaf(entry)
             } else {
-                NotPresentLeaf(dc)
+                NotPresentLeaf(entry)
             }
         } else {
             // Safety: self.table_addr is properly aligned and must point to an array of
@@ -254,7 +513,8 @@ impl<'a> DeviceDirectoryTable<'a> {
             if nle.valid() {
                 // Safety: If `nle` is valid, the PFN is guaranteed to point to a next-level
                 // directory table owned by this device directory.
-                let table = unsafe { Self::from_non_leaf_entry(nle, self.level - 1).unwrap() };
+                let table =
+                    unsafe { Self::from_non_leaf_entry(nle, self.level - 1, self.format).unwrap() };
                 NextLevel(table)
             } else {
                 Invalid(nle, self.level - 1)
@@ -279,7 +539,7 @@ impl<'a> DeviceDirectoryTable<'a> {
                 // ownership over the memory it refers to.
                 unsafe {
                     // Unwrap ok, we just marked the entry as valid.
-                    Self::from_non_leaf_entry(nle, level).unwrap()
+                    Self::from_non_leaf_entry(nle, level, self.format).unwrap()
                 }
             }
             _ => {
@@ -297,11 +557,15 @@ impl<'a> DeviceDirectoryTable<'a> {
 
 struct DeviceDirectoryInner {
     root: Page<InternalClean>,
-    num_levels: usize,
+    mode: DirectoryMode,
+    format: DeviceContextFormat,
 }
 
 impl DeviceDirectoryInner {
-    fn get_context_for_id(&mut self, id: DeviceId) -> Option<&mut DeviceContext> {
+    fn get_context_for_id(&mut self, id: DeviceId) -> Option<DeviceContextEntry<'_>> {
+        if !self.mode.supports_device_id(id, self.format) {
+            return None;
+        }
         let mut entry = DeviceDirectoryTable::from_root(self).entry_for_id(id);
         use DeviceDirectoryEntry::*;
         while let NextLevel(mut t) = entry {
@@ -316,38 +580,62 @@ impl DeviceDirectoryInner {
 
 /// Defines the layout of the device directory table. Intermediate and leaf tables have the same
 /// format regardless of the number of levels.
-pub trait DirectoryMode {
-    /// The number of levels in the device directory hierarchy.
-    const LEVELS: usize;
-    /// The value that should be programmed into ddtp.iommu_mode for this translation mode.
-    const IOMMU_MODE: u64;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirectoryMode {
+    /// A single leaf table, supporting 6-bit (7-bit with base-format contexts) device IDs.
+    OneLevel,
+    /// A 2-level table supporting 15-bit (16-bit with base-format contexts) device IDs.
+    TwoLevel,
+    /// A 3-level table supporting up to 24-bit device IDs.
+    ThreeLevel,
 }
 
-/// A 3-level device directory table supporting up to 24-bit requester IDs.
-pub enum Ddt3Level {}
+impl DirectoryMode {
+    /// Returns the number of levels in the device directory hierarchy.
+    pub fn levels(&self) -> usize {
+        use DirectoryMode::*;
+        match self {
+            OneLevel => 1,
+            TwoLevel => 2,
+            ThreeLevel => 3,
+        }
+    }
+
+    /// Returns the value that should be programmed into ddtp.iommu_mode for this mode.
+    pub fn iommu_mode(&self) -> u64 {
+        use DirectoryMode::*;
+        match self {
+            OneLevel => 2,
+            TwoLevel => 3,
+            ThreeLevel => 4,
+        }
+    }
 
-impl DirectoryMode for Ddt3Level {
-    const LEVELS: usize = 3;
-    const IOMMU_MODE: u64 = 4;
+    /// Returns if `id` can be looked up in a device directory of this mode holding device contexts
+    /// of `format`.
+    pub fn supports_device_id(&self, id: DeviceId, format: DeviceContextFormat) -> bool {
+        let bits = format.leaf_index_bits() + NON_LEAF_INDEX_BITS * (self.levels() - 1);
+        (id.bits() >> bits) == 0
+    }
 }
 
 /// Represents the device directory table for the IOMMU. The IOMMU hardware uses the DDT to map
 /// a requester ID to the translation context for the device.
-pub struct DeviceDirectory<D: DirectoryMode> {
+pub struct DeviceDirectory {
     inner: Mutex<DeviceDirectoryInner>,
-    phantom: PhantomData<D>,
 }
 
-impl<D: DirectoryMode> DeviceDirectory<D> {
-    /// Creates a new `DeviceDirectory` using `root` as the root table page.
-    pub fn new(root: Page<InternalClean>) -> Self {
-        let inner = DeviceDirectoryInner {
-            root,
-            num_levels: D::LEVELS,
-        };
+impl DeviceDirectory {
+    /// Creates a new `DeviceDirectory` of `mode`, holding device contexts of `format`, using `root`
+    /// as the root table page.
+    pub fn new(
+        root: Page<InternalClean>,
+        mode: DirectoryMode,
+        format: DeviceContextFormat,
+    ) -> Self {
+        let inner = DeviceDirectoryInner { root, mode, format };
         Self {
             inner: Mutex::new(inner),
-            phantom: PhantomData,
         }
     }
 
@@ -365,40 +653,43 @@ impl<D: DirectoryMode> DeviceDirectory<D> {
         get_page: &mut dyn FnMut() -> Option<Page<InternalClean>>,
     ) -> Result<()> {
         let mut inner = self.inner.lock();
+        if !inner.mode.supports_device_id(id, inner.format) {
+            return Err(Error::DeviceIdOutOfRange(id));
+        }
         // Silence bogus auto-deref lint, see https://github.com/rust-lang/rust-clippy/issues/9101.
         #[allow(clippy::explicit_auto_deref)]
         let mut table = DeviceDirectoryTable::from_root(&mut *inner);
         while !table.is_leaf() {
             table = table.next_level_or_fill(id, get_page)?;
         }
-        if let DeviceDirectoryEntry::NotPresentLeaf(dc) = table.entry_for_id(id) {
-            dc.init();
+        if let DeviceDirectoryEntry::NotPresentLeaf(mut entry) = table.entry_for_id(id) {
+            entry.init();
         }
         Ok(())
     }
 
     /// Enables IOMMU translation for the specified device, using `pt` for 2nd-stage translation
-    /// and `msi_pt` for MSI translation. The device must have been previously added with
-    /// `add_device()`.
+    /// and `msi_pt` for MSI translation. `ats` controls whether the device may cache translations
+    /// using ATS. The device must have been previously added with `add_device()`.
     pub fn enable_device<T: GuestStagePagingMode>(
         &self,
         id: DeviceId,
         pt: &GuestStagePageTable<T>,
         msi_pt: &MsiPageTable,
         gscid: GscId,
+        ats: AtsMode,
     ) -> Result<()> {
         if pt.page_owner_id() != msi_pt.owner() {
             return Err(Error::OwnerMismatch);
         }
         let mut inner = self.inner.lock();
-        let entry = inner
+        let mut entry = inner
             .get_context_for_id(id)
             .ok_or(Error::DeviceNotFound(id))?;
-        if entry.valid() {
+        if entry.dc.valid() {
             return Err(Error::DeviceAlreadyEnabled(id));
         }
-        entry.set(pt, msi_pt, gscid);
-        Ok(())
+        entry.set(pt, msi_pt, gscid, ats)
     }
 
     /// Disables IOMMU translation for the specified device.
@@ -406,16 +697,73 @@ impl<D: DirectoryMode> DeviceDirectory<D> {
         let mut inner = self.inner.lock();
         let entry = inner
             .get_context_for_id(id)
-            .ok_or(Error::DeviceNotFound(id))?;
+            .ok_or(Error::DeviceNotFound(id))?
+            .dc;
         if !entry.valid() {
             return Err(Error::DeviceNotEnabled(id));
         }
         entry.invalidate();
         Ok(())
     }
+
+    /// Enables first-stage translation of DMAs tagged with a process ID from the specified device,
+    /// using the process directory table of format `mode` at `root`. The process directory table
+    /// and the first-stage page tables it refers to live in the guest physical address space of
+    /// the device's owner, and are walked by the IOMMU through the device's 2nd-stage page table.
+    /// DMAs without a process ID continue to bypass first-stage translation.
+    pub fn enable_process_directory(
+        &self,
+        id: DeviceId,
+        mode: ProcessDirectoryMode,
+        root: GuestPageAddr,
+    ) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let entry = inner
+            .get_context_for_id(id)
+            .ok_or(Error::DeviceNotFound(id))?
+            .dc;
+        if !entry.valid() {
+            return Err(Error::DeviceNotEnabled(id));
+        }
+        entry.set_process_directory(Some((mode, root)));
+        Ok(())
+    }
+
+    /// Disables first-stage translation for the specified device.
+    pub fn disable_process_directory(&self, id: DeviceId) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let entry = inner
+            .get_context_for_id(id)
+            .ok_or(Error::DeviceNotFound(id))?
+            .dc;
+        if entry.process_directory_mode().is_none() {
+            return Err(Error::ProcessDirectoryNotEnabled(id));
+        }
+        entry.set_process_directory(None);
+        Ok(())
+    }
+
+    /// Returns the mode of the process directory table used by the specified device, or `None`
+    /// if first-stage translation is disabled.
+    pub fn process_directory_mode(&self, id: DeviceId) -> Result<Option<ProcessDirectoryMode>> {
+        let mut inner = self.inner.lock();
+        let entry = inner
+            .get_context_for_id(id)
+            .ok_or(Error::DeviceNotFound(id))?
+            .dc;
+        if !entry.valid() {
+            return Err(Error::DeviceNotEnabled(id));
+        }
+        Ok(entry.process_directory_mode())
+    }
 }
 
 fn _assert_ddt_layout() {
-    const_assert!(core::mem::size_of::<DeviceContext>() << LEAF_INDEX_BITS == 4096);
+    const_assert!(core::mem::size_of::<DeviceContext>() << BASE_LEAF_INDEX_BITS == 4096);
+    const_assert!(
+        (core::mem::size_of::<DeviceContext>() + core::mem::size_of::<MsiContext>())
+            << EXTENDED_LEAF_INDEX_BITS
+            == 4096
+    );
     const_assert!(core::mem::size_of::<NonLeafEntry>() << NON_LEAF_INDEX_BITS == 4096);
 }
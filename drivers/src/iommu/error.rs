@@ -4,7 +4,7 @@
 
 use riscv_pages::SupervisorPageAddr;
 
-use super::device_directory::{DeviceId, GscId};
+use super::device_directory::{DeviceId, GscId, ProcessDirectoryMode, ProcessId};
 use crate::imsic::ImsicLocation;
 use crate::pci::{Address, PciError};
 
@@ -23,6 +23,12 @@ pub enum Error {
     MissingGStageSupport,
     /// Missing required MSI translation support.
     MissingMsiSupport,
+    /// None of the device directory modes able to hold the platform's device IDs are supported.
+    UnsupportedDirectoryMode,
+    /// Missing Sv39 or Sv48 first-stage translation support.
+    MissingFirstStageSupport,
+    /// The IOMMU doesn't support process directory tables of the given format.
+    UnsupportedProcessDirectoryMode(ProcessDirectoryMode),
     /// Not enough pages were supplied to create an MSI page table.
     InsufficientMsiTablePages,
     /// The supplied MSI page table pages were not properly aligned.
@@ -39,18 +45,36 @@ pub enum Error {
     MsiNotMapped(ImsicLocation),
     /// Failed to allocate a page.
     OutOfPages,
+    /// Failed to allocate memory.
+    AllocError,
     /// Got a leaf entry when a non-leaf entry was expected.
     NotIntermediateTable,
     /// Unable to map a PCI BDF address to an IOMMU device ID.
     PciAddressTooLarge(Address),
     /// Mismatch between page table and device ownership.
     OwnerMismatch,
+    /// A platform device in the device-tree names an invalid device ID.
+    InvalidPlatformDeviceId(u32),
+    /// A platform device in the device-tree uses the same device ID as a PCI device.
+    PlatformDeviceIdConflict(DeviceId),
+    /// An "iommus" property in the device-tree is malformed or refers to an unknown IOMMU.
+    InvalidIommusProperty,
+    /// The IOMMU's "#iommu-cells" property in the device-tree is missing or isn't 1.
+    InvalidIommuCells,
+    /// The device ID is too large to be looked up in the device directory.
+    DeviceIdOutOfRange(DeviceId),
     /// No device context found.
     DeviceNotFound(DeviceId),
+    /// DMA from the PCI device isn't translated by the IOMMU.
+    DeviceNotTranslated(DeviceId),
     /// The device already has an active device context.
     DeviceAlreadyEnabled(DeviceId),
     /// The device does not have an active device context.
     DeviceNotEnabled(DeviceId),
+    /// The device does not have a process directory table.
+    ProcessDirectoryNotEnabled(DeviceId),
+    /// The process ID can't be looked up in the device's process directory table.
+    InvalidProcessId(ProcessId),
     /// The head/tail pointer is out of bounds for the queue.
     InvalidQueuePointer(usize),
     /// No more elements can be pushed to the queue.
@@ -65,6 +89,18 @@ pub enum Error {
     GscIdAlreadyFree(GscId),
     /// Attempted to free a GSCID that's currently being used for translation.
     GscIdInUse(GscId),
+    /// The IOMMU can't signal interrupts with MSIs.
+    MissingMsiInterrupts,
+    /// The IOMMU didn't process the posted commands in time.
+    CommandQueueStalled,
+    /// The IOMMU didn't turn the command queue on or off in time.
+    CommandQueueUnresponsive,
+    /// The IOMMU encountered an illegal command in the command queue.
+    IllegalCommand,
+    /// A command didn't complete in time, e.g. a device failed to acknowledge an ATS invalidation.
+    CommandTimeout,
+    /// The IOMMU encountered a memory fault accessing the command queue.
+    CommandQueueMemoryFault,
 }
 
 /// Holds results for IOMMU operations.
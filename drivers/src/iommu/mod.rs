@@ -5,26 +5,33 @@
 mod core;
 mod device_directory;
 mod error;
+#[cfg(test)]
+mod model;
 mod msi_page_table;
 mod queue;
 mod registers;
 
-pub use self::core::Iommu;
-pub use device_directory::{DeviceId, GscId};
+pub use self::core::{CompletionWaiter, Iommu};
+pub use device_directory::{DeviceId, GscId, ProcessDirectoryMode, ProcessId, PscId};
 pub use error::Error as IommuError;
 pub use error::Result as IommuResult;
 pub use msi_page_table::MsiPageTable;
+pub use queue::FaultRecord;
 
 #[cfg(test)]
 mod tests {
     use super::device_directory::*;
+    use super::model::*;
     use super::queue::*;
     use super::*;
     use crate::imsic::*;
+    use crate::pci::{Address, PciDevice};
+    use device_tree::DeviceTree;
     use page_tracking::{HwMemMapBuilder, HypPageAlloc, PageList, PageTracker};
     use riscv_page_tables::{GuestStagePageTable, PagingMode, Sv48x4};
     use riscv_pages::*;
     use std::marker::PhantomData;
+    use std::vec::Vec;
 
     const IMSIC_START: u64 = 0x2800_0000;
     const IMSIC_SIZE: u64 = 0x0010_0000;
@@ -143,6 +150,96 @@ mod tests {
         GuestStagePageTable::new(root_pages, owner, page_tracker).unwrap()
     }
 
+    fn pci_address(dev: u32) -> Address {
+        Address::try_from_components(0, 0, dev, 0).unwrap()
+    }
+
+    // Probes a software model of the IOMMU, with PCI devices 00:02.0 and 00:03.0 in its device
+    // directory.
+    fn stub_iommu(
+        page_tracker: PageTracker,
+        pages: &mut PageList<Page<ConvertedClean>>,
+    ) -> (Iommu, IommuModel) {
+        let model = IommuModel::new();
+        let pci_devices = [pci_address(2), pci_address(3)].map(|a| DeviceId::try_from(a).unwrap());
+        let iommu = Iommu::new(
+            model.registers(),
+            DeviceId::new(0xff).unwrap(),
+            &pci_devices,
+            &DeviceTree::new(),
+            None,
+            &mut || {
+                page_tracker
+                    .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+                    .ok()
+            },
+        )
+        .unwrap();
+        (iommu, model)
+    }
+
+    // Returns a device tree with our IOMMU, with phandle 1 and `iommu_cells` cells per "iommus"
+    // entry, another IOMMU with phandle 2 and two cells per entry, and a DMA master with `iommus`
+    // as its "iommus" property.
+    fn iommus_dt(iommu_cells: u32, iommus: &[u32]) -> DeviceTree {
+        let mut tree = DeviceTree::new();
+        let root = tree.add_node("", None).unwrap();
+        for (name, phandle, cells) in [("iommu@0", 1, iommu_cells), ("iommu@1", 2, 2)] {
+            let node_id = tree.add_node(name, Some(root)).unwrap();
+            let node = tree.get_mut_node(node_id).unwrap();
+            node.add_prop("phandle")
+                .unwrap()
+                .set_value_u32(&[phandle])
+                .unwrap();
+            node.add_prop("#iommu-cells")
+                .unwrap()
+                .set_value_u32(&[cells])
+                .unwrap();
+        }
+        let node_id = tree.add_node("dma@0", Some(root)).unwrap();
+        let node = tree.get_mut_node(node_id).unwrap();
+        node.add_prop("iommus")
+            .unwrap()
+            .set_value_u32(iommus)
+            .unwrap();
+        tree
+    }
+
+    // Returns the config space of a PCI Express endpoint with ATS and PRI capabilities. Devices hold
+    // on to their registers, so it's never freed.
+    fn ats_config() -> &'static mut [u32] {
+        let config = vec![0u32; 1024].leak();
+        config[0] = 0x1000_1af4; // Device and vendor ID
+        config[1] = 0x0010_0000; // Capabilities list
+        config[13] = 0x40; // Start of the capability list.
+        config[16] = 0x0002_0010; // PCI Express, version 2 endpoint.
+        config[0x100 / 4] = 0x1101_000f; // ATS
+        config[0x110 / 4] = 0x0001_0013; // PRI
+        config
+    }
+
+    // Maps a page from `pages` at `gpa` in `pt`, returning the address of the page.
+    fn stub_map_page(
+        pt: &GuestStagePageTable<Sv48x4>,
+        page_tracker: &PageTracker,
+        pages: &mut PageList<Page<ConvertedClean>>,
+        gpa: GuestPageAddr,
+    ) -> SupervisorPageAddr {
+        let owner = pt.page_owner_id();
+        let mapper = pt
+            .map_range(gpa, PageSize::Size4k, 1, &mut || {
+                page_tracker
+                    .assign_page_for_internal_state(pages.pop().unwrap(), owner)
+                    .ok()
+            })
+            .unwrap();
+        let page = pages.pop().unwrap();
+        let addr = page.addr();
+        let mappable = page_tracker.assign_page_for_mapping(page, owner).unwrap();
+        mapper.map_page(gpa, mappable).unwrap();
+        addr
+    }
+
     #[test]
     fn msi_page_table() {
         let (page_tracker, mut pages) = stub_mem();
@@ -204,7 +301,11 @@ mod tests {
         let ddt_page = page_tracker
             .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
             .unwrap();
-        let ddt = DeviceDirectory::<Ddt3Level>::new(ddt_page);
+        let ddt = DeviceDirectory::new(
+            ddt_page,
+            DirectoryMode::ThreeLevel,
+            DeviceContextFormat::Extended,
+        );
         for i in 0..16 {
             let id = DeviceId::new(i).unwrap();
             ddt.add_device(id, &mut || {
@@ -217,17 +318,144 @@ mod tests {
 
         let gscid = GscId::new(0);
         let dev = DeviceId::new(2).unwrap();
-        assert!(ddt.enable_device(dev, &pt, &msi_pt, gscid).is_ok());
+        assert!(ddt
+            .enable_device(dev, &pt, &msi_pt, gscid, AtsMode::Disabled)
+            .is_ok());
         assert!(ddt.disable_device(dev).is_ok());
         let bad_dev = DeviceId::new(1 << 16).unwrap();
-        assert!(ddt.enable_device(bad_dev, &pt, &msi_pt, gscid).is_err());
+        assert!(ddt
+            .enable_device(bad_dev, &pt, &msi_pt, gscid, AtsMode::Disabled)
+            .is_err());
 
         let (bad_msi_pt, _) = stub_msi_page_table(
             page_tracker.clone(),
             &mut pages,
             PageOwnerId::new(5).unwrap(),
         );
-        assert!(ddt.enable_device(dev, &pt, &bad_msi_pt, gscid).is_err());
+        assert!(ddt
+            .enable_device(dev, &pt, &bad_msi_pt, gscid, AtsMode::Disabled)
+            .is_err());
+    }
+
+    #[test]
+    fn directory_modes() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (msi_pt, _) =
+            stub_msi_page_table(page_tracker.clone(), &mut pages, PageOwnerId::host());
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, PageOwnerId::host());
+        let mut get_page = || {
+            page_tracker
+                .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+                .ok()
+        };
+
+        let gscid = GscId::new(0);
+        let dev = DeviceId::new(0x3f).unwrap();
+        let ddt = DeviceDirectory::new(
+            get_page().unwrap(),
+            DirectoryMode::OneLevel,
+            DeviceContextFormat::Extended,
+        );
+        assert!(ddt.add_device(dev, &mut get_page).is_ok());
+        // Device IDs that alias in a single leaf table must be rejected.
+        let bad_dev = DeviceId::new(0x7f).unwrap();
+        assert!(ddt.add_device(bad_dev, &mut get_page).is_err());
+        assert!(ddt
+            .enable_device(bad_dev, &pt, &msi_pt, gscid, AtsMode::Disabled)
+            .is_err());
+        assert!(ddt
+            .enable_device(dev, &pt, &msi_pt, gscid, AtsMode::Disabled)
+            .is_ok());
+
+        let dev = DeviceId::new(0x7fff).unwrap();
+        let ddt = DeviceDirectory::new(
+            get_page().unwrap(),
+            DirectoryMode::TwoLevel,
+            DeviceContextFormat::Extended,
+        );
+        assert!(ddt.add_device(dev, &mut get_page).is_ok());
+        assert!(ddt
+            .add_device(DeviceId::new(0x8000).unwrap(), &mut get_page)
+            .is_err());
+        assert!(ddt
+            .enable_device(dev, &pt, &msi_pt, gscid, AtsMode::Disabled)
+            .is_ok());
+
+        // Base-format device contexts can't translate MSIs.
+        let dev = DeviceId::new(0x7f).unwrap();
+        let ddt = DeviceDirectory::new(
+            get_page().unwrap(),
+            DirectoryMode::OneLevel,
+            DeviceContextFormat::Base,
+        );
+        assert!(ddt.add_device(dev, &mut get_page).is_ok());
+        assert!(ddt
+            .enable_device(dev, &pt, &msi_pt, gscid, AtsMode::Disabled)
+            .is_err());
+
+        let base = DeviceContextFormat::Base;
+        let extended = DeviceContextFormat::Extended;
+        let id = DeviceId::new(0xffff).unwrap();
+        assert!(!DirectoryMode::TwoLevel.supports_device_id(id, extended));
+        assert!(DirectoryMode::TwoLevel.supports_device_id(id, base));
+        let id = DeviceId::new(0xff_ffff).unwrap();
+        assert!(DirectoryMode::ThreeLevel.supports_device_id(id, extended));
+    }
+
+    #[test]
+    fn process_directory() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (msi_pt, _) =
+            stub_msi_page_table(page_tracker.clone(), &mut pages, PageOwnerId::host());
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, PageOwnerId::host());
+
+        let ddt_page = page_tracker
+            .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+            .unwrap();
+        let ddt = DeviceDirectory::new(
+            ddt_page,
+            DirectoryMode::ThreeLevel,
+            DeviceContextFormat::Extended,
+        );
+        let dev = DeviceId::new(2).unwrap();
+        ddt.add_device(dev, &mut || {
+            page_tracker
+                .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+                .ok()
+        })
+        .unwrap();
+
+        let root = PageAddr::new(RawAddr::guest(0x8000_0000, PageOwnerId::host())).unwrap();
+        // The device must be enabled before it can use a process directory.
+        assert!(ddt
+            .enable_process_directory(dev, ProcessDirectoryMode::Pd8, root)
+            .is_err());
+        ddt.enable_device(dev, &pt, &msi_pt, GscId::new(0), AtsMode::Enabled)
+            .unwrap();
+        assert_eq!(ddt.process_directory_mode(dev).unwrap(), None);
+        assert!(ddt.disable_process_directory(dev).is_err());
+        assert!(ddt
+            .enable_process_directory(dev, ProcessDirectoryMode::Pd17, root)
+            .is_ok());
+        assert_eq!(
+            ddt.process_directory_mode(dev).unwrap(),
+            Some(ProcessDirectoryMode::Pd17)
+        );
+        assert!(ddt.disable_process_directory(dev).is_ok());
+        assert_eq!(ddt.process_directory_mode(dev).unwrap(), None);
+
+        // Re-enabling the device resets first-stage translation.
+        ddt.enable_process_directory(dev, ProcessDirectoryMode::Pd20, root)
+            .unwrap();
+        ddt.disable_device(dev).unwrap();
+        ddt.enable_device(dev, &pt, &msi_pt, GscId::new(0), AtsMode::Enabled)
+            .unwrap();
+        assert_eq!(ddt.process_directory_mode(dev).unwrap(), None);
+
+        let pid = ProcessId::new(0x1ff).unwrap();
+        assert!(!ProcessDirectoryMode::Pd8.supports_process_id(pid));
+        assert!(ProcessDirectoryMode::Pd17.supports_process_id(pid));
+        assert!(ProcessId::new(1 << 20).is_none());
     }
 
     #[test]
@@ -240,14 +468,513 @@ mod tests {
         assert!(cq.is_empty());
         assert!(cq.push(Command::iotinval_gvma(None, None)).is_ok());
         assert!(cq.push(Command::iodir_inval_ddt(DeviceId::new(2))).is_ok());
-        assert!(cq.push(Command::iofence()).is_ok());
+        assert!(cq.push(Command::iofence(false)).is_ok());
         assert!(!cq.is_empty());
         assert_eq!(cq.tail(), 3);
         assert!(cq.update_head(7).is_err());
         assert!(cq.update_head(3).is_ok());
         assert!(cq.push(Command::iodir_inval_ddt(None)).is_ok());
-        assert!(cq.push(Command::iofence()).is_ok());
+        assert!(cq.push(Command::iofence(false)).is_ok());
         assert!(cq.update_head(1).is_err());
         assert!(cq.update_head(4).is_ok());
     }
+
+    #[test]
+    fn ats_commands() {
+        let (page_tracker, mut pages) = stub_mem();
+        let queue_page = page_tracker
+            .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+            .unwrap();
+        let mut cq = CommandQueue::new(queue_page);
+        let dev = DeviceId::new(0x1234).unwrap();
+        let pid = ProcessId::new(5);
+        assert!(cq.push(Command::ats_inval(dev, None)).is_ok());
+        assert!(cq.push(Command::ats_inval(dev, pid)).is_ok());
+        assert!(cq
+            .push(Command::ats_prgr(
+                dev,
+                None,
+                3,
+                PageResponseCode::InvalidRequest
+            ))
+            .is_ok());
+        assert!(cq
+            .push(Command::ats_prgr(
+                dev,
+                pid,
+                0x1ff,
+                PageResponseCode::ResponseFailure
+            ))
+            .is_ok());
+        let entries = cq.base_address().bits() as *const [u64; 2];
+        // Safety: the queue page is owned by `cq` and holds the four commands we just pushed.
+        let entries: Vec<[u64; 2]> = (0..4).map(|i| unsafe { entries.add(i).read() }).collect();
+        // ATS commands target the device ID in bits 63:40, with DSV set, and carry the PCIe
+        // message payload in the second dword.
+        let op = (0x1234 << 40) | (1 << 10) | 0x4;
+        let pid_bits = (1 << 11) | (5 << 12);
+        let inval_all = 0x7fff_ffff_ffff_f800;
+        assert_eq!(
+            entries,
+            [
+                [op, inval_all],
+                [op | pid_bits, inval_all],
+                [op | (1 << 7), (3 << 32) | (0x1 << 44)],
+                [op | (1 << 7) | pid_bits, (0x1ff << 32) | (0xf << 44)],
+            ]
+        );
+    }
+
+    #[test]
+    fn page_request_queue() {
+        let (page_tracker, mut pages) = stub_mem();
+        let queue_page = page_tracker
+            .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+            .unwrap();
+        let mut pq = PageRequestQueue::new(queue_page);
+        // Fill in two requests from device 0x10, the second of which is tagged with process ID 5
+        // and ends page request group 3.
+        let entries = pq.base_address().bits() as *mut [u64; 2];
+        // Safety: the queue page is owned by `pq` and is large enough to hold two entries.
+        unsafe {
+            entries.write([0x10 << 40, 0x1000]);
+            entries
+                .add(1)
+                .write([(0x10 << 40) | (1 << 32) | (5 << 12), 0x2000 | (3 << 3) | 4]);
+        }
+        assert!(pq.is_empty());
+        assert!(pq.update_tail(pq.capacity()).is_err());
+        assert!(pq.update_tail(2).is_ok());
+        let req = pq.pop().unwrap();
+        assert_eq!(req.device_id(), DeviceId::new(0x10).unwrap());
+        assert!(req.process_id().is_none());
+        assert!(!req.is_last_in_group());
+        let req = pq.pop().unwrap();
+        assert_eq!(req.process_id(), ProcessId::new(5));
+        assert!(req.is_last_in_group());
+        assert_eq!(req.group_index(), 3);
+        assert!(pq.pop().is_err());
+        assert_eq!(pq.head(), 2);
+    }
+
+    #[test]
+    fn gscid_alloc() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, _model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let owner = PageOwnerId::host();
+        let gscids: Vec<GscId> = (0..64).map(|_| iommu.alloc_gscid(owner).unwrap()).collect();
+        assert!(matches!(
+            iommu.alloc_gscid(owner),
+            Err(IommuError::OutOfGscIds)
+        ));
+        assert!(iommu.free_gscid(gscids[5]).is_ok());
+        assert!(matches!(
+            iommu.free_gscid(gscids[5]),
+            Err(IommuError::GscIdAlreadyFree(_))
+        ));
+        assert_eq!(iommu.alloc_gscid(owner).unwrap(), gscids[5]);
+        assert!(matches!(
+            iommu.free_gscid(GscId::new(64)),
+            Err(IommuError::InvalidGscId(_))
+        ));
+
+        // GSCIDs can't be freed while devices are attached with them.
+        let (msi_pt, _) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let mut dev = PciDevice::new_in_test(pci_address(2), owner);
+        iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscids[0])
+            .unwrap();
+        assert!(matches!(
+            iommu.free_gscid(gscids[0]),
+            Err(IommuError::GscIdInUse(_))
+        ));
+        iommu.detach_pci_device(&mut dev, gscids[0]).unwrap();
+        assert!(iommu.free_gscid(gscids[0]).is_ok());
+    }
+
+    #[test]
+    fn attach_pci_device() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let owner = PageOwnerId::host();
+        let (msi_pt, dest_geometry) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let gpa = PageAddr::new(RawAddr::guest(0x8000_0000, owner)).unwrap();
+        let spa = stub_map_page(&pt, &page_tracker, &mut pages, gpa);
+
+        let src_loc = ImsicLocation::new(
+            ImsicGroupId::new(0),
+            ImsicHartId::new(4),
+            ImsicFileId::supervisor(),
+        );
+        let dest_loc = ImsicLocation::new(
+            ImsicGroupId::new(0),
+            ImsicHartId::new(3),
+            ImsicFileId::guest(0),
+        );
+        let dest_addr = dest_geometry.location_to_addr(dest_loc).unwrap();
+        // Not safe, just a test.
+        let imsic_page = unsafe { StubImsicPage::<ConvertedClean>::new(dest_addr) };
+        page_tracker
+            .assign_page_for_mapping(imsic_page, owner)
+            .unwrap();
+        msi_pt.map(src_loc, dest_loc).unwrap();
+        let msi_addr = msi_pt.src_geometry().location_to_addr(src_loc).unwrap();
+
+        // DMA is blocked until the device is attached.
+        let dev_id = DeviceId::try_from(pci_address(2)).unwrap();
+        assert!(model.dma(dev_id.bits(), gpa.bits(), false).is_none());
+
+        let gscid = iommu.alloc_gscid(owner).unwrap();
+        let mut other_dev = PciDevice::new_in_test(pci_address(2), PageOwnerId::new(5).unwrap());
+        assert!(matches!(
+            iommu.attach_pci_device(&mut other_dev, &pt, &msi_pt, gscid),
+            Err(IommuError::OwnerMismatch)
+        ));
+        assert!(!other_dev.is_iommu_attached());
+        let mut dev = PciDevice::new_in_test(pci_address(2), owner);
+        assert!(iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscid)
+            .is_ok());
+        assert!(dev.is_iommu_attached());
+        assert_eq!(
+            model.dma(dev_id.bits(), gpa.bits() + 0x10, true),
+            Some(spa.bits() + 0x10)
+        );
+        assert_eq!(
+            model.dma(dev_id.bits(), msi_addr.bits(), true),
+            Some(dest_addr.bits())
+        );
+
+        // Devices that weren't found behind the IOMMU at probe time can't be attached.
+        let address = Address::try_from_components(0, 1, 0, 0).unwrap();
+        assert!(!iommu.translates_pci_device(address));
+        assert!(iommu.translates_pci_device(pci_address(2)));
+        let mut unknown_dev = PciDevice::new_in_test(address, owner);
+        assert!(matches!(
+            iommu.attach_pci_device(&mut unknown_dev, &pt, &msi_pt, gscid),
+            Err(IommuError::DeviceNotTranslated(_))
+        ));
+        assert!(!unknown_dev.is_iommu_attached());
+
+        // Detaching the device flushes its device context from the IOMMU.
+        iommu.detach_pci_device(&mut dev, gscid).unwrap();
+        assert!(!dev.is_iommu_attached());
+        assert!(model
+            .take_commands()
+            .contains(&ModelCommand::IodirInvalDdt {
+                dev: Some(dev_id.bits())
+            }));
+        assert!(model.dma(dev_id.bits(), gpa.bits(), false).is_none());
+    }
+
+    #[test]
+    fn fence() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let owner = PageOwnerId::host();
+        let (msi_pt, _) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let gpa = PageAddr::new(RawAddr::guest(0x8000_0000, owner)).unwrap();
+        let spa = stub_map_page(&pt, &page_tracker, &mut pages, gpa);
+        let gscid = iommu.alloc_gscid(owner).unwrap();
+        let mut dev = PciDevice::new_in_test(pci_address(2), owner);
+        iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscid)
+            .unwrap();
+        let dev_id = DeviceId::try_from(pci_address(2)).unwrap();
+        assert_eq!(
+            model.dma(dev_id.bits(), gpa.bits(), false),
+            Some(spa.bits())
+        );
+
+        // The IOMMU keeps using the cached translation until it's fenced.
+        assert_eq!(
+            pt.invalidate_range(gpa, PageSize::Size4k as u64, |_, _| true)
+                .unwrap()
+                .count(),
+            1
+        );
+        assert_eq!(
+            model.dma(dev_id.bits(), gpa.bits(), false),
+            Some(spa.bits())
+        );
+        model.take_commands();
+        assert!(iommu.fence(gscid, None).is_ok());
+        assert_eq!(
+            model.take_commands(),
+            [
+                ModelCommand::IotinvalGvma {
+                    gscid: Some(gscid.bits()),
+                    addr: None
+                },
+                ModelCommand::Iofence { interrupt: false }
+            ]
+        );
+        assert!(model.dma(dev_id.bits(), gpa.bits(), false).is_none());
+
+        // A fence the IOMMU rejects is reported, and doesn't stop later fences from completing.
+        model.fail_next_command();
+        assert!(matches!(
+            iommu.fence(gscid, None),
+            Err(IommuError::IllegalCommand)
+        ));
+        assert!(iommu.fence(gscid, Some(gpa)).is_ok());
+        assert!(model.take_commands().contains(&ModelCommand::IotinvalGvma {
+            gscid: Some(gscid.bits()),
+            addr: Some(gpa.bits())
+        }));
+    }
+
+    #[test]
+    fn device_tlb_invalidation() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let owner = PageOwnerId::host();
+        let (msi_pt, _) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let gpa = PageAddr::new(RawAddr::guest(0x8000_0000, owner)).unwrap();
+        stub_map_page(&pt, &page_tracker, &mut pages, gpa);
+        let gscid = iommu.alloc_gscid(owner).unwrap();
+        let mut dev = PciDevice::new_endpoint_in_test(pci_address(2), owner, ats_config());
+        iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscid)
+            .unwrap();
+        let mut other_dev = PciDevice::new_in_test(pci_address(3), owner);
+        iommu
+            .attach_pci_device(&mut other_dev, &pt, &msi_pt, gscid)
+            .unwrap();
+        let dev_id = DeviceId::try_from(pci_address(2)).unwrap();
+        let other_id = DeviceId::try_from(pci_address(3)).unwrap();
+
+        // Only devices that support ATS are allowed to cache translations.
+        assert!(model
+            .ats_translate(other_id.bits(), gpa.bits(), false)
+            .is_none());
+        assert_eq!(
+            model.ats_translate(dev_id.bits(), gpa.bits(), false),
+            Some(gpa.bits())
+        );
+        assert!(model.in_device_tlb(dev_id.bits(), gpa.bits()));
+
+        // Fences shoot down the translations cached by the devices attached with the GSCID.
+        model.take_commands();
+        assert!(iommu.fence(gscid, None).is_ok());
+        assert_eq!(
+            model.take_commands(),
+            [
+                ModelCommand::IotinvalGvma {
+                    gscid: Some(gscid.bits()),
+                    addr: None
+                },
+                ModelCommand::AtsInval { dev: dev_id.bits() },
+                ModelCommand::Iofence { interrupt: false }
+            ]
+        );
+        assert!(!model.in_device_tlb(dev_id.bits(), gpa.bits()));
+
+        // So does detaching the device.
+        model.ats_translate(dev_id.bits(), gpa.bits(), false);
+        iommu.detach_pci_device(&mut dev, gscid).unwrap();
+        assert!(model
+            .take_commands()
+            .contains(&ModelCommand::AtsInval { dev: dev_id.bits() }));
+        assert!(!model.in_device_tlb(dev_id.bits(), gpa.bits()));
+    }
+
+    #[test]
+    fn page_requests() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let owner = PageOwnerId::host();
+        let (msi_pt, _) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let gpa = PageAddr::new(RawAddr::guest(0x8000_0000, owner)).unwrap();
+        stub_map_page(&pt, &page_tracker, &mut pages, gpa);
+        let gscid = iommu.alloc_gscid(owner).unwrap();
+        let mut dev = PciDevice::new_endpoint_in_test(pci_address(2), owner, ats_config());
+        iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscid)
+            .unwrap();
+        let mut other_dev = PciDevice::new_in_test(pci_address(3), owner);
+        iommu
+            .attach_pci_device(&mut other_dev, &pt, &msi_pt, gscid)
+            .unwrap();
+        let dev_id = DeviceId::try_from(pci_address(2)).unwrap();
+        let other_id = DeviceId::try_from(pci_address(3)).unwrap();
+
+        // Groups are only completed successfully if all their pages are mapped for the device's
+        // owner, and if the device has PRI enabled.
+        let unmapped_gpa = gpa.checked_add_pages(1).unwrap();
+        model.page_request(dev_id.bits(), gpa.bits(), 1, true);
+        model.page_request(dev_id.bits(), unmapped_gpa.bits(), 2, false);
+        model.page_request(dev_id.bits(), gpa.bits(), 2, true);
+        model.page_request(dev_id.bits(), gpa.bits(), 3, false);
+        model.page_request(dev_id.bits(), gpa.bits(), 3, true);
+        model.page_request(other_id.bits(), gpa.bits(), 4, true);
+        model.take_commands();
+        iommu
+            .process_page_requests(|o, addr| o == owner && addr == gpa)
+            .unwrap();
+        let response = |dev: DeviceId, prgi, code| ModelCommand::AtsPrgr {
+            dev: dev.bits(),
+            prgi,
+            code,
+        };
+        assert_eq!(
+            model.take_commands(),
+            [
+                response(dev_id, 1, 0x0),
+                response(dev_id, 2, 0x1),
+                response(dev_id, 3, 0x0),
+                response(other_id, 4, 0x1),
+                ModelCommand::Iofence { interrupt: false }
+            ]
+        );
+
+        // Requests are only responded to once.
+        iommu.process_page_requests(|_, _| true).unwrap();
+        assert!(model.take_commands().is_empty());
+
+        // The groups in which a device made invalid requests are forgotten once it's detached.
+        model.page_request(dev_id.bits(), unmapped_gpa.bits(), 5, false);
+        iommu
+            .process_page_requests(|o, addr| o == owner && addr == gpa)
+            .unwrap();
+        iommu.detach_pci_device(&mut dev, gscid).unwrap();
+        iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscid)
+            .unwrap();
+        model.page_request(dev_id.bits(), gpa.bits(), 5, true);
+        model.take_commands();
+        iommu
+            .process_page_requests(|o, addr| o == owner && addr == gpa)
+            .unwrap();
+        assert_eq!(
+            model.take_commands(),
+            [
+                response(dev_id, 5, 0x0),
+                ModelCommand::Iofence { interrupt: false }
+            ]
+        );
+    }
+
+    #[test]
+    fn platform_devices() {
+        let (page_tracker, mut pages) = stub_mem();
+        let model = IommuModel::new();
+        let pci_devices = [DeviceId::try_from(pci_address(2)).unwrap()];
+        let mut new_iommu = |dt: &DeviceTree| {
+            Iommu::new(
+                model.registers(),
+                DeviceId::new(0xff).unwrap(),
+                &pci_devices,
+                dt,
+                Some(1),
+                &mut || {
+                    page_tracker
+                        .assign_page_for_internal_state(pages.pop().unwrap(), PageOwnerId::host())
+                        .ok()
+                },
+            )
+        };
+
+        // Platform device IDs must not collide with PCI device IDs, and the entries in "iommus"
+        // must match the "#iommu-cells" of the IOMMU they refer to.
+        let pci_id = pci_devices[0].bits();
+        assert!(matches!(
+            new_iommu(&iommus_dt(1, &[1, pci_id])).err(),
+            Some(IommuError::PlatformDeviceIdConflict(id)) if id.bits() == pci_id
+        ));
+        assert!(matches!(
+            new_iommu(&iommus_dt(2, &[1, 0x400, 0])).err(),
+            Some(IommuError::InvalidIommuCells)
+        ));
+        assert!(matches!(
+            new_iommu(&iommus_dt(1, &[3, 0x400])).err(),
+            Some(IommuError::InvalidIommusProperty)
+        ));
+        assert!(matches!(
+            new_iommu(&iommus_dt(1, &[2, 0x400])).err(),
+            Some(IommuError::InvalidIommusProperty)
+        ));
+
+        // Only the entries referring to our IOMMU name our platform devices, and the device
+        // directory grows to hold their IDs.
+        let iommu = new_iommu(&iommus_dt(1, &[2, 0x10, 0x11, 1, 0x400])).unwrap();
+        let owner = PageOwnerId::host();
+        let (msi_pt, _) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let gpa = PageAddr::new(RawAddr::guest(0x8000_0000, owner)).unwrap();
+        let spa = stub_map_page(&pt, &page_tracker, &mut pages, gpa);
+        let gscid = iommu.alloc_gscid(owner).unwrap();
+        iommu.attach_platform_devices(&pt, &msi_pt, gscid).unwrap();
+        assert_eq!(model.dma(0x400, gpa.bits(), false), Some(spa.bits()));
+        assert!(model.dma(0x10, gpa.bits(), false).is_none());
+    }
+
+    struct StubCompletionWaiter;
+
+    impl CompletionWaiter for StubCompletionWaiter {
+        fn wakeup_msi(&self) -> (SupervisorPageAddr, u32) {
+            (PageAddr::new(RawAddr::supervisor(IMSIC_START)).unwrap(), 7)
+        }
+
+        fn wait(&self, _timeout_ms: u64) {}
+    }
+
+    static STUB_COMPLETION_WAITER: StubCompletionWaiter = StubCompletionWaiter;
+
+    #[test]
+    fn completion_interrupts() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let gscid = iommu.alloc_gscid(PageOwnerId::host()).unwrap();
+        iommu
+            .enable_completion_interrupts(&STUB_COMPLETION_WAITER)
+            .unwrap();
+        assert!(iommu.fence(gscid, None).is_ok());
+        assert!(iommu.fence(gscid, None).is_ok());
+        assert_eq!(model.take_msis(), [(IMSIC_START, 7), (IMSIC_START, 7)]);
+    }
+
+    #[test]
+    fn fault_delivery() {
+        let (page_tracker, mut pages) = stub_mem();
+        let (iommu, model) = stub_iommu(page_tracker.clone(), &mut pages);
+        let owner = PageOwnerId::host();
+        let (msi_pt, _) = stub_msi_page_table(page_tracker.clone(), &mut pages, owner);
+        let pt = stub_guest_page_table(page_tracker.clone(), &mut pages, owner);
+        let gscid = iommu.alloc_gscid(owner).unwrap();
+        let mut dev = PciDevice::new_in_test(pci_address(2), owner);
+        iommu
+            .attach_pci_device(&mut dev, &pt, &msi_pt, gscid)
+            .unwrap();
+
+        let attached_id = DeviceId::try_from(pci_address(2)).unwrap();
+        let unattached_id = DeviceId::try_from(pci_address(3)).unwrap();
+        assert!(model.dma(attached_id.bits(), 0x8000_0000, true).is_none());
+        assert!(model
+            .dma(unattached_id.bits(), 0x8000_1000, false)
+            .is_none());
+        let mut faults = Vec::new();
+        iommu.process_faults(|fault, owner| {
+            faults.push((fault.device_id(), fault.cause(), fault.iotval(), owner));
+        });
+        assert_eq!(
+            faults,
+            [
+                (attached_id, 23, 0x8000_0000, Some(owner)),
+                (unattached_id, 258, 0x8000_1000, None)
+            ]
+        );
+
+        // Records are only delivered once.
+        iommu.process_faults(|_, _| panic!("unexpected fault"));
+        assert!(model.dma(attached_id.bits(), 0x8000_2000, false).is_none());
+        let mut causes = Vec::new();
+        iommu.process_faults(|fault, _| causes.push(fault.cause()));
+        assert_eq!(causes, [21]);
+    }
 }
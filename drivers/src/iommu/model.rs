@@ -0,0 +1,587 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A software model of the IOMMU for exercising the driver in unit tests. The model owns an
+//! in-memory register set, processes the command queue, walks the device directory, MSI page
+//! tables and 2nd-stage page tables to translate DMAs, and reports faults in the fault queue.
+//!
+//! Plain memory can't trap register accesses, so the model acts on the register writes made by
+//! the driver whenever `step()` is called. The driver calls it in place of pausing or sleeping
+//! while it polls the IOMMU registers.
+//!
+//! Only what the driver relies on is modeled: the device directory must use extended-format
+//! device contexts, first-stage translation is ignored, and `ipsr` isn't updated. Devices with ATS
+//! enabled cache the guest physical addresses they've translated in a modeled device TLB, since
+//! the IOMMU always operates with T2GPA set, and can post page requests.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::registers::ReadOnly;
+use tock_registers::LocalRegisterCopy;
+
+use super::registers::*;
+
+/// A command that was processed by the model.
+// Some fields are only ever compared against by tests.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelCommand {
+    IotinvalVma {
+        gscid: Option<u16>,
+        pscid: Option<u32>,
+    },
+    IotinvalGvma {
+        gscid: Option<u16>,
+        addr: Option<u64>,
+    },
+    IodirInvalDdt {
+        dev: Option<u32>,
+    },
+    IodirInvalPdt {
+        dev: u32,
+        pid: u32,
+    },
+    AtsInval {
+        dev: u32,
+    },
+    AtsPrgr {
+        dev: u32,
+        prgi: u16,
+        code: u8,
+    },
+    Iofence {
+        interrupt: bool,
+    },
+}
+
+impl ModelCommand {
+    // Decodes a command queue entry, returning `None` if it isn't a valid command.
+    fn decode(op: u64, addr: u64) -> Option<Self> {
+        let bit = |b: u64| (op & (1 << b)) != 0;
+        let dev = (op >> 40) as u32;
+        let gscid = (op >> 40) as u16;
+        use ModelCommand::*;
+        let cmd = match (op & 0x7f, (op >> 7) & 0x7) {
+            (0x1, 0x0) => IotinvalVma {
+                gscid: bit(12).then_some(gscid),
+                pscid: bit(13).then_some(((op >> 20) & 0xf_ffff) as u32),
+            },
+            (0x1, 0x1) => IotinvalGvma {
+                gscid: bit(12).then_some(gscid),
+                addr: bit(11).then_some(addr),
+            },
+            (0x2, 0x0) => Iofence { interrupt: bit(12) },
+            (0x3, 0x0) => IodirInvalDdt {
+                dev: bit(10).then_some(dev),
+            },
+            (0x3, 0x1) => IodirInvalPdt {
+                dev,
+                pid: ((op >> 12) & 0xf_ffff) as u32,
+            },
+            (0x4, 0x0) => AtsInval { dev },
+            (0x4, 0x1) => AtsPrgr {
+                dev,
+                prgi: ((addr >> 32) & 0x1ff) as u16,
+                code: ((addr >> 44) & 0xf) as u8,
+            },
+            _ => {
+                return None;
+            }
+        };
+        Some(cmd)
+    }
+}
+
+// Fault causes and transaction types reported in fault records.
+const CAUSE_READ_GUEST_PAGE_FAULT: u64 = 21;
+const CAUSE_WRITE_GUEST_PAGE_FAULT: u64 = 23;
+const CAUSE_ALL_DISALLOWED: u64 = 256;
+const CAUSE_DDT_ENTRY_INVALID: u64 = 258;
+const CAUSE_MSI_PTE_INVALID: u64 = 262;
+const TTYP_UNTRANSLATED_READ: u64 = 2;
+const TTYP_UNTRANSLATED_WRITE: u64 = 3;
+
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+// The bits shared by the command, fault and page-request queue CSRs.
+const CSR_CONTROL_BITS: u32 = 0x3;
+const CSR_W1C_BITS: u32 = 0xf00;
+const CSR_ENABLE: u32 = 1 << 0;
+const CSR_INTERRUPT_ENABLE: u32 = 1 << 1;
+const CSR_ON: u32 = 1 << 16;
+const CQ_ILLEGAL: u32 = 1 << 10;
+const CQ_FENCE_W_IP: u32 = 1 << 11;
+const FQ_OVERFLOW: u32 = 1 << 9;
+
+// The translation control bit that enables ATS in a device context.
+const DC_EN_ATS: u64 = 1 << 1;
+
+// The state of a queue CSR. Software controls Enable and InterruptEnable, while the remaining
+// bits report status and error bits are cleared by writing 1 to them. A write from software is
+// detected by the register no longer holding the value the model last published.
+#[derive(Default)]
+struct QueueCsr {
+    published: u32,
+    control: u32,
+    status: u32,
+}
+
+impl QueueCsr {
+    // Applies a write from software, if `val` is one, and returns the value to publish along with
+    // whether the queue was just turned on.
+    fn sync(&mut self, val: u32) -> (u32, bool) {
+        if val != self.published {
+            self.control = val & CSR_CONTROL_BITS;
+            self.status &= !(val & CSR_W1C_BITS);
+        }
+        let turned_on = (self.control & CSR_ENABLE) != 0 && (self.status & CSR_ON) == 0;
+        if (self.control & CSR_ENABLE) != 0 {
+            self.status |= CSR_ON;
+        } else {
+            self.status &= !CSR_ON;
+        }
+        self.published = self.control | self.status;
+        (self.published, turned_on)
+    }
+
+    // Returns if the queue is on and hasn't stopped due to an error.
+    fn active(&self) -> bool {
+        (self.status & CSR_ON) != 0 && (self.status & CSR_W1C_BITS & !CQ_FENCE_W_IP) == 0
+    }
+}
+
+// The fields of a valid device context that the model uses for translation.
+#[derive(Clone, Copy, Debug)]
+struct CachedDeviceContext {
+    id: u32,
+    ats: bool,
+    iohgatp: u64,
+    msiptp: u64,
+    msi_addr_mask: u64,
+    msi_addr_pattern: u64,
+}
+
+// A cached 2nd-stage translation of a 4kB guest page.
+#[derive(Clone, Copy, Debug)]
+struct CachedTranslation {
+    gscid: u16,
+    gfn: u64,
+    spa_page: u64,
+}
+
+// Reads the u64 at the physical address `addr`. Physical addresses are host addresses in tests.
+fn read_u64(addr: u64) -> u64 {
+    // Safety: The driver only points the IOMMU at memory it has allocated for the test.
+    unsafe { (addr as *const u64).read_volatile() }
+}
+
+// Sets a register that's read-only to software.
+fn set_read_only(reg: &ReadOnly<u32>, val: u32) {
+    // Safety: `reg` is in the register page allocated by the model, which is only ever accessed
+    // with volatile reads and writes.
+    unsafe { (reg as *const ReadOnly<u32> as *mut u32).write_volatile(val) };
+}
+
+// Returns the address and capacity of the queue described by `qb`.
+fn queue_location(qb: LocalRegisterCopy<u64, QueueBase::Register>) -> (u64, u32) {
+    let base = qb.read(QueueBase::Ppn) << 12;
+    let capacity = 1 << (qb.read(QueueBase::Log2SzMinus1) + 1);
+    (base, capacity)
+}
+
+// Gathers the bits of `val` selected by `mask` into the low bits of the result.
+fn extract_bits(val: u64, mask: u64) -> u64 {
+    let mut result = 0;
+    let mut out = 0;
+    for i in 0..64 {
+        if (mask & (1 << i)) != 0 {
+            result |= ((val >> i) & 1) << out;
+            out += 1;
+        }
+    }
+    result
+}
+
+struct ModelState {
+    regs: &'static IommuRegisters,
+    cqcsr: QueueCsr,
+    fqcsr: QueueCsr,
+    pqcsr: QueueCsr,
+    cqh: u32,
+    fqt: u32,
+    pqt: u32,
+    device_contexts: Vec<CachedDeviceContext>,
+    translations: Vec<CachedTranslation>,
+    // The (device, guest frame number) pairs cached in device TLBs.
+    device_tlb: Vec<(u32, u64)>,
+    commands: Vec<ModelCommand>,
+    msis: Vec<(u64, u32)>,
+    fail_next_command: bool,
+}
+
+impl ModelState {
+    // Acts on the register writes made by software since the last step.
+    fn step(&mut self) {
+        let (cqcsr, cq_on) = self.cqcsr.sync(self.regs.cqcsr.get());
+        self.regs.cqcsr.set(cqcsr);
+        if cq_on {
+            self.cqh = 0;
+            set_read_only(&self.regs.cqh, 0);
+        }
+        let (fqcsr, fq_on) = self.fqcsr.sync(self.regs.fqcsr.get());
+        self.regs.fqcsr.set(fqcsr);
+        if fq_on {
+            self.fqt = 0;
+            set_read_only(&self.regs.fqt, 0);
+        }
+        let (pqcsr, pq_on) = self.pqcsr.sync(self.regs.pqcsr.get());
+        self.regs.pqcsr.set(pqcsr);
+        if pq_on {
+            self.pqt = 0;
+            set_read_only(&self.regs.pqt, 0);
+        }
+        self.process_commands();
+    }
+
+    // Processes the commands between the command queue head and the tail written by software.
+    fn process_commands(&mut self) {
+        let (base, capacity) = queue_location(self.regs.cqb.extract());
+        let tail = self.regs.cqt.get();
+        while self.cqcsr.active() && self.cqh != tail {
+            let entry = base + self.cqh as u64 * 16;
+            let mut cmd = ModelCommand::decode(read_u64(entry), read_u64(entry + 8));
+            if core::mem::take(&mut self.fail_next_command) {
+                cmd = None;
+            }
+            let Some(cmd) = cmd else {
+                // The head is left pointing at the illegal command.
+                self.cqcsr.status |= CQ_ILLEGAL;
+                break;
+            };
+            self.execute(cmd);
+            self.commands.push(cmd);
+            self.cqh = (self.cqh + 1) & (capacity - 1);
+        }
+        set_read_only(&self.regs.cqh, self.cqh);
+        self.regs.cqcsr.set(self.cqcsr.control | self.cqcsr.status);
+        self.cqcsr.published = self.regs.cqcsr.get();
+    }
+
+    fn execute(&mut self, cmd: ModelCommand) {
+        use ModelCommand::*;
+        match cmd {
+            IotinvalGvma { gscid, addr } => {
+                self.translations.retain(|t| {
+                    gscid.map_or(false, |g| g != t.gscid)
+                        || addr.map_or(false, |a| (a >> 12) != t.gfn)
+                });
+            }
+            IodirInvalDdt { dev } => {
+                self.device_contexts
+                    .retain(|dc| dev.map_or(false, |d| d != dc.id));
+            }
+            AtsInval { dev } => {
+                self.device_tlb.retain(|&(d, _)| d != dev);
+            }
+            Iofence { interrupt } => {
+                if interrupt && (self.cqcsr.control & CSR_INTERRUPT_ENABLE) != 0 {
+                    self.cqcsr.status |= CQ_FENCE_W_IP;
+                    self.send_interrupt(self.regs.icvec.read(InterruptVectors::Civ) as usize);
+                }
+            }
+            // First-stage translation isn't modeled, and page request responses are only logged.
+            IotinvalVma { .. } | IodirInvalPdt { .. } | AtsPrgr { .. } => (),
+        }
+    }
+
+    // Sends the MSI programmed in entry `vector` of the MSI configuration table.
+    fn send_interrupt(&mut self, vector: usize) {
+        if self.regs.fctrl.is_set(FeatureControl::Wsi) {
+            return;
+        }
+        let entry = &self.regs.msi_cfg_tbl[vector];
+        if !entry.vector_control.is_set(MsiVectorControl::Mask) {
+            self.msis.push((entry.addr.get(), entry.data.get()));
+        }
+    }
+
+    // Returns the device context for `dev`, walking the device directory if it isn't cached.
+    fn device_context(&mut self, dev: u32) -> Result<CachedDeviceContext, u64> {
+        if let Some(dc) = self.device_contexts.iter().find(|dc| dc.id == dev) {
+            return Ok(*dc);
+        }
+        let ddtp = self.regs.ddtp.extract();
+        let levels = match ddtp.read(DirectoryPointer::Mode) {
+            2 => 1,
+            3 => 2,
+            4 => 3,
+            _ => {
+                return Err(CAUSE_ALL_DISALLOWED);
+            }
+        };
+        // Extended-format device contexts are 64 bytes, leaving 6 index bits for leaf tables.
+        let id_bits = 6 + 9 * (levels - 1);
+        if (dev >> id_bits) != 0 {
+            return Err(CAUSE_DDT_ENTRY_INVALID);
+        }
+        let mut table = ddtp.read(DirectoryPointer::Ppn) << 12;
+        for level in (1..levels).rev() {
+            let index = (dev >> (6 + 9 * (level - 1))) & 0x1ff;
+            let nle = read_u64(table + index as u64 * 8);
+            if (nle & 1) == 0 {
+                return Err(CAUSE_DDT_ENTRY_INVALID);
+            }
+            table = ((nle >> 10) & PPN_MASK) << 12;
+        }
+        let entry = table + (dev & 0x3f) as u64 * 64;
+        let tc = read_u64(entry);
+        if (tc & 1) == 0 {
+            return Err(CAUSE_DDT_ENTRY_INVALID);
+        }
+        let dc = CachedDeviceContext {
+            id: dev,
+            ats: (tc & DC_EN_ATS) != 0,
+            iohgatp: read_u64(entry + 8),
+            msiptp: read_u64(entry + 32),
+            msi_addr_mask: read_u64(entry + 40),
+            msi_addr_pattern: read_u64(entry + 48),
+        };
+        self.device_contexts.push(dc);
+        Ok(dc)
+    }
+
+    // Translates `gpa` using `dc`, returning the physical address or the fault cause.
+    fn translate(&mut self, dc: &CachedDeviceContext, gpa: u64, write: bool) -> Result<u64, u64> {
+        let gfn = gpa >> 12;
+        let offset = gpa & 0xfff;
+        let mask = dc.msi_addr_mask;
+        if (dc.msiptp >> 60) == 1 && (gfn & !mask) == (dc.msi_addr_pattern & !mask) {
+            let index = extract_bits(gfn, mask);
+            let pte = read_u64(((dc.msiptp & PPN_MASK) << 12) + index * 16);
+            // Write-through PTEs have V and W set.
+            if (pte & 0x5) != 0x5 {
+                return Err(CAUSE_MSI_PTE_INVALID);
+            }
+            return Ok((((pte >> 10) & PPN_MASK) << 12) | offset);
+        }
+
+        let gscid = (dc.iohgatp >> 44) as u16;
+        if let Some(t) = self
+            .translations
+            .iter()
+            .find(|t| t.gscid == gscid && t.gfn == gfn)
+        {
+            return Ok(t.spa_page | offset);
+        }
+        let fault = if write {
+            CAUSE_WRITE_GUEST_PAGE_FAULT
+        } else {
+            CAUSE_READ_GUEST_PAGE_FAULT
+        };
+        // Only Sv48x4 is supported, which has a 16kB root table indexed by GPA bits 49:39.
+        if (dc.iohgatp >> 60) != 9 || (gpa >> 50) != 0 {
+            return Err(fault);
+        }
+        let mut table = (dc.iohgatp & PPN_MASK) << 12;
+        for level in (0..4).rev() {
+            let shift = 12 + 9 * level;
+            let index_mask = if level == 3 { 0x7ff } else { 0x1ff };
+            let pte = read_u64(table + ((gpa >> shift) & index_mask) * 8);
+            if (pte & 1) == 0 {
+                return Err(fault);
+            }
+            let ppn = (pte >> 10) & PPN_MASK;
+            // Leaf PTEs have at least one of R, W or X set.
+            if (pte & 0xe) == 0 {
+                table = ppn << 12;
+                continue;
+            }
+            if write && (pte & 0x4) == 0 {
+                return Err(fault);
+            }
+            let spa_page = (ppn << 12) + (gpa & ((1 << shift) - 1) & !0xfff);
+            self.translations.push(CachedTranslation {
+                gscid,
+                gfn,
+                spa_page,
+            });
+            return Ok(spa_page | offset);
+        }
+        Err(fault)
+    }
+
+    // Appends a page request to the page-request queue. Requests are dropped if it's full.
+    fn post_page_request(&mut self, request: [u64; 2]) {
+        if !self.pqcsr.active() {
+            return;
+        }
+        let (base, capacity) = queue_location(self.regs.pqb.extract());
+        let next = (self.pqt + 1) & (capacity - 1);
+        if next == self.regs.pqh.get() {
+            return;
+        }
+        // Safety: The page-request queue is memory the driver allocated for it.
+        unsafe { ((base + self.pqt as u64 * 16) as *mut [u64; 2]).write_volatile(request) };
+        self.pqt = next;
+        set_read_only(&self.regs.pqt, self.pqt);
+    }
+
+    // Appends a fault record to the fault queue, or sets the overflow bit if it's full.
+    fn report_fault(&mut self, dev: u32, cause: u64, gpa: u64, write: bool) {
+        if !self.fqcsr.active() {
+            return;
+        }
+        let (base, capacity) = queue_location(self.regs.fqb.extract());
+        let next = (self.fqt + 1) & (capacity - 1);
+        if next == self.regs.fqh.get() {
+            self.fqcsr.status |= FQ_OVERFLOW;
+        } else {
+            let ttyp = if write {
+                TTYP_UNTRANSLATED_WRITE
+            } else {
+                TTYP_UNTRANSLATED_READ
+            };
+            let record = [cause | (ttyp << 34) | ((dev as u64) << 40), 0, gpa, 0];
+            // Safety: The fault queue is memory the driver allocated for it.
+            unsafe { ((base + self.fqt as u64 * 32) as *mut [u64; 4]).write_volatile(record) };
+            self.fqt = next;
+            set_read_only(&self.regs.fqt, self.fqt);
+        }
+        self.regs.fqcsr.set(self.fqcsr.control | self.fqcsr.status);
+        self.fqcsr.published = self.regs.fqcsr.get();
+    }
+}
+
+std::thread_local! {
+    // The model stepped by `step()`. Tests run on separate threads, so each gets its own.
+    static MODEL: RefCell<Option<Rc<RefCell<ModelState>>>> = RefCell::new(None);
+}
+
+/// Acts on the register writes made to the current thread's `IommuModel` since the last step.
+pub fn step() {
+    MODEL.with(|m| {
+        if let Some(state) = m.borrow().as_ref() {
+            state.borrow_mut().step();
+        }
+    });
+}
+
+#[repr(C, align(4096))]
+struct RegisterPage([u8; 4096]);
+
+/// A software model of the IOMMU, stepped by the driver from the thread that created it.
+pub struct IommuModel {
+    state: Rc<RefCell<ModelState>>,
+}
+
+impl IommuModel {
+    /// Creates a model of an IOMMU that supports MSI translation, Sv48x4 2nd-stage translation and
+    /// ATS, and signals interrupts with MSIs. Replaces any model previously created on this thread.
+    pub fn new() -> Self {
+        let page = alloc::boxed::Box::leak(alloc::boxed::Box::new(RegisterPage([0; 4096])));
+        let mut caps = LocalRegisterCopy::<u64, Capabilities::Register>::new(0);
+        caps.modify(
+            Capabilities::Sv39::SET
+                + Capabilities::Sv48::SET
+                + Capabilities::Sv48x4::SET
+                + Capabilities::MsiFlat::SET
+                + Capabilities::Ats::SET
+                + Capabilities::T2gpa::SET
+                + Capabilities::Igs::Msi,
+        );
+        let ptr = page as *mut RegisterPage;
+        // Safety: `page` is a leaked, suitably aligned allocation that's large enough to hold the
+        // register set, and nothing else references it yet.
+        let regs = unsafe {
+            (ptr as *mut u64).write_volatile(caps.get());
+            (ptr as *const IommuRegisters).as_ref().unwrap()
+        };
+        let state = Rc::new(RefCell::new(ModelState {
+            regs,
+            cqcsr: QueueCsr::default(),
+            fqcsr: QueueCsr::default(),
+            pqcsr: QueueCsr::default(),
+            cqh: 0,
+            fqt: 0,
+            pqt: 0,
+            device_contexts: Vec::new(),
+            translations: Vec::new(),
+            device_tlb: Vec::new(),
+            commands: Vec::new(),
+            msis: Vec::new(),
+            fail_next_command: false,
+        }));
+        MODEL.with(|m| *m.borrow_mut() = Some(state.clone()));
+        Self { state }
+    }
+
+    /// Returns the model's register set.
+    pub fn registers(&self) -> &'static IommuRegisters {
+        self.state.borrow().regs
+    }
+
+    /// Issues a DMA from device `dev` to `gpa`, returning the physical address it was translated
+    /// to. If translation fails, a fault is reported in the fault queue and `None` is returned.
+    pub fn dma(&self, dev: u32, gpa: u64, write: bool) -> Option<u64> {
+        let mut state = self.state.borrow_mut();
+        state.step();
+        let result = state
+            .device_context(dev)
+            .and_then(|dc| state.translate(&dc, gpa, write));
+        match result {
+            Ok(spa) => Some(spa),
+            Err(cause) => {
+                state.report_fault(dev, cause, gpa, write);
+                None
+            }
+        }
+    }
+
+    /// Issues an ATS translation request from device `dev` for `gpa`, returning the translated
+    /// address and caching it in the device's TLB. With T2GPA, the translated address is the guest
+    /// physical address itself, once the IOMMU has checked that it's mapped. Returns `None` if the
+    /// device doesn't have ATS enabled or `gpa` isn't mapped.
+    pub fn ats_translate(&self, dev: u32, gpa: u64, write: bool) -> Option<u64> {
+        let mut state = self.state.borrow_mut();
+        state.step();
+        let dc = state.device_context(dev).ok().filter(|dc| dc.ats)?;
+        state.translate(&dc, gpa, write).ok()?;
+        state.device_tlb.push((dev, gpa >> 12));
+        Some(gpa)
+    }
+
+    /// Returns if the translation of `gpa` is cached in the TLB of device `dev`.
+    pub fn in_device_tlb(&self, dev: u32, gpa: u64) -> bool {
+        let mut state = self.state.borrow_mut();
+        state.step();
+        state.device_tlb.contains(&(dev, gpa >> 12))
+    }
+
+    /// Posts a request from device `dev` for the page at `addr` in page request group `prgi` to
+    /// the page-request queue.
+    pub fn page_request(&self, dev: u32, addr: u64, prgi: u16, last: bool) {
+        let mut state = self.state.borrow_mut();
+        state.step();
+        // Requests are always for reads.
+        let payload = (addr & !0xfff) | ((prgi as u64) << 3) | ((last as u64) << 2) | 1;
+        state.post_page_request([(dev as u64) << 40, payload]);
+    }
+
+    /// Returns and clears the log of commands processed by the model.
+    pub fn take_commands(&self) -> Vec<ModelCommand> {
+        core::mem::take(&mut self.state.borrow_mut().commands)
+    }
+
+    /// Returns and clears the log of MSIs (address, data) sent by the model.
+    pub fn take_msis(&self) -> Vec<(u64, u32)> {
+        core::mem::take(&mut self.state.borrow_mut().msis)
+    }
+
+    /// Makes the model treat the next command it processes as an illegal command.
+    pub fn fail_next_command(&self) {
+        self.state.borrow_mut().fail_next_command = true;
+    }
+}
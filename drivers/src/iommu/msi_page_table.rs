@@ -224,6 +224,25 @@ impl MsiPageTable {
         Ok(())
     }
 
+    /// Returns if `addr` is in the guest physical page of an IMSIC interrupt file that is mapped in
+    /// this page table. Devices translated by this page table may only be programmed to send MSIs
+    /// to such addresses.
+    pub fn is_mapped(&self, addr: GuestPhysAddr) -> bool {
+        let mut inner = self.inner.lock();
+        let page_addr = PageAddr::with_round_down(addr, PageSize::Size4k);
+        let index = match inner
+            .src_geometry
+            .addr_to_location(page_addr)
+            .and_then(|location| MsiPageTableIndex::from(&inner.src_geometry, location))
+        {
+            Some(index) => index,
+            None => return false,
+        };
+        inner
+            .entry_for_index(index)
+            .is_some_and(|entry| entry.valid())
+    }
+
     /// Returns the base physical address of this page table.
     pub fn base_address(&self) -> SupervisorPageAddr {
         self.inner.lock().pages.base()
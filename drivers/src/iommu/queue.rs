@@ -7,7 +7,7 @@ use core::mem::size_of;
 use data_model::{DataInit, VolatileMemory, VolatileSlice};
 use riscv_pages::*;
 
-use super::device_directory::{DeviceId, GscId};
+use super::device_directory::{DeviceId, GscId, ProcessId, PscId};
 use super::error::*;
 
 /// Type marker for a queue where software is the producer.
@@ -76,7 +76,6 @@ impl<T: DataInit, Q> Queue<T, Q> {
     }
 
     /// Returns the head index of the queue.
-    #[allow(dead_code)]
     pub fn head(&self) -> usize {
         self.head
     }
@@ -85,6 +84,13 @@ impl<T: DataInit, Q> Queue<T, Q> {
     pub fn tail(&self) -> usize {
         self.tail
     }
+
+    /// Discards the contents of the queue. Expected to be used after the IOMMU has reset its head
+    /// and tail pointers for the queue to 0.
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+    }
 }
 
 impl<T: DataInit> Queue<T, Producer> {
@@ -117,8 +123,6 @@ impl<T: DataInit> Queue<T, Producer> {
     }
 }
 
-// TODO: Remove once we have support for the fault queue in place.
-#[allow(dead_code)]
 impl<T: DataInit> Queue<T, Consumer> {
     /// Updates the tail pointer of the queue to `tail`. Expected to be used to update the queue's
     /// software tail pointer with a tail pointer read from an IOMMU register.
@@ -141,7 +145,7 @@ impl<T: DataInit> Queue<T, Consumer> {
         }
         // Unwrap ok since `self.head` must be in bounds.
         let head_ref = self.mem.get_ref(self.head * size_of::<T>()).unwrap();
-        self.head += 1;
+        self.head = (self.head + 1) & (self.capacity - 1);
         Ok(head_ref.load())
     }
 }
@@ -189,6 +193,31 @@ impl Command {
         Self { op, addr }
     }
 
+    /// Creates a new `IOTINVAL.VMA` command for flushing first-stage translation caches.
+    ///
+    /// If `gscid` is not `None`, only translations matching the specified GSCID are flushed.
+    ///
+    /// If `pscid` is not `None`, only translations matching the specified PSCID are flushed.
+    pub fn iotinval_vma(gscid: Option<GscId>, pscid: Option<PscId>) -> Self {
+        const IOTINVAL_OP: u64 = 0x1;
+        const VMA_FUNC: u64 = 0x0;
+        let mut op = IOTINVAL_OP | (VMA_FUNC << FUNC3_SHIFT);
+
+        const GV: u64 = 1 << 12;
+        const GSCID_SHIFT: u64 = 40;
+        if let Some(g) = gscid {
+            op |= GV | ((g.bits() as u64) << GSCID_SHIFT);
+        }
+
+        const PSCV: u64 = 1 << 13;
+        const PSCID_SHIFT: u64 = 20;
+        if let Some(p) = pscid {
+            op |= PSCV | ((p.bits() as u64) << PSCID_SHIFT);
+        }
+
+        Self { op, addr: 0 }
+    }
+
     /// Creates a new `IODIR.INVAL_DDT` command for flushing device directory table caches.
     ///
     /// If `dev` is not `None`, only translations for the specified device ID are flushed.
@@ -206,19 +235,92 @@ impl Command {
         Self { op, addr: 0 }
     }
 
+    /// Creates a new `IODIR.INVAL_PDT` command for flushing the cached process context for process
+    /// `pid` of device `dev`.
+    pub fn iodir_inval_pdt(dev: DeviceId, pid: ProcessId) -> Self {
+        const IODIR_OP: u64 = 0x3;
+        const INVAL_PDT_FUNC: u64 = 0x1;
+        const DV: u64 = 1 << 10;
+        const PID_SHIFT: u64 = 12;
+        const DID_SHIFT: u64 = 40;
+        let op = IODIR_OP
+            | (INVAL_PDT_FUNC << FUNC3_SHIFT)
+            | DV
+            | ((pid.bits() as u64) << PID_SHIFT)
+            | ((dev.bits() as u64) << DID_SHIFT);
+
+        Self { op, addr: 0 }
+    }
+
+    /// Creates a new `ATS.INVAL` command for invalidating the entire device TLB of `dev`.
+    ///
+    /// If `pid` is not `None`, only translations for the specified process ID are invalidated.
+    pub fn ats_inval(dev: DeviceId, pid: Option<ProcessId>) -> Self {
+        const INVAL_FUNC: u64 = 0x0;
+        // The payload is the body of the PCIe Invalidate Request message. An untranslated address
+        // with all bits below bit 63 set, along with the Size bit, covers the entire address space.
+        const INVAL_ALL: u64 = 0x7fff_ffff_ffff_f800;
+        Self {
+            op: Self::ats_op(INVAL_FUNC, dev, pid),
+            addr: INVAL_ALL,
+        }
+    }
+
+    /// Creates a new `ATS.PRGR` command for completing page request group `prgi` from `dev` with
+    /// `code`.
+    ///
+    /// If `pid` is not `None`, the response is tagged with the specified process ID.
+    pub fn ats_prgr(
+        dev: DeviceId,
+        pid: Option<ProcessId>,
+        prgi: u16,
+        code: PageResponseCode,
+    ) -> Self {
+        const PRGR_FUNC: u64 = 0x1;
+        // The payload is the body of the PCIe PRG Response message.
+        const PRGI_SHIFT: u64 = 32;
+        const PRGI_MASK: u64 = 0x1ff;
+        const CODE_SHIFT: u64 = 44;
+        Self {
+            op: Self::ats_op(PRGR_FUNC, dev, pid),
+            addr: ((prgi as u64 & PRGI_MASK) << PRGI_SHIFT) | ((code as u64) << CODE_SHIFT),
+        }
+    }
+
+    // Returns the `op` field of an ATS command with `func` targeting `dev`.
+    fn ats_op(func: u64, dev: DeviceId, pid: Option<ProcessId>) -> u64 {
+        const ATS_OP: u64 = 0x4;
+        // The device ID is made up of the segment and routing ID of the device.
+        const DSV: u64 = 1 << 10;
+        const RID_SHIFT: u64 = 40;
+        let mut op = ATS_OP | (func << FUNC3_SHIFT) | DSV | ((dev.bits() as u64) << RID_SHIFT);
+
+        const PV: u64 = 1 << 11;
+        const PID_SHIFT: u64 = 12;
+        if let Some(p) = pid {
+            op |= PV | ((p.bits() as u64) << PID_SHIFT);
+        }
+
+        op
+    }
+
     /// Creates a new `IOFENCE.C` command for synchronizing the command queue. Upon completion of
     /// this command, all prior commands submitted to the command queue are guaranteed to have
     /// completed.
-    pub fn iofence() -> Self {
+    ///
+    /// If `interrupt` is set, the IOMMU raises a command queue interrupt once the fence completes.
+    pub fn iofence(interrupt: bool) -> Self {
         const IOFENCE_OP: u64 = 0x2;
         const PR: u64 = 1 << 10;
         const PW: u64 = 1 << 11;
+        const WSI: u64 = 1 << 12;
 
         // TODO: Make PR/PW optional. Probably not needed on every fence.
-        Self {
-            op: IOFENCE_OP | PR | PW,
-            addr: 0,
+        let mut op = IOFENCE_OP | PR | PW;
+        if interrupt {
+            op |= WSI;
         }
+        Self { op, addr: 0 }
     }
 }
 
@@ -229,4 +331,136 @@ unsafe impl DataInit for Command {}
 /// The IOMMU command queue.
 pub type CommandQueue = Queue<Command, Producer>;
 
-// TODO: Fault queue.
+/// The response code sent to a device in a PCIe PRG Response message.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageResponseCode {
+    /// The pages in the request group were made resident; the device should retry translation.
+    Success = 0x0,
+    /// The request group was invalid; the device should report an error for it.
+    InvalidRequest = 0x1,
+    /// Page requests can't be serviced; the device should stop sending them.
+    ResponseFailure = 0xf,
+}
+
+/// An entry in the IOMMU page-request queue. Holds a PCIe Page Request message received from a
+/// device, asking for the page at an address that it failed to translate to be made resident.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PageRequest {
+    hdr: u64,
+    payload: u64,
+}
+
+const PR_PID_SHIFT: u64 = 12;
+const PR_PID_MASK: u64 = (1 << 20) - 1;
+const PR_PV: u64 = 1 << 32;
+const PR_DID_SHIFT: u64 = 40;
+const PR_LAST: u64 = 1 << 2;
+const PR_PRGI_SHIFT: u64 = 3;
+const PR_PRGI_MASK: u64 = 0x1ff;
+const PR_PAGE_OFFSET_MASK: u64 = 0xfff;
+
+impl PageRequest {
+    /// Returns the ID of the device that sent the request.
+    pub fn device_id(&self) -> DeviceId {
+        // Unwrap ok: the remaining 24 bits of the header can't exceed the device ID width.
+        DeviceId::new((self.hdr >> PR_DID_SHIFT) as u32).unwrap()
+    }
+
+    /// Returns the process ID the request was tagged with, if any.
+    pub fn process_id(&self) -> Option<ProcessId> {
+        if (self.hdr & PR_PV) != 0 {
+            ProcessId::new(((self.hdr >> PR_PID_SHIFT) & PR_PID_MASK) as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Returns if this is the last request in its page request group. The device expects a
+    /// response once the last request in a group has been handled.
+    pub fn is_last_in_group(&self) -> bool {
+        (self.payload & PR_LAST) != 0
+    }
+
+    /// Returns the index of the page request group this request belongs to.
+    pub fn group_index(&self) -> u16 {
+        ((self.payload >> PR_PRGI_SHIFT) & PR_PRGI_MASK) as u16
+    }
+
+    /// Returns the address of the page the device is requesting. Requests without a process ID
+    /// are for guest physical addresses, since translated requests are always treated as such.
+    pub fn page_addr(&self) -> u64 {
+        self.payload & !PR_PAGE_OFFSET_MASK
+    }
+}
+
+// Safety: `PageRequest` is a POD struct without implicit padding and therefore can be initialized
+// from a byte array.
+unsafe impl DataInit for PageRequest {}
+
+/// The IOMMU page-request queue.
+pub type PageRequestQueue = Queue<PageRequest, Consumer>;
+
+/// An entry in the IOMMU fault queue, describing a request from a device that the IOMMU couldn't
+/// translate or a problem the IOMMU ran into while walking the device's translation tables.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FaultRecord {
+    hdr: u64,
+    _reserved: u64,
+    iotval: u64,
+    iotval2: u64,
+}
+
+const FR_CAUSE_MASK: u64 = 0xfff;
+const FR_PID_SHIFT: u64 = 12;
+const FR_PID_MASK: u64 = (1 << 20) - 1;
+const FR_PV: u64 = 1 << 32;
+const FR_TTYP_SHIFT: u64 = 34;
+const FR_TTYP_MASK: u64 = 0x3f;
+const FR_DID_SHIFT: u64 = 40;
+
+impl FaultRecord {
+    /// Returns the ID of the device that issued the faulting request.
+    pub fn device_id(&self) -> DeviceId {
+        // Unwrap ok: the remaining 24 bits of the header can't exceed the device ID width.
+        DeviceId::new((self.hdr >> FR_DID_SHIFT) as u32).unwrap()
+    }
+
+    /// Returns the process ID the faulting request was tagged with, if any.
+    pub fn process_id(&self) -> Option<ProcessId> {
+        if (self.hdr & FR_PV) != 0 {
+            ProcessId::new(((self.hdr >> FR_PID_SHIFT) & FR_PID_MASK) as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the cause of the fault, as defined by the RISC-V IOMMU specification.
+    pub fn cause(&self) -> u16 {
+        (self.hdr & FR_CAUSE_MASK) as u16
+    }
+
+    /// Returns the type of the faulting transaction, as defined by the RISC-V IOMMU specification.
+    pub fn transaction_type(&self) -> u8 {
+        ((self.hdr >> FR_TTYP_SHIFT) & FR_TTYP_MASK) as u8
+    }
+
+    /// Returns the faulting address, or other information about the fault depending on its cause.
+    pub fn iotval(&self) -> u64 {
+        self.iotval
+    }
+
+    /// Returns the guest physical address that caused a 2nd-stage translation fault.
+    pub fn iotval2(&self) -> u64 {
+        self.iotval2
+    }
+}
+
+// Safety: `FaultRecord` is a POD struct without implicit padding and therefore can be initialized
+// from a byte array.
+unsafe impl DataInit for FaultRecord {}
+
+/// The IOMMU fault queue.
+pub type FaultQueue = Queue<FaultRecord, Consumer>;
@@ -21,6 +21,16 @@ register_bitfields![u64,
         Sv57x4 OFFSET(19) NUMBITS(1),
         MsiFlat OFFSET(22) NUMBITS(1),
         MsiMrif OFFSET(23) NUMBITS(1),
+        Ats OFFSET(25) NUMBITS(1),
+        T2gpa OFFSET(26) NUMBITS(1),
+        Igs OFFSET(28) NUMBITS(2) [
+            Msi = 0,
+            Wsi = 1,
+            Both = 2,
+        ],
+        Pd8 OFFSET(38) NUMBITS(1),
+        Pd17 OFFSET(39) NUMBITS(1),
+        Pd20 OFFSET(40) NUMBITS(1),
     ],
 
     pub DirectoryPointer [
@@ -33,25 +43,79 @@ register_bitfields![u64,
         Log2SzMinus1 OFFSET(0) NUMBITS(5),
         Ppn OFFSET(10) NUMBITS(44),
     ],
+
+    pub InterruptVectors [
+        Civ OFFSET(0) NUMBITS(4),
+        Fiv OFFSET(4) NUMBITS(4),
+        Pmiv OFFSET(8) NUMBITS(4),
+        Piv OFFSET(12) NUMBITS(4),
+    ],
 ];
 
 register_bitfields![u32,
+    pub FeatureControl [
+        BigEndian OFFSET(0) NUMBITS(1),
+        Wsi OFFSET(1) NUMBITS(1),
+        Gxl OFFSET(2) NUMBITS(1),
+    ],
+
     pub CqControl [
         Enable OFFSET(0) NUMBITS(1),
         InterruptEnable OFFSET(1) NUMBITS(1),
         MemoryFault OFFSET(8) NUMBITS(1),
         CommandTimeout OFFSET(9) NUMBITS(1),
         CommandIllegal OFFSET(10) NUMBITS(1),
+        FenceWaitInterruptPending OFFSET(11) NUMBITS(1),
+        On OFFSET(16) NUMBITS(1),
+        Busy OFFSET(17) NUMBITS(1),
+    ],
+
+    pub FqControl [
+        Enable OFFSET(0) NUMBITS(1),
+        InterruptEnable OFFSET(1) NUMBITS(1),
+        MemoryFault OFFSET(8) NUMBITS(1),
+        Overflow OFFSET(9) NUMBITS(1),
+        On OFFSET(16) NUMBITS(1),
+        Busy OFFSET(17) NUMBITS(1),
+    ],
+
+    pub PqControl [
+        Enable OFFSET(0) NUMBITS(1),
+        InterruptEnable OFFSET(1) NUMBITS(1),
+        MemoryFault OFFSET(8) NUMBITS(1),
+        Overflow OFFSET(9) NUMBITS(1),
         On OFFSET(16) NUMBITS(1),
         Busy OFFSET(17) NUMBITS(1),
     ],
+
+    pub InterruptPending [
+        Cip OFFSET(0) NUMBITS(1),
+        Fip OFFSET(1) NUMBITS(1),
+        Pmip OFFSET(2) NUMBITS(1),
+        Pip OFFSET(3) NUMBITS(1),
+    ],
+
+    pub MsiVectorControl [
+        Mask OFFSET(0) NUMBITS(1),
+    ],
 ];
 
+/// An entry in the IOMMU's MSI configuration table.
+#[repr(C)]
+pub struct MsiConfigEntry {
+    pub addr: ReadWrite<u64>,
+    pub data: ReadWrite<u32>,
+    pub vector_control: ReadWrite<u32, MsiVectorControl::Register>,
+}
+
+/// The number of entries in the MSI configuration table.
+pub const MSI_CONFIG_ENTRIES: usize = 16;
+
 /// The IOMMU register set.
 #[repr(C)]
 pub struct IommuRegisters {
     pub capabilities: ReadOnly<u64, Capabilities::Register>,
-    pub fctrl: ReadWrite<u32>,
+    pub fctrl: ReadWrite<u32, FeatureControl::Register>,
     _reserved0: u32,
     pub ddtp: ReadWrite<u64, DirectoryPointer::Register>,
     pub cqb: ReadWrite<u64, QueueBase::Register>,
@@ -64,11 +128,14 @@ pub struct IommuRegisters {
     pub pqh: ReadWrite<u32>,
     pub pqt: ReadOnly<u32>,
     pub cqcsr: ReadWrite<u32, CqControl::Register>,
-    pub fqcsr: ReadWrite<u32>,
-    pub pqcsr: ReadWrite<u32>,
-    pub ipsr: ReadWrite<u32>,
+    pub fqcsr: ReadWrite<u32, FqControl::Register>,
+    pub pqcsr: ReadWrite<u32, PqControl::Register>,
+    pub ipsr: ReadWrite<u32, InterruptPending::Register>,
     // Includes debug/performance counter registers which we don't care about at the moment.
-    _reserved1: [u32; 1002],
+    _reserved1: [u32; 168],
+    pub icvec: ReadWrite<u64, InterruptVectors::Register>,
+    pub msi_cfg_tbl: [MsiConfigEntry; MSI_CONFIG_ENTRIES],
+    _reserved2: [u32; 768],
 }
 
 fn _assert_register_layout() {
@@ -5,7 +5,13 @@
 //! # Hardware drivers
 
 #![no_std]
-#![feature(allocator_api, result_option_inspect, iter_advance_by, let_chains)]
+#![feature(
+    allocator_api,
+    result_option_inspect,
+    iter_advance_by,
+    let_chains,
+    is_some_and
+)]
 
 extern crate alloc;
 
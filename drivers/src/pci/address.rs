@@ -172,6 +172,17 @@ impl Address {
         self.0
     }
 
+    /// Returns the address whose routing ID (bus, device and function) is `offset` past this one in
+    /// the same segment, or `None` if that is out of range.
+    pub fn add_routing_offset(&self, offset: u32) -> Option<Address> {
+        const ROUTING_ID_MASK: u32 = (1 << Segment::SHIFT) - 1;
+        let rid = (self.0 & ROUTING_ID_MASK).checked_add(offset)?;
+        if rid > ROUTING_ID_MASK {
+            return None;
+        }
+        Some(Address((self.0 & !ROUTING_ID_MASK) | rid))
+    }
+
     /// Returns the address of the next PCI function or `None` if no more functions are available
     /// for the current device.
     pub fn next_function(&self) -> Option<Address> {
@@ -205,6 +216,13 @@ impl Address {
     }
 }
 
+impl From<u32> for Address {
+    fn from(bits: u32) -> Self {
+        // All 32 bits are used by the address components, so any value is a valid address.
+        Address(bits)
+    }
+}
+
 impl core::fmt::Display for Address {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
@@ -270,4 +288,19 @@ mod tests {
             .next_function()
             .is_none());
     }
+
+    #[test]
+    fn routing_offset() {
+        let a = make_address(1, 2, 0, 0);
+        assert_eq!(a.add_routing_offset(1), Some(make_address(1, 2, 0, 1)));
+        assert_eq!(
+            a.add_routing_offset(0x81),
+            Some(make_address(1, 2, 0x10, 1))
+        );
+        // Routing IDs carry into the bus number, but not into the segment.
+        assert_eq!(a.add_routing_offset(0x100), Some(make_address(1, 3, 0, 0)));
+        assert!(make_address(1, 0xff, 0x1f, 0x7)
+            .add_routing_offset(1)
+            .is_none());
+    }
 }
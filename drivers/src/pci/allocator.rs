@@ -0,0 +1,843 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use core::cmp::Reverse;
+
+use super::bus::PciBus;
+use super::device::{PciBridge, PciDevice};
+use super::error::*;
+use super::resource::*;
+use super::root::{PciArenaId, PciDeviceArena};
+
+// Bridge IO windows have a granularity of 4kB.
+const IO_WINDOW_ALIGN: u64 = 1 << 12;
+// Bridge memory windows have a granularity of 1MB.
+const MEM_WINDOW_ALIGN: u64 = 1 << 20;
+// The minimum size of the windows of ports with hotplug slots, so that there's room for devices
+// which are added to the slot after enumeration.
+const HOTPLUG_IO_WINDOW_SIZE: u64 = 1 << 12;
+const HOTPLUG_MEM_WINDOW_SIZE: u64 = 2 << 20;
+
+// The windows through which a bridge forwards resources to its secondary bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WindowType {
+    Io = 0,
+    Mem = 1,
+    PrefetchableMem = 2,
+}
+
+const NUM_WINDOW_TYPES: usize = WindowType::PrefetchableMem as usize + 1;
+const WINDOW_TYPES: [WindowType; NUM_WINDOW_TYPES] =
+    [WindowType::Io, WindowType::Mem, WindowType::PrefetchableMem];
+
+impl WindowType {
+    // Returns the type of window that maps BARs of `bar_type`.
+    fn for_bar(bar_type: PciResourceType) -> Self {
+        use PciResourceType::*;
+        match bar_type {
+            IoPort => WindowType::Io,
+            // The non-prefetchable window is only 32 bits wide, but 64-bit BARs may be placed in it.
+            Mem32 | Mem64 => WindowType::Mem,
+            PrefetchableMem32 | PrefetchableMem64 => WindowType::PrefetchableMem,
+        }
+    }
+
+    // Returns the granularity of bridge windows of this type.
+    fn granularity(self) -> u64 {
+        match self {
+            WindowType::Io => IO_WINDOW_ALIGN,
+            _ => MEM_WINDOW_ALIGN,
+        }
+    }
+
+    // Returns the minimum size of windows of this type on ports with hotplug slots.
+    fn hotplug_size(self) -> u64 {
+        match self {
+            WindowType::Io => HOTPLUG_IO_WINDOW_SIZE,
+            _ => HOTPLUG_MEM_WINDOW_SIZE,
+        }
+    }
+}
+
+// The windows implemented by the bridge upstream of a bus.
+#[derive(Clone, Copy, Debug)]
+struct BusWindows {
+    io: bool,
+    pref: bool,
+}
+
+impl BusWindows {
+    // Returns the window on the bus from which resources that would ideally be placed in a
+    // window of type `window` are allocated, if there is one.
+    fn select(&self, window: WindowType) -> Option<WindowType> {
+        match window {
+            WindowType::Io if !self.io => None,
+            // Prefetchable resources may be placed in non-prefetchable memory.
+            WindowType::PrefetchableMem if !self.pref => Some(WindowType::Mem),
+            w => Some(w),
+        }
+    }
+}
+
+// The part of a device that is assigned addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Bar(usize),
+    VfBar(usize),
+    Window(WindowType),
+}
+
+// A request for `size` bytes aligned to `align` in the `window` of a bus.
+#[derive(Debug)]
+struct Request<T> {
+    target: T,
+    window: WindowType,
+    size: u64,
+    align: u64,
+    // Whether the allocation may be placed above 4GB.
+    is_64bit: bool,
+    // The offset of the allocation within `window`, once packed.
+    offset: u64,
+}
+
+// The size and alignment of a window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct WindowSize {
+    size: u64,
+    align: u64,
+}
+
+// The windows required by a bus to map the resources of the devices on it and the buses below it.
+#[derive(Debug, Default)]
+struct BusRequirements {
+    windows: [WindowSize; NUM_WINDOW_TYPES],
+    // Whether the prefetchable window may be placed above 4GB.
+    pref_64bit: bool,
+}
+
+// Rounds `val` up to the power-of-two `align`.
+fn align_up(val: u64, align: u64) -> Option<u64> {
+    Some(val.checked_add(align - 1)? & !(align - 1))
+}
+
+// Packs `requests` into their windows, placing the requests with the largest alignment first so
+// that padding is kept to a minimum. Sets the offset of each request within its window and returns
+// the resulting size and alignment of each window.
+fn pack<T>(requests: &mut [Request<T>]) -> Result<[WindowSize; NUM_WINDOW_TYPES]> {
+    requests.sort_unstable_by_key(|r| Reverse(r.align));
+    let mut windows = [WindowSize::default(); NUM_WINDOW_TYPES];
+    for r in requests.iter_mut() {
+        let w = &mut windows[r.window as usize];
+        r.offset = align_up(w.size, r.align).ok_or(Error::OutOfResources)?;
+        w.size = r.offset.checked_add(r.size).ok_or(Error::OutOfResources)?;
+        w.align = w.align.max(r.align);
+    }
+    Ok(windows)
+}
+
+// Collects the resources requested by the devices on `bus`, including the windows of the bridges
+// on it, which are allocated from `windows`.
+fn bus_requests(
+    bus: &PciBus,
+    windows: BusWindows,
+    device_arena: &PciDeviceArena,
+) -> Result<Vec<Request<(PciArenaId, Target)>>> {
+    let mut requests = Vec::new();
+    for bd in bus.devices() {
+        // Unwrap ok: devices on a bus must be in the arena.
+        let dev = device_arena.get(bd.id).unwrap().lock();
+        let bars = dev
+            .bar_info()
+            .bars()
+            .map(|bar| (Target::Bar(bar.index()), bar, 1));
+        // Each VF BAR maps the corresponding BAR of all the VFs that may be enabled.
+        let max_vfs = dev.sriov().map(|s| s.max_vfs() as u64).unwrap_or(0);
+        let vf_bars = dev
+            .vf_bar_info()
+            .filter(|_| max_vfs != 0)
+            .into_iter()
+            .flat_map(|info| info.bars())
+            .map(|bar| (Target::VfBar(bar.index()), bar, max_vfs));
+        for (target, bar, count) in bars.chain(vf_bars) {
+            let window = match windows.select(WindowType::for_bar(bar.bar_type())) {
+                Some(w) => w,
+                None => continue,
+            };
+            requests.try_reserve(1).map_err(|_| Error::AllocError)?;
+            requests.push(Request {
+                target: (bd.id, target),
+                window,
+                size: bar.size() * count,
+                align: bar.size(),
+                is_64bit: bar.bar_type().is_64bit(),
+                offset: 0,
+            });
+        }
+
+        if let PciDevice::Bridge(ref bridge) = *dev {
+            let child_bus = match bridge.child_bus() {
+                Some(b) => b,
+                None => continue,
+            };
+            let child_windows = BusWindows {
+                io: bridge.has_io_window(),
+                pref: bridge.has_pref_window(),
+            };
+            let mut child = size_bus(child_bus, child_windows, device_arena)?;
+            if dev.has_hotplug_slot() {
+                // Leave room in each of the bridge's windows for devices added to the slot later.
+                for w in WINDOW_TYPES {
+                    if child_windows.select(w) != Some(w) {
+                        continue;
+                    }
+                    let child_size = &mut child.windows[w as usize];
+                    child_size.size = child_size.size.max(w.hotplug_size());
+                    child_size.align = child_size.align.max(w.granularity());
+                }
+            }
+            for w in WINDOW_TYPES {
+                let child_size = child.windows[w as usize];
+                if child_size.size == 0 {
+                    continue;
+                }
+                // Windows not implemented by the bridge are never requested by the child bus.
+                let window = match windows.select(w) {
+                    Some(window) => window,
+                    None => continue,
+                };
+                let align = child_size.align.max(w.granularity());
+                requests.try_reserve(1).map_err(|_| Error::AllocError)?;
+                requests.push(Request {
+                    target: (bd.id, Target::Window(w)),
+                    window,
+                    size: align_up(child_size.size, w.granularity())
+                        .ok_or(Error::OutOfResources)?,
+                    align,
+                    is_64bit: w == WindowType::PrefetchableMem
+                        && child.pref_64bit
+                        && bridge.has_64bit_pref_window(),
+                    offset: 0,
+                });
+            }
+        }
+    }
+    Ok(requests)
+}
+
+// Determines the windows required by `bus`, which is downstream of `windows`.
+fn size_bus(
+    bus: &PciBus,
+    windows: BusWindows,
+    device_arena: &PciDeviceArena,
+) -> Result<BusRequirements> {
+    let mut requests = bus_requests(bus, windows, device_arena)?;
+    let pref_64bit = requests
+        .iter()
+        .filter(|r| r.window == WindowType::PrefetchableMem)
+        .all(|r| r.is_64bit);
+    Ok(BusRequirements {
+        windows: pack(&mut requests)?,
+        pref_64bit,
+    })
+}
+
+// Assigns addresses to the devices on `bus`, and recursively to the devices on the buses below it,
+// from `windows` starting at the PCI bus addresses in `bases`.
+fn assign_bus(
+    bus: &PciBus,
+    windows: BusWindows,
+    bases: [Option<u64>; NUM_WINDOW_TYPES],
+    device_arena: &PciDeviceArena,
+) -> Result<()> {
+    let mut requests = bus_requests(bus, windows, device_arena)?;
+    pack(&mut requests)?;
+    // Returns the range assigned to `target` of the device with ID `id`.
+    let assigned = |id: PciArenaId, target: Target| {
+        let r = requests.iter().find(|r| r.target == (id, target))?;
+        let base = bases[r.window as usize]? + r.offset;
+        Some((base, base + r.size - 1))
+    };
+    for bd in bus.devices() {
+        // Unwrap ok: devices on a bus must be in the arena.
+        let mut dev = device_arena.get(bd.id).unwrap().lock();
+        let bar_info = dev.bar_info().clone();
+        for bar in bar_info.bars() {
+            if let Some((addr, _)) = assigned(bd.id, Target::Bar(bar.index())) {
+                dev.set_bar_addr(bar.index(), addr)?;
+            }
+        }
+        if let Some(vf_bar_info) = dev.vf_bar_info().cloned() {
+            for bar in vf_bar_info.bars() {
+                if let Some((addr, _)) = assigned(bd.id, Target::VfBar(bar.index())) {
+                    dev.set_vf_bar_addr(bar.index(), addr)?;
+                }
+            }
+        }
+
+        if let PciDevice::Bridge(ref mut bridge) = *dev {
+            let io_window = assigned(bd.id, Target::Window(WindowType::Io));
+            let mem_window = assigned(bd.id, Target::Window(WindowType::Mem));
+            let pref_window = assigned(bd.id, Target::Window(WindowType::PrefetchableMem));
+            bridge.set_io_window(io_window);
+            bridge.set_mem_window(mem_window);
+            bridge.set_pref_window(pref_window);
+            if let Some(child_bus) = bridge.child_bus() {
+                let child_windows = BusWindows {
+                    io: bridge.has_io_window(),
+                    pref: bridge.has_pref_window(),
+                };
+                let child_bases = [io_window, mem_window, pref_window].map(|w| w.map(|w| w.0));
+                assign_bus(child_bus, child_windows, child_bases, device_arena)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// An inclusive range of PCI bus addresses, either forwarded to a bus through a window of type
+// `window` or used by a resource that is placed in such a window.
+#[derive(Clone, Copy, Debug)]
+struct AddrRange {
+    window: WindowType,
+    base: u64,
+    limit: u64,
+}
+
+impl AddrRange {
+    // Returns the range of `size` bytes starting at `base`, or `None` if it's empty or overflows.
+    fn new(window: WindowType, base: u64, size: u64) -> Option<Self> {
+        let limit = base.checked_add(size.checked_sub(1)?)?;
+        Some(Self {
+            window,
+            base,
+            limit,
+        })
+    }
+
+    // Returns if `range` lies within `self` and is of a type that may be placed in it.
+    fn contains(&self, range: &AddrRange) -> bool {
+        // Prefetchable resources may be placed in non-prefetchable memory.
+        let compatible = self.window == range.window
+            || (self.window == WindowType::Mem && range.window == WindowType::PrefetchableMem);
+        compatible && self.base <= range.base && range.limit <= self.limit
+    }
+
+    // Returns if `self` and `other` overlap in the same address space.
+    fn overlaps(&self, other: &AddrRange) -> bool {
+        (self.window == WindowType::Io) == (other.window == WindowType::Io)
+            && self.base <= other.limit
+            && other.base <= self.limit
+    }
+}
+
+// Checks the addresses that firmware assigned to the devices on `bus`, which is downstream of
+// `windows`, and recursively to the devices on the buses below it. Returns the ranges used by the
+// BARs and bridge windows on `bus` if each of them is aligned, lies within one of `apertures` and
+// doesn't overlap any other, or `None` if the assignment can't be kept.
+fn firmware_bus_ranges(
+    bus: &PciBus,
+    windows: BusWindows,
+    apertures: &[AddrRange],
+    device_arena: &PciDeviceArena,
+) -> Result<Option<Vec<AddrRange>>> {
+    let mut used = Vec::new();
+    for bd in bus.devices() {
+        // Unwrap ok: devices on a bus must be in the arena.
+        let dev = device_arena.get(bd.id).unwrap().lock();
+        let bars = dev
+            .bar_info()
+            .bars()
+            .map(|bar| (dev.get_bar_addr(bar.index()).unwrap_or(0), bar, 1));
+        // The VF BARs hold the address of the BARs of the first VF.
+        let max_vfs = dev.sriov().map(|s| s.max_vfs() as u64).unwrap_or(0);
+        let vf_bar_addrs = dev.virtual_function_bar_addrs(0);
+        let vf_bars = dev
+            .vf_bar_info()
+            .filter(|_| max_vfs != 0)
+            .into_iter()
+            .flat_map(|info| info.bars())
+            .map(|bar| (vf_bar_addrs[bar.index()], bar, max_vfs));
+        for (addr, bar, count) in bars.chain(vf_bars) {
+            let window = WindowType::for_bar(bar.bar_type());
+            // BARs that can't be forwarded to the bus are left unassigned.
+            if windows.select(window).is_none() {
+                continue;
+            }
+            // A BAR at address 0 was never assigned.
+            let range = match bar
+                .size()
+                .checked_mul(count)
+                .and_then(|size| AddrRange::new(window, addr, size))
+            {
+                Some(r) if addr != 0 && addr & (bar.size() - 1) == 0 => r,
+                _ => return Ok(None),
+            };
+            if (!bar.bar_type().is_64bit() && range.limit > u32::MAX as u64)
+                || !apertures.iter().any(|a| a.contains(&range))
+            {
+                return Ok(None);
+            }
+            used.try_reserve(1).map_err(|_| Error::AllocError)?;
+            used.push(range);
+        }
+
+        if let PciDevice::Bridge(ref bridge) = *dev {
+            let child_windows = BusWindows {
+                io: bridge.has_io_window() && windows.io,
+                pref: bridge.has_pref_window(),
+            };
+            let current = [
+                bridge.get_io_window(),
+                bridge.get_mem_window(),
+                bridge.get_pref_window(),
+            ];
+            let mut child_apertures = ArrayVec::<AddrRange, NUM_WINDOW_TYPES>::new();
+            for w in WINDOW_TYPES {
+                if child_windows.select(w) != Some(w) {
+                    continue;
+                }
+                let range = match current[w as usize] {
+                    Some((base, limit)) => AddrRange {
+                        window: w,
+                        base,
+                        limit,
+                    },
+                    None if dev.has_hotplug_slot() => return Ok(None),
+                    None => continue,
+                };
+                // Ports with hotplug slots need room for the devices that are added later.
+                if (dev.has_hotplug_slot() && range.limit - range.base < w.hotplug_size() - 1)
+                    || !apertures.iter().any(|a| a.contains(&range))
+                {
+                    return Ok(None);
+                }
+                used.try_reserve(1).map_err(|_| Error::AllocError)?;
+                used.push(range);
+                child_apertures.push(range);
+            }
+            if let Some(child_bus) = bridge.child_bus() {
+                let child_ranges =
+                    firmware_bus_ranges(child_bus, child_windows, &child_apertures, device_arena)?;
+                if child_ranges.is_none() {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    for (i, range) in used.iter().enumerate() {
+        if used[i + 1..].iter().any(|other| range.overlaps(other)) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(used))
+}
+
+// Returns the type and the range of PCI bus addresses of the host-exposed portion of each of
+// `resources`.
+fn root_apertures(
+    resources: &PciRootResources,
+) -> ArrayVec<(PciResourceType, AddrRange), MAX_RESOURCE_TYPES> {
+    (0..MAX_RESOURCE_TYPES)
+        .filter_map(PciResourceType::from_index)
+        .filter_map(|t| {
+            let resource = resources.get(t)?;
+            // Root resources hold BARs of the same type.
+            let range = AddrRange::new(
+                WindowType::for_bar(t),
+                resource.pci_addr(),
+                resource.host_size(),
+            )?;
+            Some((t, range))
+        })
+        .collect()
+}
+
+/// Assigns PCI bus addresses to the BARs of the devices below `bridge` from the windows that are
+/// currently programmed in the bridge. Used when devices are added to a hotplug slot after
+/// enumeration. Fails if the devices don't fit in the bridge's windows.
+pub(super) fn assign_secondary_bus(
+    bridge: &PciBridge,
+    device_arena: &PciDeviceArena,
+) -> Result<()> {
+    let child_bus = match bridge.child_bus() {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+    let windows = BusWindows {
+        io: bridge.has_io_window(),
+        pref: bridge.has_pref_window(),
+    };
+    let requirements = size_bus(child_bus, windows, device_arena)?;
+    let current = [
+        bridge.get_io_window(),
+        bridge.get_mem_window(),
+        bridge.get_pref_window(),
+    ];
+    let mut bases = [None; NUM_WINDOW_TYPES];
+    for w in WINDOW_TYPES {
+        let window_size = requirements.windows[w as usize];
+        if window_size.size == 0 {
+            continue;
+        }
+        let (base, limit) = current[w as usize].ok_or(Error::OutOfResources)?;
+        if base & (window_size.align - 1) != 0 || window_size.size > limit - base + 1 {
+            return Err(Error::OutOfResources);
+        }
+        // 32-bit prefetchable BARs can't be placed in a window above 4GB.
+        if w == WindowType::PrefetchableMem && !requirements.pref_64bit && limit > u32::MAX as u64 {
+            return Err(Error::OutOfResources);
+        }
+        bases[w as usize] = Some(base);
+    }
+    assign_bus(child_bus, windows, bases, device_arena)
+}
+
+/// Assigns PCI bus addresses from `resources` to the BARs and bridge windows of all the devices
+/// on and below `root_bus`. The addresses assigned by firmware are kept if they're all valid, in
+/// which case the ranges they use are reserved in `resources`. Otherwise every device is
+/// reassigned. Devices requiring a type of resource that is not provided by the root complex are
+/// left unassigned.
+pub(super) fn assign_resources(
+    root_bus: &PciBus,
+    device_arena: &PciDeviceArena,
+    resources: &mut PciRootResources,
+) -> Result<()> {
+    use PciResourceType::*;
+    let windows = BusWindows {
+        io: resources.get(IoPort).is_some(),
+        pref: true,
+    };
+    let apertures = root_apertures(resources);
+    let root_ranges: ArrayVec<AddrRange, MAX_RESOURCE_TYPES> =
+        apertures.iter().map(|&(_, range)| range).collect();
+    if let Some(used) = firmware_bus_ranges(root_bus, windows, &root_ranges, device_arena)? {
+        // Allocations made later, e.g. for hotplugged devices, must be placed past the devices
+        // that firmware assigned.
+        for (resource_type, aperture) in apertures {
+            let limit = used
+                .iter()
+                .filter(|r| aperture.contains(r))
+                .map(|r| r.limit)
+                .max();
+            if let Some(limit) = limit {
+                // Unwrap ok: the aperture was built from this resource.
+                resources
+                    .get_mut(resource_type)
+                    .unwrap()
+                    .reserve_for_host(limit)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let requirements = size_bus(root_bus, windows, device_arena)?;
+    let mut bases = [None; NUM_WINDOW_TYPES];
+    for w in WINDOW_TYPES {
+        let window_size = requirements.windows[w as usize];
+        if window_size.size == 0 {
+            continue;
+        }
+        // Prefetchable resources are placed above 4GB if possible. Otherwise each type of window
+        // falls back to non-prefetchable memory if the root complex doesn't have a dedicated
+        // resource for it.
+        let resource_type = match w {
+            WindowType::Io => Some(IoPort),
+            WindowType::Mem => resources.find_matching(Mem32),
+            WindowType::PrefetchableMem if requirements.pref_64bit => resources
+                .find_matching(PrefetchableMem64)
+                .or_else(|| resources.find_matching(PrefetchableMem32)),
+            WindowType::PrefetchableMem => resources.find_matching(PrefetchableMem32),
+        };
+        let resource = match resource_type.and_then(|t| resources.get_mut(t)) {
+            Some(r) => r,
+            None => continue,
+        };
+        bases[w as usize] = Some(resource.alloc_for_host(window_size.size, window_size.align)?);
+    }
+    assign_bus(root_bus, windows, bases, device_arena)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::address::*;
+    use crate::pci::config_space::PciConfigSpace;
+    use alloc::alloc::Global;
+    use riscv_pages::{PageAddr, RawAddr};
+
+    // Offset of the config space of `dev`, function 0 on `bus` in ECAM, in dwords.
+    fn ecam_offset(bus: usize, dev: usize) -> usize {
+        ((bus << 20) | (dev << 15)) / 4
+    }
+
+    // Returns ECAM for `num_buses` buses. Devices hold on to their registers, so it's never freed.
+    // Reads from functions which aren't there return all-ones.
+    fn fake_ecam(num_buses: usize) -> &'static mut [u32] {
+        let config_mem = vec![!0u32; (num_buses << 20) / 4 + 1024].leak();
+        let align_offset = config_mem.as_ptr().align_offset(4096);
+        &mut config_mem[align_offset..]
+    }
+
+    // Adds an endpoint with the BAR registers `bars` to `config` at `offset`. Since the ECAM is
+    // plain memory, every BAR is sized as 16 bytes, with the type in the low bits of `bars`.
+    fn add_endpoint(config: &mut [u32], offset: usize, bars: [u32; 6]) {
+        config[offset..offset + 1024].fill(0);
+        config[offset] = 0x1000_1af4; // Device and vendor ID
+        config[offset + 2] = 0x0200_0000; // Ethernet controller
+        config[offset + 4..offset + 10].copy_from_slice(&bars);
+    }
+
+    // Adds a PCI-to-PCI bridge to `config` at `offset`.
+    fn add_bridge(config: &mut [u32], offset: usize) {
+        config[offset..offset + 1024].fill(0);
+        config[offset] = 0x0001_1b36; // Device and vendor ID
+        config[offset + 2] = 0x0604_0000; // PCI-to-PCI bridge
+        config[offset + 3] = 0x0001_0000; // Type 1 header
+    }
+
+    // Enumerates the first `num_buses` buses in `config`.
+    fn enumerate(config: &[u32], num_buses: u8) -> (PciBus, PciDeviceArena) {
+        let config_space = PciConfigSpace::new(
+            PageAddr::new(RawAddr::supervisor(config.as_ptr() as u64)).unwrap(),
+            (num_buses as u64) << 20,
+            Segment::default(),
+            BusRange {
+                start: Bus::try_from(0u8).unwrap(),
+                end: Bus::try_from(num_buses - 1).unwrap(),
+            },
+        );
+        let mut device_arena = PciDeviceArena::new(Global);
+        let root_bus = PciBus::enumerate(
+            &config_space,
+            config_space.bus_range().start,
+            &mut device_arena,
+        )
+        .unwrap();
+        (root_bus, device_arena)
+    }
+
+    // Returns a 16MB 32-bit memory resource at 0x4000_0000, and a 1GB 64-bit prefetchable memory
+    // resource at 0x1_0000_0000.
+    fn root_resources() -> PciRootResources {
+        let mut resources = PciRootResources::new();
+        let mem_addr = PageAddr::new(RawAddr::supervisor(0x4000_0000)).unwrap();
+        resources
+            .insert(
+                PciResourceType::Mem32,
+                PciRootResource::new(mem_addr, 0x100_0000, 0x4000_0000),
+            )
+            .unwrap();
+        let pref_addr = PageAddr::new(RawAddr::supervisor(0x1_0000_0000)).unwrap();
+        resources
+            .insert(
+                PciResourceType::PrefetchableMem64,
+                PciRootResource::new(pref_addr, 0x4000_0000, 0x1_0000_0000),
+            )
+            .unwrap();
+        resources
+    }
+
+    // Returns the addresses of the BARs of the device at `address`.
+    fn bar_addrs(device_arena: &PciDeviceArena, address: Address) -> Vec<(usize, u64)> {
+        let id = device_arena
+            .ids()
+            .find(|&id| device_arena.get(id).unwrap().lock().info().address() == address)
+            .unwrap();
+        let dev = device_arena.get(id).unwrap().lock();
+        dev.bar_info()
+            .bars()
+            .map(|bar| (bar.index(), dev.get_bar_addr(bar.index()).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn assign_unassigned_bars() {
+        // An endpoint with a 64-bit prefetchable BAR 2 and 32-bit BARs 0, 1, 4 and 5, none of
+        // which were assigned by firmware.
+        let config = fake_ecam(1);
+        add_endpoint(config, ecam_offset(0, 0), [0, 0, 0xc, 0, 0, 0]);
+        let (root_bus, device_arena) = enumerate(config, 1);
+        let mut resources = root_resources();
+        assign_resources(&root_bus, &device_arena, &mut resources).unwrap();
+
+        let address = Address::try_from_components(0, 0, 0, 0).unwrap();
+        let bars = bar_addrs(&device_arena, address);
+        assert_eq!(bars.len(), 5);
+        for (index, addr) in bars {
+            assert_eq!(addr & 0xf, 0);
+            if index == 2 {
+                // The 64-bit prefetchable BAR is placed above 4GB.
+                assert!((0x1_0000_0000..0x1_4000_0000).contains(&addr));
+            } else {
+                assert!((0x4000_0000..0x4100_0000).contains(&addr));
+            }
+        }
+    }
+
+    #[test]
+    fn keep_firmware_assignment() {
+        let bars = [
+            0x4000_0000,
+            0x4000_0010,
+            0x0000_000c,
+            0x1,
+            0x4000_0020,
+            0x4000_0030,
+        ];
+        let config = fake_ecam(1);
+        add_endpoint(config, ecam_offset(0, 0), bars);
+        let (root_bus, device_arena) = enumerate(config, 1);
+        let mut resources = root_resources();
+        assign_resources(&root_bus, &device_arena, &mut resources).unwrap();
+
+        // The BARs are left where firmware put them.
+        let address = Address::try_from_components(0, 0, 0, 0).unwrap();
+        assert_eq!(
+            bar_addrs(&device_arena, address),
+            [
+                (0, 0x4000_0000),
+                (1, 0x4000_0010),
+                (2, 0x1_0000_0000),
+                (4, 0x4000_0020),
+                (5, 0x4000_0030)
+            ]
+        );
+        // And later allocations are placed after them.
+        assert_eq!(
+            resources
+                .get_mut(PciResourceType::Mem32)
+                .unwrap()
+                .alloc_for_host(0x10, 0x10)
+                .unwrap(),
+            0x4000_0040
+        );
+        assert_eq!(
+            resources
+                .get_mut(PciResourceType::PrefetchableMem64)
+                .unwrap()
+                .alloc_for_host(0x10, 0x10)
+                .unwrap(),
+            0x1_0000_0010
+        );
+
+        // Overlapping BARs can't be kept, so everything is reassigned.
+        let config = fake_ecam(1);
+        add_endpoint(
+            config,
+            ecam_offset(0, 0),
+            [0x4000_0000, 0x4000_0000, 0, 0, 0, 0],
+        );
+        let (root_bus, device_arena) = enumerate(config, 1);
+        let mut resources = root_resources();
+        assign_resources(&root_bus, &device_arena, &mut resources).unwrap();
+        let mut addrs: Vec<u64> = bar_addrs(&device_arena, address)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        addrs.sort_unstable();
+        addrs.dedup();
+        assert_eq!(addrs.len(), 6);
+    }
+
+    #[test]
+    fn assign_bus_numbers() {
+        // Two bridges on bus 0, each with an endpoint behind it. The bus numbers left in the
+        // first bridge by firmware are ignored.
+        let config = fake_ecam(3);
+        add_bridge(config, ecam_offset(0, 0));
+        config[ecam_offset(0, 0) + 6] = 0x0005_0500;
+        add_bridge(config, ecam_offset(0, 1));
+        add_endpoint(config, ecam_offset(1, 0), [0; 6]);
+        add_endpoint(config, ecam_offset(2, 0), [0; 6]);
+        let (root_bus, device_arena) = enumerate(config, 3);
+
+        // Primary, secondary and subordinate bus numbers.
+        assert_eq!(config[ecam_offset(0, 0) + 6] & 0xff_ffff, 0x01_0100);
+        assert_eq!(config[ecam_offset(0, 1) + 6] & 0xff_ffff, 0x02_0200);
+
+        // Each endpoint's BARs are assigned from the memory window of the bridge above it.
+        let mut resources = root_resources();
+        assign_resources(&root_bus, &device_arena, &mut resources).unwrap();
+        for (bridge, bus) in [(0, 1), (1, 2)] {
+            let bridge_address = Address::try_from_components(0, 0, bridge, 0).unwrap();
+            let (base, limit) = device_arena
+                .ids()
+                .find_map(|id| {
+                    let dev = device_arena.get(id).unwrap().lock();
+                    match *dev {
+                        PciDevice::Bridge(ref b) if dev.info().address() == bridge_address => {
+                            b.get_mem_window()
+                        }
+                        _ => None,
+                    }
+                })
+                .unwrap();
+            let ep_address = Address::try_from_components(0, bus, 0, 0).unwrap();
+            for (_, addr) in bar_addrs(&device_arena, ep_address) {
+                assert!(base <= addr && addr + 0xf <= limit);
+            }
+        }
+    }
+
+    fn request(target: usize, window: WindowType, size: u64) -> Request<usize> {
+        Request {
+            target,
+            window,
+            size,
+            align: size,
+            is_64bit: false,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn pack_windows() {
+        let mut requests = [
+            request(0, WindowType::Mem, 0x1000),
+            request(1, WindowType::Mem, 0x10_0000),
+            request(2, WindowType::Io, 0x20),
+            request(3, WindowType::Mem, 0x4000),
+            request(4, WindowType::PrefetchableMem, 0x20_0000),
+            request(5, WindowType::Io, 0x100),
+            // A bridge window with a size that isn't a multiple of its alignment.
+            Request {
+                align: 0x4_0000,
+                ..request(6, WindowType::Mem, 0x30_0000)
+            },
+        ];
+        let windows = pack(&mut requests).unwrap();
+        let offset = |target| requests.iter().find(|r| r.target == target).unwrap().offset;
+        assert_eq!(offset(1), 0);
+        assert_eq!(offset(6), 0x10_0000);
+        assert_eq!(offset(3), 0x40_0000);
+        assert_eq!(offset(0), 0x40_4000);
+        assert_eq!(offset(4), 0);
+        assert_eq!(offset(5), 0);
+        assert_eq!(offset(2), 0x100);
+        assert_eq!(
+            windows[WindowType::Io as usize],
+            WindowSize {
+                size: 0x120,
+                align: 0x100
+            }
+        );
+        assert_eq!(
+            windows[WindowType::Mem as usize],
+            WindowSize {
+                size: 0x40_5000,
+                align: 0x10_0000
+            }
+        );
+        assert_eq!(
+            windows[WindowType::PrefetchableMem as usize],
+            WindowSize {
+                size: 0x20_0000,
+                align: 0x20_0000
+            }
+        );
+    }
+}
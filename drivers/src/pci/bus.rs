@@ -47,10 +47,36 @@ impl PciBus {
                 // same device as the one referred to by info.address(). We guarantee that the created
                 // device has unique ownership of the register space via the bus enumeration process
                 // by creating at most one device per PCI address.
-                let pci_dev = unsafe { PciDevice::new(registers_ptr, info.clone()) }?;
+                let mut pci_dev = unsafe { PciDevice::new(registers_ptr, info.clone()) }?;
+                let vf_addrs = pci_dev.probe_virtual_functions();
                 let id = device_arena
                     .try_insert(Mutex::new(pci_dev))
                     .map_err(|_| Error::AllocError)?;
+                // The arena can't grow once enumeration is complete, so reserve entries for the
+                // SR-IOV virtual functions of the device now. They remain absent until the VFs are
+                // enabled.
+                if !vf_addrs.is_empty() {
+                    let mut vfs = Vec::new();
+                    vfs.try_reserve(vf_addrs.len())
+                        .map_err(|_| Error::AllocError)?;
+                    for vf_addr in vf_addrs {
+                        // Unwrap ok: we've just added the PF.
+                        let vf = device_arena
+                            .get(id)
+                            .unwrap()
+                            .lock()
+                            .new_virtual_function(id, vf_addr);
+                        let vf_id = device_arena
+                            .try_insert(Mutex::new(vf))
+                            .map_err(|_| Error::AllocError)?;
+                        vfs.push(vf_id);
+                    }
+                    device_arena
+                        .get(id)
+                        .unwrap()
+                        .lock()
+                        .set_virtual_functions(vfs);
+                }
                 let entry = BusDevice {
                     address: info.address(),
                     id,
@@ -78,8 +104,18 @@ impl PciBus {
                 _ => continue,
             };
 
-            let child_bus = PciBus::enumerate(config_space, sec_bus, device_arena)?;
+            let mut child_bus = PciBus::enumerate(config_space, sec_bus, device_arena)?;
             let sub_bus = child_bus.subordinate_bus_num();
+            let has_hotplug_slot = device_arena
+                .get(bridge_id)
+                .unwrap()
+                .lock()
+                .has_hotplug_slot();
+            // Anything found behind a hotplug port at this point is in its slot.
+            let slot_populated = child_bus.devices().next().is_some();
+            if has_hotplug_slot {
+                child_bus.reserve_slot_functions(config_space.segment(), device_arena)?;
+            }
 
             // Avoid double mutable borrow of device_arena by re-acquiring the reference to the bridge
             // device here. PciBus::enumerate() may have added devices and re-allocated the arena.
@@ -91,6 +127,7 @@ impl PciBus {
                         end: sub_bus,
                     });
                     bridge.set_child_bus(child_bus);
+                    bridge.set_slot_populated(slot_populated);
                 }
                 // The device must be a bridge.
                 _ => unreachable!(),
@@ -109,11 +146,50 @@ impl PciBus {
         })
     }
 
+    // Reserves entries in `device_arena` for the functions of the hotplug slot on this bus that
+    // aren't populated. Slots are below PCIe downstream ports, so the device in the slot is always
+    // device 0. The arena can't grow once enumeration is complete, so devices which are added to
+    // the slot later replace the reserved entries.
+    fn reserve_slot_functions(
+        &mut self,
+        segment: Segment,
+        device_arena: &mut PciDeviceArena,
+    ) -> Result<()> {
+        let mut next = Some(Address::new(
+            segment,
+            self.bus_range.start,
+            Device::default(),
+            Function::default(),
+        ));
+        while let Some(address) = next {
+            next = address.next_function();
+            // Skip functions that are populated, including SR-IOV virtual functions of a device
+            // that was present at enumeration.
+            if device_arena
+                .iter()
+                .any(|dev| dev.lock().info().address() == address)
+            {
+                continue;
+            }
+            let id = device_arena
+                .try_insert(Mutex::new(PciDevice::empty(address)))
+                .map_err(|_| Error::AllocError)?;
+            self.devices.try_reserve(1).map_err(|_| Error::AllocError)?;
+            self.devices.push(BusDevice { address, id });
+        }
+        Ok(())
+    }
+
     /// Returns an iterator over the device IDs on this bus.
     pub fn devices(&self) -> core::slice::Iter<BusDevice> {
         self.devices.iter()
     }
 
+    /// Returns the secondary bus number (the number of this bus).
+    pub fn secondary_bus_num(&self) -> Bus {
+        self.bus_range.start
+    }
+
     /// Returns the subordinate bus number (the highest-numbered downstream bus) for this bus.
     pub fn subordinate_bus_num(&self) -> Bus {
         self.bus_range.end
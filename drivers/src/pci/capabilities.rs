@@ -6,11 +6,14 @@ use arrayvec::ArrayVec;
 use core::mem::size_of;
 use enum_dispatch::enum_dispatch;
 use memoffset::offset_of;
-use tock_registers::interfaces::{Readable, Writeable};
+use s_mode_utils::print::*;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+use tock_registers::registers::ReadWrite;
 use tock_registers::LocalRegisterCopy;
 
 use super::error::*;
 use super::mmio_builder::{MmioReadBuilder, MmioWriteBuilder};
+use super::msix::MsiXTableInfo;
 use super::registers::*;
 
 // Standard PCI capability IDs.
@@ -41,6 +44,50 @@ impl CapabilityId {
     }
 }
 
+// PCI Express extended capability IDs.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExtendedCapabilityId {
+    Aer = 0x1,
+    Acs = 0xd,
+    Ats = 0xf,
+    SrIov = 0x10,
+    Pri = 0x13,
+    Pasid = 0x1b,
+    Dvsec = 0x23,
+}
+
+impl ExtendedCapabilityId {
+    // Returns the `ExtendedCapabilityId` from the raw register value.
+    fn from_raw(id: u16) -> Option<Self> {
+        use ExtendedCapabilityId::*;
+        match id {
+            0x1 => Some(Aer),
+            0xd => Some(Acs),
+            0xf => Some(Ats),
+            0x10 => Some(SrIov),
+            0x13 => Some(Pri),
+            0x1b => Some(Pasid),
+            0x23 => Some(Dvsec),
+            _ => None,
+        }
+    }
+
+    // Returns the size of the registers of a capability of this type.
+    fn registers_size(self) -> usize {
+        use ExtendedCapabilityId::*;
+        match self {
+            Aer => size_of::<AerRegisters>(),
+            Acs => size_of::<AcsRegisters>(),
+            Ats => size_of::<AtsRegisters>(),
+            SrIov => size_of::<SriovRegisters>(),
+            Pri => size_of::<PriRegisters>(),
+            Pasid => size_of::<PasidRegisters>(),
+            Dvsec => size_of::<DvsecRegisters>(),
+        }
+    }
+}
+
 mod header_offsets {
     use super::CapabilityHeader;
     use crate::define_field_span;
@@ -103,6 +150,82 @@ mod express_offsets {
     define_field_span!(ExpressRegisters, dev_control, u16);
     define_field_span!(ExpressRegisters, link_caps, u32);
     define_field_span!(ExpressRegisters, link_status, u16);
+    define_field_span!(ExpressRegisters, slot_caps, u32);
+    define_field_span!(ExpressRegisters, slot_control, u16);
+    define_field_span!(ExpressRegisters, slot_status, u16);
+}
+
+mod ext_header_offsets {
+    use super::ExtendedCapabilityHeader;
+    use crate::define_field_span;
+
+    define_field_span!(ExtendedCapabilityHeader, header, u32);
+}
+
+mod aer_offsets {
+    use super::AerRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(AerRegisters, uncor_status, u32);
+    define_field_span!(AerRegisters, uncor_mask, u32);
+    define_field_span!(AerRegisters, uncor_severity, u32);
+    define_field_span!(AerRegisters, cor_status, u32);
+    define_field_span!(AerRegisters, cor_mask, u32);
+    define_field_span!(AerRegisters, caps_control, u32);
+    define_field_span!(AerRegisters, header_log, [u32; 4]);
+}
+
+mod acs_offsets {
+    use super::AcsRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(AcsRegisters, acs_caps, u16);
+    define_field_span!(AcsRegisters, acs_control, u16);
+}
+
+mod ats_offsets {
+    use super::AtsRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(AtsRegisters, ats_caps, u16);
+    define_field_span!(AtsRegisters, ats_control, u16);
+}
+
+mod sriov_offsets {
+    use super::SriovRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(SriovRegisters, sriov_caps, u32);
+    define_field_span!(SriovRegisters, sriov_control, u16);
+    define_field_span!(SriovRegisters, sriov_status, u16);
+    define_field_span!(SriovRegisters, initial_vfs, u16);
+    define_field_span!(SriovRegisters, total_vfs, u16);
+    define_field_span!(SriovRegisters, num_vfs, u16);
+    define_field_span!(SriovRegisters, func_dep_link, u8);
+    define_field_span!(SriovRegisters, first_vf_offset, u16);
+    define_field_span!(SriovRegisters, vf_stride, u16);
+    define_field_span!(SriovRegisters, vf_device_id, u16);
+    define_field_span!(SriovRegisters, supported_page_sizes, u32);
+    define_field_span!(SriovRegisters, system_page_size, u32);
+    define_field_span!(SriovRegisters, vf_bar, [u32; 6]);
+}
+
+mod pri_offsets {
+    use super::PriRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(PriRegisters, pri_control, u16);
+    define_field_span!(PriRegisters, pri_status, u16);
+    define_field_span!(PriRegisters, request_capacity, u32);
+    define_field_span!(PriRegisters, request_allocation, u32);
+}
+
+mod pasid_offsets {
+    use super::PasidRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(PasidRegisters, pasid_caps, u16);
+    define_field_span!(PasidRegisters, pasid_control, u16);
 }
 
 // Type-specific capability structures.
@@ -114,6 +237,13 @@ enum CapabilityType {
     Vendor,
     BridgeSubsystem,
     PciExpress,
+    Aer,
+    Acs,
+    Ats,
+    SrIov,
+    Pri,
+    Pasid,
+    Dvsec,
 }
 
 // Common functionality required by all capabilities.
@@ -129,8 +259,9 @@ trait Capability {
     fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize);
 }
 
-struct PowerManagement {
+pub(super) struct PowerManagement {
     registers: &'static mut PowerManagementRegisters,
+    state_request: Option<PowerState>,
 }
 
 impl PowerManagement {
@@ -142,7 +273,34 @@ impl PowerManagement {
                 .as_mut()
                 .unwrap()
         };
-        Self { registers }
+        Self {
+            registers,
+            state_request: None,
+        }
+    }
+
+    /// Returns the current power state of the device.
+    pub(super) fn power_state(&self) -> Option<PowerState> {
+        self.registers
+            .pmcsr
+            .read_as_enum(PmControlStatus::PowerState)
+    }
+
+    /// Moves the device to the power state `state`.
+    pub(super) fn set_power_state(&mut self, state: PowerState) {
+        self.registers
+            .pmcsr
+            .modify(PmControlStatus::PowerState.val(state as u16));
+    }
+
+    /// Returns if the device preserves its internal state when moving from D3hot to D0.
+    pub(super) fn no_soft_reset(&self) -> bool {
+        self.registers.pmcsr.is_set(PmControlStatus::NoSoftReset)
+    }
+
+    /// Returns the power state requested by a VM, if any.
+    pub(super) fn take_state_request(&mut self) -> Option<PowerState> {
+        self.state_request.take()
     }
 }
 
@@ -166,9 +324,27 @@ impl Capability for PowerManagement {
         }
     }
 
-    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, _cap_offset: usize) {
-        // TODO: Support PME and D-state transitions if necessary.
-        op.pop_byte();
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use pmc_offsets::*;
+        match cap_offset {
+            pmcsr::span!() => {
+                let reg = LocalRegisterCopy::<u16, PmControlStatus::Register>::new(
+                    op.pop_word(self.registers.pmcsr.get()),
+                );
+                // Latch the transition so that the hypervisor can wait for it to complete. D1 and
+                // D2 aren't supported, so requests to enter them are ignored.
+                let state = reg.read_as_enum(PmControlStatus::PowerState);
+                if matches!(state, Some(PowerState::D0) | Some(PowerState::D3Hot))
+                    && state != self.power_state()
+                {
+                    self.state_request = state;
+                }
+            }
+            _ => {
+                // PME isn't supported.
+                op.pop_byte();
+            }
+        }
     }
 }
 
@@ -296,6 +472,37 @@ impl MsiX {
         };
         Self { registers }
     }
+
+    // Returns the location of the MSI-X table and PBA.
+    fn table_info(&self) -> MsiXTableInfo {
+        let table = &self.registers.table_offset;
+        let pba = &self.registers.pba_offset;
+        MsiXTableInfo::new(
+            table.read(MsiXTableOffset::Bir) as usize,
+            (table.read(MsiXTableOffset::Offset) as u64) << MsiXTableOffset::Offset.shift,
+            pba.read(MsiXTableOffset::Bir) as usize,
+            (pba.read(MsiXTableOffset::Offset) as u64) << MsiXTableOffset::Offset.shift,
+            // The table size is encoded as N-1.
+            self.registers
+                .msg_control
+                .read(MsiXMessageControl::TableSize) as usize
+                + 1,
+        )
+    }
+
+    // Returns if all the vectors of the function are masked.
+    fn function_masked(&self) -> bool {
+        self.registers
+            .msg_control
+            .is_set(MsiXMessageControl::FunctionMask)
+    }
+
+    // Sets or clears the mask for all vectors of the function.
+    fn set_function_mask(&mut self, masked: bool) {
+        self.registers
+            .msg_control
+            .modify(MsiXMessageControl::FunctionMask.val(masked as u16));
+    }
 }
 
 impl Capability for MsiX {
@@ -496,10 +703,11 @@ impl PciExpressDeviceType {
     }
 }
 
-struct PciExpress {
+pub(super) struct PciExpress {
     registers: &'static mut ExpressRegisters,
     version: u8,
     device_type: PciExpressDeviceType,
+    reset_request: bool,
 }
 
 impl PciExpress {
@@ -522,8 +730,82 @@ impl PciExpress {
             registers,
             version,
             device_type,
+            reset_request: false,
         })
     }
+
+    /// Returns if the device supports function-level reset.
+    pub(super) fn has_flr(&self) -> bool {
+        self.registers
+            .dev_caps
+            .is_set(DeviceCapabilities::FunctionLevelReset)
+    }
+
+    /// Returns if the device has non-posted requests outstanding.
+    pub(super) fn transactions_pending(&self) -> bool {
+        self.registers
+            .dev_status
+            .is_set(DeviceStatus::TransactionsPending)
+    }
+
+    /// Initiates a function-level reset of the device.
+    pub(super) fn initiate_flr(&mut self) {
+        self.registers
+            .dev_control
+            .modify(DeviceControl::FunctionLevelReset.val(1));
+    }
+
+    /// Returns if a VM has requested a function-level reset of the device.
+    pub(super) fn take_reset_request(&mut self) -> bool {
+        core::mem::take(&mut self.reset_request)
+    }
+
+    // Returns if the port implements the slot registers.
+    fn has_slot(&self) -> bool {
+        use PciExpressDeviceType::*;
+        matches!(self.device_type, RootPort | DownstreamSwitchPort)
+            && self
+                .registers
+                .exp_caps
+                .is_set(ExpressCapabilities::SlotImplemented)
+    }
+
+    /// Returns if the device is a port with a hotplug-capable slot.
+    pub(super) fn has_hotplug_slot(&self) -> bool {
+        self.has_slot()
+            && self
+                .registers
+                .slot_caps
+                .is_set(SlotCapabilities::HotPlugCapable)
+    }
+
+    /// Returns if the slot of the port is occupied by a device that is powered and, if the port is
+    /// capable of reporting it, has an active link.
+    pub(super) fn slot_occupied(&self) -> bool {
+        let powered = !self
+            .registers
+            .slot_caps
+            .is_set(SlotCapabilities::PowerControllerPresent)
+            || self
+                .registers
+                .slot_control
+                .matches_all(SlotControl::PowerControllerControl::On);
+        let link_active = !self
+            .registers
+            .link_caps
+            .is_set(LinkCapabilities::DataLinkLayerLinkActiveReportingCapable)
+            || self
+                .registers
+                .link_status
+                .is_set(LinkStatus::DataLinkLayerLinkActive);
+        self.has_slot()
+            && self
+                .registers
+                .slot_status
+                .is_set(SlotStatus::PresenceDetectState)
+            && powered
+            && link_active
+    }
 }
 
 impl Capability for PciExpress {
@@ -532,6 +814,8 @@ impl Capability for PciExpress {
             size_of::<ExpressRegisters>()
         } else if self.device_type.has_root_control() {
             offset_of!(ExpressRegisters, dev_caps2)
+        } else if self.has_slot() {
+            offset_of!(ExpressRegisters, root_control)
         } else if self.device_type.has_link_control() {
             offset_of!(ExpressRegisters, slot_caps)
         } else {
@@ -557,6 +841,15 @@ impl Capability for PciExpress {
             link_status::span!() if self.device_type.has_link_control() => {
                 op.push_word(self.registers.link_status.readable_bits());
             }
+            slot_caps::span!() if self.has_slot() => {
+                op.push_dword(self.registers.slot_caps.readable_bits());
+            }
+            slot_control::span!() if self.has_slot() => {
+                op.push_word(self.registers.slot_control.readable_bits());
+            }
+            slot_status::span!() if self.has_slot() => {
+                op.push_word(self.registers.slot_status.readable_bits());
+            }
             _ => {
                 // Make all other capability and status bits appear unimplemented.
                 op.push_byte(0);
@@ -568,10 +861,31 @@ impl Capability for PciExpress {
         use express_offsets::*;
         match cap_offset {
             dev_control::span!() => {
-                let reg = LocalRegisterCopy::<u16, DeviceControl::Register>::new(
-                    op.pop_word(self.registers.dev_control.get()),
+                let current = self.registers.dev_control.get();
+                let reg =
+                    LocalRegisterCopy::<u16, DeviceControl::Register>::new(op.pop_word(current));
+                // Latch FLR requests so that the hypervisor can wait for the reset to complete.
+                if reg.is_set(DeviceControl::FunctionLevelReset) && self.has_flr() {
+                    self.reset_request = true;
+                }
+                // Preserve the bits that aren't writeable by the VM.
+                let preserved = current & !DeviceControl::Register::writeable_mask();
+                self.registers
+                    .dev_control
+                    .set(preserved | reg.writeable_bits());
+            }
+            slot_control::span!() if self.has_slot() => {
+                let reg = LocalRegisterCopy::<u16, SlotControl::Register>::new(
+                    op.pop_word(self.registers.slot_control.get()),
+                );
+                self.registers.slot_control.set(reg.writeable_bits());
+            }
+            slot_status::span!() if self.has_slot() => {
+                // Make sure we only write the RW1C bits if the write operation covers that byte.
+                let reg = LocalRegisterCopy::<u16, SlotStatus::Register>::new(
+                    op.pop_word(self.registers.slot_status.non_clearable_bits()),
                 );
-                self.registers.dev_control.set(reg.writeable_bits());
+                self.registers.slot_status.set(reg.writeable_bits());
             }
             _ => {
                 // We don't support writes to any of the othe control registers for now.
@@ -581,153 +895,1001 @@ impl Capability for PciExpress {
     }
 }
 
-// Represents a single PCI capability.
-struct PciCapability {
-    id: CapabilityId,
-    offset: usize,
-    next: usize,
-    cap_type: CapabilityType,
+struct Aer {
+    registers: &'static mut AerRegisters,
 }
 
-impl PciCapability {
-    // Creates a new capability of type `id` at `header`, which itself is at `offset` within the
-    // configuration space.
-    fn new(header: &mut CapabilityHeader, id: CapabilityId, offset: usize) -> Result<Self> {
-        let cap_type = match id {
-            CapabilityId::PowerManagement => PowerManagement::new(header).into(),
-            CapabilityId::Msi => Msi::new(header)?.into(),
-            CapabilityId::MsiX => MsiX::new(header).into(),
-            CapabilityId::Vendor => Vendor::new(header)?.into(),
-            CapabilityId::BridgeSubsystem => BridgeSubsystem::new(header).into(),
-            CapabilityId::PciExpress => PciExpress::new(header)?.into(),
+impl Aer {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut AerRegisters)
+                .as_mut()
+                .unwrap()
         };
-        Ok(PciCapability {
-            id,
-            offset,
-            next: 0,
-            cap_type,
-        })
-    }
-
-    // Returns the ID of this capability.
-    fn id(&self) -> CapabilityId {
-        self.id
-    }
-
-    // Returns the offset of this capability within the configuration space of this device.
-    fn offset(&self) -> usize {
-        self.offset
-    }
-
-    // Sets the offset of the next capability (from the start of the configuration space) in the
-    // linked list.
-    fn set_next(&mut self, next: usize) {
-        self.next = next;
+        Self { registers }
     }
+}
 
-    // Returns the length of this capability structure.
+impl Capability for Aer {
     fn length(&self) -> usize {
-        self.cap_type.length()
+        size_of::<AerRegisters>()
     }
 
-    // Emulates a read from this capability structure.
-    fn emulate_read(&self, op: &mut MmioReadBuilder) {
-        let cap_offset = op.offset() - self.offset;
-        use header_offsets::*;
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use aer_offsets::*;
         match cap_offset {
-            cap_id::span!() => {
-                op.push_byte(self.id as u8);
+            uncor_status::span!() => {
+                op.push_dword(self.registers.uncor_status.get());
             }
-            next_cap::span!() => {
-                op.push_byte(self.next as u8);
+            uncor_mask::span!() => {
+                op.push_dword(self.registers.uncor_mask.get());
+            }
+            uncor_severity::span!() => {
+                op.push_dword(self.registers.uncor_severity.get());
+            }
+            cor_status::span!() => {
+                op.push_dword(self.registers.cor_status.get());
+            }
+            cor_mask::span!() => {
+                op.push_dword(self.registers.cor_mask.get());
+            }
+            caps_control::span!() => {
+                op.push_dword(self.registers.caps_control.readable_bits());
+            }
+            header_log::span!() => {
+                let index = (cap_offset - header_log::START_OFFSET) / size_of::<u32>();
+                op.push_dword(self.registers.header_log[index].get());
             }
             _ => {
-                self.cap_type.emulate_read(op, cap_offset);
+                op.push_byte(0);
             }
         }
     }
 
-    // Emulates a write to this capability structure.
-    fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
-        let cap_offset = op.offset() - self.offset;
-        use header_offsets::*;
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use aer_offsets::*;
+        // The error status registers are RW1C, so only clear the bytes covered by the write.
         match cap_offset {
-            cap_id::span!() | next_cap::span!() => {
-                op.pop_byte();
+            uncor_status::span!() => {
+                let reg = op.pop_dword(0);
+                self.registers.uncor_status.set(reg);
+            }
+            uncor_mask::span!() => {
+                let reg = op.pop_dword(self.registers.uncor_mask.get());
+                self.registers.uncor_mask.set(reg);
+            }
+            uncor_severity::span!() => {
+                let reg = op.pop_dword(self.registers.uncor_severity.get());
+                self.registers.uncor_severity.set(reg);
+            }
+            cor_status::span!() => {
+                let reg = op.pop_dword(0);
+                self.registers.cor_status.set(reg);
+            }
+            cor_mask::span!() => {
+                let reg = op.pop_dword(self.registers.cor_mask.get());
+                self.registers.cor_mask.set(reg);
+            }
+            caps_control::span!() => {
+                let reg = LocalRegisterCopy::<u32, AerCapabilitiesControl::Register>::new(
+                    op.pop_dword(self.registers.caps_control.get()),
+                );
+                self.registers.caps_control.set(reg.writeable_bits());
             }
             _ => {
-                self.cap_type.emulate_write(op, cap_offset);
+                op.pop_byte();
             }
         }
     }
 }
 
-// The maximum number of capabilities we support for a single device. Enough for the typical PCI
-// devices virtualized by QEMU.
-const MAX_PCI_CAPS: usize = 8;
+struct Acs {
+    registers: &'static mut AcsRegisters,
+}
 
-/// Maps the location of PCI capabilities in a device's config space and handles emulation of
-/// reads and writes to these capabilities.
-pub struct PciCapabilities {
-    caps: ArrayVec<PciCapability, MAX_PCI_CAPS>,
+impl Acs {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut AcsRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        Self { registers }
+    }
 }
 
-impl PciCapabilities {
-    /// Creates a new `PciCapabilities` by parsing the PCI capability linked-list starting at
-    /// `start_offset` within the standard PCI configuration space pointed to by `config_regs`.
-    pub fn new(config_regs: &mut CommonRegisters, start_offset: usize) -> Result<Self> {
-        let mut caps = ArrayVec::new();
-        let mut current_offset = start_offset;
-        while current_offset > PCI_TYPE_HEADER_END && current_offset < PCI_CONFIG_SPACE_END {
-            let cap_ptr = (config_regs as *mut CommonRegisters as usize + current_offset)
-                as *mut CapabilityHeader;
-            // Safety: `cap_ptr` is within the valid and uniquely-owned PCI configuration space
-            // referred to by `config_regs` and we are trusting that the hardware has initialized the
-            // capability offset registers such that they refer to valid PCI capability headers.
-            let header = unsafe { cap_ptr.as_mut().unwrap() };
-            let offset = current_offset;
-            // Per the spec, the bottom two bits of the next capability pointer should always be
-            // discarded.
-            //
-            // TODO: We should really check that capabilities don't overlap, but this is difficult
-            // since they're dynamically sized and the list can be in any order. So for now we trust
-            // that the hardware provides a valid config space.
-            current_offset = (header.next_cap.get() as usize) & !0x3;
-            if let Some(id) = CapabilityId::from_raw(header.cap_id.get()) {
-                let cap = PciCapability::new(header, id, offset)?;
-                caps.try_push(cap).map_err(|_| Error::TooManyCapabilities)?;
-            };
-        }
+impl Capability for Acs {
+    fn length(&self) -> usize {
+        size_of::<AcsRegisters>()
+    }
 
-        // Now link all the capabilities together to form a linked-list in the virtual config space.
-        for i in 1..caps.len() {
-            let next_ptr = caps[i].offset();
-            caps[i - 1].set_next(next_ptr);
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use acs_offsets::*;
+        match cap_offset {
+            acs_caps::span!() => {
+                op.push_word(self.registers.acs_caps.get());
+            }
+            acs_control::span!() => {
+                op.push_word(self.registers.acs_control.get());
+            }
+            _ => {
+                op.push_byte(0);
+            }
         }
-
-        Ok(Self { caps })
     }
 
-    /// Returns if an MSI capability is present.
-    pub fn has_msi(&self) -> bool {
-        self.capability_by_id(CapabilityId::Msi).is_some()
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, _cap_offset: usize) {
+        // ACS controls the isolation between devices, which is up to us rather than VMs.
+        op.pop_byte();
     }
+}
 
-    /// Returns if an MSI-X capability is present.
-    pub fn has_msix(&self) -> bool {
-        self.capability_by_id(CapabilityId::MsiX).is_some()
+struct Ats {
+    registers: &'static mut AtsRegisters,
+}
+
+impl Ats {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut AtsRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        Self { registers }
     }
+}
 
-    /// Returns if a PCI-Express capability is present.
-    pub fn is_pcie(&self) -> bool {
-        self.capability_by_id(CapabilityId::PciExpress).is_some()
+impl Capability for Ats {
+    fn length(&self) -> usize {
+        size_of::<AtsRegisters>()
     }
 
-    /// Emulates a read from this device's capabilities structures.
-    pub fn emulate_read(&self, op: &mut MmioReadBuilder) {
-        if let Some(cap) = self.capability_by_offset(op.offset()) {
-            cap.emulate_read(op);
-        } else {
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use ats_offsets::*;
+        match cap_offset {
+            ats_caps::span!() => {
+                op.push_word(self.registers.ats_caps.readable_bits());
+            }
+            ats_control::span!() => {
+                op.push_word(self.registers.ats_control.readable_bits());
+            }
+            _ => {
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use ats_offsets::*;
+        match cap_offset {
+            ats_control::span!() => {
+                let reg = LocalRegisterCopy::<u16, AtsControl::Register>::new(
+                    op.pop_word(self.registers.ats_control.get()),
+                );
+                self.registers.ats_control.set(reg.writeable_bits());
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+/// The maximum number of virtual functions we enumerate for a single SR-IOV physical function.
+pub const MAX_SRIOV_VFS: usize = 16;
+
+/// The SR-IOV capability of a physical function.
+///
+/// NumVFs, the system page size and the VF BARs are passed through to the device while the VFs are
+/// disabled. Requests to change VF Enable or VF MSE are latched and left for the owner of the VFs to
+/// validate and apply with `set_vf_enable()` and `set_vf_memory_space()`.
+pub(super) struct SrIov {
+    registers: &'static mut SriovRegisters,
+    max_vfs: u16,
+    control_request: Option<LocalRegisterCopy<u16, SriovControl::Register>>,
+}
+
+impl SrIov {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut SriovRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        // Make sure we start from a known state, with no VFs enabled.
+        registers.sriov_control.set(0);
+        registers.num_vfs.set(0);
+        Self {
+            registers,
+            max_vfs: 0,
+            control_request: None,
+        }
+    }
+
+    /// Returns the number of VFs supported by the device.
+    pub(super) fn total_vfs(&self) -> u16 {
+        self.registers.total_vfs.get()
+    }
+
+    /// Returns the number of VFs that may be enabled by a VM.
+    pub(super) fn max_vfs(&self) -> u16 {
+        self.max_vfs
+    }
+
+    /// Limits the number of VFs that may be enabled by a VM to `max_vfs`.
+    pub(super) fn set_max_vfs(&mut self, max_vfs: u16) {
+        self.max_vfs = max_vfs.min(self.total_vfs());
+    }
+
+    /// Returns the number of VFs programmed in the device.
+    pub(super) fn num_vfs(&self) -> u16 {
+        self.registers.num_vfs.get()
+    }
+
+    /// Programs the number of VFs in the device. The first VF offset and VF stride may change as a
+    /// result. Has no effect if the VFs are enabled.
+    pub(super) fn set_num_vfs(&mut self, num_vfs: u16) {
+        if !self.vf_enabled() {
+            self.registers.num_vfs.set(num_vfs);
+        }
+    }
+
+    /// Returns the routing ID offset of the first VF from the physical function.
+    pub(super) fn first_vf_offset(&self) -> u16 {
+        self.registers.first_vf_offset.get()
+    }
+
+    /// Returns the routing ID distance between consecutive VFs.
+    pub(super) fn vf_stride(&self) -> u16 {
+        self.registers.vf_stride.get()
+    }
+
+    /// Returns the device ID reported by the VFs.
+    pub(super) fn vf_device_id(&self) -> u16 {
+        self.registers.vf_device_id.get()
+    }
+
+    /// Returns if the VFs are enabled in the device.
+    pub(super) fn vf_enabled(&self) -> bool {
+        self.registers.sriov_control.is_set(SriovControl::VfEnable)
+    }
+
+    /// Sets VF Enable in the device.
+    pub(super) fn set_vf_enable(&mut self, enable: bool) {
+        self.registers
+            .sriov_control
+            .modify(SriovControl::VfEnable.val(enable as u16));
+    }
+
+    /// Returns if memory space access is enabled for the VFs in the device.
+    pub(super) fn vf_memory_space_enabled(&self) -> bool {
+        self.registers
+            .sriov_control
+            .is_set(SriovControl::VfMemorySpaceEnable)
+    }
+
+    /// Sets VF MSE in the device.
+    pub(super) fn set_vf_memory_space(&mut self, enable: bool) {
+        self.registers
+            .sriov_control
+            .modify(SriovControl::VfMemorySpaceEnable.val(enable as u16));
+    }
+
+    /// Returns the VF BAR registers. Each VF BAR describes the BAR of the first VF, with the BARs of
+    /// subsequent VFs following contiguously.
+    pub(super) fn vf_bars(&self) -> &[ReadWrite<u32, BaseAddress::Register>] {
+        &self.registers.vf_bar
+    }
+
+    /// Returns the VF BAR registers for probing.
+    pub(super) fn vf_bars_mut(&mut self) -> &mut [ReadWrite<u32, BaseAddress::Register>] {
+        &mut self.registers.vf_bar
+    }
+
+    /// Returns the pending VF Enable / VF MSE request from a VM, if any.
+    pub(super) fn take_control_request(
+        &mut self,
+    ) -> Option<LocalRegisterCopy<u16, SriovControl::Register>> {
+        self.control_request.take()
+    }
+}
+
+impl Capability for SrIov {
+    fn length(&self) -> usize {
+        size_of::<SriovRegisters>()
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use sriov_offsets::*;
+        match cap_offset {
+            sriov_control::span!() => {
+                op.push_word(self.registers.sriov_control.readable_bits());
+            }
+            initial_vfs::span!() => {
+                op.push_word(self.registers.initial_vfs.get().min(self.max_vfs));
+            }
+            total_vfs::span!() => {
+                op.push_word(self.max_vfs);
+            }
+            num_vfs::span!() => {
+                op.push_word(self.num_vfs());
+            }
+            func_dep_link::span!() => {
+                op.push_byte(self.registers.func_dep_link.get());
+            }
+            first_vf_offset::span!() => {
+                op.push_word(self.first_vf_offset());
+            }
+            vf_stride::span!() => {
+                op.push_word(self.vf_stride());
+            }
+            vf_device_id::span!() => {
+                op.push_word(self.vf_device_id());
+            }
+            supported_page_sizes::span!() => {
+                op.push_dword(self.registers.supported_page_sizes.get());
+            }
+            system_page_size::span!() => {
+                op.push_dword(self.registers.system_page_size.get());
+            }
+            vf_bar::span!() => {
+                let index = (cap_offset - vf_bar::START_OFFSET) / size_of::<u32>();
+                op.push_dword(self.registers.vf_bar[index].get());
+            }
+            _ => {
+                // VF migration isn't supported, so the capabilities, status and migration state
+                // registers read as zero.
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use sriov_offsets::*;
+        match cap_offset {
+            sriov_control::span!() => {
+                let reg = LocalRegisterCopy::<u16, SriovControl::Register>::new(
+                    op.pop_word(self.registers.sriov_control.get()),
+                );
+                // None of the control bits are passed through directly. Latch the request so that
+                // VF Enable and VF MSE can be validated by the owner of the VFs.
+                self.control_request = Some(reg);
+            }
+            num_vfs::span!() => {
+                let reg = op.pop_word(self.num_vfs());
+                self.set_num_vfs(reg.min(self.max_vfs));
+            }
+            system_page_size::span!() => {
+                let reg = op.pop_dword(self.registers.system_page_size.get());
+                if !self.vf_enabled() {
+                    self.registers.system_page_size.set(reg);
+                }
+            }
+            vf_bar::span!() => {
+                let index = (cap_offset - vf_bar::START_OFFSET) / size_of::<u32>();
+                let reg = op.pop_dword(self.registers.vf_bar[index].get());
+                // Discard VF BAR writes if the VF BARs are enabled.
+                if !self.vf_memory_space_enabled() {
+                    self.registers.vf_bar[index].set(reg);
+                }
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+struct Pri {
+    registers: &'static mut PriRegisters,
+}
+
+impl Pri {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut PriRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        Self { registers }
+    }
+}
+
+impl Capability for Pri {
+    fn length(&self) -> usize {
+        size_of::<PriRegisters>()
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use pri_offsets::*;
+        match cap_offset {
+            pri_control::span!() => {
+                op.push_word(self.registers.pri_control.readable_bits());
+            }
+            pri_status::span!() => {
+                op.push_word(self.registers.pri_status.readable_bits());
+            }
+            request_capacity::span!() => {
+                op.push_dword(self.registers.request_capacity.get());
+            }
+            request_allocation::span!() => {
+                op.push_dword(self.registers.request_allocation.get());
+            }
+            _ => {
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use pri_offsets::*;
+        match cap_offset {
+            pri_control::span!() => {
+                let reg = LocalRegisterCopy::<u16, PriControl::Register>::new(
+                    op.pop_word(self.registers.pri_control.get()),
+                );
+                self.registers.pri_control.set(reg.writeable_bits());
+            }
+            pri_status::span!() => {
+                // Make sure we only write the RW1C bits if the write operation covers that byte.
+                let reg = LocalRegisterCopy::<u16, PriStatus::Register>::new(
+                    op.pop_word(self.registers.pri_status.non_clearable_bits()),
+                );
+                self.registers.pri_status.set(reg.writeable_bits());
+            }
+            request_allocation::span!() => {
+                let val = op.pop_dword(self.registers.request_allocation.get());
+                self.registers.request_allocation.set(val);
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+struct Pasid {
+    registers: &'static mut PasidRegisters,
+}
+
+impl Pasid {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut PasidRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        Self { registers }
+    }
+}
+
+impl Capability for Pasid {
+    fn length(&self) -> usize {
+        size_of::<PasidRegisters>()
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use pasid_offsets::*;
+        match cap_offset {
+            pasid_caps::span!() => {
+                op.push_word(self.registers.pasid_caps.readable_bits());
+            }
+            pasid_control::span!() => {
+                op.push_word(self.registers.pasid_control.readable_bits());
+            }
+            _ => {
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use pasid_offsets::*;
+        match cap_offset {
+            pasid_control::span!() => {
+                let reg = LocalRegisterCopy::<u16, PasidControl::Register>::new(
+                    op.pop_word(self.registers.pasid_control.get()),
+                );
+                self.registers.pasid_control.set(reg.writeable_bits());
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+struct Dvsec {
+    header: &'static mut DvsecRegisters,
+    length: usize,
+}
+
+impl Dvsec {
+    fn new(header: &mut ExtendedCapabilityHeader, offset: usize) -> Result<Self> {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let dvsec_header = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut DvsecRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        let length = dvsec_header.dvsec_header1.read(DvsecHeader1::Length) as usize;
+        // Sanity check the reported length.
+        let min_length = offset_of!(DvsecRegisters, dvsec_header2) + size_of::<u16>();
+        if length < min_length || offset + length > PCIE_CONFIG_SPACE_END + 1 {
+            return Err(Error::InvalidDvsecLength(length));
+        }
+        Ok(Self {
+            header: dvsec_header,
+            length,
+        })
+    }
+}
+
+impl Capability for Dvsec {
+    fn length(&self) -> usize {
+        self.length
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        // Safety: We've verified that the read offset is within the bounds of the capability
+        // structure. Further, byte-sized reads are always valid in PCI configuration space.
+        let reg = unsafe {
+            let ptr = (self.header as *const DvsecRegisters as *const u8).add(cap_offset);
+            core::ptr::read_volatile(ptr)
+        };
+        op.push_byte(reg);
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, _cap_offset: usize) {
+        // Like the vendor capability, the DVSEC structure is opaque so treat it as read-only.
+        op.pop_byte();
+    }
+}
+
+// Represents a single PCI capability.
+struct PciCapability {
+    id: CapabilityId,
+    offset: usize,
+    next: usize,
+    cap_type: CapabilityType,
+}
+
+impl PciCapability {
+    // Creates a new capability of type `id` at `header`, which itself is at `offset` within the
+    // configuration space.
+    fn new(header: &mut CapabilityHeader, id: CapabilityId, offset: usize) -> Result<Self> {
+        let cap_type = match id {
+            CapabilityId::PowerManagement => PowerManagement::new(header).into(),
+            CapabilityId::Msi => Msi::new(header)?.into(),
+            CapabilityId::MsiX => MsiX::new(header).into(),
+            CapabilityId::Vendor => Vendor::new(header)?.into(),
+            CapabilityId::BridgeSubsystem => BridgeSubsystem::new(header).into(),
+            CapabilityId::PciExpress => PciExpress::new(header)?.into(),
+        };
+        Ok(PciCapability {
+            id,
+            offset,
+            next: 0,
+            cap_type,
+        })
+    }
+
+    // Returns the ID of this capability.
+    fn id(&self) -> CapabilityId {
+        self.id
+    }
+
+    // Returns the offset of this capability within the configuration space of this device.
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // Sets the offset of the next capability (from the start of the configuration space) in the
+    // linked list.
+    fn set_next(&mut self, next: usize) {
+        self.next = next;
+    }
+
+    // Returns the length of this capability structure.
+    fn length(&self) -> usize {
+        self.cap_type.length()
+    }
+
+    // Emulates a read from this capability structure.
+    fn emulate_read(&self, op: &mut MmioReadBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use header_offsets::*;
+        match cap_offset {
+            cap_id::span!() => {
+                op.push_byte(self.id as u8);
+            }
+            next_cap::span!() => {
+                op.push_byte(self.next as u8);
+            }
+            _ => {
+                self.cap_type.emulate_read(op, cap_offset);
+            }
+        }
+    }
+
+    // Emulates a write to this capability structure.
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use header_offsets::*;
+        match cap_offset {
+            cap_id::span!() | next_cap::span!() => {
+                op.pop_byte();
+            }
+            _ => {
+                self.cap_type.emulate_write(op, cap_offset);
+            }
+        }
+    }
+}
+
+// Represents a single PCI Express extended capability.
+struct PciExtendedCapability {
+    id: ExtendedCapabilityId,
+    version: u32,
+    offset: usize,
+    next: usize,
+    cap_type: CapabilityType,
+}
+
+impl PciExtendedCapability {
+    // Creates a new extended capability of type `id` at `header`, which itself is at `offset`
+    // within the configuration space.
+    fn new(
+        header: &mut ExtendedCapabilityHeader,
+        id: ExtendedCapabilityId,
+        offset: usize,
+    ) -> Result<Self> {
+        let version = header.header.read(ExtendedCapability::Version);
+        let cap_type = match id {
+            ExtendedCapabilityId::Aer => Aer::new(header).into(),
+            ExtendedCapabilityId::Acs => Acs::new(header).into(),
+            ExtendedCapabilityId::Ats => Ats::new(header).into(),
+            ExtendedCapabilityId::SrIov => SrIov::new(header).into(),
+            ExtendedCapabilityId::Pri => Pri::new(header).into(),
+            ExtendedCapabilityId::Pasid => Pasid::new(header).into(),
+            ExtendedCapabilityId::Dvsec => Dvsec::new(header, offset)?.into(),
+        };
+        Ok(PciExtendedCapability {
+            id,
+            version,
+            offset,
+            next: 0,
+            cap_type,
+        })
+    }
+
+    // Returns the ID of this capability.
+    fn id(&self) -> ExtendedCapabilityId {
+        self.id
+    }
+
+    // Returns the offset of this capability within the configuration space of this device.
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // Sets the offset of the next capability (from the start of the configuration space) in the
+    // linked list.
+    fn set_next(&mut self, next: usize) {
+        self.next = next;
+    }
+
+    // Returns the length of this capability structure.
+    fn length(&self) -> usize {
+        self.cap_type.length()
+    }
+
+    // Emulates a read from this capability structure.
+    fn emulate_read(&self, op: &mut MmioReadBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use ext_header_offsets::*;
+        match cap_offset {
+            header::span!() => {
+                op.push_dword(extended_header(self.id as u32, self.version, self.next));
+            }
+            _ => {
+                self.cap_type.emulate_read(op, cap_offset);
+            }
+        }
+    }
+
+    // Emulates a write to this capability structure.
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use ext_header_offsets::*;
+        match cap_offset {
+            header::span!() => {
+                op.pop_byte();
+            }
+            _ => {
+                self.cap_type.emulate_write(op, cap_offset);
+            }
+        }
+    }
+}
+
+// Returns the raw value of an extended capability header.
+fn extended_header(id: u32, version: u32, next: usize) -> u32 {
+    let mut reg = LocalRegisterCopy::<u32, ExtendedCapability::Register>::new(0);
+    reg.modify(
+        ExtendedCapability::Id.val(id)
+            + ExtendedCapability::Version.val(version)
+            + ExtendedCapability::NextCap.val(next as u32),
+    );
+    reg.get()
+}
+
+// The maximum number of capabilities we support for a single device. Enough for the typical PCI
+// devices virtualized by QEMU.
+const MAX_PCI_CAPS: usize = 8;
+
+// The maximum number of extended capabilities we expose for a single device. Any further
+// capability is hidden.
+const MAX_PCIE_EXT_CAPS: usize = 16;
+
+// The maximum number of extended capability headers that fit in the extended configuration space.
+// Used to bound the walk of a (possibly cyclic) extended capability list.
+const MAX_PCIE_EXT_CAP_HEADERS: usize =
+    (PCIE_CONFIG_SPACE_END + 1 - PCIE_EXT_CAPS_START) / size_of::<ExtendedCapabilityHeader>();
+
+/// Maps the location of PCI capabilities in a device's config space and handles emulation of
+/// reads and writes to these capabilities.
+///
+/// Extended capabilities of PCI Express devices are either emulated, passed through read-only, or
+/// hidden from the virtual extended capability list, depending on the type of the capability.
+pub struct PciCapabilities {
+    caps: ArrayVec<PciCapability, MAX_PCI_CAPS>,
+    ext_caps: ArrayVec<PciExtendedCapability, MAX_PCIE_EXT_CAPS>,
+}
+
+impl PciCapabilities {
+    /// Creates an empty `PciCapabilities` for a function whose configuration space isn't accessible.
+    pub fn empty() -> Self {
+        Self {
+            caps: ArrayVec::new(),
+            ext_caps: ArrayVec::new(),
+        }
+    }
+
+    /// Creates a new `PciCapabilities` by parsing the PCI capability linked-list starting at
+    /// `start_offset` within the standard PCI configuration space pointed to by `config_regs`.
+    pub fn new(config_regs: &mut CommonRegisters, start_offset: usize) -> Result<Self> {
+        let mut caps = ArrayVec::new();
+        let mut current_offset = start_offset;
+        while current_offset > PCI_TYPE_HEADER_END && current_offset < PCI_CONFIG_SPACE_END {
+            let cap_ptr = (config_regs as *mut CommonRegisters as usize + current_offset)
+                as *mut CapabilityHeader;
+            // Safety: `cap_ptr` is within the valid and uniquely-owned PCI configuration space
+            // referred to by `config_regs` and we are trusting that the hardware has initialized the
+            // capability offset registers such that they refer to valid PCI capability headers.
+            let header = unsafe { cap_ptr.as_mut().unwrap() };
+            let offset = current_offset;
+            // Per the spec, the bottom two bits of the next capability pointer should always be
+            // discarded.
+            //
+            // TODO: We should really check that capabilities don't overlap, but this is difficult
+            // since they're dynamically sized and the list can be in any order. So for now we trust
+            // that the hardware provides a valid config space.
+            current_offset = (header.next_cap.get() as usize) & !0x3;
+            if let Some(id) = CapabilityId::from_raw(header.cap_id.get()) {
+                let cap = PciCapability::new(header, id, offset)?;
+                caps.try_push(cap).map_err(|_| Error::TooManyCapabilities)?;
+            };
+        }
+
+        // Now link all the capabilities together to form a linked-list in the virtual config space.
+        for i in 1..caps.len() {
+            let next_ptr = caps[i].offset();
+            caps[i - 1].set_next(next_ptr);
+        }
+
+        // Only PCI Express devices have an extended configuration space.
+        let is_pcie = caps.iter().any(|cap| cap.id() == CapabilityId::PciExpress);
+        let ext_caps = if is_pcie {
+            Self::parse_extended(config_regs)?
+        } else {
+            ArrayVec::new()
+        };
+
+        Ok(Self { caps, ext_caps })
+    }
+
+    // Parses the PCI Express extended capability linked-list in the extended configuration space
+    // following `config_regs`. A list that loops or reads as all-ones (e.g. because the extended
+    // configuration space isn't accessible) can't be trusted, so no extended capability is exposed
+    // for it.
+    fn parse_extended(
+        config_regs: &mut CommonRegisters,
+    ) -> Result<ArrayVec<PciExtendedCapability, MAX_PCIE_EXT_CAPS>> {
+        let mut ext_caps: ArrayVec<PciExtendedCapability, MAX_PCIE_EXT_CAPS> = ArrayVec::new();
+        let mut current_offset = PCIE_EXT_CAPS_START;
+        let mut headers = 0;
+        while current_offset >= PCIE_EXT_CAPS_START && current_offset < PCIE_CONFIG_SPACE_END {
+            headers += 1;
+            if headers > MAX_PCIE_EXT_CAP_HEADERS {
+                println!("PCIe extended capability list loops, hiding extended capabilities");
+                return Ok(ArrayVec::new());
+            }
+            let cap_ptr = (config_regs as *mut CommonRegisters as usize + current_offset)
+                as *mut ExtendedCapabilityHeader;
+            // Safety: `cap_ptr` is within the valid and uniquely-owned PCI Express configuration
+            // space referred to by `config_regs` and we are trusting that the hardware has
+            // initialized the capability offset registers such that they refer to valid PCI
+            // extended capability headers.
+            let header = unsafe { cap_ptr.as_mut().unwrap() };
+            let offset = current_offset;
+            // An all-zeroes header at the start of the extended space means there are no
+            // extended capabilities.
+            if header.header.get() == 0 {
+                break;
+            }
+            if header.header.get() == !0 {
+                println!(
+                    "Invalid PCIe extended capability header at 0x{:x}, hiding extended capabilities",
+                    offset
+                );
+                return Ok(ArrayVec::new());
+            }
+            // As for standard capabilities, the bottom two bits of the next pointer are reserved.
+            current_offset = (header.header.read(ExtendedCapability::NextCap) as usize) & !0x3;
+            let raw_id = header.header.read(ExtendedCapability::Id) as u16;
+            if let Some(id) = ExtendedCapabilityId::from_raw(raw_id) {
+                // The capability registers must fit in the extended configuration space.
+                if offset + id.registers_size() > PCIE_CONFIG_SPACE_END + 1 {
+                    return Err(Error::ExtendedCapabilityOutOfBounds(offset));
+                }
+                // Capabilities beyond what we can track are hidden, like the unknown ones.
+                if !ext_caps.is_full() {
+                    ext_caps.push(PciExtendedCapability::new(header, id, offset)?);
+                }
+            }
+        }
+
+        // Link the exposed capabilities, skipping over the hidden ones.
+        for i in 1..ext_caps.len() {
+            let next_ptr = ext_caps[i].offset();
+            ext_caps[i - 1].set_next(next_ptr);
+        }
+
+        Ok(ext_caps)
+    }
+
+    /// Returns if an MSI capability is present.
+    pub fn has_msi(&self) -> bool {
+        self.capability_by_id(CapabilityId::Msi).is_some()
+    }
+
+    /// Returns if an MSI-X capability is present.
+    pub fn has_msix(&self) -> bool {
+        self.capability_by_id(CapabilityId::MsiX).is_some()
+    }
+
+    /// Returns the location of the MSI-X table and PBA if an MSI-X capability is present.
+    pub(super) fn msix_table_info(&self) -> Option<MsiXTableInfo> {
+        self.caps.iter().find_map(|cap| match cap.cap_type {
+            CapabilityType::MsiX(ref msix) => Some(msix.table_info()),
+            _ => None,
+        })
+    }
+
+    /// Returns if the Function Mask bit is set in the MSI-X capability. Returns false if there's no
+    /// MSI-X capability.
+    pub(super) fn msix_function_masked(&self) -> bool {
+        self.caps.iter().any(|cap| match cap.cap_type {
+            CapabilityType::MsiX(ref msix) => msix.function_masked(),
+            _ => false,
+        })
+    }
+
+    /// Sets or clears the Function Mask bit in the MSI-X capability, if present.
+    pub(super) fn set_msix_function_mask(&mut self, masked: bool) {
+        for cap in self.caps.iter_mut() {
+            if let CapabilityType::MsiX(ref mut msix) = cap.cap_type {
+                msix.set_function_mask(masked);
+            }
+        }
+    }
+
+    /// Returns if a PCI-Express capability is present.
+    pub fn is_pcie(&self) -> bool {
+        self.capability_by_id(CapabilityId::PciExpress).is_some()
+    }
+
+    /// Returns if an Address Translation Services capability is present.
+    pub fn has_ats(&self) -> bool {
+        self.extended_capability_by_id(ExtendedCapabilityId::Ats)
+            .is_some()
+    }
+
+    /// Returns if a Single Root I/O Virtualization capability is present.
+    pub fn has_sriov(&self) -> bool {
+        self.extended_capability_by_id(ExtendedCapabilityId::SrIov)
+            .is_some()
+    }
+
+    /// Returns if a Page Request Interface capability is present.
+    pub fn has_pri(&self) -> bool {
+        self.extended_capability_by_id(ExtendedCapabilityId::Pri)
+            .is_some()
+    }
+
+    /// Returns if a Process Address Space ID capability is present.
+    pub fn has_pasid(&self) -> bool {
+        self.extended_capability_by_id(ExtendedCapabilityId::Pasid)
+            .is_some()
+    }
+
+    /// Returns the power management capability of this device, if present.
+    pub(super) fn power_management(&self) -> Option<&PowerManagement> {
+        self.caps.iter().find_map(|cap| match cap.cap_type {
+            CapabilityType::PowerManagement(ref pm) => Some(pm),
+            _ => None,
+        })
+    }
+
+    /// Returns a mutable reference to the power management capability of this device, if present.
+    pub(super) fn power_management_mut(&mut self) -> Option<&mut PowerManagement> {
+        self.caps.iter_mut().find_map(|cap| match cap.cap_type {
+            CapabilityType::PowerManagement(ref mut pm) => Some(pm),
+            _ => None,
+        })
+    }
+
+    /// Returns the PCI Express capability of this device, if present.
+    pub(super) fn pcie(&self) -> Option<&PciExpress> {
+        self.caps.iter().find_map(|cap| match cap.cap_type {
+            CapabilityType::PciExpress(ref pcie) => Some(pcie),
+            _ => None,
+        })
+    }
+
+    /// Returns a mutable reference to the PCI Express capability of this device, if present.
+    pub(super) fn pcie_mut(&mut self) -> Option<&mut PciExpress> {
+        self.caps.iter_mut().find_map(|cap| match cap.cap_type {
+            CapabilityType::PciExpress(ref mut pcie) => Some(pcie),
+            _ => None,
+        })
+    }
+
+    /// Returns the SR-IOV capability of this device, if present.
+    pub(super) fn sriov(&self) -> Option<&SrIov> {
+        self.ext_caps.iter().find_map(|cap| match cap.cap_type {
+            CapabilityType::SrIov(ref sriov) => Some(sriov),
+            _ => None,
+        })
+    }
+
+    /// Returns a mutable reference to the SR-IOV capability of this device, if present.
+    pub(super) fn sriov_mut(&mut self) -> Option<&mut SrIov> {
+        self.ext_caps.iter_mut().find_map(|cap| match cap.cap_type {
+            CapabilityType::SrIov(ref mut sriov) => Some(sriov),
+            _ => None,
+        })
+    }
+
+    /// Emulates a read from this device's capabilities structures.
+    pub fn emulate_read(&self, op: &mut MmioReadBuilder) {
+        if let Some(cap) = self.capability_by_offset(op.offset()) {
+            cap.emulate_read(op);
+        } else {
             op.push_byte(0);
         }
     }
@@ -741,6 +1903,29 @@ impl PciCapabilities {
         }
     }
 
+    /// Emulates a read from this device's extended capabilities structures.
+    pub fn emulate_extended_read(&self, op: &mut MmioReadBuilder) {
+        if let Some(cap) = self.extended_capability_by_offset(op.offset()) {
+            cap.emulate_read(op);
+        } else if op.offset() < PCIE_EXT_CAPS_START + size_of::<ExtendedCapabilityHeader>() {
+            // The extended capability list must start at the beginning of the extended space, so
+            // use a null capability to point to the first exposed capability if it's elsewhere.
+            let first = self.ext_caps.first().map(|cap| cap.offset()).unwrap_or(0);
+            op.push_dword(extended_header(0, 0, first));
+        } else {
+            op.push_byte(0);
+        }
+    }
+
+    /// Emulates a write to this device's extended capabilities structures.
+    pub fn emulate_extended_write(&mut self, op: &mut MmioWriteBuilder) {
+        if let Some(cap) = self.extended_capability_by_offset_mut(op.offset()) {
+            cap.emulate_write(op);
+        } else {
+            op.pop_byte();
+        }
+    }
+
     /// Returns the offset (in the PCI configuration space) of the first emulated capability.
     pub fn start_offset(&self) -> usize {
         self.caps.get(0).map(|cap| cap.offset()).unwrap_or(0)
@@ -764,6 +1949,31 @@ impl PciCapabilities {
     fn capability_by_id(&self, id: CapabilityId) -> Option<&PciCapability> {
         self.caps.iter().find(|cap| cap.id() == id)
     }
+
+    // Returns a reference to the extended capability at `offset`.
+    fn extended_capability_by_offset(&self, offset: usize) -> Option<&PciExtendedCapability> {
+        self.ext_caps
+            .iter()
+            .find(|cap| cap.offset() <= offset && offset < (cap.offset() + cap.length()))
+    }
+
+    // Returns a mutable reference to the extended capability at `offset`.
+    fn extended_capability_by_offset_mut(
+        &mut self,
+        offset: usize,
+    ) -> Option<&mut PciExtendedCapability> {
+        self.ext_caps
+            .iter_mut()
+            .find(|cap| cap.offset() <= offset && offset < (cap.offset() + cap.length()))
+    }
+
+    // Gets the extended capability with the given ID.
+    fn extended_capability_by_id(
+        &self,
+        id: ExtendedCapabilityId,
+    ) -> Option<&PciExtendedCapability> {
+        self.ext_caps.iter().find(|cap| cap.id() == id)
+    }
 }
 
 #[cfg(test)]
@@ -807,4 +2017,297 @@ mod tests {
             0x5c
         );
     }
+
+    // Returns a PCI Express endpoint config space with an empty extended capability list.
+    fn pcie_config() -> Vec<u32> {
+        let mut test_config = vec![0; 1024];
+        test_config[13] = 0x40; // Start of the capability list.
+        test_config[16] = 0x0002_0010; // PCI Express, version 2 endpoint.
+        test_config
+    }
+
+    // Returns the byte representation of `config`.
+    fn config_bytes(config: &[u32]) -> Vec<u8> {
+        config.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn parse_ext_caps() {
+        let mut test_config = pcie_config();
+        test_config[0x100 / 4] = 0x1401_000b; // Vendor-specific (hidden)
+        test_config[0x140 / 4] = 0x1601_000f; // ATS
+        test_config[0x144 / 4] = 0x8000_0020;
+        test_config[0x160 / 4] = 0x2001_0023; // DVSEC
+        test_config[0x164 / 4] = 0x0100_1234;
+        test_config[0x200 / 4] = 0x0001_0001; // AER
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.is_pcie());
+        assert!(caps.has_ats());
+        assert!(!caps.has_sriov());
+        assert!(!caps.has_pasid());
+
+        let read = |caps: &PciCapabilities, offset, len| {
+            let mut op = MmioReadBuilder::new(offset, len);
+            while !op.done() {
+                caps.emulate_extended_read(&mut op);
+            }
+            op.result()
+        };
+        // The hidden capability is replaced by a null capability pointing to the ATS capability.
+        assert_eq!(read(&caps, 0x100, 4), 0x1400_0000);
+        assert_eq!(read(&caps, 0x140, 4), 0x1601_000f);
+        assert_eq!(read(&caps, 0x144, 2), 0x0020);
+        assert_eq!(read(&caps, 0x160, 4), 0x2001_0023);
+        assert_eq!(read(&caps, 0x164, 4), 0x0100_1234);
+        assert_eq!(read(&caps, 0x200, 4), 0x0001_0001);
+        assert_eq!(read(&caps, 0x146, 2), 0x8000);
+
+        // VMs may enable ATS; the IOMMU decides whether to accept translation requests.
+        let mut op = MmioWriteBuilder::new(0x146, 0x0001, 2);
+        while !op.done() {
+            caps.emulate_extended_write(&mut op);
+        }
+        assert_eq!(header_mem[0x146], 0x01);
+        assert_eq!(header_mem[0x147], 0x00);
+        let mut op = MmioWriteBuilder::new(0x146, 0x8001, 2);
+        while !op.done() {
+            caps.emulate_extended_write(&mut op);
+        }
+        assert_eq!(header_mem[0x147], 0x80);
+    }
+
+    #[test]
+    fn pri_emulation() {
+        let mut test_config = pcie_config();
+        test_config[0x100 / 4] = 0x0001_0013; // PRI
+        test_config[0x104 / 4] = 0x8003_0000; // Response Failure, UPRGI, PASID required.
+        test_config[0x108 / 4] = 0x0000_0020; // 32 outstanding requests.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.has_pri());
+
+        let read = |caps: &PciCapabilities, offset, len| {
+            let mut op = MmioReadBuilder::new(offset, len);
+            while !op.done() {
+                caps.emulate_extended_read(&mut op);
+            }
+            op.result()
+        };
+        let write = |caps: &mut PciCapabilities, offset, value, len| {
+            let mut op = MmioWriteBuilder::new(offset, value, len);
+            while !op.done() {
+                caps.emulate_extended_write(&mut op);
+            }
+        };
+        assert_eq!(read(&caps, 0x104, 4), 0x8003_0000);
+        assert_eq!(read(&caps, 0x108, 4), 0x20);
+
+        // Enable PRI with 16 outstanding requests, resetting the PRI state. Reset is write-only.
+        write(&mut caps, 0x10c, 0x10, 4);
+        write(&mut caps, 0x104, 0x3, 2);
+        assert_eq!(header_mem[0x104], 0x03);
+        assert_eq!(read(&caps, 0x104, 4), 0x8003_0001);
+        assert_eq!(read(&caps, 0x10c, 4), 0x10);
+    }
+
+    #[test]
+    fn sriov_emulation() {
+        let mut test_config = pcie_config();
+        test_config[0x100 / 4] = 0x0001_0010; // SR-IOV
+        test_config[0x108 / 4] = 0x0000_0009; // VF Enable and VF MSE set by firmware.
+        test_config[0x10c / 4] = 0x0040_0040; // 64 initial and total VFs.
+        test_config[0x110 / 4] = 0x0000_0004;
+        test_config[0x114 / 4] = 0x0001_0080; // First VF offset 0x80, stride 1.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.has_sriov());
+        let sriov = caps.sriov_mut().unwrap();
+        // VFs start out disabled.
+        assert!(!sriov.vf_enabled());
+        assert_eq!(sriov.num_vfs(), 0);
+        assert_eq!(sriov.total_vfs(), 64);
+        sriov.set_max_vfs(8);
+
+        let read = |caps: &PciCapabilities, offset, len| {
+            let mut op = MmioReadBuilder::new(offset, len);
+            while !op.done() {
+                caps.emulate_extended_read(&mut op);
+            }
+            op.result()
+        };
+        let write = |caps: &mut PciCapabilities, offset, value, len| {
+            let mut op = MmioWriteBuilder::new(offset, value, len);
+            while !op.done() {
+                caps.emulate_extended_write(&mut op);
+            }
+        };
+        // InitialVFs and TotalVFs are capped to the number of VFs a VM may enable.
+        assert_eq!(read(&caps, 0x10c, 4), 0x0008_0008);
+        assert_eq!(read(&caps, 0x114, 4), 0x0001_0080);
+
+        // NumVFs is clamped.
+        write(&mut caps, 0x110, 0x20, 2);
+        assert_eq!(read(&caps, 0x110, 2), 8);
+
+        // VF Enable and VF MSE are latched rather than written to the device.
+        write(&mut caps, 0x108, 0x9, 2);
+        assert_eq!(header_mem[0x108], 0);
+        let sriov = caps.sriov_mut().unwrap();
+        let request = sriov.take_control_request().unwrap();
+        assert!(request.is_set(SriovControl::VfEnable));
+        assert!(request.is_set(SriovControl::VfMemorySpaceEnable));
+        assert!(sriov.take_control_request().is_none());
+
+        // VF BARs may only be written while VF MSE is clear.
+        write(&mut caps, 0x124, 0x8000_0000, 4);
+        assert_eq!(read(&caps, 0x124, 4), 0x8000_0000);
+        caps.sriov_mut().unwrap().set_vf_memory_space(true);
+        assert_eq!(read(&caps, 0x108, 2), 0x8);
+        write(&mut caps, 0x124, 0x9000_0000, 4);
+        assert_eq!(read(&caps, 0x124, 4), 0x8000_0000);
+    }
+
+    #[test]
+    fn reset_and_power_state() {
+        let mut test_config = pcie_config();
+        test_config[16] = 0x0002_8010; // PCI Express, followed by power management.
+        test_config[17] = 0x1000_0000; // FLR capable.
+        test_config[18] = 0x0000_2020; // MPS of 256 bytes, MRRS of 512 bytes.
+        test_config[32] = 0x0003_0001; // Power management.
+        test_config[33] = 0x0000_0008; // D0, No_Soft_Reset.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.pcie().unwrap().has_flr());
+
+        let write = |caps: &mut PciCapabilities, offset, value, len| {
+            let mut op = MmioWriteBuilder::new(offset, value, len);
+            while !op.done() {
+                caps.emulate_write(&mut op);
+            }
+        };
+        // FLR is latched rather than written to the device, and MPS and MRRS are preserved.
+        write(&mut caps, 0x48, 0x8010, 2);
+        assert!(caps.pcie_mut().unwrap().take_reset_request());
+        assert!(!caps.pcie_mut().unwrap().take_reset_request());
+        assert_eq!(header_mem[0x48], 0x30);
+        assert_eq!(header_mem[0x49], 0x20);
+
+        // Moves to D3hot are latched. D1, D2 and no-op transitions are ignored.
+        write(&mut caps, 0x84, 0x3, 2);
+        let pm = caps.power_management_mut().unwrap();
+        assert_eq!(pm.take_state_request(), Some(PowerState::D3Hot));
+        assert_eq!(pm.power_state(), Some(PowerState::D0));
+        write(&mut caps, 0x84, 0x1, 2);
+        write(&mut caps, 0x84, 0x0, 2);
+        let pm = caps.power_management_mut().unwrap();
+        assert!(pm.take_state_request().is_none());
+        assert!(pm.no_soft_reset());
+    }
+
+    #[test]
+    fn hotplug_slot() {
+        let mut test_config = pcie_config();
+        test_config[16] = 0x0162_0010; // PCI Express downstream port with a slot.
+        test_config[19] = 0x0010_0000; // Link active reporting capable.
+        test_config[20] = 0x2000_0000; // Link active.
+        test_config[21] = 0x0028_0042; // Hotplug capable with power controller, slot 5.
+        test_config[22] = 0x0048_0008; // Presence detect state and changed, power on.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.pcie().unwrap().has_hotplug_slot());
+        assert!(caps.pcie().unwrap().slot_occupied());
+
+        let read = |caps: &PciCapabilities, offset, len| {
+            let mut op = MmioReadBuilder::new(offset, len);
+            while !op.done() {
+                caps.emulate_read(&mut op);
+            }
+            op.result()
+        };
+        let write = |caps: &mut PciCapabilities, offset, value, len| {
+            let mut op = MmioWriteBuilder::new(offset, value, len);
+            while !op.done() {
+                caps.emulate_write(&mut op);
+            }
+        };
+        // The slot registers are visible to the owner of the port.
+        assert_eq!(read(&caps, 0x42, 2), 0x0162);
+        assert_eq!(read(&caps, 0x52, 2), 0x2000);
+        assert_eq!(read(&caps, 0x54, 4), 0x0028_0042);
+        assert_eq!(read(&caps, 0x58, 4), 0x0048_0008);
+
+        // Powering off the slot leaves it unoccupied.
+        write(&mut caps, 0x58, 0x0408, 2);
+        assert_eq!(header_mem[0x59], 0x04);
+        assert!(!caps.pcie().unwrap().slot_occupied());
+    }
+
+    #[test]
+    fn cyclic_ext_caps() {
+        let mut test_config = pcie_config();
+        test_config[0x100 / 4] = 0x1001_000b; // Vendor-specific, pointing to itself.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.is_pcie());
+        assert!(caps.ext_caps.is_empty());
+    }
+
+    #[test]
+    fn all_ones_ext_caps() {
+        let mut test_config = pcie_config();
+        test_config[0x100 / 4] = 0x2001_000f; // ATS
+        test_config[0x200 / 4] = !0; // Unreadable header.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.is_pcie());
+        assert!(!caps.has_ats());
+        assert!(caps.ext_caps.is_empty());
+    }
+
+    #[test]
+    fn ext_cap_out_of_bounds() {
+        let mut test_config = pcie_config();
+        test_config[0x100 / 4] = 0xff81_000b; // Vendor-specific (hidden), next at 0xff8.
+        test_config[0xff8 / 4] = 0x0001_0010; // SR-IOV, overflowing the config space.
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        assert!(matches!(
+            PciCapabilities::new(regs, 0x40),
+            Err(Error::ExtendedCapabilityOutOfBounds(0xff8))
+        ));
+    }
+
+    #[test]
+    fn too_many_ext_caps() {
+        let mut test_config = pcie_config();
+        // A chain of ATS capabilities, one every 8 bytes.
+        let num_caps = MAX_PCIE_EXT_CAPS + 4;
+        for i in 0..num_caps {
+            let offset = 0x100 + i * 8;
+            let next = if i + 1 < num_caps { offset + 8 } else { 0 };
+            test_config[offset / 4] = ((next as u32) << 20) | 0x0001_000f;
+        }
+        let mut header_mem = config_bytes(&test_config);
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert_eq!(caps.ext_caps.len(), MAX_PCIE_EXT_CAPS);
+        assert!(caps.has_ats());
+    }
 }
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use alloc::vec::Vec;
 use arrayvec::ArrayVec;
 use core::fmt;
 use core::mem::size_of;
@@ -17,8 +18,18 @@ use super::bus::PciBus;
 use super::capabilities::*;
 use super::error::*;
 use super::mmio_builder::*;
+use super::msix::*;
 use super::registers::*;
 use super::resource::*;
+use super::root::PciArenaId;
+use crate::cpu::time_ms;
+
+// Time for a function-level reset to complete.
+const FLR_COMPLETION_MS: u64 = 100;
+// Time for a transition to or from D3hot to complete.
+const D3HOT_TRANSITION_MS: u64 = 10;
+// Maximum time a reset device may take before it responds to configuration requests.
+const RESET_READY_TIMEOUT_MS: u64 = 1000;
 
 /// The Vendor Id from the PCI header.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
@@ -86,7 +97,7 @@ impl fmt::Display for HeaderType {
 }
 
 /// Attributes of a PCI function read from the common PCI configuration space header.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PciDeviceInfo {
     address: Address,
     vendor_id: VendorId,
@@ -127,6 +138,39 @@ impl PciDeviceInfo {
         Some(info)
     }
 
+    // Creates a placeholder `PciDeviceInfo` for an empty hotplug slot function at `address`.
+    fn for_empty_function(address: Address) -> Self {
+        Self {
+            address,
+            vendor_id: VendorId::invalid(),
+            device_id: DeviceId(0xffff),
+            class: Class::default(),
+            subclass: SubClass::default(),
+            multi_function: false,
+            header_type: HeaderType::Endpoint,
+        }
+    }
+
+    // Creates the `PciDeviceInfo` for an SR-IOV virtual function at `address`. VFs don't implement
+    // the vendor and device ID registers, so these are taken from the PF and its SR-IOV capability.
+    fn for_virtual_function(
+        address: Address,
+        vendor_id: VendorId,
+        device_id: DeviceId,
+        class: Class,
+        subclass: SubClass,
+    ) -> Self {
+        Self {
+            address,
+            vendor_id,
+            device_id,
+            class,
+            subclass,
+            multi_function: false,
+            header_type: HeaderType::Endpoint,
+        }
+    }
+
     /// Returns the PCI Adress of this PCI header.
     pub fn address(&self) -> Address {
         self.address
@@ -257,18 +301,27 @@ pub struct PciDeviceBarInfo {
 }
 
 impl PciDeviceBarInfo {
-    // Probes the size and type of each BAR from `registers`.
+    // Creates a `PciDeviceBarInfo` for a device without BARs.
+    fn empty() -> Self {
+        Self {
+            bars: ArrayVec::new(),
+        }
+    }
+
+    // Probes the size and type of each BAR from `registers`. The addresses programmed in the BARs
+    // are preserved.
     fn new(registers: &mut [ReadWrite<u32, BaseAddress::Register>]) -> Result<Self> {
         let mut bars = ArrayVec::new();
         let mut index = 0;
         while index < registers.len() {
             let bar_index = index;
+            let orig = registers[index].get();
             let bar_type = PciResourceType::from_bar_register(registers[index].extract());
 
             // Write all 1s to detect the number of bits that are implemented.
             registers[index].set(!0);
             let val = registers[index].get();
-            registers[index].set(0);
+            registers[index].set(orig);
             index += 1;
             if val == 0 {
                 // If we read back all 0s, the BAR is unimplemented.
@@ -280,9 +333,10 @@ impl PciDeviceBarInfo {
                 if bar_index % 2 != 0 {
                     return Err(Error::Invalid64BitBarIndex);
                 }
+                let orig = registers[index].get();
                 registers[index].set(!0);
                 let val = registers[index].get();
-                registers[index].set(0);
+                registers[index].set(orig);
                 index += 1;
                 val
             } else {
@@ -331,28 +385,57 @@ struct PciDeviceCommon {
     bar_info: PciDeviceBarInfo,
     owner: Option<PageOwnerId>,
     iommu_attached: bool,
+    // The virtualized MSI-X table, if the table is virtualized for the device's owner.
+    msix_table: Option<VirtualMsiXTable>,
+    // The state of the last reset or power state transition, if the device isn't ready for use.
+    reset_state: Option<ResetState>,
+}
+
+// The state of a device that has been reset or moved to another power state.
+#[derive(Clone, Copy, Debug)]
+enum ResetState {
+    // The device must not be accessed until `ready_at`, and must respond to configuration requests
+    // by `timeout_at`. Both are in milliseconds, as returned by `time_ms()`.
+    Pending { ready_at: u64, timeout_at: u64 },
+    // The device didn't come back from the reset as the same device. It stays inaccessible.
+    Failed(Error),
 }
 
 /// Represents a PCI endpoint.
 pub struct PciEndpoint {
     registers: &'static mut EndpointRegisters,
     common: PciDeviceCommon,
+    // The BARs of each VF if this is an SR-IOV physical function.
+    vf_bar_info: PciDeviceBarInfo,
+    virtual_functions: Vec<PciArenaId>,
 }
 
 impl PciEndpoint {
     /// Creates a new `PciEndpoint` using the config space at `registers`.
     fn new(registers: &'static mut EndpointRegisters, info: PciDeviceInfo) -> Result<Self> {
-        let capabilities =
+        let mut capabilities =
             PciCapabilities::new(&mut registers.common, registers.cap_ptr.get() as usize)?;
         let bar_info = PciDeviceBarInfo::new(&mut registers.bar)?;
+        // Sizing a VF BAR yields the size of the BAR in a single VF.
+        let vf_bar_info = match capabilities.sriov_mut() {
+            Some(sriov) => PciDeviceBarInfo::new(sriov.vf_bars_mut())?,
+            None => PciDeviceBarInfo::empty(),
+        };
         let common = PciDeviceCommon {
             info,
             capabilities,
             bar_info,
             owner: None,
             iommu_attached: false,
+            msix_table: None,
+            reset_state: None,
         };
-        Ok(Self { registers, common })
+        Ok(Self {
+            registers,
+            common,
+            vf_bar_info,
+            virtual_functions: Vec::new(),
+        })
     }
 
     // Emulate a read from the endpoint-specific registers of this device's config space.
@@ -404,6 +487,129 @@ impl PciEndpoint {
     }
 }
 
+/// Represents a function of a hotplug slot that isn't occupied by a device. Empty functions are
+/// reserved in the device arena when the slot's port is enumerated, and are replaced with the
+/// device that appears at their address when a device is added to the slot.
+pub struct PciEmptyFunction {
+    common: PciDeviceCommon,
+}
+
+impl PciEmptyFunction {
+    /// Creates a new `PciEmptyFunction` at `address`.
+    fn new(address: Address) -> Self {
+        let common = PciDeviceCommon {
+            info: PciDeviceInfo::for_empty_function(address),
+            capabilities: PciCapabilities::empty(),
+            bar_info: PciDeviceBarInfo::empty(),
+            owner: None,
+            iommu_attached: false,
+            msix_table: None,
+            reset_state: None,
+        };
+        Self { common }
+    }
+}
+
+/// Represents an SR-IOV virtual function. VFs are reserved in the device arena when their physical
+/// function is enumerated, but only have a configuration space while VFs are enabled in the PF.
+pub struct PciVirtualFunction {
+    registers: Option<&'static mut EndpointRegisters>,
+    common: PciDeviceCommon,
+    physfn: PciArenaId,
+    bar_addrs: [u64; PCI_ENDPOINT_BARS],
+}
+
+impl PciVirtualFunction {
+    /// Creates a new, disabled `PciVirtualFunction` of the PF at `physfn`, which will have `info`
+    /// and the BARs described by `bar_info` once enabled.
+    fn new(physfn: PciArenaId, info: PciDeviceInfo, bar_info: PciDeviceBarInfo) -> Self {
+        let common = PciDeviceCommon {
+            info,
+            capabilities: PciCapabilities::empty(),
+            bar_info,
+            owner: None,
+            iommu_attached: false,
+            msix_table: None,
+            reset_state: None,
+        };
+        Self {
+            registers: None,
+            common,
+            physfn,
+            bar_addrs: [0; PCI_ENDPOINT_BARS],
+        }
+    }
+
+    /// Attaches the VF to its config space at `registers_ptr` after VFs have been enabled in the PF.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `registers_ptr` points to the valid and uniquely-owned
+    /// configuration space of this VF.
+    pub(super) unsafe fn enable(&mut self, registers_ptr: NonNull<CommonRegisters>) -> Result<()> {
+        let registers: &'static mut EndpointRegisters = registers_ptr.cast().as_mut();
+        let capabilities =
+            PciCapabilities::new(&mut registers.common, registers.cap_ptr.get() as usize)?;
+        let info = &self.common.info;
+        self.common.info = PciDeviceInfo::for_virtual_function(
+            info.address(),
+            info.vendor_id(),
+            info.device_id(),
+            Class(registers.common.class.get()),
+            SubClass(registers.common.subclass.get()),
+        );
+        self.common.capabilities = capabilities;
+        self.registers = Some(registers);
+        Ok(())
+    }
+
+    /// Detaches the VF from its config space after VFs have been disabled in the PF.
+    pub(super) fn disable(&mut self) {
+        self.registers = None;
+        self.common.capabilities = PciCapabilities::empty();
+    }
+
+    /// Sets the PCI bus addresses of this VF's BARs, as programmed in the PF.
+    pub(super) fn set_bar_addrs(&mut self, bar_addrs: [u64; PCI_ENDPOINT_BARS]) {
+        self.bar_addrs = bar_addrs;
+    }
+
+    // Returns the config space of this VF. Panics if the VF is not enabled.
+    fn registers(&self) -> &EndpointRegisters {
+        self.registers.as_deref().unwrap()
+    }
+
+    // Emulate a read from the endpoint-specific registers of this VF's config space.
+    fn emulate_config_read(&self, op: &mut MmioReadBuilder) {
+        use endpoint_offsets::*;
+        match op.offset() {
+            bar::span!() => {
+                // VF BARs are read-only zero; they're programmed in the SR-IOV capability of the
+                // PF.
+                op.push_dword(0);
+            }
+            subsys_vendor_id::span!() => {
+                op.push_word(self.registers().subsys_vendor_id.get());
+            }
+            subsys_id::span!() => {
+                op.push_word(self.registers().subsys_id.get());
+            }
+            cap_ptr::span!() => {
+                op.push_byte(self.common.capabilities.start_offset() as u8);
+            }
+            _ => {
+                op.push_byte(0);
+            }
+        }
+    }
+
+    // Emulate a write to the endpoint-specific registers of this VF's config space.
+    fn emulate_config_write(&mut self, op: &mut MmioWriteBuilder) {
+        // Nothing is writeable in the header of a VF.
+        op.pop_byte();
+    }
+}
+
 /// Represents a PCI bridge.
 pub struct PciBridge {
     registers: &'static mut BridgeRegisters,
@@ -414,6 +620,7 @@ pub struct PciBridge {
     virtual_bus_reset: u16,
     has_io_window: bool,
     has_pref_window: bool,
+    slot_populated: bool,
 }
 
 impl PciBridge {
@@ -425,17 +632,20 @@ impl PciBridge {
         registers.sec_bus.set(0);
         registers.pri_bus.set(0);
         // Check if the IO and prefetchable memory windows are implemented. We need to do this by
-        // checking if the registers are writeable since 0 is a valid base and limit.
+        // checking if the registers are writeable since 0 is a valid base and limit. The windows
+        // programmed by firmware are left as they were.
         let has_io_window = {
+            let orig = registers.io_limit.get();
             registers.io_limit.set(!0);
             let val = registers.io_limit.get();
-            registers.io_limit.set(0);
+            registers.io_limit.set(orig);
             val != 0
         };
         let has_pref_window = {
+            let orig = registers.pref_limit.get();
             registers.pref_limit.set(!0);
             let val = registers.pref_limit.get();
-            registers.pref_limit.set(0);
+            registers.pref_limit.set(orig);
             val != 0
         };
         let capabilities =
@@ -447,6 +657,8 @@ impl PciBridge {
             bar_info,
             owner: None,
             iommu_attached: false,
+            msix_table: None,
+            reset_state: None,
         };
         Ok(Self {
             registers,
@@ -457,6 +669,7 @@ impl PciBridge {
             virtual_bus_reset: 0,
             has_io_window,
             has_pref_window,
+            slot_populated: false,
         })
     }
 
@@ -480,6 +693,89 @@ impl PciBridge {
         self.child_bus.as_ref()
     }
 
+    /// Returns if the devices in the hotplug slot below this bridge have been added to the
+    /// hierarchy.
+    pub(super) fn slot_populated(&self) -> bool {
+        self.slot_populated
+    }
+
+    /// Sets whether the devices in the hotplug slot below this bridge have been added to the
+    /// hierarchy.
+    pub(super) fn set_slot_populated(&mut self, populated: bool) {
+        self.slot_populated = populated;
+    }
+
+    /// Returns if the bridge implements an IO window.
+    pub(super) fn has_io_window(&self) -> bool {
+        self.has_io_window
+    }
+
+    /// Returns if the bridge implements a prefetchable memory window.
+    pub(super) fn has_pref_window(&self) -> bool {
+        self.has_pref_window
+    }
+
+    /// Returns if the bridge implements a prefetchable memory window that may be placed above 4GB.
+    pub(super) fn has_64bit_pref_window(&self) -> bool {
+        self.has_pref_window
+            && self
+                .registers
+                .pref_base
+                .matches_all(MemWindow::AddressWidth::Bits64)
+    }
+
+    /// Programs the IO window of the bridge to forward the inclusive range of IO port addresses
+    /// `window`, or closes the window if `window` is `None`.
+    pub(super) fn set_io_window(&mut self, window: Option<(u64, u64)>) {
+        const SHIFT: usize = 12;
+        if !self.has_io_window {
+            return;
+        }
+        // A window is closed by programming a base above its limit.
+        let (base, limit) = window.unwrap_or((!0, 0));
+        self.registers
+            .io_base
+            .write(IoWindow::Address.val((base >> SHIFT) as u8));
+        self.registers.io_base_upper.set((base >> 16) as u16);
+        self.registers
+            .io_limit
+            .write(IoWindow::Address.val((limit >> SHIFT) as u8));
+        self.registers.io_limit_upper.set((limit >> 16) as u16);
+    }
+
+    /// Programs the 32-bit memory window of the bridge to forward the inclusive range of PCI bus
+    /// addresses `window`, or closes the window if `window` is `None`.
+    pub(super) fn set_mem_window(&mut self, window: Option<(u64, u64)>) {
+        const SHIFT: usize = 20;
+        let (base, limit) = window.unwrap_or((!0, 0));
+        self.registers
+            .mem_base
+            .write(MemWindow::Address.val((base >> SHIFT) as u16));
+        self.registers
+            .mem_limit
+            .write(MemWindow::Address.val((limit >> SHIFT) as u16));
+    }
+
+    /// Programs the prefetchable memory window of the bridge to forward the inclusive range of PCI
+    /// bus addresses `window`, or closes the window if `window` is `None`.
+    pub(super) fn set_pref_window(&mut self, window: Option<(u64, u64)>) {
+        const SHIFT: usize = 20;
+        if !self.has_pref_window {
+            return;
+        }
+        let (base, limit) = window.unwrap_or((!0, 0));
+        self.registers
+            .pref_base
+            .write(MemWindow::Address.val((base >> SHIFT) as u16));
+        self.registers
+            .pref_limit
+            .write(MemWindow::Address.val((limit >> SHIFT) as u16));
+        if self.has_64bit_pref_window() {
+            self.registers.pref_base_upper.set((base >> 32) as u32);
+            self.registers.pref_limit_upper.set((limit >> 32) as u32);
+        }
+    }
+
     // Emulate a read from the bridge-specific registers of this device's config space.
     fn emulate_config_read(&self, op: &mut MmioReadBuilder) {
         use bridge_offsets::*;
@@ -671,8 +967,8 @@ impl PciBridge {
         }
     }
 
-    // Returns the base and limit of the bridge's IO window if it's implemented and enabled.
-    fn get_io_window(&self) -> Option<(u64, u64)> {
+    /// Returns the base and limit of the bridge's IO window if it's implemented and enabled.
+    pub(super) fn get_io_window(&self) -> Option<(u64, u64)> {
         const SHIFT: usize = 12;
         if self.has_io_window {
             let base = {
@@ -695,8 +991,8 @@ impl PciBridge {
         }
     }
 
-    // Returns the base and limit of the bridge's 32-bit memory window if it's enabled.
-    fn get_mem_window(&self) -> Option<(u64, u64)> {
+    /// Returns the base and limit of the bridge's 32-bit memory window if it's enabled.
+    pub(super) fn get_mem_window(&self) -> Option<(u64, u64)> {
         const SHIFT: usize = 20;
         let base = (self.registers.mem_base.read(MemWindow::Address) as u64) << SHIFT;
         let limit = ((self.registers.mem_limit.read(MemWindow::Address) as u64) << SHIFT)
@@ -708,9 +1004,9 @@ impl PciBridge {
         }
     }
 
-    // Returns the base and limit of the bridge's prefetchable memory window if it's implemented and
-    // enabled.
-    fn get_pref_window(&self) -> Option<(u64, u64)> {
+    /// Returns the base and limit of the bridge's prefetchable memory window if it's implemented
+    /// and enabled.
+    pub(super) fn get_pref_window(&self) -> Option<(u64, u64)> {
         const SHIFT: usize = 20;
         if self.has_pref_window {
             let base = {
@@ -740,6 +1036,10 @@ pub enum PciDevice {
     Endpoint(PciEndpoint),
     /// A bridge (type 1) device.
     Bridge(PciBridge),
+    /// An SR-IOV virtual function.
+    VirtualFunction(PciVirtualFunction),
+    /// A function of an empty hotplug slot.
+    Empty(PciEmptyFunction),
 }
 
 // Returns `Ok` if `range` is PCI BAR memory owned by `guest_id`.
@@ -761,6 +1061,37 @@ fn bar_range_is_owned(
     Ok(())
 }
 
+// Returns the PCI bus address programmed in `bar` in the set of BAR registers `regs`.
+fn bar_addr(regs: &[ReadWrite<u32, BaseAddress::Register>], bar: &PciBarInfo) -> u64 {
+    let index = bar.index();
+    let addr_lo = regs[index].get() & !((1u32 << BaseAddress::Address.shift) - 1);
+    let addr_hi = if bar.bar_type().is_64bit() {
+        regs[index + 1].get()
+    } else {
+        0
+    };
+    (addr_lo as u64) | ((addr_hi as u64) << 32)
+}
+
+// Returns if an access of `len` bytes at `pci_addr` is supported in a virtualized MSI-X table or
+// PBA.
+fn valid_msix_access(pci_addr: u64, len: usize) -> bool {
+    // The MSI-X table and PBA may only be accessed with naturally-aligned dwords or qwords.
+    matches!(len, 4 | 8) && (pci_addr & (len as u64 - 1)) == 0
+}
+
+// Returns if an access of `len` bytes at `pci_addr` may be passed through to a BAR.
+fn valid_bar_access(pci_addr: u64, len: usize) -> bool {
+    matches!(len, 1 | 2 | 4 | 8) && (pci_addr & (len as u64 - 1)) == 0
+}
+
+// Returns the address of the VF at `index` of the SR-IOV physical function at `pf_address`, using
+// the VF offset and stride currently reported by `sriov`.
+fn vf_address(pf_address: Address, sriov: &SrIov, index: u16) -> Option<Address> {
+    let offset = sriov.first_vf_offset() as u32 + (index as u32) * (sriov.vf_stride() as u32);
+    pf_address.add_routing_offset(offset)
+}
+
 // Returns `Ok` if the specified bridge window is assigned a valid address for the VM in `context`.
 fn bridge_window_is_valid(base: u64, limit: u64, context: &MmioEmulationContext) -> Result<()> {
     let phys_addr = context
@@ -800,6 +1131,55 @@ impl PciDevice {
         }
     }
 
+    /// Creates an empty hotplug slot function at `address`.
+    pub(super) fn empty(address: Address) -> Self {
+        PciDevice::Empty(PciEmptyFunction::new(address))
+    }
+
+    /// Creates a device at `address` that's owned by `owner`, but has no config space, for testing
+    /// code that only tracks devices by address and owner.
+    #[cfg(test)]
+    pub(crate) fn new_in_test(address: Address, owner: PageOwnerId) -> Self {
+        let mut dev = Self::empty(address);
+        dev.common_mut().owner = Some(owner);
+        dev
+    }
+
+    /// Creates the endpoint at `address` from the config space in `config`, owned by `owner`, for
+    /// testing code that depends on the device's capabilities.
+    #[cfg(test)]
+    pub(crate) fn new_endpoint_in_test(
+        address: Address,
+        owner: PageOwnerId,
+        config: &'static mut [u32],
+    ) -> Self {
+        assert!(config.len() * size_of::<u32>() >= PCIE_CONFIG_SPACE_END + 1);
+        let registers_ptr = NonNull::new(config.as_mut_ptr() as *mut CommonRegisters).unwrap();
+        // Not safe - just a test. `config` is a uniquely-owned config space.
+        let mut dev = unsafe {
+            let info = PciDeviceInfo::read_from(address, registers_ptr.as_ref()).unwrap();
+            Self::new(registers_ptr, info).unwrap()
+        };
+        dev.common_mut().owner = Some(owner);
+        dev
+    }
+
+    /// Makes `owner` the owner of the device without resetting it, for testing code that emulates
+    /// accesses to the device by its owner.
+    #[cfg(test)]
+    pub(crate) fn set_owner_in_test(&mut self, owner: PageOwnerId) {
+        self.set_owner(owner).unwrap();
+    }
+
+    /// Replaces this device with `dev`, which has the same address. Used when a device is added to
+    /// or removed from a hotplug slot. Ownership and IOMMU attachment are tied to the address of
+    /// the device, so `dev` inherits them from the device it replaces.
+    pub(super) fn replace(&mut self, mut dev: PciDevice) {
+        dev.common_mut().owner = self.owner();
+        dev.common_mut().iommu_attached = self.is_iommu_attached();
+        *self = dev;
+    }
+
     /// Returns the `PciDeviceInfo` for this device.
     pub fn info(&self) -> &PciDeviceInfo {
         &self.common().info
@@ -820,6 +1200,16 @@ impl PciDevice {
         self.common().capabilities.has_msix()
     }
 
+    /// Returns if the device supports Address Translation Services.
+    pub fn has_ats(&self) -> bool {
+        self.common().capabilities.has_ats()
+    }
+
+    /// Returns if the device supports the Page Request Interface.
+    pub fn has_pri(&self) -> bool {
+        self.common().capabilities.has_pri()
+    }
+
     /// Returns if the device is a PCI-Express device.
     pub fn is_pcie(&self) -> bool {
         self.common().capabilities.is_pcie()
@@ -830,6 +1220,11 @@ impl PciDevice {
         self.common().owner
     }
 
+    /// Returns if the device is attached to an active IOMMU context.
+    pub fn is_iommu_attached(&self) -> bool {
+        self.common().iommu_attached
+    }
+
     /// Takes ownership over the device if it is not already owned.
     pub(super) fn take(&mut self, owner: PageOwnerId) -> Result<()> {
         if self.owner().is_some() {
@@ -841,6 +1236,657 @@ impl PciDevice {
         Ok(())
     }
 
+    // Checks that the device can be transferred from `from` to another owner. Only endpoints other
+    // than SR-IOV physical functions, and enabled virtual functions, which support function-level
+    // reset and aren't attached to an IOMMU context may change owners.
+    fn check_transferable(&self, from: PageOwnerId) -> Result<()> {
+        match self {
+            PciDevice::Endpoint(_) if self.sriov().is_none() => {}
+            PciDevice::VirtualFunction(_) => {}
+            _ => return Err(Error::DeviceNotAssignable),
+        }
+        if !self.is_present() {
+            return Err(Error::DeviceNotPresent(self.info().address()));
+        }
+        if !self.has_flr() {
+            return Err(Error::FunctionLevelResetNotSupported(self.info().address()));
+        }
+        if self.owner() != Some(from) {
+            return Err(Error::DeviceNotOwned);
+        }
+        if self.common().iommu_attached {
+            return Err(Error::DeviceAttached);
+        }
+        Ok(())
+    }
+
+    /// Prepares the device for a transfer from `from` to another owner by stopping it from issuing
+    /// new requests.
+    pub(super) fn quiesce_for_transfer(&mut self, from: PageOwnerId) -> Result<()> {
+        self.check_transferable(from)?;
+        self.common_registers()?
+            .command
+            .modify(Command::BusMasterEnable.val(0));
+        Ok(())
+    }
+
+    /// Returns if the device has non-posted requests outstanding.
+    pub(super) fn transactions_pending(&self) -> bool {
+        self.common()
+            .capabilities
+            .pcie()
+            .is_some_and(|pcie| pcie.transactions_pending())
+    }
+
+    /// Transfers ownership over the device from `from` to `to`. The device is reset before it
+    /// changes owners, and `to` can't access it until the reset has completed.
+    pub(super) fn transfer(&mut self, from: PageOwnerId, to: PageOwnerId) -> Result<()> {
+        self.check_transferable(from)?;
+        // Make sure no state set up by the previous owner is visible to the new one.
+        self.reset_function()?;
+        self.set_owner(to)
+    }
+
+    // Makes `to` the owner of the device.
+    fn set_owner(&mut self, to: PageOwnerId) -> Result<()> {
+        // The MSI-X table is virtualized for VMs other than the host so that they can only direct
+        // interrupts to their own interrupt files.
+        let msix_table = match self.msix_table_info() {
+            Some(info) if to != PageOwnerId::host() => Some(VirtualMsiXTable::new(info)?),
+            _ => None,
+        };
+        self.common_mut().msix_table = msix_table;
+        self.common_mut().owner = Some(to);
+        Ok(())
+    }
+
+    /// Transfers ownership over the function from `from` to `to` after the device it belonged to has
+    /// been removed from its slot, or after the VF has been disabled along with its PF.
+    pub(super) fn reclaim_removed(&mut self, from: PageOwnerId, to: PageOwnerId) -> Result<()> {
+        if self.is_present() {
+            return Err(Error::DeviceNotAssignable);
+        }
+        if self.owner() != Some(from) {
+            return Err(Error::DeviceNotOwned);
+        }
+        if self.common().iommu_attached {
+            return Err(Error::DeviceAttached);
+        }
+        self.common_mut().msix_table = None;
+        self.common_mut().reset_state = None;
+        self.common_mut().owner = Some(to);
+        Ok(())
+    }
+
+    /// Returns the location of the device's MSI-X table and PBA if it has an MSI-X capability.
+    pub fn msix_table_info(&self) -> Option<MsiXTableInfo> {
+        self.common().capabilities.msix_table_info()
+    }
+
+    // Returns the offset of the PCI bus address `pci_addr` in the region of `size` bytes at
+    // `offset` in the BAR at `bar_index`, if it's within the region.
+    fn offset_in_bar_region(
+        &self,
+        pci_addr: u64,
+        bar_index: usize,
+        offset: u64,
+        size: u64,
+    ) -> Option<u64> {
+        let base = self.get_bar_addr(bar_index).ok()?.checked_add(offset)?;
+        let region_offset = pci_addr.checked_sub(base)?;
+        (region_offset < size).then_some(region_offset)
+    }
+
+    /// Returns if the `len` bytes at the PCI bus address `pci_addr` overlap the MSI-X table or PBA
+    /// of this device, and the table is virtualized.
+    pub(super) fn msix_table_overlaps(&self, pci_addr: u64, len: u64) -> bool {
+        let info = match self.common().msix_table {
+            Some(ref table) => *table.info(),
+            None => return false,
+        };
+        let regions = [
+            (info.table_bar(), info.table_offset(), info.table_size()),
+            (info.pba_bar(), info.pba_offset(), info.pba_size()),
+        ];
+        regions.iter().any(|&(bar_index, offset, size)| {
+            self.get_bar_addr(bar_index)
+                .ok()
+                .and_then(|addr| addr.checked_add(offset))
+                .is_some_and(|base| base < pci_addr.saturating_add(len) && pci_addr < base + size)
+        })
+    }
+
+    // Returns the physical address of the `len` bytes at the PCI bus address `pci_addr` if they're
+    // within a memory BAR of this device.
+    fn bar_access_addr(
+        &self,
+        pci_addr: u64,
+        len: usize,
+        resources: &PciRootResources,
+    ) -> Result<SupervisorPhysAddr> {
+        if !valid_bar_access(pci_addr, len) {
+            return Err(Error::UnsupportedMsiXAccess);
+        }
+        let in_bar = self
+            .bar_info()
+            .bars()
+            .filter(|bar| bar.bar_type() != PciResourceType::IoPort)
+            .any(|bar| {
+                self.get_bar_addr(bar.index())
+                    .ok()
+                    .and_then(|base| pci_addr.checked_sub(base))
+                    .is_some_and(|offset| offset + len as u64 <= bar.size())
+            });
+        if !in_bar {
+            return Err(Error::UnsupportedMsiXAccess);
+        }
+        resources
+            .pci_to_physical_addr(pci_addr)
+            .ok_or(Error::InvalidBarAddress(pci_addr))
+    }
+
+    // Reads `len` bytes at the PCI bus address `pci_addr` in a memory BAR of this device on behalf
+    // of its owner.
+    fn read_bar(&self, pci_addr: u64, len: usize, resources: &PciRootResources) -> Result<u64> {
+        let ptr = self.bar_access_addr(pci_addr, len, resources)?.bits();
+        // Safety: The access is naturally aligned and within a memory BAR of this device, which is
+        // in the BAR resources of the root complex and mapped in the hypervisor's address space.
+        // The device's owner could have made the access directly if the page didn't contain the
+        // MSI-X table.
+        let value = unsafe {
+            match len {
+                1 => core::ptr::read_volatile(ptr as *const u8) as u64,
+                2 => core::ptr::read_volatile(ptr as *const u16) as u64,
+                4 => core::ptr::read_volatile(ptr as *const u32) as u64,
+                _ => core::ptr::read_volatile(ptr as *const u64),
+            }
+        };
+        Ok(value)
+    }
+
+    // Writes `len` bytes of `value` at the PCI bus address `pci_addr` in a memory BAR of this
+    // device on behalf of its owner.
+    fn write_bar(
+        &self,
+        pci_addr: u64,
+        value: u64,
+        len: usize,
+        resources: &PciRootResources,
+    ) -> Result<()> {
+        let ptr = self.bar_access_addr(pci_addr, len, resources)?.bits();
+        // Safety: The access is naturally aligned and within a memory BAR of this device, which is
+        // in the BAR resources of the root complex and mapped in the hypervisor's address space.
+        // The device's owner could have made the access directly if the page didn't contain the
+        // MSI-X table.
+        unsafe {
+            match len {
+                1 => core::ptr::write_volatile(ptr as *mut u8, value as u8),
+                2 => core::ptr::write_volatile(ptr as *mut u16, value as u16),
+                4 => core::ptr::write_volatile(ptr as *mut u32, value as u32),
+                _ => core::ptr::write_volatile(ptr as *mut u64, value),
+            }
+        }
+        Ok(())
+    }
+
+    /// Emulates a read of `len` bytes at the PCI bus address `pci_addr` in the page of a BAR that
+    /// contains the virtualized MSI-X table or PBA of this device. Reads of the table return the
+    /// vectors as programmed by the device's owner, while the rest of the page, including the PBA,
+    /// is read from the device.
+    pub(super) fn emulate_msix_read(
+        &self,
+        pci_addr: u64,
+        len: usize,
+        resources: &PciRootResources,
+    ) -> Result<u64> {
+        if !self.is_present() {
+            return Err(Error::DeviceNotPresent(self.info().address()));
+        }
+        let table = self
+            .common()
+            .msix_table
+            .as_ref()
+            .ok_or(Error::MsiXNotVirtualized)?;
+        let info = *table.info();
+        if let Some(offset) = self.offset_in_bar_region(
+            pci_addr,
+            info.table_bar(),
+            info.table_offset(),
+            info.table_size(),
+        ) {
+            if !valid_msix_access(pci_addr, len) {
+                return Err(Error::UnsupportedMsiXAccess);
+            }
+            let mut value = table.read_dword(offset) as u64;
+            if len == size_of::<u64>() {
+                value |= (table.read_dword(offset + 4) as u64) << 32;
+            }
+            return Ok(value);
+        }
+        self.read_bar(pci_addr, len, resources)
+    }
+
+    /// Emulates a write of `len` bytes of `value` at the PCI bus address `pci_addr` in the page of
+    /// a BAR that contains the virtualized MSI-X table or PBA of this device. Vectors written by
+    /// the device's owner are only programmed into the device if `is_valid_target` returns true
+    /// for their message address, and only once the owner unmasks the function if it's masked.
+    /// Writes to the PBA are ignored, while writes to the rest of the page go to the device.
+    pub(super) fn emulate_msix_write(
+        &mut self,
+        pci_addr: u64,
+        value: u64,
+        len: usize,
+        resources: &PciRootResources,
+        is_valid_target: impl Fn(u64) -> bool,
+    ) -> Result<()> {
+        if !self.is_present() {
+            return Err(Error::DeviceNotPresent(self.info().address()));
+        }
+        let info = *self
+            .common()
+            .msix_table
+            .as_ref()
+            .ok_or(Error::MsiXNotVirtualized)?
+            .info();
+        let in_pba = self
+            .offset_in_bar_region(pci_addr, info.pba_bar(), info.pba_offset(), info.pba_size())
+            .is_some();
+        let offset = match self.offset_in_bar_region(
+            pci_addr,
+            info.table_bar(),
+            info.table_offset(),
+            info.table_size(),
+        ) {
+            Some(offset) => offset,
+            // The PBA is read-only.
+            None if in_pba => return Ok(()),
+            None => return self.write_bar(pci_addr, value, len, resources),
+        };
+        if !valid_msix_access(pci_addr, len) {
+            return Err(Error::UnsupportedMsiXAccess);
+        }
+        // Unwrap ok: we've checked that the table is virtualized above.
+        let table = self.common_mut().msix_table.as_mut().unwrap();
+        let mut index = table.write_dword(offset, value as u32, &is_valid_target);
+        if len == size_of::<u64>() {
+            index = table
+                .write_dword(offset + 4, (value >> 32) as u32, &is_valid_target)
+                .or(index);
+        }
+        // Vectors written while the function is masked are programmed when it's unmasked.
+        if self.common().capabilities.msix_function_masked() {
+            return Ok(());
+        }
+        if let Some(index) = index {
+            self.program_msix_vector(index, resources)?;
+        }
+        Ok(())
+    }
+
+    // Programs the vector at `index` of the virtualized MSI-X table into the device's MSI-X table.
+    fn program_msix_vector(&self, index: usize, resources: &PciRootResources) -> Result<()> {
+        let table = self
+            .common()
+            .msix_table
+            .as_ref()
+            .ok_or(Error::MsiXNotVirtualized)?;
+        let info = table.info();
+        let table_addr = self.get_bar_addr(info.table_bar())?;
+        let entry_addr = table_addr
+            .checked_add(info.table_offset() + (index * size_of::<MsiXTableEntry>()) as u64)
+            .ok_or(Error::InvalidBarAddress(table_addr))?;
+        let phys_addr = resources
+            .pci_to_physical_addr(entry_addr)
+            .ok_or(Error::InvalidBarAddress(entry_addr))?;
+        // Safety: The table entry is within a BAR of this device, which is in the BAR resources
+        // of the root complex and mapped in the hypervisor's address space. The MSI-X table is
+        // only written through the virtualized table while it is virtualized.
+        let entry = unsafe { &*(phys_addr.bits() as *const MsiXTableEntry) };
+        table.program_vector(index, entry);
+        Ok(())
+    }
+
+    // Programs all the vectors of the virtualized MSI-X table into the device's MSI-X table after
+    // the function has been unmasked. The function is kept masked while the vectors are updated,
+    // and stays masked if they couldn't be.
+    fn sync_msix_vectors(&mut self, resources: &PciRootResources) {
+        let num_vectors = match self.common().msix_table {
+            Some(ref table) => table.info().num_vectors(),
+            None => return,
+        };
+        self.common_mut().capabilities.set_msix_function_mask(true);
+        if (0..num_vectors)
+            .try_for_each(|index| self.program_msix_vector(index, resources))
+            .is_ok()
+        {
+            self.common_mut().capabilities.set_msix_function_mask(false);
+        }
+    }
+
+    /// Checks the message addresses in the virtualized MSI-X table again with `is_valid_target`,
+    /// after the interrupt files the device's owner may signal changed. The vectors are programmed
+    /// into the device again if their validity changed, the owner has unmasked the function and
+    /// the device is ready to be accessed.
+    pub(super) fn revalidate_msix_targets(
+        &mut self,
+        resources: &PciRootResources,
+        is_valid_target: impl Fn(u64) -> bool,
+    ) {
+        let changed = match self.common_mut().msix_table {
+            Some(ref mut table) => table.revalidate_targets(is_valid_target),
+            None => return,
+        };
+        if changed
+            && self.is_present()
+            && !self.common().capabilities.msix_function_masked()
+            && self.check_ready().is_ok()
+        {
+            self.sync_msix_vectors(resources);
+        }
+    }
+
+    /// Returns if the device supports function-level reset. Bridges are never reset since the
+    /// hypervisor owns their bus numbers.
+    pub fn has_flr(&self) -> bool {
+        !matches!(self, PciDevice::Bridge(_))
+            && self
+                .common()
+                .capabilities
+                .pcie()
+                .is_some_and(|pcie| pcie.has_flr())
+    }
+
+    /// Returns if a VM has requested a function-level reset of the device.
+    pub(super) fn take_reset_request(&mut self) -> bool {
+        self.common_mut()
+            .capabilities
+            .pcie_mut()
+            .is_some_and(|pcie| pcie.take_reset_request())
+    }
+
+    /// Returns the power state requested by a VM, if any.
+    pub(super) fn take_power_state_request(&mut self) -> Option<PowerState> {
+        self.common_mut()
+            .capabilities
+            .power_management_mut()
+            .and_then(|pm| pm.take_state_request())
+    }
+
+    /// Returns if moving the device to `state` resets it.
+    pub(super) fn power_state_change_resets(&self, state: PowerState) -> bool {
+        self.common()
+            .capabilities
+            .power_management()
+            .is_some_and(|pm| {
+                pm.power_state() == Some(PowerState::D3Hot)
+                    && state == PowerState::D0
+                    && !pm.no_soft_reset()
+            })
+    }
+
+    /// Initiates a function-level reset of the device. The device isn't ready for use until
+    /// `check_ready()` succeeds. Waiting for outstanding transactions to complete before the reset
+    /// is up to the caller.
+    pub(super) fn reset_function(&mut self) -> Result<()> {
+        let address = self.info().address();
+        if !self.has_flr() {
+            return Err(Error::FunctionLevelResetNotSupported(address));
+        }
+        self.common_registers()?
+            .command
+            .modify(Command::BusMasterEnable.val(0));
+        // Unwrap ok: the device must have a PCI Express capability to support FLR.
+        self.common_mut()
+            .capabilities
+            .pcie_mut()
+            .unwrap()
+            .initiate_flr();
+        self.start_reset(FLR_COMPLETION_MS, true);
+        Ok(())
+    }
+
+    /// Moves the device to power state `state`. The device isn't ready for use until
+    /// `check_ready()` succeeds.
+    pub(super) fn set_power_state(&mut self, state: PowerState) -> Result<()> {
+        let address = self.info().address();
+        // Bridges lose their bus numbers if reset.
+        if let PciDevice::Bridge(_) = self {
+            return Err(Error::PowerManagementNotSupported(address));
+        }
+        let resets = self.power_state_change_resets(state);
+        let pm = self
+            .common_mut()
+            .capabilities
+            .power_management_mut()
+            .ok_or(Error::PowerManagementNotSupported(address))?;
+        pm.set_power_state(state);
+        self.start_reset(D3HOT_TRANSITION_MS, resets);
+        Ok(())
+    }
+
+    // Marks the device as inaccessible for the next `delay` milliseconds while it's reset, or
+    // changes power state if `resets` is false.
+    fn start_reset(&mut self, delay: u64, resets: bool) {
+        // The reset masks all the vectors in the MSI-X table.
+        if resets && let Some(table) = self.common_mut().msix_table.as_mut() {
+            table.reset();
+        }
+        let ready_at = time_ms() + delay;
+        self.common_mut().reset_state = Some(ResetState::Pending {
+            ready_at,
+            timeout_at: ready_at + RESET_READY_TIMEOUT_MS,
+        });
+    }
+
+    /// Checks that the device has completed its last reset or power state transition, if any, and
+    /// is still the same device. Returns `DeviceNotReady` until the device is ready for use. Devices
+    /// which fail to come back from a reset stay inaccessible.
+    pub(super) fn check_ready(&mut self) -> Result<()> {
+        let address = self.info().address();
+        let timeout_at = match self.common().reset_state {
+            None => return Ok(()),
+            Some(ResetState::Failed(err)) => return Err(err),
+            Some(ResetState::Pending { ready_at, .. }) if time_ms() < ready_at => {
+                return Err(Error::DeviceNotReady(address));
+            }
+            Some(ResetState::Pending { timeout_at, .. }) => timeout_at,
+        };
+        // VFs report an invalid vendor ID, and are ready as soon as the reset has completed.
+        if !matches!(self, PciDevice::VirtualFunction(_)) {
+            // Configuration requests to a device that isn't yet ready complete with an all-ones
+            // value.
+            let err = match PciDeviceInfo::read_from(address, self.common_registers()?) {
+                Some(info) if info == *self.info() => None,
+                Some(_) => Some(Error::DeviceChangedAfterReset(address)),
+                None if time_ms() >= timeout_at => Some(Error::DeviceNotReady(address)),
+                None => return Err(Error::DeviceNotReady(address)),
+            };
+            if let Some(err) = err {
+                self.common_mut().reset_state = Some(ResetState::Failed(err));
+                return Err(err);
+            }
+        }
+        self.common_mut().reset_state = None;
+        Ok(())
+    }
+
+    /// Returns if the configuration space of the device is accessible. SR-IOV virtual functions are
+    /// absent while VFs are disabled in their physical function, and the functions of hotplug slots
+    /// are absent while the slot is empty.
+    pub fn is_present(&self) -> bool {
+        match self {
+            PciDevice::VirtualFunction(vf) => vf.registers.is_some(),
+            PciDevice::Empty(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Returns if the device is a bridge with a hotplug-capable slot below it.
+    pub(super) fn has_hotplug_slot(&self) -> bool {
+        matches!(self, PciDevice::Bridge(_))
+            && self
+                .common()
+                .capabilities
+                .pcie()
+                .is_some_and(|pcie| pcie.has_hotplug_slot())
+    }
+
+    /// Returns if the hotplug slot below this bridge is occupied by a device that is ready to be
+    /// enumerated.
+    pub(super) fn slot_occupied(&self) -> bool {
+        self.has_hotplug_slot()
+            && self
+                .common()
+                .capabilities
+                .pcie()
+                .is_some_and(|pcie| pcie.slot_occupied())
+    }
+
+    /// Returns the ID of this device's physical function if it is an SR-IOV virtual function.
+    pub fn physfn(&self) -> Option<PciArenaId> {
+        match self {
+            PciDevice::VirtualFunction(vf) => Some(vf.physfn),
+            _ => None,
+        }
+    }
+
+    /// Returns the IDs of the SR-IOV virtual functions reserved for this device.
+    pub fn virtual_functions(&self) -> &[PciArenaId] {
+        match self {
+            PciDevice::Endpoint(ep) => &ep.virtual_functions,
+            _ => &[],
+        }
+    }
+
+    /// Returns the SR-IOV capability of this device if it is an SR-IOV physical function.
+    pub(super) fn sriov(&self) -> Option<&SrIov> {
+        self.common().capabilities.sriov()
+    }
+
+    /// Returns a mutable reference to the SR-IOV capability of this device if it is an SR-IOV
+    /// physical function.
+    pub(super) fn sriov_mut(&mut self) -> Option<&mut SrIov> {
+        self.common_mut().capabilities.sriov_mut()
+    }
+
+    /// Fixes the routing IDs of the SR-IOV virtual functions of this device, returning the address
+    /// of each VF that may be enabled. At most `MAX_SRIOV_VFS` VFs are supported, and only those on
+    /// the same bus as the PF.
+    pub(super) fn probe_virtual_functions(&mut self) -> ArrayVec<Address, MAX_SRIOV_VFS> {
+        let mut addrs = ArrayVec::new();
+        let address = self.info().address();
+        let sriov = match self.sriov_mut() {
+            Some(sriov) => sriov,
+            None => return addrs,
+        };
+        // The VF offset and stride depend on NumVFs, so we probe them with the largest number of
+        // VFs we support and only allow VFs to be enabled at those addresses.
+        let num_vfs = sriov.total_vfs().min(MAX_SRIOV_VFS as u16);
+        sriov.set_num_vfs(num_vfs);
+        for index in 0..num_vfs {
+            match vf_address(address, sriov, index) {
+                Some(vf_addr) if vf_addr.bus() == address.bus() => addrs.push(vf_addr),
+                _ => break,
+            }
+        }
+        sriov.set_num_vfs(0);
+        sriov.set_max_vfs(addrs.len() as u16);
+        addrs
+    }
+
+    /// Creates a disabled SR-IOV virtual function of this device, which is `physfn` in the device
+    /// arena, at `address`.
+    pub(super) fn new_virtual_function(&self, physfn: PciArenaId, address: Address) -> Self {
+        let vf_device_id = self.sriov().map(|s| s.vf_device_id()).unwrap_or_default();
+        let info = PciDeviceInfo::for_virtual_function(
+            address,
+            self.info().vendor_id(),
+            DeviceId(vf_device_id),
+            self.info().class(),
+            self.info().subclass(),
+        );
+        let bar_info = match self {
+            PciDevice::Endpoint(ep) => ep.vf_bar_info.clone(),
+            _ => PciDeviceBarInfo::empty(),
+        };
+        PciDevice::VirtualFunction(PciVirtualFunction::new(physfn, info, bar_info))
+    }
+
+    /// Sets the IDs of the SR-IOV virtual functions reserved for this device.
+    pub(super) fn set_virtual_functions(&mut self, vfs: Vec<PciArenaId>) {
+        if let PciDevice::Endpoint(ep) = self {
+            ep.virtual_functions = vfs;
+        }
+    }
+
+    /// Returns the address of the SR-IOV virtual function at `index` of this device, given the
+    /// current configuration of the PF.
+    pub(super) fn virtual_function_address(&self, index: u16) -> Option<Address> {
+        vf_address(self.info().address(), self.sriov()?, index)
+    }
+
+    /// Returns the BARs of a single SR-IOV virtual function of this device if it is an SR-IOV
+    /// physical function.
+    pub(super) fn vf_bar_info(&self) -> Option<&PciDeviceBarInfo> {
+        match self {
+            PciDevice::Endpoint(ep) if self.sriov().is_some() => Some(&ep.vf_bar_info),
+            _ => None,
+        }
+    }
+
+    /// Programs the SR-IOV VF BAR at `index` with the given address. The BAR of each VF follows
+    /// that of the previous VF at an increment of the BAR size.
+    pub(super) fn set_vf_bar_addr(&mut self, index: usize, pci_addr: u64) -> Result<()> {
+        let bar = self
+            .vf_bar_info()
+            .and_then(|b| b.get(index))
+            .ok_or(Error::BarNotPresent(index))?;
+        let is_64bit = bar.bar_type().is_64bit();
+        // Unwrap ok: we have VF BARs, so the device must have an SR-IOV capability.
+        let regs = self.sriov_mut().unwrap().vf_bars_mut();
+        regs[index].set(pci_addr as u32);
+        if is_64bit {
+            regs[index + 1].set((pci_addr >> 32) as u32);
+        }
+        Ok(())
+    }
+
+    /// Returns the PCI bus addresses of the BARs of the SR-IOV virtual function at `index` of this
+    /// device.
+    pub(super) fn virtual_function_bar_addrs(&self, index: u16) -> [u64; PCI_ENDPOINT_BARS] {
+        let mut bar_addrs = [0; PCI_ENDPOINT_BARS];
+        if let (PciDevice::Endpoint(ep), Some(sriov)) = (self, self.sriov()) {
+            for bar in ep.vf_bar_info.bars() {
+                bar_addrs[bar.index()] =
+                    bar_addr(sriov.vf_bars(), bar) + (index as u64) * bar.size();
+            }
+        }
+        bar_addrs
+    }
+
+    /// Returns `Ok` if memory space access can safely be enabled for the SR-IOV virtual functions
+    /// of this device by the VM in `context`.
+    pub(super) fn can_enable_vf_memory_space(&self, context: &MmioEmulationContext) -> Result<()> {
+        let (ep, sriov) = match (self, self.sriov()) {
+            (PciDevice::Endpoint(ep), Some(sriov)) => (ep, sriov),
+            _ => return Ok(()),
+        };
+        // Each VF BAR covers the BARs of all the VFs that may be enabled.
+        for bar in ep.vf_bar_info.bars() {
+            let pci_addr = bar_addr(sriov.vf_bars(), bar);
+            let phys_addr = context
+                .resources
+                .pci_to_physical_addr(pci_addr)
+                .ok_or(Error::InvalidBarAddress(pci_addr))?;
+            let page_range = SupervisorPageRange::new(
+                PageAddr::with_round_down(phys_addr, PageSize::Size4k),
+                PageSize::num_4k_pages(bar.size() * sriov.max_vfs() as u64),
+            );
+            bar_range_is_owned(page_range, &context.page_tracker, context.guest_id)?;
+        }
+        Ok(())
+    }
+
     /// Emulates a read from the configuration space of this device at `offset`.
     pub(super) fn emulate_config_read(
         &self,
@@ -849,8 +1895,14 @@ impl PciDevice {
         _context: MmioEmulationContext,
     ) -> u32 {
         let mut op = MmioReadBuilder::new(offset, len);
+        let Ok(regs) = self.common_registers() else {
+            // Configuration reads from a function that isn't there complete with all-ones.
+            while !op.done() {
+                op.push_byte(!0x0);
+            }
+            return op.result();
+        };
         while !op.done() {
-            let regs = self.common_registers();
             let info = self.info();
             use common_offsets::*;
             match op.offset() {
@@ -892,16 +1944,16 @@ impl PciDevice {
                 PCI_CAPS_START..=PCI_CONFIG_SPACE_END => {
                     self.common().capabilities.emulate_read(&mut op);
                 }
+                PCIE_EXT_CAPS_START..=PCIE_CONFIG_SPACE_END if self.is_pcie() => {
+                    self.common().capabilities.emulate_extended_read(&mut op);
+                }
                 offset => {
                     if offset <= PCI_COMMON_HEADER_END {
                         // Everything else in the common part of the header is unimplemented and we can
                         // safely return 0.
                         op.push_byte(0);
                     } else {
-                        // Make everything beyond the standard PCI configuration space appear
-                        // unimplemented.
-                        //
-                        // TODO: Extended config space emulation?
+                        // Conventional PCI devices have no extended configuration space.
                         op.push_dword(!0x0);
                     }
                 }
@@ -920,13 +1972,22 @@ impl PciDevice {
     ) {
         let mut op = MmioWriteBuilder::new(offset, value, len);
         while !op.done() {
+            // Configuration writes to a function that isn't there are dropped.
+            let Ok(regs) = self.common_registers() else {
+                return;
+            };
             use common_offsets::*;
             match op.offset() {
                 command::span!() => {
                     let mut reg = LocalRegisterCopy::<u16, Command::Register>::new(
-                        op.pop_word(self.common_registers().command.get()),
+                        op.pop_word(regs.command.get()),
                     );
 
+                    // Memory space for VFs is enabled in the SR-IOV capability of the PF.
+                    if let PciDevice::VirtualFunction(_) = self {
+                        reg.modify(Command::IoEnable.val(0) + Command::MemoryEnable.val(0));
+                    }
+
                     // Check that the VM has assigned valid BARs / bridge windows for this device
                     // before allowing it to enable IO or memory space access.
                     if reg.is_set(Command::IoEnable) && self.can_enable_io_space(&context).is_err()
@@ -944,26 +2005,33 @@ impl PciDevice {
                         reg.modify(Command::BusMasterEnable.val(0));
                     }
 
-                    self.common_registers().command.set(reg.writeable_bits());
+                    regs.command.set(reg.writeable_bits());
                 }
                 status::span!() => {
                     // Make sure we only write the RW1C bits if the write operation covers that byte.
                     let reg = LocalRegisterCopy::<u16, Status::Register>::new(
-                        op.pop_word(self.common_registers().status.non_clearable_bits()),
+                        op.pop_word(regs.status.non_clearable_bits()),
                     );
-                    self.common_registers().status.set(reg.writeable_bits());
+                    regs.status.set(reg.writeable_bits());
                 }
                 PCI_TYPE_HEADER_START..=PCI_TYPE_HEADER_END => {
                     self.emulate_type_specific_write(&mut op);
                 }
                 PCI_CAPS_START..=PCI_CONFIG_SPACE_END => {
+                    let was_masked = self.common().capabilities.msix_function_masked();
                     self.common_mut().capabilities.emulate_write(&mut op);
+                    if was_masked && !self.common().capabilities.msix_function_masked() {
+                        self.sync_msix_vectors(context.resources);
+                    }
+                }
+                PCIE_EXT_CAPS_START..=PCIE_CONFIG_SPACE_END if self.is_pcie() => {
+                    self.common_mut()
+                        .capabilities
+                        .emulate_extended_write(&mut op);
                 }
                 _ => {
-                    // We don't allow writes to other bits of the common header and everything beyond
-                    // the standard config space is unimplemented.
-                    //
-                    // TODO: Extended config space emulation?
+                    // We don't allow writes to other bits of the common header, or to the extended
+                    // config space of conventional PCI devices.
                     op.pop_byte();
                 }
             }
@@ -976,14 +2044,10 @@ impl PciDevice {
             .bar_info()
             .get(index)
             .ok_or(Error::BarNotPresent(index))?;
-        let regs = self.bar_registers();
-        let addr_lo = regs[index].get() & !((1u32 << BaseAddress::Address.shift) - 1);
-        let addr_hi = if bar.bar_type().is_64bit() {
-            regs[index + 1].get()
-        } else {
-            0
-        };
-        Ok((addr_lo as u64) | ((addr_hi as u64) << 32))
+        if let PciDevice::VirtualFunction(vf) = self {
+            return Ok(vf.bar_addrs[index]);
+        }
+        Ok(bar_addr(self.bar_registers()?, bar))
     }
 
     /// Programs the BAR at `bar_index` with the given address.
@@ -992,7 +2056,7 @@ impl PciDevice {
             .bar_info()
             .get(index)
             .ok_or(Error::BarNotPresent(index))?;
-        let regs = self.bar_registers();
+        let regs = self.bar_registers()?;
         regs[index].set(pci_addr as u32);
         if bar.bar_type().is_64bit() {
             regs[index + 1].set((pci_addr >> 32) as u32);
@@ -1002,23 +2066,23 @@ impl PciDevice {
 
     /// Enables IO port space access for this device.
     pub(super) fn enable_io_space(&mut self) {
-        self.common_registers()
-            .command
-            .modify(Command::IoEnable.val(1));
+        if let Ok(regs) = self.common_registers() {
+            regs.command.modify(Command::IoEnable.val(1));
+        }
     }
 
     /// Enables memory space access for this device.
     pub(super) fn enable_mem_space(&mut self) {
-        self.common_registers()
-            .command
-            .modify(Command::MemoryEnable.val(1));
+        if let Ok(regs) = self.common_registers() {
+            regs.command.modify(Command::MemoryEnable.val(1));
+        }
     }
 
     /// Enables DMA ("bus mastering") for this device.
     pub(super) fn enable_dma(&mut self) {
-        self.common_registers()
-            .command
-            .modify(Command::BusMasterEnable.val(1));
+        if let Ok(regs) = self.common_registers() {
+            regs.command.modify(Command::BusMasterEnable.val(1));
+        }
     }
 
     /// Marks the device as being attached to an active IOMMU context, allowing DMA to be safely
@@ -1029,10 +2093,11 @@ impl PciDevice {
 
     /// Marks the device as no longer being attached to an active IOMMU context.
     pub(crate) fn clear_iommu_attached(&mut self) {
-        // Disable bus mastering to prevent any further DMAs.
-        self.common_registers()
-            .command
-            .modify(Command::BusMasterEnable.val(1));
+        // Disable bus mastering to prevent any further DMAs. Functions without a config space,
+        // like disabled VFs, can't issue DMAs.
+        if let Ok(regs) = self.common_registers() {
+            regs.command.modify(Command::BusMasterEnable.val(0));
+        }
         self.common_mut().iommu_attached = false;
     }
 
@@ -1090,19 +2155,31 @@ impl PciDevice {
         Ok(())
     }
 
-    // Returns a reference to the common portion of this device's PCI header.
-    fn common_registers(&self) -> &CommonRegisters {
+    // Returns a reference to the common portion of this device's PCI header, or an error if the
+    // device has no config space.
+    fn common_registers(&self) -> Result<&CommonRegisters> {
         match self {
-            PciDevice::Endpoint(ep) => &ep.registers.common,
-            PciDevice::Bridge(bridge) => &bridge.registers.common,
+            PciDevice::Endpoint(ep) => Ok(&ep.registers.common),
+            PciDevice::Bridge(bridge) => Ok(&bridge.registers.common),
+            PciDevice::VirtualFunction(PciVirtualFunction {
+                registers: Some(regs),
+                ..
+            }) => Ok(&regs.common),
+            _ => Err(Error::DeviceNotPresent(self.info().address())),
         }
     }
 
-    // Returns a reference to this device's BAR registers.
-    fn bar_registers(&self) -> &[ReadWrite<u32, BaseAddress::Register>] {
+    // Returns a reference to this device's BAR registers, or an error if the device has no config
+    // space.
+    fn bar_registers(&self) -> Result<&[ReadWrite<u32, BaseAddress::Register>]> {
         match self {
-            PciDevice::Endpoint(ep) => &ep.registers.bar,
-            PciDevice::Bridge(bridge) => &bridge.registers.bar,
+            PciDevice::Endpoint(ep) => Ok(&ep.registers.bar),
+            PciDevice::Bridge(bridge) => Ok(&bridge.registers.bar),
+            PciDevice::VirtualFunction(PciVirtualFunction {
+                registers: Some(regs),
+                ..
+            }) => Ok(&regs.bar),
+            _ => Err(Error::DeviceNotPresent(self.info().address())),
         }
     }
 
@@ -1111,6 +2188,9 @@ impl PciDevice {
         match self {
             PciDevice::Endpoint(ep) => ep.emulate_config_read(read_op),
             PciDevice::Bridge(bridge) => bridge.emulate_config_read(read_op),
+            PciDevice::VirtualFunction(vf) => vf.emulate_config_read(read_op),
+            // Reads from a function that isn't there complete with all-ones.
+            PciDevice::Empty(_) => read_op.push_byte(!0x0),
         }
     }
 
@@ -1119,6 +2199,11 @@ impl PciDevice {
         match self {
             PciDevice::Endpoint(ep) => ep.emulate_config_write(write_op),
             PciDevice::Bridge(bridge) => bridge.emulate_config_write(write_op),
+            PciDevice::VirtualFunction(vf) => vf.emulate_config_write(write_op),
+            // Writes to a function that isn't there are dropped.
+            PciDevice::Empty(_) => {
+                write_op.pop_byte();
+            }
         }
     }
 
@@ -1127,6 +2212,8 @@ impl PciDevice {
         match self {
             PciDevice::Endpoint(ep) => &ep.common,
             PciDevice::Bridge(bridge) => &bridge.common,
+            PciDevice::VirtualFunction(vf) => &vf.common,
+            PciDevice::Empty(empty) => &empty.common,
         }
     }
 
@@ -1135,12 +2222,15 @@ impl PciDevice {
         match self {
             PciDevice::Endpoint(ep) => &mut ep.common,
             PciDevice::Bridge(bridge) => &mut bridge.common,
+            PciDevice::VirtualFunction(vf) => &mut vf.common,
+            PciDevice::Empty(empty) => &mut empty.common,
         }
     }
 }
 
-// PciEndpoint and PciBridge hold raw pointers to their config spaces. Access to that config space is
-// done through their respective interfaces which allow them to be shared and sent between threads.
+// PciEndpoint, PciBridge and PciVirtualFunction hold raw pointers to their config spaces. Access to
+// that config space is done through their respective interfaces which allow them to be shared and
+// sent between threads.
 unsafe impl Send for PciDevice {}
 unsafe impl Sync for PciDevice {}
 
@@ -4,7 +4,7 @@
 
 use riscv_pages::SupervisorPageAddr;
 
-use super::address::{Address, Bus};
+use super::address::{Address, Bus, Segment};
 use super::device::HeaderType;
 use super::resource::PciResourceType;
 
@@ -26,6 +26,12 @@ pub enum Error {
     NoConfigBase,
     /// No compatible PCI host controller found in the device tree.
     NoCompatibleHostNode,
+    /// More PCI host controllers were found in the device tree than are supported.
+    TooManyRoots,
+    /// Multiple PCI host controllers in the device tree are in the same segment.
+    DuplicateSegment(Segment),
+    /// The device tree entry for a PCI host specified an invalid segment number.
+    InvalidSegment(u32),
     /// The device tree entry for the PCI host didn't provide a size register for Configuration
     /// Space.
     NoConfigSize,
@@ -47,6 +53,10 @@ pub enum Error {
     MissingMsiParent,
     /// The 'msi-parent' property did not refer to an IMSIC.
     InvalidMsiParent,
+    /// The 'iommu-map' property is malformed, has too many entries, or remaps routing IDs.
+    InvalidIommuMap,
+    /// Too many IOMMU functions are described below a root complex.
+    TooManyIommus,
     /// Invalid value in a PCI header at `address`.
     UnsupportedHeaderType(Address, HeaderType),
     /// Bus is not within the bounds of a config space.
@@ -63,6 +73,10 @@ pub enum Error {
     DeviceNotPresent(Address),
     /// Too many capabilities were found for a PCI device.
     TooManyCapabilities,
+    /// An extended capability at the given offset overflows the extended configuration space.
+    ExtendedCapabilityOutOfBounds(usize),
+    /// The device has a designated vendor-specific capability with an invalid length field.
+    InvalidDvsecLength(usize),
     /// The device has MSI support, but is not 64-bit capable.
     MsiNot64BitCapable,
     /// The device has a vendor capability structure with an invalid length field.
@@ -79,6 +93,9 @@ pub enum Error {
     InvalidBarAddress(u64),
     /// The device does not have a BAR at the specified index.
     BarNotPresent(usize),
+    /// The BAR at the specified index is smaller than a page, so its pages may be shared with other
+    /// BARs.
+    BarNotPageSized(usize),
     /// A VM has programmed a BAR or bridge window to cover a page it does not own.
     UnownedBarPage(SupervisorPageAddr),
     /// The PCI device is already owned.
@@ -89,6 +106,29 @@ pub enum Error {
     DeviceNotFound,
     /// The PCI device was expected to be on the root bus, but wasn't.
     DeviceNotOnRootBus,
+    /// The PCI device can't be assigned to another VM.
+    DeviceNotAssignable,
+    /// The PCI device is attached to an IOMMU context.
+    DeviceAttached,
+    /// An SR-IOV virtual function would be enabled at a different address than the one reserved
+    /// for it.
+    VirtualFunctionMoved(Address),
+    /// SR-IOV virtual functions are assigned to a VM other than the owner of the physical function.
+    VirtualFunctionsAssigned,
+    /// The PCI device doesn't support function-level reset.
+    FunctionLevelResetNotSupported(Address),
+    /// The PCI device doesn't support power management, or is of a type that can't be
+    /// power-managed by VMs.
+    PowerManagementNotSupported(Address),
+    /// The PCI device isn't, or didn't become, ready to respond to configuration requests after a
+    /// reset.
+    DeviceNotReady(Address),
+    /// The PCI device reported a different identity after a reset.
+    DeviceChangedAfterReset(Address),
+    /// The MSI-X table of the PCI device isn't virtualized.
+    MsiXNotVirtualized,
+    /// Unsupported access to a virtualized MSI-X table, or to the BAR page that contains it.
+    UnsupportedMsiXAccess,
 }
 
 /// Holds results for PCI operations.
@@ -3,19 +3,23 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod address;
+mod allocator;
 mod bus;
 mod capabilities;
 mod config_space;
 mod device;
 mod error;
 mod mmio_builder;
+mod msix;
 mod registers;
 mod resource;
 mod root;
 
-pub use address::Address;
+pub use address::{Address, Segment};
 pub use device::{DeviceId, PciDevice, PciDeviceInfo, VendorId};
 pub use error::Error as PciError;
 pub use error::Result as PciResult;
+pub use msix::MsiXTableInfo;
+pub use registers::PCI_ENDPOINT_BARS;
 pub use resource::PciResourceType;
-pub use root::{PciArenaId, PciBarPage, PciBarPageIter, PciResourceIter, PcieRoot};
+pub use root::{PciArenaId, PciBarPage, PciBarPageIter, PciResourceIter, PcieRoot, MAX_PCIE_ROOTS};
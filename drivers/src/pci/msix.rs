@@ -0,0 +1,312 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use tock_registers::interfaces::{ReadWriteable, Writeable};
+use tock_registers::LocalRegisterCopy;
+
+use super::error::*;
+use super::registers::*;
+
+mod table_entry_offsets {
+    use super::MsiXTableEntry;
+    use crate::define_field_span;
+
+    define_field_span!(MsiXTableEntry, msg_addr, u32);
+    define_field_span!(MsiXTableEntry, msg_upper_addr, u32);
+    define_field_span!(MsiXTableEntry, msg_data, u32);
+    define_field_span!(MsiXTableEntry, vector_control, u32);
+}
+
+// The size of an entry in the MSI-X table.
+const TABLE_ENTRY_SIZE: u64 = size_of::<MsiXTableEntry>() as u64;
+// The number of vectors covered by each qword of the PBA.
+const VECTORS_PER_PBA_ENTRY: u64 = 64;
+
+/// The location of a function's MSI-X table and Pending Bit Array (PBA) in its BARs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsiXTableInfo {
+    table_bar: usize,
+    table_offset: u64,
+    pba_bar: usize,
+    pba_offset: u64,
+    num_vectors: usize,
+}
+
+impl MsiXTableInfo {
+    /// Creates a new `MsiXTableInfo` for a table of `num_vectors` entries at `table_offset` in
+    /// `table_bar`, with its PBA at `pba_offset` in `pba_bar`.
+    pub(super) fn new(
+        table_bar: usize,
+        table_offset: u64,
+        pba_bar: usize,
+        pba_offset: u64,
+        num_vectors: usize,
+    ) -> Self {
+        Self {
+            table_bar,
+            table_offset,
+            pba_bar,
+            pba_offset,
+            num_vectors,
+        }
+    }
+
+    /// Returns the index of the BAR containing the MSI-X table.
+    pub fn table_bar(&self) -> usize {
+        self.table_bar
+    }
+
+    /// Returns the offset of the MSI-X table in its BAR.
+    pub fn table_offset(&self) -> u64 {
+        self.table_offset
+    }
+
+    /// Returns the size of the MSI-X table in bytes.
+    pub fn table_size(&self) -> u64 {
+        self.num_vectors as u64 * TABLE_ENTRY_SIZE
+    }
+
+    /// Returns the index of the BAR containing the PBA.
+    pub fn pba_bar(&self) -> usize {
+        self.pba_bar
+    }
+
+    /// Returns the offset of the PBA in its BAR.
+    pub fn pba_offset(&self) -> u64 {
+        self.pba_offset
+    }
+
+    /// Returns the size of the PBA in bytes.
+    pub fn pba_size(&self) -> u64 {
+        let num_entries =
+            (self.num_vectors as u64 + VECTORS_PER_PBA_ENTRY - 1) / VECTORS_PER_PBA_ENTRY;
+        num_entries * size_of::<u64>() as u64
+    }
+
+    /// Returns the number of vectors in the MSI-X table.
+    pub fn num_vectors(&self) -> usize {
+        self.num_vectors
+    }
+}
+
+// A vector as programmed by the VM that owns the function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct MsiXVector {
+    addr: u64,
+    data: u32,
+    masked: bool,
+    // Whether the VM may send interrupts to `addr`.
+    valid_target: bool,
+}
+
+impl Default for MsiXVector {
+    fn default() -> Self {
+        // Vectors are masked on reset.
+        Self {
+            addr: 0,
+            data: 0,
+            masked: true,
+            valid_target: false,
+        }
+    }
+}
+
+/// A virtualized MSI-X table. The VM that owns the function programs this copy of the table instead
+/// of the table in the function's BAR. A vector is only written to the physical table if the VM
+/// may send interrupts to the address it targets; otherwise the physical vector is left masked.
+pub(super) struct VirtualMsiXTable {
+    info: MsiXTableInfo,
+    vectors: Vec<MsiXVector>,
+}
+
+impl VirtualMsiXTable {
+    /// Creates a virtualized MSI-X table with the layout described by `info`. All vectors start
+    /// out masked.
+    pub fn new(info: MsiXTableInfo) -> Result<Self> {
+        let mut vectors = Vec::new();
+        vectors
+            .try_reserve_exact(info.num_vectors())
+            .map_err(|_| Error::AllocError)?;
+        vectors.resize(info.num_vectors(), MsiXVector::default());
+        Ok(Self { info, vectors })
+    }
+
+    /// Returns the location of the MSI-X table and PBA.
+    pub fn info(&self) -> &MsiXTableInfo {
+        &self.info
+    }
+
+    /// Masks all vectors, as is done by a function-level reset.
+    pub fn reset(&mut self) {
+        self.vectors.fill(MsiXVector::default());
+    }
+
+    /// Returns the dword at `offset` in the virtualized table.
+    pub fn read_dword(&self, offset: u64) -> u32 {
+        use table_entry_offsets::*;
+        let vector = match self.vectors.get((offset / TABLE_ENTRY_SIZE) as usize) {
+            Some(v) => v,
+            None => return 0,
+        };
+        match (offset % TABLE_ENTRY_SIZE) as usize {
+            msg_addr::START_OFFSET => vector.addr as u32,
+            msg_upper_addr::START_OFFSET => (vector.addr >> 32) as u32,
+            msg_data::START_OFFSET => vector.data,
+            vector_control::START_OFFSET => vector.masked as u32,
+            // Accesses must be dword-aligned.
+            _ => 0,
+        }
+    }
+
+    /// Writes `value` to the dword at `offset` in the virtualized table, returning the index of the
+    /// vector that was written. The message address is checked with `is_valid_target` when it
+    /// changes, and again when the vector is unmasked.
+    pub fn write_dword(
+        &mut self,
+        offset: u64,
+        value: u32,
+        is_valid_target: impl Fn(u64) -> bool,
+    ) -> Option<usize> {
+        use table_entry_offsets::*;
+        let index = (offset / TABLE_ENTRY_SIZE) as usize;
+        let vector = self.vectors.get_mut(index)?;
+        match (offset % TABLE_ENTRY_SIZE) as usize {
+            msg_addr::START_OFFSET => {
+                // The message address must be dword-aligned.
+                vector.addr = (vector.addr & !(u32::MAX as u64)) | (value & !0x3) as u64;
+                vector.valid_target = is_valid_target(vector.addr);
+            }
+            msg_upper_addr::START_OFFSET => {
+                vector.addr = (vector.addr & u32::MAX as u64) | ((value as u64) << 32);
+                vector.valid_target = is_valid_target(vector.addr);
+            }
+            msg_data::START_OFFSET => {
+                vector.data = value;
+            }
+            vector_control::START_OFFSET => {
+                let reg = LocalRegisterCopy::<u32, MsiXVectorControl::Register>::new(value);
+                vector.masked = reg.is_set(MsiXVectorControl::Masked);
+                // The interrupt files the VM may signal may have changed since the address was
+                // written.
+                if !vector.masked {
+                    vector.valid_target = is_valid_target(vector.addr);
+                }
+            }
+            _ => return None,
+        }
+        Some(index)
+    }
+
+    /// Checks the message address of every vector with `is_valid_target` again, after the interrupt
+    /// files the VM may signal changed. Returns if the validity of any vector changed, in which
+    /// case the vectors must be programmed again.
+    pub fn revalidate_targets(&mut self, is_valid_target: impl Fn(u64) -> bool) -> bool {
+        let mut changed = false;
+        for vector in self.vectors.iter_mut() {
+            let valid_target = is_valid_target(vector.addr);
+            changed |= vector.valid_target != valid_target;
+            vector.valid_target = valid_target;
+        }
+        changed
+    }
+
+    /// Programs the vector at `index` into `entry` in the physical table if the VM is allowed to
+    /// target its address. Otherwise the vector is masked in the physical table.
+    pub fn program_vector(&self, index: usize, entry: &MsiXTableEntry) {
+        let vector = match self.vectors.get(index) {
+            Some(v) => v,
+            None => return,
+        };
+        // Keep the vector masked while it's updated so the function never signals a partially
+        // written message.
+        entry.vector_control.modify(MsiXVectorControl::Masked::SET);
+        if vector.masked || !vector.valid_target {
+            return;
+        }
+        entry.msg_addr.set(vector.addr as u32);
+        entry.msg_upper_addr.set((vector.addr >> 32) as u32);
+        entry.msg_data.set(vector.data);
+        entry
+            .vector_control
+            .modify(MsiXVectorControl::Masked::CLEAR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use tock_registers::interfaces::Readable;
+
+    fn masked(entry: &MsiXTableEntry) -> bool {
+        entry.vector_control.is_set(MsiXVectorControl::Masked)
+    }
+
+    #[test]
+    fn table_layout() {
+        let info = MsiXTableInfo::new(0, 0x2000, 2, 0x800, 65);
+        assert_eq!(info.table_size(), 65 * 16);
+        assert_eq!(info.pba_size(), 16);
+    }
+
+    #[test]
+    fn program_vectors() {
+        let info = MsiXTableInfo::new(0, 0, 0, 0x1000, 2);
+        let mut table = VirtualMsiXTable::new(info).unwrap();
+        let table_mem: Vec<u32> = [0; 8].to_vec();
+        let entries =
+            unsafe { core::slice::from_raw_parts(table_mem.as_ptr() as *const MsiXTableEntry, 2) };
+        let valid_target = |addr: u64| addr == 0x2800_1000;
+
+        // Vectors are masked until the VM unmasks them.
+        assert_eq!(table.read_dword(0xc), 1);
+        assert_eq!(table.write_dword(0x0, 0x2800_1000, valid_target), Some(0));
+        assert_eq!(table.write_dword(0x4, 0, valid_target), Some(0));
+        assert_eq!(table.write_dword(0x8, 0x42, valid_target), Some(0));
+        table.program_vector(0, &entries[0]);
+        assert!(masked(&entries[0]));
+        assert_eq!(entries[0].msg_addr.get(), 0);
+
+        assert_eq!(table.write_dword(0xc, 0, valid_target), Some(0));
+        table.program_vector(0, &entries[0]);
+        assert!(!masked(&entries[0]));
+        assert_eq!(entries[0].msg_addr.get(), 0x2800_1000);
+        assert_eq!(entries[0].msg_data.get(), 0x42);
+        assert_eq!(table.read_dword(0x8), 0x42);
+        assert_eq!(table.read_dword(0xc), 0);
+
+        // Vectors targeting an address the VM doesn't own are never unmasked.
+        assert_eq!(table.write_dword(0x10, 0x3000_0000, valid_target), Some(1));
+        assert_eq!(table.write_dword(0x1c, 0, valid_target), Some(1));
+        table.program_vector(1, &entries[1]);
+        assert!(masked(&entries[1]));
+        assert_eq!(entries[1].msg_addr.get(), 0);
+        assert_eq!(table.read_dword(0x10), 0x3000_0000);
+
+        // Targets are checked again when a vector is unmasked, and when the interrupt files the VM
+        // may signal change.
+        let no_target = |_: u64| false;
+        assert_eq!(table.write_dword(0xc, 1, no_target), Some(0));
+        assert_eq!(table.write_dword(0xc, 0, no_target), Some(0));
+        table.program_vector(0, &entries[0]);
+        assert!(masked(&entries[0]));
+        assert!(table.revalidate_targets(valid_target));
+        assert!(!table.revalidate_targets(valid_target));
+        table.program_vector(0, &entries[0]);
+        assert!(!masked(&entries[0]));
+        assert!(table.revalidate_targets(no_target));
+        table.program_vector(0, &entries[0]);
+        assert!(masked(&entries[0]));
+
+        // Out of range.
+        assert_eq!(table.write_dword(0x20, 0, valid_target), None);
+        assert_eq!(table.read_dword(0x20), 0);
+
+        table.reset();
+        assert_eq!(table.read_dword(0xc), 1);
+        assert_eq!(table.read_dword(0x0), 0);
+    }
+}
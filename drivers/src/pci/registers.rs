@@ -63,7 +63,12 @@ register_bitfields![u16,
     ],
 
     pub PmControlStatus [
-        PowerState OFFSET(0) NUMBITS(2),
+        PowerState OFFSET(0) NUMBITS(2) [
+            D0 = 0,
+            D1 = 1,
+            D2 = 2,
+            D3Hot = 3,
+        ],
         NoSoftReset OFFSET(3) NUMBITS(1),
         PmeEn OFFSET(8) NUMBITS(1),
         DataSelect OFFSET(9) NUMBITS(4),
@@ -103,14 +108,96 @@ register_bitfields![u16,
         FunctionLevelReset OFFSET(15) NUMBITS(1),
     ],
 
+    pub DeviceStatus [
+        TransactionsPending OFFSET(5) NUMBITS(1),
+    ],
+
     pub LinkStatus [
         LinkSpeed OFFSET(0) NUMBITS(4),
         LinkWidth OFFSET(4) NUMBITS(6),
+        DataLinkLayerLinkActive OFFSET(13) NUMBITS(1),
+    ],
+
+    pub SlotControl [
+        AttentionButtonPressedEnable OFFSET(0) NUMBITS(1),
+        PowerFaultDetectedEnable OFFSET(1) NUMBITS(1),
+        MrlSensorChangedEnable OFFSET(2) NUMBITS(1),
+        PresenceDetectChangedEnable OFFSET(3) NUMBITS(1),
+        CommandCompletedInterruptEnable OFFSET(4) NUMBITS(1),
+        HotPlugInterruptEnable OFFSET(5) NUMBITS(1),
+        AttentionIndicatorControl OFFSET(6) NUMBITS(2),
+        PowerIndicatorControl OFFSET(8) NUMBITS(2),
+        PowerControllerControl OFFSET(10) NUMBITS(1) [
+            On = 0,
+            Off = 1,
+        ],
+        ElectromechanicalInterlockControl OFFSET(11) NUMBITS(1),
+        DataLinkLayerStateChangedEnable OFFSET(12) NUMBITS(1),
+    ],
+
+    pub SlotStatus [
+        AttentionButtonPressed OFFSET(0) NUMBITS(1),
+        PowerFaultDetected OFFSET(1) NUMBITS(1),
+        MrlSensorChanged OFFSET(2) NUMBITS(1),
+        PresenceDetectChanged OFFSET(3) NUMBITS(1),
+        CommandCompleted OFFSET(4) NUMBITS(1),
+        MrlSensorState OFFSET(5) NUMBITS(1),
+        PresenceDetectState OFFSET(6) NUMBITS(1),
+        ElectromechanicalInterlockStatus OFFSET(7) NUMBITS(1),
+        DataLinkLayerStateChanged OFFSET(8) NUMBITS(1),
     ],
 
     pub MemWindow [
+        AddressWidth OFFSET(0) NUMBITS(4) [
+            Bits32 = 0,
+            Bits64 = 1,
+        ],
         Address OFFSET(4) NUMBITS(12) [],
     ],
+
+    pub AtsCapabilities [
+        InvalidateQueueDepth OFFSET(0) NUMBITS(5),
+        PageAlignedRequest OFFSET(5) NUMBITS(1),
+        GlobalInvalidateSupported OFFSET(6) NUMBITS(1),
+        RelaxedOrderingSupported OFFSET(7) NUMBITS(1),
+    ],
+
+    pub AtsControl [
+        SmallestTranslationUnit OFFSET(0) NUMBITS(5),
+        Enable OFFSET(15) NUMBITS(1),
+    ],
+
+    pub SriovControl [
+        VfEnable OFFSET(0) NUMBITS(1),
+        VfMigrationEnable OFFSET(1) NUMBITS(1),
+        VfMigrationInterruptEnable OFFSET(2) NUMBITS(1),
+        VfMemorySpaceEnable OFFSET(3) NUMBITS(1),
+        AriCapableHierarchy OFFSET(4) NUMBITS(1),
+    ],
+
+    pub PriControl [
+        Enable OFFSET(0) NUMBITS(1),
+        Reset OFFSET(1) NUMBITS(1),
+    ],
+
+    pub PriStatus [
+        ResponseFailure OFFSET(0) NUMBITS(1),
+        UnexpectedPrgIndex OFFSET(1) NUMBITS(1),
+        Stopped OFFSET(8) NUMBITS(1),
+        PrgResponsePasidRequired OFFSET(15) NUMBITS(1),
+    ],
+
+    pub PasidCapabilities [
+        ExecutePermissionSupported OFFSET(1) NUMBITS(1),
+        PrivilegedModeSupported OFFSET(2) NUMBITS(1),
+        MaxPasidWidth OFFSET(8) NUMBITS(5),
+    ],
+
+    pub PasidControl [
+        Enable OFFSET(0) NUMBITS(1),
+        ExecutePermissionEnable OFFSET(1) NUMBITS(1),
+        PrivilegedModeEnable OFFSET(2) NUMBITS(1),
+    ],
 ];
 
 register_bitfields![u8,
@@ -129,6 +216,10 @@ register_bitfields![u8,
     ],
 
     pub IoWindow [
+        AddressWidth OFFSET(0) NUMBITS(4) [
+            Bits16 = 0,
+            Bits32 = 1,
+        ],
         Address OFFSET(4) NUMBITS(4) [],
     ],
 ];
@@ -155,10 +246,58 @@ register_bitfields![u32,
     pub LinkCapabilities [
         MaxLinkSpeed OFFSET(0) NUMBITS(4),
         MaxLinkWidth OFFSET(4) NUMBITS(6),
+        DataLinkLayerLinkActiveReportingCapable OFFSET(20) NUMBITS(1),
         PortNumber OFFSET(24) NUMBITS(8),
     ],
+
+    pub SlotCapabilities [
+        AttentionButtonPresent OFFSET(0) NUMBITS(1),
+        PowerControllerPresent OFFSET(1) NUMBITS(1),
+        MrlSensorPresent OFFSET(2) NUMBITS(1),
+        AttentionIndicatorPresent OFFSET(3) NUMBITS(1),
+        PowerIndicatorPresent OFFSET(4) NUMBITS(1),
+        HotPlugSurprise OFFSET(5) NUMBITS(1),
+        HotPlugCapable OFFSET(6) NUMBITS(1),
+        SlotPowerLimitValue OFFSET(7) NUMBITS(8),
+        SlotPowerLimitScale OFFSET(15) NUMBITS(2),
+        ElectromechanicalInterlockPresent OFFSET(17) NUMBITS(1),
+        NoCommandCompletedSupport OFFSET(18) NUMBITS(1),
+        PhysicalSlotNumber OFFSET(19) NUMBITS(13),
+    ],
+
+    pub ExtendedCapability [
+        Id OFFSET(0) NUMBITS(16),
+        Version OFFSET(16) NUMBITS(4),
+        NextCap OFFSET(20) NUMBITS(12),
+    ],
+
+    pub AerCapabilitiesControl [
+        FirstErrorPointer OFFSET(0) NUMBITS(5),
+        EcrcGenerationCapable OFFSET(5) NUMBITS(1),
+        EcrcGenerationEnable OFFSET(6) NUMBITS(1),
+        EcrcCheckCapable OFFSET(7) NUMBITS(1),
+        EcrcCheckEnable OFFSET(8) NUMBITS(1),
+    ],
+
+    pub DvsecHeader1 [
+        VendorId OFFSET(0) NUMBITS(16),
+        Revision OFFSET(16) NUMBITS(4),
+        Length OFFSET(20) NUMBITS(12),
+    ],
+
+    pub MsiXTableOffset [
+        Bir OFFSET(0) NUMBITS(3),
+        Offset OFFSET(3) NUMBITS(29),
+    ],
+
+    pub MsiXVectorControl [
+        Masked OFFSET(0) NUMBITS(1),
+    ],
 ];
 
+/// The power states of a PCI function.
+pub type PowerState = PmControlStatus::PowerState::Value;
+
 /// Common portion of the PCI configuration header.
 #[repr(C)]
 #[derive(FieldOffsets)]
@@ -254,6 +393,19 @@ pub const PCI_CONFIG_SPACE_END: usize = 0xff;
 /// The maximum number of bytes that can be occupied by PCI capability structures.
 pub const PCI_MAX_CAP_LENGTH: usize = PCI_CONFIG_SPACE_END - PCI_CAPS_START + 1;
 
+/// PCI Express extended capability header.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct ExtendedCapabilityHeader {
+    pub header: ReadOnly<u32, ExtendedCapability::Register>,
+}
+
+/// Start byte offset of the PCI Express extended configuration space, where extended capability
+/// structures are located.
+pub const PCIE_EXT_CAPS_START: usize = PCI_CONFIG_SPACE_END + 1;
+/// End byte offset of the PCI Express extended configuration space.
+pub const PCIE_CONFIG_SPACE_END: usize = 0xfff;
+
 /// PCI power management capability.
 #[repr(C)]
 #[derive(FieldOffsets)]
@@ -285,8 +437,18 @@ pub struct MsiRegisters {
 pub struct MsiXRegisters {
     pub header: CapabilityHeader,
     pub msg_control: ReadWrite<u16, MsiXMessageControl::Register>,
-    pub table_offset: ReadOnly<u32>,
-    pub pba_offset: ReadOnly<u32>,
+    pub table_offset: ReadOnly<u32, MsiXTableOffset::Register>,
+    pub pba_offset: ReadOnly<u32, MsiXTableOffset::Register>,
+}
+
+/// An entry in the MSI-X table, which is located in one of the function's BARs.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct MsiXTableEntry {
+    pub msg_addr: ReadWrite<u32>,
+    pub msg_upper_addr: ReadWrite<u32>,
+    pub msg_data: ReadWrite<u32>,
+    pub vector_control: ReadWrite<u32, MsiXVectorControl::Register>,
 }
 
 /// Vendor-specific capability. These capabilities are dynamically-sized.
@@ -320,16 +482,15 @@ pub struct ExpressRegisters {
     pub exp_caps: ReadOnly<u16, ExpressCapabilities::Register>,
     pub dev_caps: ReadOnly<u32, DeviceCapabilities::Register>,
     pub dev_control: ReadWrite<u16, DeviceControl::Register>,
-    pub dev_status: ReadWrite<u16>,
+    pub dev_status: ReadWrite<u16, DeviceStatus::Register>,
     // All devices with links. We only expose the link speed and width.
     pub link_caps: ReadOnly<u32, LinkCapabilities::Register>,
     pub link_control: ReadWrite<u16>,
     pub link_status: ReadWrite<u16, LinkStatus::Register>,
-    // All devices with slots. We hide the FLAGS_SLOT bit, so these registers will appear as
-    // unimplemented to a VM.
-    pub slot_caps: ReadOnly<u32>,
-    pub slot_control: ReadWrite<u16>,
-    pub slot_status: ReadWrite<u16>,
+    // All devices with slots. Only exposed for root and downstream ports.
+    pub slot_caps: ReadOnly<u32, SlotCapabilities::Register>,
+    pub slot_control: ReadWrite<u16, SlotControl::Register>,
+    pub slot_status: ReadWrite<u16, SlotStatus::Register>,
     // All root ports or root complex event collectors. We don't expose any of the capabilities here.
     pub root_control: ReadWrite<u16>,
     pub root_caps: ReadOnly<u16>,
@@ -346,6 +507,91 @@ pub struct ExpressRegisters {
     pub slot_status2: ReadOnly<u16>,
 }
 
+/// Advanced Error Reporting extended capability. We only cover the registers implemented by all
+/// functions; the root port registers and the TLP prefix log are left unimplemented.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct AerRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub uncor_status: ReadWrite<u32>,
+    pub uncor_mask: ReadWrite<u32>,
+    pub uncor_severity: ReadWrite<u32>,
+    pub cor_status: ReadWrite<u32>,
+    pub cor_mask: ReadWrite<u32>,
+    pub caps_control: ReadWrite<u32, AerCapabilitiesControl::Register>,
+    pub header_log: [ReadOnly<u32>; 4],
+}
+
+/// Access Control Services extended capability. The egress control vector is not exposed.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct AcsRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub acs_caps: ReadOnly<u16>,
+    pub acs_control: ReadWrite<u16>,
+}
+
+/// Address Translation Services extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct AtsRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub ats_caps: ReadOnly<u16, AtsCapabilities::Register>,
+    pub ats_control: ReadWrite<u16, AtsControl::Register>,
+}
+
+/// Single Root I/O Virtualization extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct SriovRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub sriov_caps: ReadOnly<u32>,
+    pub sriov_control: ReadWrite<u16, SriovControl::Register>,
+    pub sriov_status: ReadWrite<u16>,
+    pub initial_vfs: ReadOnly<u16>,
+    pub total_vfs: ReadOnly<u16>,
+    pub num_vfs: ReadWrite<u16>,
+    pub func_dep_link: ReadOnly<u8>,
+    _reserved: u8,
+    pub first_vf_offset: ReadOnly<u16>,
+    pub vf_stride: ReadOnly<u16>,
+    _reserved2: u16,
+    pub vf_device_id: ReadOnly<u16>,
+    pub supported_page_sizes: ReadOnly<u32>,
+    pub system_page_size: ReadWrite<u32>,
+    pub vf_bar: [ReadWrite<u32, BaseAddress::Register>; PCI_ENDPOINT_BARS],
+    pub migration_state: ReadOnly<u32>,
+}
+
+/// Page Request Interface extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct PriRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub pri_control: ReadWrite<u16, PriControl::Register>,
+    pub pri_status: ReadWrite<u16, PriStatus::Register>,
+    pub request_capacity: ReadOnly<u32>,
+    pub request_allocation: ReadWrite<u32>,
+}
+
+/// Process Address Space ID extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct PasidRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub pasid_caps: ReadOnly<u16, PasidCapabilities::Register>,
+    pub pasid_control: ReadWrite<u16, PasidControl::Register>,
+}
+
+/// Designated vendor-specific extended capability. These capabilities are dynamically-sized.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct DvsecRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub dvsec_header1: ReadOnly<u32, DvsecHeader1::Register>,
+    pub dvsec_header2: ReadOnly<u16>,
+}
+
 /// Trait for specifying various mask values for a register.
 ///
 /// TODO: Make the `*_mask()` functions const values.
@@ -473,9 +719,7 @@ impl RegisterMasks for BridgeControl::Register {
     }
 }
 
-// Hide D1/D2 and PME support for now.
-//
-// TODO: Support power management control by VMs.
+// Hide D1/D2 and PME support for now. VMs may only move the device between D0 and D3hot.
 impl RegisterMasks for PmCapabilities::Register {
     type RegType = u16;
 
@@ -497,7 +741,8 @@ impl RegisterMasks for PmCapabilities::Register {
     }
 }
 
-// Don't expose the ability to make power state transitions.
+// Power state transitions are virtualized since the hypervisor needs to wait for them to complete
+// and may need to re-check the state of the device afterwards.
 impl RegisterMasks for PmControlStatus::Register {
     type RegType = u16;
 
@@ -507,6 +752,7 @@ impl RegisterMasks for PmControlStatus::Register {
 
     fn readable_mask() -> u16 {
         let mut mask = LocalRegisterCopy::<u16, PmControlStatus::Register>::new(0);
+        mask.modify(PmControlStatus::PowerState.val(PmControlStatus::PowerState.mask));
         mask.modify(PmControlStatus::NoSoftReset.val(1));
         mask.get()
     }
@@ -570,7 +816,6 @@ impl RegisterMasks for MsiXMessageControl::Register {
     }
 }
 
-// Hide FLAGS_SLOT so that we can leave the slot registers unimplemented.
 impl RegisterMasks for ExpressCapabilities::Register {
     type RegType = u16;
 
@@ -582,6 +827,7 @@ impl RegisterMasks for ExpressCapabilities::Register {
         let mut mask = LocalRegisterCopy::<u16, ExpressCapabilities::Register>::new(0);
         mask.modify(ExpressCapabilities::Version.val(ExpressCapabilities::Version.mask));
         mask.modify(ExpressCapabilities::DeviceType.val(ExpressCapabilities::DeviceType.mask));
+        mask.modify(ExpressCapabilities::SlotImplemented.val(1));
         mask.modify(
             ExpressCapabilities::InterruptMessageNumber
                 .val(ExpressCapabilities::InterruptMessageNumber.mask),
@@ -594,10 +840,8 @@ impl RegisterMasks for ExpressCapabilities::Register {
     }
 }
 
-// Hide everything but MPS for now. Phantom functions, extended tags, etc could affect requester IDs
-// and confuse the IOMMU.
-//
-// TODO: Expose function-level reset capability.
+// Hide everything but MPS and FLR for now. Phantom functions, extended tags, etc could affect
+// requester IDs and confuse the IOMMU.
 impl RegisterMasks for DeviceCapabilities::Register {
     type RegType = u32;
 
@@ -610,6 +854,7 @@ impl RegisterMasks for DeviceCapabilities::Register {
         mask.modify(
             DeviceCapabilities::MaxPayloadSize.val(DeviceCapabilities::MaxPayloadSize.mask),
         );
+        mask.modify(DeviceCapabilities::FunctionLevelReset.val(1));
         mask.get()
     }
 
@@ -618,9 +863,8 @@ impl RegisterMasks for DeviceCapabilities::Register {
     }
 }
 
-// Allow reads from (but not writes to) payload size fields since they can have system-wide effects
-//
-// TODO: Allow function-level resets.
+// Allow reads from (but not writes to) payload size fields since they can have system-wide effects.
+// Function-level resets are virtualized since the hypervisor must wait for the reset to complete.
 impl RegisterMasks for DeviceControl::Register {
     type RegType = u16;
 
@@ -644,7 +888,7 @@ impl RegisterMasks for DeviceControl::Register {
     }
 }
 
-// Hide everything but link speed/width and port number.
+// Hide everything but link speed/width, link active reporting and port number.
 impl RegisterMasks for LinkCapabilities::Register {
     type RegType = u32;
 
@@ -656,6 +900,7 @@ impl RegisterMasks for LinkCapabilities::Register {
         let mut mask = LocalRegisterCopy::<u32, LinkCapabilities::Register>::new(0);
         mask.modify(LinkCapabilities::MaxLinkSpeed.val(LinkCapabilities::MaxLinkSpeed.mask));
         mask.modify(LinkCapabilities::MaxLinkWidth.val(LinkCapabilities::MaxLinkWidth.mask));
+        mask.modify(LinkCapabilities::DataLinkLayerLinkActiveReportingCapable.val(1));
         mask.modify(LinkCapabilities::PortNumber.val(LinkCapabilities::PortNumber.mask));
         mask.get()
     }
@@ -665,7 +910,7 @@ impl RegisterMasks for LinkCapabilities::Register {
     }
 }
 
-// Like LNKCAP, hide everything except link speed/width.
+// Like LNKCAP, hide everything except link speed/width and whether the link is active.
 impl RegisterMasks for LinkStatus::Register {
     type RegType = u16;
 
@@ -677,9 +922,270 @@ impl RegisterMasks for LinkStatus::Register {
         let mut mask = LocalRegisterCopy::<u16, LinkStatus::Register>::new(0);
         mask.modify(LinkStatus::LinkSpeed.val(LinkStatus::LinkSpeed.mask));
         mask.modify(LinkStatus::LinkWidth.val(LinkStatus::LinkWidth.mask));
+        mask.modify(LinkStatus::DataLinkLayerLinkActive.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+// The slot registers are passed through to the VM that owns the port so that it can drive hotplug
+// of the devices in the slot. Salus itself only reacts to the resulting changes in presence.
+impl RegisterMasks for SlotCapabilities::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        0
+    }
+
+    fn readable_mask() -> u32 {
+        !0
+    }
+
+    fn clearable_mask() -> u32 {
+        0
+    }
+}
+
+impl RegisterMasks for SlotControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SlotControl::Register>::new(0);
+        mask.modify(SlotControl::AttentionButtonPressedEnable.val(1));
+        mask.modify(SlotControl::PowerFaultDetectedEnable.val(1));
+        mask.modify(SlotControl::MrlSensorChangedEnable.val(1));
+        mask.modify(SlotControl::PresenceDetectChangedEnable.val(1));
+        mask.modify(SlotControl::CommandCompletedInterruptEnable.val(1));
+        mask.modify(SlotControl::HotPlugInterruptEnable.val(1));
+        mask.modify(
+            SlotControl::AttentionIndicatorControl.val(SlotControl::AttentionIndicatorControl.mask),
+        );
+        mask.modify(
+            SlotControl::PowerIndicatorControl.val(SlotControl::PowerIndicatorControl.mask),
+        );
+        mask.modify(SlotControl::PowerControllerControl.val(1));
+        mask.modify(SlotControl::ElectromechanicalInterlockControl.val(1));
+        mask.modify(SlotControl::DataLinkLayerStateChangedEnable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+impl RegisterMasks for SlotStatus::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SlotStatus::Register>::new(0);
+        mask.modify(SlotStatus::AttentionButtonPressed.val(1));
+        mask.modify(SlotStatus::PowerFaultDetected.val(1));
+        mask.modify(SlotStatus::MrlSensorChanged.val(1));
+        mask.modify(SlotStatus::PresenceDetectChanged.val(1));
+        mask.modify(SlotStatus::CommandCompleted.val(1));
+        mask.modify(SlotStatus::DataLinkLayerStateChanged.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SlotStatus::Register>::new(Self::writeable_mask());
+        mask.modify(SlotStatus::MrlSensorState.val(1));
+        mask.modify(SlotStatus::PresenceDetectState.val(1));
+        mask.modify(SlotStatus::ElectromechanicalInterlockStatus.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+}
+
+// Expose the ECRC controls, which only affect the link the device is on.
+impl RegisterMasks for AerCapabilitiesControl::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, AerCapabilitiesControl::Register>::new(0);
+        mask.modify(AerCapabilitiesControl::EcrcGenerationEnable.val(1));
+        mask.modify(AerCapabilitiesControl::EcrcCheckEnable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u32 {
+        let mut mask =
+            LocalRegisterCopy::<u32, AerCapabilitiesControl::Register>::new(Self::writeable_mask());
+        mask.modify(
+            AerCapabilitiesControl::FirstErrorPointer
+                .val(AerCapabilitiesControl::FirstErrorPointer.mask),
+        );
+        mask.modify(AerCapabilitiesControl::EcrcGenerationCapable.val(1));
+        mask.modify(AerCapabilitiesControl::EcrcCheckCapable.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u32 {
+        0
+    }
+}
+
+impl RegisterMasks for AtsCapabilities::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        0
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, AtsCapabilities::Register>::new(0);
+        mask.modify(
+            AtsCapabilities::InvalidateQueueDepth.val(AtsCapabilities::InvalidateQueueDepth.mask),
+        );
+        mask.modify(AtsCapabilities::PageAlignedRequest.val(1));
+        mask.modify(AtsCapabilities::GlobalInvalidateSupported.val(1));
+        mask.modify(AtsCapabilities::RelaxedOrderingSupported.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+// ATS may be enabled by the device's owner. The IOMMU only accepts translation requests from
+// devices it has enabled ATS for, in which case it invalidates the device's TLB along with its own
+// translation caches.
+impl RegisterMasks for AtsControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, AtsControl::Register>::new(0);
+        mask.modify(
+            AtsControl::SmallestTranslationUnit.val(AtsControl::SmallestTranslationUnit.mask),
+        );
+        mask.modify(AtsControl::Enable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+// VF Enable and VF MSE are virtualized: the hypervisor enables the VFs once it has validated the
+// request. Migration isn't supported, and the ARI Capable Hierarchy bit is left as programmed by
+// firmware since it changes the routing IDs of the VFs.
+impl RegisterMasks for SriovControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        0
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SriovControl::Register>::new(0);
+        mask.modify(SriovControl::VfEnable.val(1));
+        mask.modify(SriovControl::VfMemorySpaceEnable.val(1));
+        mask.modify(SriovControl::AriCapableHierarchy.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+impl RegisterMasks for PriControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PriControl::Register>::new(0);
+        mask.modify(PriControl::Enable.val(1));
+        mask.modify(PriControl::Reset.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PriControl::Register>::new(0);
+        mask.modify(PriControl::Enable.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+impl RegisterMasks for PriStatus::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PriStatus::Register>::new(0);
+        mask.modify(PriStatus::ResponseFailure.val(1));
+        mask.modify(PriStatus::UnexpectedPrgIndex.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PriStatus::Register>::new(Self::writeable_mask());
+        mask.modify(PriStatus::Stopped.val(1));
+        mask.modify(PriStatus::PrgResponsePasidRequired.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+}
+
+impl RegisterMasks for PasidCapabilities::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        0
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PasidCapabilities::Register>::new(0);
+        mask.modify(PasidCapabilities::ExecutePermissionSupported.val(1));
+        mask.modify(PasidCapabilities::PrivilegedModeSupported.val(1));
+        mask.modify(PasidCapabilities::MaxPasidWidth.val(PasidCapabilities::MaxPasidWidth.mask));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+// PASIDs may be enabled by the device's owner. The IOMMU rejects PASID-tagged requests from the
+// device until its owner sets up a process directory for it.
+impl RegisterMasks for PasidControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PasidControl::Register>::new(0);
+        mask.modify(PasidControl::Enable.val(1));
+        mask.modify(PasidControl::ExecutePermissionEnable.val(1));
+        mask.modify(PasidControl::PrivilegedModeEnable.val(1));
         mask.get()
     }
 
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
     fn clearable_mask() -> u16 {
         0
     }
@@ -714,6 +1220,10 @@ fn _assert_register_layout() {
     const_assert!(core::mem::size_of::<CommonRegisters>() == 0x10);
     const_assert!(core::mem::size_of::<EndpointRegisters>() == 0x40);
     const_assert!(core::mem::size_of::<BridgeRegisters>() == 0x40);
+    const_assert!(core::mem::size_of::<AerRegisters>() == 0x2c);
+    const_assert!(core::mem::size_of::<SriovRegisters>() == 0x40);
+    const_assert!(core::mem::size_of::<PriRegisters>() == 0x10);
+    const_assert!(core::mem::size_of::<MsiXTableEntry>() == 0x10);
 }
 
 /// Macro that itself defines a `span!()` macro for the given struct field which evaluates to a
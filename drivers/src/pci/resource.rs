@@ -128,6 +128,8 @@ pub struct PciRootResource {
     host_size: u64,
     // Total size of the resource including space allocated by the hypervisor.
     total_size: u64,
+    // Size of the start of the resource assigned to devices that are passed through to the host.
+    host_assigned: u64,
     // Whether or not the resource has been claimed by the host.
     taken: bool,
     pci_addr: u64,
@@ -140,6 +142,7 @@ impl PciRootResource {
             addr,
             host_size: size,
             total_size: size,
+            host_assigned: 0,
             taken: false,
             pci_addr,
         }
@@ -173,11 +176,15 @@ impl PciRootResource {
         // not in the middle of page.
         let size = core::cmp::max(size, PageSize::Size4k as u64).next_power_of_two();
         // The start address must be `size`-aligned.
-        self.host_size = self
+        let host_size = self
             .host_size
             .checked_sub(size)
             .ok_or(Error::OutOfResources)?
             & !(size - 1);
+        if host_size < self.host_assigned {
+            return Err(Error::OutOfResources);
+        }
+        self.host_size = host_size;
         // Unwrap ok since `host_size` is less than what it was before and it must've been valid
         // to begin with.
         Ok(self
@@ -186,6 +193,40 @@ impl PciRootResource {
             .unwrap())
     }
 
+    /// Allocates `size` bytes aligned to `align` from the start of the host-exposed portion of the
+    /// resource for assignment to the BARs or bridge windows of host devices. `align` must be a
+    /// power of two. Returns the PCI bus address of the allocation.
+    pub fn alloc_for_host(&mut self, size: u64, align: u64) -> Result<u64> {
+        let offset = self
+            .pci_addr
+            .checked_add(self.host_assigned)
+            .and_then(|a| a.checked_add(align - 1))
+            .ok_or(Error::OutOfResources)?
+            & !(align - 1);
+        let offset = offset - self.pci_addr;
+        let end = offset.checked_add(size).ok_or(Error::OutOfResources)?;
+        if end > self.host_size {
+            return Err(Error::OutOfResources);
+        }
+        self.host_assigned = end;
+        Ok(self.pci_addr + offset)
+    }
+
+    /// Marks the host-exposed portion of the resource up to and including the PCI bus address
+    /// `limit` as assigned to host devices, so that later allocations are placed after it. Used to
+    /// keep the assignments made by firmware.
+    pub fn reserve_for_host(&mut self, limit: u64) -> Result<()> {
+        let end = limit
+            .checked_sub(self.pci_addr)
+            .and_then(|offset| offset.checked_add(1))
+            .ok_or(Error::OutOfResources)?;
+        if end > self.host_size {
+            return Err(Error::OutOfResources);
+        }
+        self.host_assigned = self.host_assigned.max(end);
+        Ok(())
+    }
+
     /// Marks the host-exposed portion of the resource as exclusively allocated.
     pub fn take_for_host(&mut self) -> Result<()> {
         if self.taken {
@@ -293,3 +334,32 @@ impl Default for PciRootResources {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use riscv_pages::RawAddr;
+
+    #[test]
+    fn host_and_hypervisor_allocation() {
+        let addr = SupervisorPageAddr::new(RawAddr::supervisor(0x4000_0000)).unwrap();
+        let mut resource = PciRootResource::new(addr, 0x100_0000, 0x4000_0000);
+        assert_eq!(
+            resource.alloc_for_host(0x1000, 0x1000).unwrap(),
+            0x4000_0000
+        );
+        assert_eq!(
+            resource.alloc_for_host(0x20_0000, 0x20_0000).unwrap(),
+            0x4020_0000
+        );
+        assert!(resource.alloc_for_hypervisor(0x80_0000).is_ok());
+        assert_eq!(resource.host_size(), 0x80_0000);
+        assert!(resource.alloc_for_host(0x80_0000, 0x80_0000).is_err());
+        assert_eq!(
+            resource.alloc_for_host(0x1000, 0x1000).unwrap(),
+            0x4040_0000
+        );
+        // The hypervisor can't take space that's already been assigned to host devices.
+        assert!(resource.alloc_for_hypervisor(0x40_0000).is_err());
+    }
+}
@@ -3,22 +3,27 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use alloc::alloc::Global;
+use alloc::vec::Vec;
 use arrayvec::{ArrayString, ArrayVec};
 use core::marker::PhantomData;
-use device_tree::{DeviceTree, DeviceTreeResult};
+use device_tree::{DeviceTree, DeviceTreeNode, DeviceTreeResult};
 use hyp_alloc::{Arena, ArenaId};
 use page_tracking::{HwMemMap, PageTracker};
 use riscv_pages::*;
-use sync::{Mutex, Once};
+use sync::{Mutex, MutexGuard, Once};
 
+use crate::cpu::delay_ms;
 use crate::imsic::Imsic;
 
 use super::address::*;
+use super::allocator::{assign_resources, assign_secondary_bus};
 use super::bus::PciBus;
+use super::capabilities::MAX_SRIOV_VFS;
 use super::config_space::PciConfigSpace;
 use super::device::*;
 use super::error::*;
 use super::mmio_builder::MmioEmulationContext;
+use super::registers::{SriovControl, PCI_ENDPOINT_BARS};
 use super::resource::*;
 
 /// An arena of PCI devices.
@@ -31,6 +36,24 @@ pub type PciArenaId = ArenaId<Mutex<PciDevice>>;
 const PCI_ADDR_CELLS: usize = 3;
 // Number of u32 cells per 'ranges' property in the device tree.
 const CELLS_PER_RANGE: usize = PCI_ADDR_CELLS + 4;
+// Number of u32 cells per 'iommu-map' entry: the requester ID base, the IOMMU phandle, the IOMMU
+// specifier base and the length.
+const CELLS_PER_IOMMU_MAP_ENTRY: usize = 4;
+// The maximum number of 'iommu-map' entries we support per root complex.
+const MAX_IOMMU_MAP_ENTRIES: usize = 8;
+// The maximum number of IOMMU functions described in the device tree per root complex.
+const MAX_PCI_IOMMUS: usize = 4;
+
+/// The maximum number of PCIe root complexes we support.
+pub const MAX_PCIE_ROOTS: usize = 8;
+
+// Time to wait for outstanding transactions to complete before resetting a device that changes
+// owners.
+const FLR_TRANSACTIONS_PENDING_MS: u64 = 100;
+
+// The time it takes SR-IOV virtual functions to become ready for config requests after VF Enable
+// is set.
+const VF_ENABLE_READY_MS: u64 = 100;
 
 /// Represents a PCI-Express root complex.
 pub struct PcieRoot {
@@ -39,9 +62,22 @@ pub struct PcieRoot {
     device_arena: PciDeviceArena,
     resources: Mutex<PciRootResources>,
     msi_parent_phandle: u32,
+    iommu_map: Option<ArrayVec<IommuMapEntry, MAX_IOMMU_MAP_ENTRIES>>,
+    pci_iommus: ArrayVec<(Address, u32), MAX_PCI_IOMMUS>,
+    hotplug_ports: Vec<PciArenaId>,
+    hotplug_changes: Mutex<Vec<PciArenaId>>,
 }
 
-static PCIE_ROOT: Once<PcieRoot> = Once::new();
+// An 'iommu-map' entry, mapping `length` requester IDs starting at `rid_base` to the IOMMU with
+// `phandle`.
+#[derive(Clone, Copy, Debug)]
+struct IommuMapEntry {
+    rid_base: u32,
+    phandle: u32,
+    length: u32,
+}
+
+static PCIE_ROOTS: Once<ArrayVec<PcieRoot, MAX_PCIE_ROOTS>> = Once::new();
 
 // A `u64` from two `u32` cells in a device tree.
 struct U64Cell(u32, u32);
@@ -59,13 +95,35 @@ fn valid_config_mmio_access(offset: u64, len: usize) -> bool {
 }
 
 impl PcieRoot {
-    /// Creates a `PcieRoot` singleton by finding a supported configuration in the passed `DeviceTree`.
+    /// Creates a `PcieRoot` for each supported configuration in the passed `DeviceTree`. Each root
+    /// complex must be in a distinct PCI segment.
     pub fn probe_from(dt: &DeviceTree, mem_map: &mut HwMemMap) -> Result<()> {
-        let pci_node = dt
+        let mut roots = ArrayVec::<PcieRoot, MAX_PCIE_ROOTS>::new();
+        for (index, pci_node) in dt
             .iter()
-            .find(|n| n.compatible(["pci-host-ecam-generic"]) && !n.disabled())
-            .ok_or(Error::NoCompatibleHostNode)?;
+            .filter(|n| n.compatible(["pci-host-ecam-generic"]) && !n.disabled())
+            .enumerate()
+        {
+            let root = Self::probe_node(dt, pci_node, index, mem_map)?;
+            if roots.iter().any(|r| r.segment() == root.segment()) {
+                return Err(Error::DuplicateSegment(root.segment()));
+            }
+            roots.try_push(root).map_err(|_| Error::TooManyRoots)?;
+        }
+        if roots.is_empty() {
+            return Err(Error::NoCompatibleHostNode);
+        }
+        PCIE_ROOTS.call_once(|| roots);
+        Ok(())
+    }
 
+    // Creates a `PcieRoot` from the `index`th compatible host node, `pci_node`, in `dt`.
+    fn probe_node(
+        dt: &DeviceTree,
+        pci_node: &DeviceTreeNode,
+        index: usize,
+        mem_map: &mut HwMemMap,
+    ) -> Result<Self> {
         // Find the ECAM MMIO region, which should be the first entry in the `reg` property.
         let mut regs = pci_node
             .props()
@@ -129,13 +187,15 @@ impl PcieRoot {
             }
         };
 
-        // Find the segment number specified in the device tree, if any.
-        let segment = pci_node
+        // Find the segment number specified in the device tree. Like Linux, we number the segments
+        // in device tree order if it isn't specified.
+        let segment_index = pci_node
             .props()
             .find(|p| p.name() == "linux,pci-domain")
             .and_then(|p| p.value_u32().next())
-            .and_then(|v| Segment::try_from(v).ok())
-            .unwrap_or_default();
+            .unwrap_or(index as u32);
+        let segment =
+            Segment::try_from(segment_index).map_err(|_| Error::InvalidSegment(segment_index))?;
         let config_space = PciConfigSpace::new(config_base, config_size, segment, bus_range);
 
         // Parse the 'ranges' property for the various BAR resources. Assuming '#address-cells' is 3
@@ -188,24 +248,186 @@ impl PcieRoot {
             }
         }
 
+        // Find which IOMMU translates DMA from each function below this root complex. Device IDs are
+        // derived from the segment and routing ID of a function, so the map must not remap routing
+        // IDs.
+        let iommu_map = match pci_node.props().find(|p| p.name() == "iommu-map") {
+            Some(p) => {
+                let mut cells = p.value_u32();
+                if cells.len() % CELLS_PER_IOMMU_MAP_ENTRY != 0 {
+                    return Err(Error::InvalidIommuMap);
+                }
+                let mut entries = ArrayVec::new();
+                while let (Some(rid_base), Some(phandle), Some(iommu_base), Some(length)) =
+                    (cells.next(), cells.next(), cells.next(), cells.next())
+                {
+                    if iommu_base != rid_base {
+                        return Err(Error::InvalidIommuMap);
+                    }
+                    let entry = IommuMapEntry {
+                        rid_base,
+                        phandle,
+                        length,
+                    };
+                    entries
+                        .try_push(entry)
+                        .map_err(|_| Error::InvalidIommuMap)?;
+                }
+                Some(entries)
+            }
+            None => None,
+        };
+        // IOMMUs which are themselves PCI functions are described by a child node of the host node
+        // whose address is that of the function.
+        let mut pci_iommus = ArrayVec::new();
+        for node in pci_node
+            .children()
+            .filter_map(|&id| dt.get_node(id))
+            .filter(|n| n.compatible(["riscv,pci-iommu"]) && !n.disabled())
+        {
+            let rid = node
+                .props()
+                .find(|p| p.name() == "reg")
+                .and_then(|p| p.value_u32().next())
+                .map(|cell| (cell >> 8) & 0xffff);
+            let phandle = node
+                .props()
+                .find(|p| p.name() == "phandle")
+                .and_then(|p| p.value_u32().next());
+            if let (Some(rid), Some(phandle)) = (rid, phandle) {
+                // Unwrap ok: `rid` is a 16-bit routing ID.
+                let address = Address::try_from_components(
+                    segment.bits(),
+                    rid >> 8,
+                    (rid >> 3) & 0x1f,
+                    rid & 0x7,
+                )
+                .unwrap();
+                pci_iommus
+                    .try_push((address, phandle))
+                    .map_err(|_| Error::TooManyIommus)?;
+            }
+        }
+
+        Self::new(
+            config_space,
+            resources,
+            msi_parent_phandle,
+            iommu_map,
+            pci_iommus,
+        )
+    }
+
+    // Creates a `PcieRoot` by enumerating the PCI hierarchy in `config_space` and assigning it
+    // resources from `resources`.
+    fn new(
+        config_space: PciConfigSpace,
+        mut resources: PciRootResources,
+        msi_parent_phandle: u32,
+        iommu_map: Option<ArrayVec<IommuMapEntry, MAX_IOMMU_MAP_ENTRIES>>,
+        pci_iommus: ArrayVec<(Address, u32), MAX_PCI_IOMMUS>,
+    ) -> Result<Self> {
         // Enumerate the PCI hierarchy.
         let mut device_arena = PciDeviceArena::new(Global);
-        let root_bus = PciBus::enumerate(&config_space, bus_range.start, &mut device_arena)?;
+        let root_bus = PciBus::enumerate(
+            &config_space,
+            config_space.bus_range().start,
+            &mut device_arena,
+        )?;
+
+        // Keep the BARs and bridge windows that firmware assigned if they're valid, or allocate
+        // them for every device ourselves if firmware didn't assign them or got them wrong. The
+        // host may reassign them later.
+        assign_resources(&root_bus, &device_arena, &mut resources)?;
+
+        let mut hotplug_ports = Vec::new();
+        for id in device_arena.ids() {
+            // Unwrap ok: the ID was just returned by the arena.
+            if device_arena.get(id).unwrap().lock().has_hotplug_slot() {
+                hotplug_ports
+                    .try_reserve(1)
+                    .map_err(|_| Error::AllocError)?;
+                hotplug_ports.push(id);
+            }
+        }
 
-        PCIE_ROOT.call_once(|| Self {
+        Ok(Self {
             config_space,
             root_bus,
             device_arena,
             resources: Mutex::new(resources),
             msi_parent_phandle,
-        });
-        Ok(())
+            iommu_map,
+            pci_iommus,
+            hotplug_ports,
+            hotplug_changes: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns if this root complex describes which IOMMU translates DMA from each of its functions
+    /// with an 'iommu-map' property.
+    pub fn has_iommu_map(&self) -> bool {
+        self.iommu_map.is_some()
+    }
+
+    /// Returns the phandle of the IOMMU that translates DMA from the function at `address`
+    /// according to this root complex's 'iommu-map', if any.
+    pub fn iommu_phandle_for(&self, address: Address) -> Option<u32> {
+        if address.segment() != self.segment() {
+            return None;
+        }
+        let rid = address.bits() & 0xffff;
+        self.iommu_map
+            .as_ref()?
+            .iter()
+            .find(|e| rid >= e.rid_base && rid - e.rid_base < e.length)
+            .map(|e| e.phandle)
+    }
+
+    /// Returns the phandle of the device tree node describing the IOMMU function at `address`, if
+    /// any.
+    pub fn pci_iommu_phandle(&self, address: Address) -> Option<u32> {
+        self.pci_iommus
+            .iter()
+            .find(|(a, _)| *a == address)
+            .map(|(_, phandle)| *phandle)
+    }
+
+    /// Returns an iterator over all PCIe root complexes. Panics if `PcieRoot::probe_from()` has not
+    /// yet been called to initialize them.
+    pub fn roots() -> impl Iterator<Item = &'static Self> {
+        PCIE_ROOTS.get().unwrap().iter()
     }
 
-    /// Gets a reference to the `PcieRoot` singleton. Panics if `PcieRoot::probe_from()` has not yet
-    /// been called to initialize it.
-    pub fn get() -> &'static Self {
-        PCIE_ROOT.get().unwrap()
+    /// Returns the PCIe root complex for `segment`, if any.
+    pub fn for_segment(segment: Segment) -> Option<&'static Self> {
+        Self::roots().find(|r| r.segment() == segment)
+    }
+
+    /// Returns the PCIe root complex whose config space contains the physical address `addr`, if
+    /// any.
+    pub fn for_config_addr(addr: u64) -> Option<&'static Self> {
+        Self::roots().find(|r| {
+            let config_mem = r.config_space();
+            addr >= config_mem.base().bits()
+                && addr - config_mem.base().bits() < config_mem.length_bytes()
+        })
+    }
+
+    /// Returns the PCI segment of this root complex.
+    pub fn segment(&self) -> Segment {
+        self.config_space.segment()
+    }
+
+    /// Returns the highest address a function below this root complex can have, including functions
+    /// that are yet to be hotplugged.
+    pub fn max_function_address(&self) -> Address {
+        Address::new(
+            self.segment(),
+            self.config_space.bus_range().end,
+            Device::max(),
+            Function::max(),
+        )
     }
 
     /// Returns an iterator over all PCI devices.
@@ -271,6 +493,92 @@ impl PcieRoot {
         self.device_arena.get(arena_id)
     }
 
+    /// Returns the present device at the virtualized PCI address `address`, i.e. the address at
+    /// which VMs see it when enumerating this root complex.
+    pub fn device_by_virtual_address(&self, address: Address) -> Option<&Mutex<PciDevice>> {
+        self.device_id_by_virtual_address(address)
+            .and_then(|id| self.device_arena.get(id))
+    }
+
+    /// Returns the ID of the present device at the virtualized PCI address `address`.
+    pub fn device_id_by_virtual_address(&self, address: Address) -> Option<PciArenaId> {
+        if address.segment() != self.segment() {
+            return None;
+        }
+        self.device_by_virtual_address_on(&self.root_bus, address)
+    }
+
+    /// Returns the IDs of the devices owned by `owner`.
+    pub fn devices_owned_by(&self, owner: PageOwnerId) -> Vec<PciArenaId> {
+        self.device_arena
+            .ids()
+            .filter(|&id| self.device_arena.get(id).unwrap().lock().owner() == Some(owner))
+            .collect()
+    }
+
+    /// Returns the CPU physical page ranges occupied by the memory BARs of `dev`.
+    pub fn device_bar_pages(
+        &self,
+        dev: &PciDevice,
+    ) -> Result<ArrayVec<SupervisorPageRange, PCI_ENDPOINT_BARS>> {
+        let mut ranges = ArrayVec::new();
+        for bar in dev.bar_info().bars() {
+            if bar.bar_type() == PciResourceType::IoPort {
+                continue;
+            }
+            // BARs are aligned to their size, so only BARs of at least a page occupy whole pages.
+            if bar.size() < PageSize::Size4k as u64 {
+                return Err(Error::BarNotPageSized(bar.index()));
+            }
+            let pci_addr = dev.get_bar_addr(bar.index())?;
+            let base = self
+                .pci_to_physical_addr(pci_addr)
+                .and_then(PageAddr::new)
+                .ok_or(Error::InvalidBarAddress(pci_addr))?;
+            ranges.push(SupervisorPageRange::new(
+                base,
+                PageSize::num_4k_pages(bar.size()),
+            ));
+        }
+        Ok(ranges)
+    }
+
+    /// Adds or removes the devices in every hotplug slot whose occupancy has changed since it was
+    /// last checked, without waiting for the host to access the slot's port. Returns the first
+    /// error encountered, after all the slots have been checked.
+    pub fn poll_hotplug_slots(&self) -> Result<()> {
+        let mut result = Ok(());
+        for &id in self.hotplug_ports.iter() {
+            // Unwrap ok: the hotplug ports were found in the arena.
+            let mut port = self.device_arena.get(id).unwrap().lock();
+            if let Err(err) = self.update_hotplug_slot(&mut port) {
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Returns the IDs of the devices that have been added to or removed from hotplug slots since
+    /// the last call.
+    pub fn take_hotplug_changes(&self) -> Vec<PciArenaId> {
+        core::mem::take(&mut *self.hotplug_changes.lock())
+    }
+
+    /// Transfers the function with `arena_id`, which must have been removed from its slot, from
+    /// `from` to `to`. There's nothing left of the device to reset, so only its owner changes.
+    pub fn reclaim_removed_device(
+        &self,
+        arena_id: PciArenaId,
+        from: PageOwnerId,
+        to: PageOwnerId,
+    ) -> Result<()> {
+        self.device_arena
+            .get(arena_id)
+            .ok_or(Error::DeviceNotFound)?
+            .lock()
+            .reclaim_removed(from, to)
+    }
+
     /// Takes ownership over all unowned devices in the PCI hierarchy on behalf of the host VM.
     pub fn take_host_devices(&self) {
         for dev in self.devices() {
@@ -278,6 +586,32 @@ impl PcieRoot {
         }
     }
 
+    /// Transfers ownership over the device identified by `arena_id` from `from` to `to`. Only
+    /// endpoints and enabled SR-IOV virtual functions which aren't attached to an IOMMU context may
+    /// be transferred. The device is reset with a function-level reset before changing owners, and
+    /// `to` can't access it until the reset has completed.
+    pub fn transfer_device(
+        &self,
+        arena_id: PciArenaId,
+        from: PageOwnerId,
+        to: PageOwnerId,
+    ) -> Result<()> {
+        let dev = self
+            .device_arena
+            .get(arena_id)
+            .ok_or(Error::DeviceNotFound)?;
+        dev.lock().quiesce_for_transfer(from)?;
+        // Give outstanding transactions a chance to complete without holding the device lock. The
+        // reset goes ahead even if they don't.
+        for _ in 0..FLR_TRANSACTIONS_PENDING_MS {
+            if !dev.lock().transactions_pending() {
+                break;
+            }
+            delay_ms(1);
+        }
+        dev.lock().transfer(from, to)
+    }
+
     /// Takes ownership over the PCI device with the given `vendor_id` and `device_id`, and enables
     /// it for use within the hypervisor by assigning it resources. Returns a `PciDeviceId` which
     /// can be used to retrieve a reference to the device on success.
@@ -413,6 +747,94 @@ impl PcieRoot {
         let _ = self.do_emulate_config_write(offset, value, len, page_tracker, guest_id);
     }
 
+    /// Emulates a read of `len` bytes at `addr` in a page containing the virtualized MSI-X table or
+    /// PBA of a device owned by the VM with `guest_id`. Reads outside the table are passed through
+    /// to the device's BAR.
+    pub fn emulate_msix_read(
+        &self,
+        addr: SupervisorPhysAddr,
+        len: usize,
+        guest_id: PageOwnerId,
+    ) -> Result<u64> {
+        let pci_addr = self
+            .physical_to_pci_addr(addr)
+            .ok_or(Error::DeviceNotFound)?;
+        let mut dev =
+            self.msix_table_owner(PageAddr::with_round_down(addr, PageSize::Size4k), guest_id)?;
+        dev.check_ready()?;
+        let resources = self.resources.lock();
+        dev.emulate_msix_read(pci_addr, len, &resources)
+    }
+
+    /// Emulates a write of `len` bytes of `value` at `addr` in a page containing the virtualized
+    /// MSI-X table or PBA of a device owned by the VM with `guest_id`. Vectors are only programmed
+    /// into the device if `is_valid_target` returns true for their message address, which must be
+    /// the address of an interrupt file the VM may signal through the IOMMU's MSI page table.
+    /// Writes outside the table and PBA are passed through to the device's BAR.
+    pub fn emulate_msix_write(
+        &self,
+        addr: SupervisorPhysAddr,
+        value: u64,
+        len: usize,
+        guest_id: PageOwnerId,
+        is_valid_target: impl Fn(u64) -> bool,
+    ) -> Result<()> {
+        let pci_addr = self
+            .physical_to_pci_addr(addr)
+            .ok_or(Error::DeviceNotFound)?;
+        let mut dev =
+            self.msix_table_owner(PageAddr::with_round_down(addr, PageSize::Size4k), guest_id)?;
+        dev.check_ready()?;
+        let resources = self.resources.lock();
+        dev.emulate_msix_write(pci_addr, value, len, &resources, is_valid_target)
+    }
+
+    /// Returns if `addr` is in a page containing the virtualized MSI-X table or PBA of a device
+    /// owned by the VM with `guest_id`. Such pages must not be mapped into the VM so that accesses
+    /// to them can be emulated.
+    pub fn is_msix_table_page(&self, addr: SupervisorPageAddr, guest_id: PageOwnerId) -> bool {
+        self.msix_table_owner(addr, guest_id).is_ok()
+    }
+
+    /// Checks the message addresses in the virtualized MSI-X tables of the devices owned by the VM
+    /// with `guest_id` again with `is_valid_target`, after the interrupt files the VM may signal
+    /// changed. Vectors whose target became invalid are masked in the device, while vectors whose
+    /// target became valid are programmed into it.
+    pub fn revalidate_msix_targets(
+        &self,
+        guest_id: PageOwnerId,
+        is_valid_target: impl Fn(u64) -> bool,
+    ) {
+        for dev in self.devices() {
+            let mut dev = dev.lock();
+            if dev.owner() != Some(guest_id) {
+                continue;
+            }
+            let resources = self.resources.lock();
+            dev.revalidate_msix_targets(&resources, &is_valid_target);
+        }
+    }
+
+    // Returns the device owned by `guest_id` whose virtualized MSI-X table or PBA is in the page at
+    // `page_addr`. The page is within a single BAR of the device since the BARs of devices owned
+    // by VMs other than the host are page-sized and aligned.
+    fn msix_table_owner(
+        &self,
+        page_addr: SupervisorPageAddr,
+        guest_id: PageOwnerId,
+    ) -> Result<MutexGuard<PciDevice>> {
+        let pci_addr = self
+            .physical_to_pci_addr(page_addr.into())
+            .ok_or(Error::DeviceNotFound)?;
+        self.devices()
+            .map(|dev| dev.lock())
+            .find(|dev| {
+                dev.owner() == Some(guest_id)
+                    && dev.msix_table_overlaps(pci_addr, PageSize::Size4k as u64)
+            })
+            .ok_or(Error::DeviceNotFound)
+    }
+
     fn do_emulate_config_read(
         &self,
         offset: u64,
@@ -425,10 +847,14 @@ impl PcieRoot {
         }
         let (dev_id, dev_offset) = self.virtual_config_offset_to_device(offset as usize)?;
         // If the device ID is present in the hierarchy, then it must be in the arena.
-        let dev = self.device_arena.get(dev_id).unwrap().lock();
+        let mut dev = self.device_arena.get(dev_id).unwrap().lock();
         if dev.owner() != Some(guest_id) {
             return Err(Error::DeviceNotOwned);
         }
+        dev.check_ready()?;
+        // Bring the slot up to date before the VM reads its state so that the devices in the slot
+        // are present once the VM sees them.
+        self.update_hotplug_slot(&mut dev)?;
         let resources = self.resources.lock();
         let context = MmioEmulationContext {
             page_tracker,
@@ -455,16 +881,256 @@ impl PcieRoot {
         if dev.owner() != Some(guest_id) {
             return Err(Error::DeviceNotOwned);
         }
-        let resources = self.resources.lock();
-        let context = MmioEmulationContext {
-            page_tracker,
-            guest_id,
-            resources: &resources,
+        dev.check_ready()?;
+        {
+            let resources = self.resources.lock();
+            let context = MmioEmulationContext {
+                page_tracker,
+                guest_id,
+                resources: &resources,
+            };
+            dev.emulate_config_write(dev_offset, value as u32, len, context);
+        }
+        self.update_power_state(&mut dev)?;
+        self.update_virtual_functions(&mut dev, page_tracker, guest_id)?;
+        self.update_hotplug_slot(&mut dev)
+    }
+
+    // Adds the devices in the hotplug slot below `port` if the slot has become occupied, or removes
+    // them if the slot has been emptied.
+    fn update_hotplug_slot(&self, port: &mut PciDevice) -> Result<()> {
+        let has_hotplug_slot = port.has_hotplug_slot();
+        let occupied = port.slot_occupied();
+        let bridge = match *port {
+            PciDevice::Bridge(ref mut bridge) if has_hotplug_slot => bridge,
+            _ => return Ok(()),
         };
-        dev.emulate_config_write(dev_offset, value as u32, len, context);
+        if occupied == bridge.slot_populated() {
+            return Ok(());
+        }
+        bridge.set_slot_populated(occupied);
+        // Unwrap ok: buses must have been assigned to the bridge during enumeration.
+        let child_bus = bridge.child_bus().unwrap();
+
+        let mut changes = self.hotplug_changes.lock();
+        changes
+            .try_reserve(child_bus.devices().len())
+            .map_err(|_| Error::AllocError)?;
+        if occupied {
+            // Only the functions which had arena entries reserved for them when the port was
+            // enumerated can be added. No buses were reserved beyond the slot's own bus either, so
+            // switches can't be added to the slot; any bridge in it stays hidden.
+            let mut result = Ok(());
+            let bus_config = self
+                .config_space
+                .bus(child_bus.secondary_bus_num())
+                .ok_or(Error::OutOfBoundsBusNumber(child_bus.secondary_bus_num()))?;
+            for info in bus_config.devices().flat_map(|d| d.functions()) {
+                let bd = match child_bus.devices().find(|bd| bd.address == info.address()) {
+                    Some(bd) => bd,
+                    None => continue,
+                };
+                if info.header_type() != HeaderType::Endpoint {
+                    result = Err(Error::UnsupportedHeaderType(
+                        info.address(),
+                        info.header_type(),
+                    ));
+                    continue;
+                }
+                let mut dev = self.device_arena.get(bd.id).unwrap().lock();
+                if dev.is_present() {
+                    continue;
+                }
+                // Unwrap ok, if we have a header the config space for the corresponding function
+                // must exist.
+                let registers_ptr = self.config_space.registers_for(info.address()).unwrap();
+                // Safety: The address of the function was reserved for it when enumerating the port,
+                // so we trust that PciConfigSpace returned a valid config space pointer that isn't
+                // in use by any other device.
+                let new_dev = unsafe { PciDevice::new(registers_ptr, info.clone()) }?;
+                dev.replace(new_dev);
+                if !changes.contains(&bd.id) {
+                    changes.push(bd.id);
+                }
+            }
+            drop(changes);
+            // The port's windows were sized for hotplug when it was enumerated, so the new devices
+            // should fit.
+            assign_secondary_bus(bridge, &self.device_arena)?;
+            result
+        } else {
+            for bd in child_bus.devices() {
+                let mut dev = self.device_arena.get(bd.id).unwrap().lock();
+                if !dev.is_present() {
+                    continue;
+                }
+                // The device is gone, so its VFs are too regardless of who they're assigned to.
+                if dev.sriov().is_some_and(|sriov| sriov.vf_enabled()) {
+                    self.release_virtual_functions(&mut dev);
+                    changes
+                        .try_reserve(dev.virtual_functions().len())
+                        .map_err(|_| Error::AllocError)?;
+                    changes.extend(dev.virtual_functions());
+                }
+                dev.replace(PciDevice::empty(bd.address));
+                if !changes.contains(&bd.id) {
+                    changes.push(bd.id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Starts any function-level reset or power state transition requested by the VM that owns
+    // `dev`. The VM sees the device as not ready until it has completed.
+    fn update_power_state(&self, dev: &mut PciDevice) -> Result<()> {
+        if dev.take_reset_request() {
+            self.prepare_for_reset(dev)?;
+            dev.reset_function()?;
+        }
+        if let Some(state) = dev.take_power_state_request() {
+            if dev.power_state_change_resets(state) {
+                self.prepare_for_reset(dev)?;
+            }
+            dev.set_power_state(state)?;
+        }
+        Ok(())
+    }
+
+    // Readies `dev` for a reset. Resetting an SR-IOV physical function disables its VFs, so they
+    // must not be in use by any other VM.
+    fn prepare_for_reset(&self, dev: &mut PciDevice) -> Result<()> {
+        if dev.sriov().is_some_and(|sriov| sriov.vf_enabled()) {
+            self.disable_virtual_functions(dev)?;
+        }
         Ok(())
     }
 
+    // Applies any change to VF Enable or VF MSE requested by the VM with `guest_id` in the SR-IOV
+    // capability of `pf`.
+    fn update_virtual_functions(
+        &self,
+        pf: &mut PciDevice,
+        page_tracker: PageTracker,
+        guest_id: PageOwnerId,
+    ) -> Result<()> {
+        let request = match pf.sriov_mut().and_then(|s| s.take_control_request()) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        // Unwraps ok: the device must have an SR-IOV capability to have made a request.
+        let vf_enable = request.is_set(SriovControl::VfEnable);
+        if vf_enable != pf.sriov().unwrap().vf_enabled() {
+            if vf_enable {
+                self.enable_virtual_functions(pf)?;
+            } else {
+                self.disable_virtual_functions(pf)?;
+            }
+        }
+
+        let vf_mse = request.is_set(SriovControl::VfMemorySpaceEnable);
+        if vf_mse != pf.sriov().unwrap().vf_memory_space_enabled() {
+            if vf_mse {
+                {
+                    let resources = self.resources.lock();
+                    let context = MmioEmulationContext {
+                        page_tracker,
+                        guest_id,
+                        resources: &resources,
+                    };
+                    pf.can_enable_vf_memory_space(&context)?;
+                }
+                // The VF BARs can't be changed while VF MSE is set, so latch the addresses of the
+                // individual VF BARs now.
+                for (index, &vf_id) in pf.virtual_functions().iter().enumerate() {
+                    let bar_addrs = pf.virtual_function_bar_addrs(index as u16);
+                    if let PciDevice::VirtualFunction(ref mut vf) =
+                        *self.device_arena.get(vf_id).unwrap().lock()
+                    {
+                        vf.set_bar_addrs(bar_addrs);
+                    }
+                }
+            }
+            pf.sriov_mut().unwrap().set_vf_memory_space(vf_mse);
+        }
+        Ok(())
+    }
+
+    // Enables the SR-IOV virtual functions of `pf` and attaches them to their config spaces. VFs
+    // are only enabled at the addresses reserved for them when `pf` was enumerated.
+    fn enable_virtual_functions(&self, pf: &mut PciDevice) -> Result<()> {
+        // Unwrap ok: the caller has checked that `pf` has an SR-IOV capability.
+        let num_vfs = pf.sriov().unwrap().num_vfs() as usize;
+        let vf_ids: ArrayVec<PciArenaId, MAX_SRIOV_VFS> = pf
+            .virtual_functions()
+            .iter()
+            .take(num_vfs)
+            .copied()
+            .collect();
+        for (index, &vf_id) in vf_ids.iter().enumerate() {
+            let address = self
+                .device_arena
+                .get(vf_id)
+                .unwrap()
+                .lock()
+                .info()
+                .address();
+            if pf.virtual_function_address(index as u16) != Some(address) {
+                return Err(Error::VirtualFunctionMoved(address));
+            }
+        }
+
+        pf.sriov_mut().unwrap().set_vf_enable(true);
+        // VFs may not respond to config requests until 100ms after VF Enable is set, and they
+        // don't report a valid vendor ID which could be polled for readiness.
+        delay_ms(VF_ENABLE_READY_MS);
+        let result = vf_ids.iter().try_for_each(|&vf_id| {
+            let mut dev = self.device_arena.get(vf_id).unwrap().lock();
+            let address = dev.info().address();
+            let registers_ptr = self
+                .config_space
+                .registers_for(address)
+                .ok_or(Error::DeviceNotPresent(address))?;
+            match *dev {
+                // Safety: The address of the VF was reserved for it when enumerating the PF, so we
+                // trust that PciConfigSpace returned a valid config space pointer that isn't in use
+                // by any other device.
+                PciDevice::VirtualFunction(ref mut vf) => unsafe { vf.enable(registers_ptr) },
+                // Only VFs are reserved for PFs.
+                _ => Err(Error::VirtualFunctionMoved(address)),
+            }
+        });
+        if result.is_err() {
+            self.release_virtual_functions(pf);
+        }
+        result
+    }
+
+    // Disables the SR-IOV virtual functions of `pf`, provided that none of them are assigned to
+    // another VM.
+    fn disable_virtual_functions(&self, pf: &mut PciDevice) -> Result<()> {
+        for &vf_id in pf.virtual_functions() {
+            if self.device_arena.get(vf_id).unwrap().lock().owner() != pf.owner() {
+                return Err(Error::VirtualFunctionsAssigned);
+            }
+        }
+        self.release_virtual_functions(pf);
+        Ok(())
+    }
+
+    // Clears VF Enable in `pf` and detaches its VFs from their config spaces.
+    fn release_virtual_functions(&self, pf: &mut PciDevice) {
+        // Unwrap ok: the caller has checked that `pf` has an SR-IOV capability.
+        pf.sriov_mut().unwrap().set_vf_enable(false);
+        for &vf_id in pf.virtual_functions() {
+            if let PciDevice::VirtualFunction(ref mut vf) =
+                *self.device_arena.get(vf_id).unwrap().lock()
+            {
+                vf.disable();
+            }
+        }
+    }
+
     // Returns the device ID for the device at the virtualized PCI address `address` on `bus`.
     fn device_by_virtual_address_on(&self, bus: &PciBus, address: Address) -> Option<PciArenaId> {
         if address.bus() == bus.virtual_secondary_bus_num() {
@@ -474,8 +1140,10 @@ impl PcieRoot {
                 .find(|bd| {
                     bd.address.device() == address.device()
                         && bd.address.function() == address.function()
+                        && self.device_arena.get(bd.id).unwrap().lock().is_present()
                 })
-                .map(|bd| bd.id);
+                .map(|bd| bd.id)
+                .or_else(|| self.virtual_function_on(bus, address));
         } else {
             for bd in bus.devices() {
                 let dev = self.device_arena.get(bd.id).unwrap();
@@ -495,6 +1163,25 @@ impl PcieRoot {
         None
     }
 
+    // Returns the ID of the enabled SR-IOV virtual function at the virtualized PCI address `address`
+    // among the physical functions on `bus`.
+    fn virtual_function_on(&self, bus: &PciBus, address: Address) -> Option<PciArenaId> {
+        for bd in bus.devices() {
+            let pf = self.device_arena.get(bd.id).unwrap().lock();
+            for &vf_id in pf.virtual_functions() {
+                let vf = self.device_arena.get(vf_id).unwrap().lock();
+                let vf_addr = vf.info().address();
+                if vf.is_present()
+                    && vf_addr.device() == address.device()
+                    && vf_addr.function() == address.function()
+                {
+                    return Some(vf_id);
+                }
+            }
+        }
+        None
+    }
+
     // Returns the ID of the device whose config space is mapped at `offset` in the virtualized
     // config space, along with the offset within the device's config space.
     fn virtual_config_offset_to_device(&self, offset: usize) -> Result<(PciArenaId, usize)> {
@@ -622,3 +1309,284 @@ impl Iterator for PciBarPageIter {
 }
 
 impl ExactSizeIterator for PciBarPageIter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use page_tracking::{HwMemMapBuilder, HypPageAlloc};
+
+    // Offset of the config space of device 0, function 0 on `bus` in ECAM, in dwords.
+    fn ecam_offset(bus: usize) -> usize {
+        (bus << 20) / 4
+    }
+
+    #[test]
+    fn hotplug_add_remove() {
+        // ECAM for buses 0 and 1. Devices hold on to their registers, so it's never freed. Reads
+        // from functions which aren't there return all-ones.
+        let config_mem = vec![!0u32; (2 << 20) / 4 + 1024].leak();
+        let align_offset = config_mem.as_ptr().align_offset(4096);
+        let config = &mut config_mem[align_offset..];
+        let config_start = config.as_ptr() as u64;
+
+        // A downstream port with an empty hotplug slot on bus 0.
+        config[0..1024].fill(0);
+        config[0] = 0x000c_1b36; // Device and vendor ID
+        config[1] = 0x0010_0000; // Capabilities list
+        config[2] = 0x0604_0000; // PCI-to-PCI bridge
+        config[3] = 0x0001_0000; // Type 1 header
+        config[13] = 0x40; // Start of the capability list.
+        config[16] = 0x0162_0010; // PCI Express, version 2 downstream port with a slot.
+        config[21] = 0x0000_0040; // Hot-plug capable slot.
+
+        let mut resources = PciRootResources::new();
+        let mem_addr = PageAddr::new(RawAddr::supervisor(0x4000_0000)).unwrap();
+        resources
+            .insert(
+                PciResourceType::Mem32,
+                PciRootResource::new(mem_addr, 0x100_0000, 0x4000_0000),
+            )
+            .unwrap();
+        let config_space = PciConfigSpace::new(
+            PageAddr::new(RawAddr::supervisor(config_start)).unwrap(),
+            2 << 20,
+            Segment::default(),
+            BusRange {
+                start: Bus::try_from(0u8).unwrap(),
+                end: Bus::try_from(1u8).unwrap(),
+            },
+        );
+        let root = PcieRoot::new(config_space, resources, 0, None, ArrayVec::new()).unwrap();
+
+        let slot_address = Address::try_from_components(0, 1, 0, 0).unwrap();
+        let slot_id = root
+            .device_arena
+            .ids()
+            .find(|&id| root.get_device(id).unwrap().lock().info().address() == slot_address)
+            .unwrap();
+        let is_present = || root.get_device(slot_id).unwrap().lock().is_present();
+        assert!(!is_present());
+        root.poll_hotplug_slots().unwrap();
+        assert!(root.take_hotplug_changes().is_empty());
+
+        // Plug in an endpoint.
+        let ep = ecam_offset(1);
+        config[ep..ep + 1024].fill(0);
+        config[ep] = 0x1000_1af4; // Device and vendor ID
+        config[ep + 2] = 0x0200_0000; // Ethernet controller
+        config[22] = 0x0040_0000; // Presence detected.
+        root.poll_hotplug_slots().unwrap();
+        assert!(is_present());
+        assert_eq!(root.take_hotplug_changes(), [slot_id]);
+        // The endpoint's BARs are assigned from the port's windows.
+        let bar_addr = root.get_device(slot_id).unwrap().lock().get_bar_addr(0);
+        assert!(bar_addr.is_ok_and(|addr| addr >= 0x4000_0000));
+        root.poll_hotplug_slots().unwrap();
+        assert!(root.take_hotplug_changes().is_empty());
+
+        // Unplug it.
+        config[ep..ep + 1024].fill(!0);
+        config[22] = 0;
+        root.poll_hotplug_slots().unwrap();
+        assert!(!is_present());
+        assert_eq!(root.take_hotplug_changes(), [slot_id]);
+        // Accesses to the function that was removed complete like those to an empty slot.
+        assert!(root
+            .get_device(slot_id)
+            .unwrap()
+            .lock()
+            .get_bar_addr(0)
+            .is_err());
+
+        // Switches can't be added to the slot, and that's only reported once.
+        config[ep..ep + 1024].fill(0);
+        config[ep] = 0x000e_1b36; // Device and vendor ID
+        config[ep + 2] = 0x0604_0000; // PCI-to-PCI bridge
+        config[ep + 3] = 0x0001_0000; // Type 1 header
+        config[22] = 0x0040_0000; // Presence detected.
+        assert!(matches!(
+            root.poll_hotplug_slots(),
+            Err(Error::UnsupportedHeaderType(a, HeaderType::PciBridge)) if a == slot_address
+        ));
+        assert!(!is_present());
+        assert!(root.take_hotplug_changes().is_empty());
+        root.poll_hotplug_slots().unwrap();
+    }
+
+    fn stub_page_tracker() -> PageTracker {
+        const ONE_MEG: usize = 1024 * 1024;
+        const MEM_ALIGN: usize = 2 * ONE_MEG;
+        const MEM_SIZE: usize = 256 * ONE_MEG;
+        let backing_mem = vec![0u8; MEM_SIZE + MEM_ALIGN];
+        let aligned_pointer = unsafe {
+            // Not safe - just a test
+            backing_mem
+                .as_ptr()
+                .add(backing_mem.as_ptr().align_offset(MEM_ALIGN))
+        };
+        let start_pa = RawAddr::supervisor(aligned_pointer as u64);
+        let mut hw_map = unsafe {
+            // Not safe - just a test
+            HwMemMapBuilder::new(PageSize::Size4k as u64)
+                .add_memory_region(start_pa, MEM_SIZE.try_into().unwrap())
+                .unwrap()
+                .build()
+        };
+        let hyp_mem = HypPageAlloc::new(&mut hw_map).unwrap();
+        let (page_tracker, _) = PageTracker::from(hyp_mem, PageSize::Size4k as u64);
+        // Leak the backing ram so it doesn't get freed
+        std::mem::forget(backing_mem);
+        page_tracker
+    }
+
+    #[test]
+    fn msix_table_emulation() {
+        // ECAM for bus 0, with an endpoint at 00:00.0 which has a single MSI-X vector. The table is
+        // at the start of BAR 0 and the PBA at the start of BAR 1. All of the endpoint's BARs are
+        // 16 bytes, so they're packed in the same page.
+        let config_mem = vec![!0u32; (1 << 20) / 4 + 1024].leak();
+        let align_offset = config_mem.as_ptr().align_offset(4096);
+        let config = &mut config_mem[align_offset..];
+        let config_start = config.as_ptr() as u64;
+        config[0..1024].fill(0);
+        config[0] = 0x1000_1af4; // Device and vendor ID
+        config[1] = 0x0010_0000; // Capabilities list
+        config[2] = 0x0200_0000; // Ethernet controller
+        config[13] = 0x40; // Start of the capability list.
+        config[16] = 0x0000_0011; // MSI-X with a single vector.
+        config[17] = 0; // Table in BAR 0.
+        config[18] = 1; // PBA in BAR 1.
+
+        // Memory backing the endpoint's BARs. Never freed since the root holds on to it.
+        let bar_mem = vec![0u32; (1 << 20) / 4 + 1024].leak();
+        let align_offset = bar_mem.as_ptr().align_offset(4096);
+        let bar_mem_start = bar_mem[align_offset..].as_ptr() as u64;
+        let mut resources = PciRootResources::new();
+        let mem_addr = PageAddr::new(RawAddr::supervisor(bar_mem_start)).unwrap();
+        resources
+            .insert(
+                PciResourceType::Mem32,
+                PciRootResource::new(mem_addr, 1 << 20, 0x4000_0000),
+            )
+            .unwrap();
+        let config_space = PciConfigSpace::new(
+            PageAddr::new(RawAddr::supervisor(config_start)).unwrap(),
+            1 << 20,
+            Segment::default(),
+            BusRange {
+                start: Bus::try_from(0u8).unwrap(),
+                end: Bus::try_from(0u8).unwrap(),
+            },
+        );
+        let root = PcieRoot::new(config_space, resources, 0, None, ArrayVec::new()).unwrap();
+
+        let guest_id = PageOwnerId::new(5).unwrap();
+        let dev_id = root.device_arena.ids().next().unwrap();
+        let (table_addr, pba_addr, other_addr) = {
+            let mut dev = root.get_device(dev_id).unwrap().lock();
+            dev.set_owner_in_test(guest_id);
+            let to_cpu_addr = |bar| bar_mem_start + dev.get_bar_addr(bar).unwrap() - 0x4000_0000;
+            (to_cpu_addr(0), to_cpu_addr(1), to_cpu_addr(2))
+        };
+        let table_page =
+            PageAddr::with_round_down(RawAddr::supervisor(table_addr), PageSize::Size4k);
+        assert!(root.is_msix_table_page(table_page, guest_id));
+        assert!(!root.is_msix_table_page(table_page, PageOwnerId::host()));
+        // Reads the dword at `offset` in the device's MSI-X table.
+        let table_dword = |offset: u64| unsafe {
+            // Safe since the table is in the leaked BAR memory.
+            core::ptr::read_volatile((table_addr + offset) as *const u32)
+        };
+        let valid_target = |addr: u64| addr == 0x2800_1000;
+        let write_at = |addr: u64, value: u64, len: usize| {
+            root.emulate_msix_write(
+                RawAddr::supervisor(addr),
+                value,
+                len,
+                guest_id,
+                valid_target,
+            )
+        };
+        let write = |offset: u64, value: u64, len: usize| write_at(table_addr + offset, value, len);
+
+        // Vectors are programmed into the device's table once they're unmasked.
+        write(0x0, 0x2800_1000, 8).unwrap();
+        write(0x8, 0x42, 4).unwrap();
+        assert_eq!(table_dword(0xc) & 1, 1);
+        write(0xc, 0, 4).unwrap();
+        assert_eq!(table_dword(0x0), 0x2800_1000);
+        assert_eq!(table_dword(0x8), 0x42);
+        assert_eq!(table_dword(0xc) & 1, 0);
+        let read = |addr: u64, len: usize| {
+            root.emulate_msix_read(RawAddr::supervisor(addr), len, guest_id)
+        };
+        assert!(read(table_addr + 0x8, 8).is_ok_and(|val| val == 0x42));
+        assert!(read(pba_addr, 8).is_ok_and(|val| val == 0));
+        assert!(matches!(
+            read(table_addr + 0x2, 2),
+            Err(Error::UnsupportedMsiXAccess)
+        ));
+        assert!(matches!(
+            root.emulate_msix_read(RawAddr::supervisor(table_addr), 4, PageOwnerId::host()),
+            Err(Error::DeviceNotFound)
+        ));
+
+        // Updates made while the function is masked only take effect once it's unmasked.
+        let page_tracker = stub_page_tracker();
+        let set_function_mask = |masked: bool| {
+            root.emulate_config_write(0x42, (masked as u64) << 14, 2, page_tracker, guest_id)
+        };
+        set_function_mask(true);
+        write(0x8, 0x43, 4).unwrap();
+        assert_eq!(table_dword(0x8), 0x42);
+        write(0x0, 0x3000_0000, 4).unwrap();
+        assert_eq!(table_dword(0x0), 0x2800_1000);
+        set_function_mask(false);
+        assert_eq!(config[16] & (1 << 30), 0);
+        // The vector's target isn't valid, so it's masked in the device.
+        assert_eq!(table_dword(0xc) & 1, 1);
+        write(0x0, 0x2800_1000, 4).unwrap();
+        assert_eq!(table_dword(0x0), 0x2800_1000);
+        assert_eq!(table_dword(0x8), 0x43);
+        assert_eq!(table_dword(0xc) & 1, 0);
+
+        // Vectors are masked in the device while their target isn't valid, including when they're
+        // unmasked by the VM.
+        root.revalidate_msix_targets(guest_id, |_| false);
+        assert_eq!(table_dword(0xc) & 1, 1);
+        root.revalidate_msix_targets(guest_id, valid_target);
+        assert_eq!(table_dword(0xc) & 1, 0);
+        write(0xc, 1, 4).unwrap();
+        assert_eq!(table_dword(0xc) & 1, 1);
+        root.emulate_msix_write(
+            RawAddr::supervisor(table_addr + 0xc),
+            0,
+            4,
+            guest_id,
+            |_| false,
+        )
+        .unwrap();
+        assert_eq!(table_dword(0xc) & 1, 1);
+
+        // Accesses to the rest of the page go to the device's BARs, except for writes to the PBA.
+        assert_eq!(
+            PageAddr::with_round_down(RawAddr::supervisor(other_addr), PageSize::Size4k),
+            table_page
+        );
+        write_at(other_addr + 2, 0xab, 1).unwrap();
+        // Safe since the BAR is in the leaked BAR memory.
+        let other_dword = unsafe { core::ptr::read_volatile(other_addr as *const u32) };
+        assert_eq!(other_dword, 0x00ab_0000);
+        assert!(read(other_addr + 2, 1).is_ok_and(|val| val == 0xab));
+        assert!(matches!(
+            read(other_addr + 1, 2),
+            Err(Error::UnsupportedMsiXAccess)
+        ));
+        assert!(matches!(
+            read(table_page.bits() + 0x800, 4),
+            Err(Error::UnsupportedMsiXAccess)
+        ));
+        write_at(pba_addr, !0, 8).unwrap();
+        assert!(read(pba_addr, 8).is_ok_and(|val| val == 0));
+    }
+}
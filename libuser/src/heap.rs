@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use sync::Mutex;
+
+// The heap region, defined by the U-mode linker script.
+extern "C" {
+    static _heap_start: u8;
+    static _heap_end: u8;
+}
+
+struct UmodeHeapInner {
+    // Start address of the heap region. Zero until the first allocation.
+    start: usize,
+    // End address of the heap region.
+    end: usize,
+    // Address of the first free byte.
+    next: usize,
+    // Start address of the most recent allocation.
+    last: usize,
+    // Number of live allocations.
+    live: usize,
+}
+
+impl UmodeHeapInner {
+    // Initializes the heap region from the linker symbols on first use.
+    fn init(&mut self) {
+        if self.start == 0 {
+            // Safe because we only take the address of the linker symbols.
+            unsafe {
+                self.start = ptr::addr_of!(_heap_start) as usize;
+                self.end = ptr::addr_of!(_heap_end) as usize;
+            }
+            self.next = self.start;
+            self.last = self.start;
+        }
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.init();
+        let block_start = match self.next.checked_add(layout.align() - 1) {
+            Some(addr) => addr & !(layout.align() - 1),
+            None => return ptr::null_mut(),
+        };
+        match block_start.checked_add(layout.size()) {
+            Some(block_end) if block_end <= self.end => {
+                self.last = block_start;
+                self.next = block_end;
+                self.live += 1;
+                block_start as *mut u8
+            }
+            _ => ptr::null_mut(),
+        }
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8) {
+        self.live -= 1;
+        if self.live == 0 {
+            // Nothing is allocated anymore: the whole heap is free.
+            self.next = self.start;
+            self.last = self.start;
+        } else if ptr as usize == self.last {
+            // The most recent allocation can be reclaimed.
+            self.next = self.last;
+        }
+    }
+
+    fn realloc(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // The most recent allocation can grow or shrink in place.
+        if ptr as usize == self.last {
+            if let Some(block_end) = self.last.checked_add(new_size) {
+                if block_end <= self.end {
+                    self.next = block_end;
+                    return ptr;
+                }
+            }
+        }
+        // Safe because `layout` has a non-zero power of two alignment, and `new_size` rounded up
+        // to it doesn't overflow as per `GlobalAlloc::realloc()` safety requirements.
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            // Safe because both blocks are valid for the copied size, and a new block never
+            // overlaps with a live one.
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+            }
+            self.dealloc(ptr);
+        }
+        new_ptr
+    }
+}
+
+/// A bump-pointer allocator backed by the heap region of the U-mode ELF.
+///
+/// U-mode requests are short-lived, so memory is reclaimed in bulk: the heap is reset when the
+/// last live allocation is freed. The most recent allocation is also reclaimed, or resized in
+/// place, which covers growing a single buffer.
+pub struct UmodeHeap {
+    inner: Mutex<UmodeHeapInner>,
+}
+
+impl UmodeHeap {
+    /// Creates an allocator for the heap region.
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(UmodeHeapInner {
+                start: 0,
+                end: 0,
+                next: 0,
+                last: 0,
+                live: 0,
+            }),
+        }
+    }
+}
+
+impl Default for UmodeHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for UmodeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.inner.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        self.inner.lock().dealloc(ptr)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.inner.lock().realloc(ptr, layout, new_size)
+    }
+}
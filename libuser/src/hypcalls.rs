@@ -3,7 +3,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use core::arch::asm;
-use u_mode_api::{CdiOp, CdiSel, Error as UmodeApiError, HypCall, TryIntoRegisters, UmodeRequest};
+use u_mode_api::{
+    CdiOp, CdiSel, Error as UmodeApiError, HypCall, IntoRegisters, TryIntoRegisters, UmodeRequest,
+};
 
 /// Send an ecall to the hypervisor.
 ///
@@ -69,7 +71,7 @@ pub fn hyp_nextop(result: Result<u64, UmodeApiError>) -> Result<UmodeRequest, Um
 }
 
 /// Get the ID of the CDI selected by `cdi_sel`.
-pub fn hyp_cdi_id(cdi_sel: CdiSel, id: &mut [u8]) {
+pub fn hyp_cdi_id(cdi_sel: CdiSel, id: &mut [u8]) -> Result<(), UmodeApiError> {
     let mut regs = [0u64; 8];
     let hypc = HypCall::Cdi {
         cdi_sel,
@@ -84,10 +86,15 @@ pub fn hyp_cdi_id(cdi_sel: CdiSel, id: &mut [u8]) {
     unsafe {
         ecall(&mut regs);
     }
+    Result::<u64, UmodeApiError>::from_registers(&regs).map(|_| ())
 }
 
 /// Sign a message with the secret key of the CDI selected by `cdi_sel`.
-pub fn hyp_cdi_sign(cdi_sel: CdiSel, msg: &[u8], signature: &mut [u8]) {
+pub fn hyp_cdi_sign(
+    cdi_sel: CdiSel,
+    msg: &[u8],
+    signature: &mut [u8],
+) -> Result<(), UmodeApiError> {
     let mut regs = [0u64; 8];
     let hypc = HypCall::Cdi {
         cdi_sel,
@@ -105,4 +112,51 @@ pub fn hyp_cdi_sign(cdi_sel: CdiSel, msg: &[u8], signature: &mut [u8]) {
     unsafe {
         ecall(&mut regs);
     }
+    Result::<u64, UmodeApiError>::from_registers(&regs).map(|_| ())
+}
+
+/// Get the public key of the CDI selected by `cdi_sel`.
+pub fn hyp_cdi_public_key(cdi_sel: CdiSel, public_key: &mut [u8]) -> Result<(), UmodeApiError> {
+    let mut regs = [0u64; 8];
+    let hypc = HypCall::Cdi {
+        cdi_sel,
+        cdi_op: CdiOp::PublicKey {
+            pkout_addr: public_key.as_ptr() as u64,
+            pkout_len: public_key.len() as u64,
+        },
+    };
+    hypc.to_registers(&mut regs);
+    // Safety: we trust the hypervisor to write at `pkout_addr` for `pkout_len` bytes. This range
+    // is entirely contained in `public_key`, of which we have a mutable reference.
+    unsafe {
+        ecall(&mut regs);
+    }
+    Result::<u64, UmodeApiError>::from_registers(&regs).map(|_| ())
+}
+
+/// Derive a key from the CDI selected by `cdi_sel` and the derivation context `info`.
+pub fn hyp_cdi_derive_key(
+    cdi_sel: CdiSel,
+    info: &[u8],
+    key: &mut [u8],
+) -> Result<(), UmodeApiError> {
+    let mut regs = [0u64; 8];
+    let hypc = HypCall::Cdi {
+        cdi_sel,
+        cdi_op: CdiOp::DeriveKey {
+            info_addr: info.as_ptr() as u64,
+            info_len: info.len() as u64,
+            keyout_addr: key.as_ptr() as u64,
+            keyout_len: key.len() as u64,
+        },
+    };
+    hypc.to_registers(&mut regs);
+    // Safety: we trust the hypervisor to write at `keyout_addr` for `keyout_len` bytes. This range
+    // is entirely contained in `key`, of which we have a mutable reference. We also trust the
+    // hypervisor to read from `info_addr` for `info_len` bytes, which is entirely contained in
+    // `info`.
+    unsafe {
+        ecall(&mut regs);
+    }
+    Result::<u64, UmodeApiError>::from_registers(&regs).map(|_| ())
 }
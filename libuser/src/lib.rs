@@ -9,14 +9,23 @@
 //! This library implements basic functions used to create a `no_std`
 //! environment for salus U-mode, and helper functions to issue
 //! `ecall` to salus.
+//!
+//! U-mode programs get a heap from the `.heap` section of their ELF,
+//! and can use the `alloc` crate.
 
+mod heap;
 mod hypcalls;
 
+pub use crate::heap::UmodeHeap;
 pub use crate::hypcalls::*;
 use core::arch::global_asm;
 
 global_asm!(include_str!("start.S"));
 
+// Global allocator for U-mode programs.
+#[global_allocator]
+static HEAP: UmodeHeap = UmodeHeap::new();
+
 // Panic handler for U-mode programs.
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -9,4 +9,5 @@ ${QEMU_BIN} \
     ${MACH_ARGS} \
     -kernel ${SALUS_BINS}salus \
     -device guest-loader,kernel=${TELLUS_BINS}tellus_guestvm,addr=${KERNEL_ADDR} \
+    ${TELLUS_DEVICE_ARGS} \
     ${EXTRA_QEMU_ARGS}
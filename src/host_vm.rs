@@ -5,7 +5,7 @@
 use arrayvec::{ArrayString, ArrayVec};
 use core::{fmt, num, ops::ControlFlow, slice};
 use device_tree::{DeviceTree, DeviceTreeResult, DeviceTreeSerializer};
-use drivers::{imsic::*, iommu::*, pci::*, CpuId, CpuInfo};
+use drivers::{cpu::time_ms, imsic::*, iommu::*, pci::*, CpuId, CpuInfo};
 use page_tracking::collections::PageBox;
 use page_tracking::{HwMemRegion, HypPageAlloc, PageList, PageTracker};
 use riscv_page_tables::{GuestStagePageTable, GuestStagePagingMode};
@@ -103,7 +103,9 @@ impl HostDtBuilder {
         soc_node.add_prop("ranges")?;
 
         Imsic::get().add_host_imsic_node(&mut self.tree)?;
-        PcieRoot::get().add_host_pcie_node(&mut self.tree)?;
+        for pci in PcieRoot::roots() {
+            pci.add_host_pcie_node(&mut self.tree)?;
+        }
 
         Ok(self)
     }
@@ -265,26 +267,34 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             self.vm.add_imsic_pages(cpu_id, imsic_pages);
         }
 
-        let pci = PcieRoot::get();
-        pci.take_host_devices();
-        // Identity-map the PCIe BAR resources.
-        for (res_type, range) in pci.resources() {
-            let gpa = range.base().as_guest_phys(PageOwnerId::host());
-            self.vm.add_pci_region(gpa, range.length_bytes());
-            let pages = pci.take_host_resource(res_type).unwrap();
-            self.vm.add_pci_pages(gpa, pages);
-        }
-        // Attach our PCI devices to the IOMMU.
-        if Iommu::get().is_some() {
-            for dev in pci.devices() {
-                let mut dev = dev.lock();
-                if dev.owner() == Some(PageOwnerId::host()) {
-                    // Silence buggy clippy warning.
-                    #[allow(clippy::explicit_auto_deref)]
-                    self.vm.attach_pci_device(&mut *dev);
+        for pci in PcieRoot::roots() {
+            pci.take_host_devices();
+            // Identity-map the PCIe BAR resources.
+            for (res_type, range) in pci.resources() {
+                let gpa = range.base().as_guest_phys(PageOwnerId::host());
+                self.vm.add_pci_region(gpa, range.length_bytes());
+                let pages = pci.take_host_resource(res_type).unwrap();
+                self.vm.add_pci_pages(gpa, pages);
+            }
+            // Attach our PCI devices to the IOMMU. DMA from devices it doesn't translate can't be
+            // restricted, so those devices stay with the host.
+            if let Some(iommu) = Iommu::get() {
+                for dev in pci.devices() {
+                    let mut dev = dev.lock();
+                    if dev.owner() == Some(PageOwnerId::host())
+                        && iommu.translates_pci_device(dev.info().address())
+                    {
+                        // Silence buggy clippy warning.
+                        #[allow(clippy::explicit_auto_deref)]
+                        self.vm.attach_pci_device(&mut *dev);
+                    }
                 }
             }
         }
+        // DMA from platform devices is translated through our page tables as well.
+        if Iommu::get().is_some() {
+            self.vm.attach_platform_devices();
+        }
 
         // Host guarantees that the host pages it returns start at `HOST_VM_ALIGN`-aligned block,
         // and because we built the HwMemMap with a minimum region alignment of `HOST_VM_ALIGN`,
@@ -364,11 +374,13 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
         }
         self.vm.add_zero_pages(current_gpa, self.zero_pages);
 
-        // Set up MMIO emulation for the PCIe config space.
-        let config_mem = pci.config_space();
-        let config_gpa = config_mem.base().as_guest_phys(PageOwnerId::host());
-        self.vm
-            .add_mmio_region(config_gpa, config_mem.length_bytes());
+        // Set up MMIO emulation for the PCIe config spaces.
+        for pci in PcieRoot::roots() {
+            let config_mem = pci.config_space();
+            let config_gpa = config_mem.base().as_guest_phys(PageOwnerId::host());
+            self.vm
+                .add_mmio_region(config_gpa, config_mem.length_bytes());
+        }
 
         self.vm
     }
@@ -392,6 +404,63 @@ impl core::fmt::Display for MmioEmulationError {
     }
 }
 
+// Limits the rate at which IOMMU faults are reported, so that a misbehaving device can't flood the
+// console.
+#[derive(Default)]
+struct FaultLogLimiter {
+    window_start_ms: u64,
+    logged: u64,
+    suppressed: u64,
+}
+
+impl FaultLogLimiter {
+    // Maximum number of faults reported per second.
+    const MAX_PER_SEC: u64 = 16;
+
+    // Returns if a fault may be reported now. Faults that can't are counted, and the count is
+    // reported once the next one-second window starts.
+    fn allow(&mut self) -> bool {
+        let now = time_ms();
+        if now.wrapping_sub(self.window_start_ms) >= 1000 {
+            if self.suppressed != 0 {
+                println!("{} IOMMU faults were not reported", self.suppressed);
+            }
+            self.window_start_ms = now;
+            self.logged = 0;
+            self.suppressed = 0;
+        }
+        if self.logged < Self::MAX_PER_SEC {
+            self.logged += 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+}
+
+// Limits how often hotplug slots are polled for devices that were added or removed without the
+// host accessing the slot's port, since each poll reads the config space of every hotplug port.
+#[derive(Default)]
+struct HotplugPollTimer {
+    last_poll_ms: u64,
+}
+
+impl HotplugPollTimer {
+    // Minimum interval between polls.
+    const INTERVAL_MS: u64 = 100;
+
+    // Returns if the slots are due to be polled, and restarts the interval if so.
+    fn due(&mut self) -> bool {
+        let now = time_ms();
+        if now.wrapping_sub(self.last_poll_ms) < Self::INTERVAL_MS {
+            return false;
+        }
+        self.last_poll_ms = now;
+        true
+    }
+}
+
 #[derive(Default)]
 struct HostVmRunner {
     scause: u64,
@@ -399,6 +468,8 @@ struct HostVmRunner {
     htval: u64,
     htinst: u64,
     gprs: GeneralPurposeRegisters,
+    fault_log: FaultLogLimiter,
+    hotplug_poll: HotplugPollTimer,
 }
 
 impl HostVmRunner {
@@ -406,6 +477,25 @@ impl HostVmRunner {
         HostVmRunner::default()
     }
 
+    // Reports `fault` from a device owned by `owner`, or by no VM if it isn't attached.
+    fn report_iommu_fault(fault: &FaultRecord, owner: Option<PageOwnerId>) {
+        let mut owner_name = ArrayString::<32>::new();
+        match owner {
+            Some(id) if id.is_host() => owner_name.push_str("host"),
+            Some(id) => fmt::write(&mut owner_name, format_args!("guest {}", id.raw())).unwrap(),
+            None => owner_name.push_str("no VM"),
+        }
+        println!(
+            "IOMMU fault from device 0x{:x} ({}): cause {}, type {}, iotval 0x{:x}, iotval2 0x{:x}",
+            fault.device_id().bits(),
+            owner_name,
+            fault.cause(),
+            fault.transaction_type(),
+            fault.iotval(),
+            fault.iotval2()
+        );
+    }
+
     // Runs `vcpu_id` in `vm`.
     fn run<T: GuestStagePagingMode>(
         &mut self,
@@ -414,7 +504,22 @@ impl HostVmRunner {
     ) -> ControlFlow<()> {
         // Run until we shut down, or this vCPU stops.
         loop {
+            // Devices may be added to or removed from hotplug slots without the host accessing the
+            // slot's port, e.g. on surprise removal, so check the slots every so often.
+            if self.hotplug_poll.due() {
+                vm.poll_hotplug_slots();
+            }
             vm.run_vcpu(vcpu_id, VmCpuParent::Tsm(self)).unwrap();
+            // Devices stall DMA until their outstanding page requests are completed, so service
+            // them whenever we get control back.
+            vm.process_iommu_page_requests();
+            if let Some(iommu) = Iommu::get() {
+                iommu.process_faults(|fault, owner| {
+                    if self.fault_log.allow() {
+                        Self::report_iommu_fault(fault, owner);
+                    }
+                });
+            }
             if let Ok(Trap::Exception(e)) = Trap::from_scause(self.scause) {
                 use Exception::*;
                 match e {
@@ -455,7 +560,7 @@ impl HostVmRunner {
                         }
                     }
                     GuestLoadPageFault | GuestStorePageFault => {
-                        if let Err(err) = self.handle_page_fault(vm.page_tracker()) {
+                        if let Err(err) = self.handle_page_fault(&vm) {
                             println!("Unhandled page fault: {}", err);
                             return ControlFlow::Break(());
                         }
@@ -472,20 +577,15 @@ impl HostVmRunner {
         }
     }
 
-    fn handle_page_fault(
+    fn handle_page_fault<T: GuestStagePagingMode>(
         &mut self,
-        page_tracker: PageTracker,
+        vm: &FinalizedVm<T>,
     ) -> core::result::Result<(), MmioEmulationError> {
         // For now, the only thing we're expecting is MMIO emulation faults in PCI config space.
         let addr = (self.htval << 2) | (self.stval & 0x3);
-        let pci = PcieRoot::get();
-        if addr < pci.config_space().base().bits() {
-            return Err(MmioEmulationError::InvalidAddress(addr));
-        }
+        let pci =
+            PcieRoot::for_config_addr(addr).ok_or(MmioEmulationError::InvalidAddress(addr))?;
         let offset = addr - pci.config_space().base().bits();
-        if offset > pci.config_space().length_bytes() {
-            return Err(MmioEmulationError::InvalidAddress(addr));
-        }
 
         // Figure out from HTINST what the MMIO operation was. We know the source/destination is
         // always A0.
@@ -507,6 +607,7 @@ impl HostVmRunner {
             }
         };
 
+        let page_tracker = vm.page_tracker();
         if write {
             let val = self.gprs.reg(GprIndex::A0);
             pci.emulate_config_write(offset, val, width, page_tracker, PageOwnerId::host());
@@ -515,6 +616,10 @@ impl HostVmRunner {
             self.gprs.set_reg(GprIndex::A0, val);
         }
 
+        // Accessing a hotplug port may have added devices to or removed devices from its slot.
+        // Make sure DMA translation is up to date with them before the host can use them.
+        vm.update_hotplugged_devices();
+
         Ok(())
     }
 
@@ -789,6 +894,15 @@ impl<T: GuestStagePagingMode> HostVm<T> {
         vm.vm_pages().attach_pci_device(dev).unwrap();
     }
 
+    // Attaches the platform DMA masters to the host VM. DMA from the platform devices stays blocked
+    // if they can't be attached.
+    fn attach_platform_devices(&self) {
+        let vm = self.inner.as_initializing_vm().unwrap();
+        if let Err(e) = vm.vm_pages().attach_platform_devices() {
+            println!("Failed to attach platform devices to the IOMMU: {:?}", e);
+        }
+    }
+
     // Completes intialization of the host VM, making it runnable.
     fn finalize(
         &self,
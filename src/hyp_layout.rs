@@ -28,6 +28,10 @@
 // +-------------------------+ HYP_STACK_TOP (0xffff_ffff_ffe0_0000)
 // | (unused 2Mb)            |
 // +-------------------------+ End of Address Space.
+//
+// Each CPU has one page table per U-mode service. The page tables share every mapping but the
+// U-mode ones (from UMODE_START to the end of the U-mode Input Area), which belong to their
+// U-mode service.
 
 use riscv_pages::{PageAddr, PageSize, SupervisorVirt};
 use static_assertions::const_assert;
@@ -63,6 +67,30 @@ pub enum UmodeSlotId {
     B,
 }
 
+/// The separately linked U-mode services. Each service runs in its own address space, with its
+/// own ELF mappings, input region and mapping slots.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UmodeService {
+    /// Attestation evidence and certificates, signed with the TVM attestation CDI.
+    Attestation,
+    /// Sealing keys, derived from the TVM sealing CDI.
+    Sealing,
+}
+
+/// The number of U-mode services.
+pub const UMODE_SERVICES: usize = 2;
+
+impl UmodeService {
+    /// All the U-mode services, in index order.
+    pub const ALL: [UmodeService; UMODE_SERVICES] =
+        [UmodeService::Attestation, UmodeService::Sealing];
+
+    /// Returns the index of this service.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
 /// Maximum size of the private mappings area. Must be 4k-aligned.
 pub const UMODE_MAPPINGS_SIZE: u64 = UMODE_MAPPING_SLOTS * UMODE_MAPPING_SLOT_SIZE;
 /// Starting page address of slot A.
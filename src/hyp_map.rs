@@ -10,14 +10,16 @@ use data_model::{DataInit, VolatileMemory, VolatileMemoryError, VolatileSlice};
 use page_tracking::{HwMemMap, HwMemRegion, HwMemRegionType, HwReservedMemType, HypPageAlloc};
 use riscv_elf::{ElfMap, ElfSegment, ElfSegmentPerms};
 use riscv_page_tables::{
-    FirstStageMapper, FirstStagePageTable, PageTableError, PagingMode, PteFieldBits, PteLeafPerms,
-    Sv48,
+    tlb, FirstStageMapper, FirstStagePageTable, PageTableError, PagingMode, PteFieldBits,
+    PteLeafPerms, Sv48,
 };
 use riscv_pages::{
     InternalClean, InternalDirty, Page, PageAddr, PageSize, RawAddr, SeqPageIter, SequentialPages,
     SupervisorPageAddr, SupervisorPhys, SupervisorVirt,
 };
-use riscv_regs::{satp, sstatus, LocalRegisterCopy, ReadWriteable, SatpHelpers, CSR};
+use riscv_regs::{
+    satp, sstatus, LocalRegisterCopy, ReadWriteable, Readable, SatpHelpers, Writeable, CSR,
+};
 use sync::Once;
 
 // The copy to/from guest memory routines defined in extable.S.
@@ -70,6 +72,8 @@ pub enum Error {
     CreateRoot(PageTableError),
     /// Insufficient space for page table
     InsufficientSpaceForPageTable,
+    /// Wrong number of U-mode service ELFs.
+    UmodeServiceCount(usize),
 }
 
 // Represents a virtual address region of the hypervisor created from the Hardware Memory Map.
@@ -243,7 +247,7 @@ impl UmodeElfRegion {
             // Write to user mapping setting SUM in SSTATUS.
             CSR.sstatus.modify(sstatus::sum.val(1));
             // Safety:
-            // - this write is in a umode region guaranteed to be mapped by HypMap in every page table.
+            // - this write is in a umode region mapped by HypMap in the active service page table.
             // - the region starts at self.vaddr and is self.size byte long. `len` is <= `self.size`.
             unsafe {
                 core::ptr::copy(data.as_ptr(), self.vaddr.bits() as *mut u8, len);
@@ -258,7 +262,7 @@ impl UmodeElfRegion {
         // Write to user mapping setting SUM in SSTATUS.
         CSR.sstatus.modify(sstatus::sum.val(1));
         // Safety:
-        // - this write is in a umode region guaranteed to be mapped by HypMap in every page table.
+        // - this write is in a umode region mapped by HypMap in the active service page table.
         // - writing to this region start at offset `copied` and goes until the mapped size of the region.
         unsafe {
             core::ptr::write_bytes(dest as *mut u8, 0, len);
@@ -388,8 +392,8 @@ pub enum UmodeSlotPerm {
     Writable,
 }
 
-/// A page table that contains hypervisor mappings.
-pub struct HypPageTable {
+// A page table that contains the hypervisor mappings and the mappings of a U-mode service.
+struct UmodePageTable {
     /// The pagetable containing hypervisor mappings.
     sv48: FirstStagePageTable<Sv48>,
     /// U-mode input region for this page-table.
@@ -398,27 +402,104 @@ pub struct HypPageTable {
     pte_pages: RefCell<SeqPageIter<InternalClean>>,
 }
 
-impl HypPageTable {
-    /// Returns the value of the SATP register for this page table.
-    pub fn satp(&self) -> u64 {
+impl UmodePageTable {
+    // Creates a page table with the private mappings of a U-mode service: its ELF regions
+    // `elf_regions`, its own U-mode Input Region, and a pool of PTE pages for its mapping slots.
+    fn new<'a>(
+        elf_regions: impl Iterator<Item = &'a UmodeElfRegion>,
+        hyp_mem: &mut HypPageAlloc,
+    ) -> Result<Self, Error> {
+        // Create empty sv48 page table
+        let root_page = hyp_mem
+            .take_pages_for_hyp_state(1)
+            .into_iter()
+            .next()
+            .ok_or(Error::InsufficientSpaceForPageTable)?;
+        let sv48: FirstStagePageTable<Sv48> =
+            FirstStagePageTable::new(root_page).map_err(Error::CreateRoot)?;
+        // Map U-mode ELF region.
+        for r in elf_regions {
+            r.map(&sv48, hyp_mem)?;
+        }
+        // Alloc and map the U-mode Input Region for this page-table.
+        let umode_input = UmodeInputRegion::map(&sv48, hyp_mem)?;
+        // Alloc pte_pages for U-mode mappings.
+        let pte_pages = hyp_mem
+            .take_pages_for_hyp_state(Sv48::max_pte_pages(
+                UMODE_MAPPINGS_SIZE / PageSize::Size4k as u64,
+            ) as usize)
+            .into_iter();
+        Ok(UmodePageTable {
+            sv48,
+            umode_input: RefCell::new(umode_input),
+            pte_pages: RefCell::new(pte_pages),
+        })
+    }
+
+    // Returns the value of the SATP register for this page table.
+    fn satp(&self) -> u64 {
         let mut satp = LocalRegisterCopy::<u64, satp::Register>::new(0);
         satp.set_from(&self.sv48, 0);
         satp.get()
     }
+}
 
-    /// Restores U-mode ELF mappings to initial state.
-    pub fn restore_umode(&self) {
+/// A per-CPU set of page tables that contain hypervisor mappings, one for each U-mode service.
+/// The hypervisor mappings are the same in every page table, but each page table maps only the
+/// U-mode ELF, the U-mode Input Region and the U-mode slots of its own service. The hypervisor
+/// runs on the page table of the first service, and switches to the page table of a service
+/// while the service runs.
+pub struct HypPageTable {
+    umode_page_tables: ArrayVec<UmodePageTable, UMODE_SERVICES>,
+}
+
+impl HypPageTable {
+    // Returns the page table of U-mode service `service`.
+    fn umode_page_table(&self, service: UmodeService) -> &UmodePageTable {
+        &self.umode_page_tables[service.index()]
+    }
+
+    /// Returns the value of the SATP register for this page table.
+    pub fn satp(&self) -> u64 {
+        self.umode_page_table(UmodeService::ALL[0]).satp()
+    }
+
+    // Switches this CPU to the page table with SATP value `satp`.
+    fn switch_to(satp: u64) {
+        if CSR.satp.get() != satp {
+            CSR.satp.set(satp);
+            // The page tables share the same ASID: flush the stale translations.
+            tlb::sfence_vma(None, None);
+        }
+    }
+
+    /// Switches this CPU to the page table of U-mode service `service`. Must be called on the CPU
+    /// that owns this page table.
+    pub fn activate_umode(&self, service: UmodeService) {
+        Self::switch_to(self.umode_page_table(service).satp());
+    }
+
+    /// Switches this CPU back to the hypervisor page table. Must be called on the CPU that owns
+    /// this page table.
+    pub fn deactivate_umode(&self) {
+        Self::switch_to(self.satp());
+    }
+
+    /// Restores U-mode ELF mappings of `service` to initial state. The page table of `service` must
+    /// be active.
+    pub fn restore_umode(&self, service: UmodeService) {
         for r in HypMap::get()
-            .umode_elf_regions()
+            .umode_elf_regions(service)
             .filter(|r| r.pte_fields == PteFieldBits::leaf_with_perms(PteLeafPerms::URW))
         {
             r.restore();
         }
     }
 
-    /// Returns a mapper for U-mode slot `slot` for `num_pages` pages.
+    /// Returns a mapper for U-mode slot `slot` of `service` for `num_pages` pages.
     pub fn umode_slot_mapper(
         &self,
+        service: UmodeService,
         slot: UmodeSlotId,
         num_pages: u64,
         slot_perm: UmodeSlotPerm,
@@ -427,10 +508,11 @@ impl HypPageTable {
             return Err(Error::OutOfMap);
         }
         let vaddr = HypMap::umode_slot_va(slot);
-        let mapper = self
+        let page_table = self.umode_page_table(service);
+        let mapper = page_table
             .sv48
             .map_range(vaddr, PageSize::Size4k, num_pages, &mut || {
-                self.pte_pages.borrow_mut().next()
+                page_table.pte_pages.borrow_mut().next()
             })
             .map_err(Error::MapperCreationFailed)?;
         let perms = match slot_perm {
@@ -444,9 +526,11 @@ impl HypPageTable {
         })
     }
 
-    /// Unmaps `num_pages` from umode slot `slot` and returns the iterator of page addresses unmapped.
+    /// Unmaps `num_pages` from umode slot `slot` of `service` and returns the iterator of page
+    /// addresses unmapped.
     pub fn unmap_umode_slot(
         &self,
+        service: UmodeService,
         slot: UmodeSlotId,
         num_pages: u64,
     ) -> Result<impl Iterator<Item = SupervisorPageAddr> + '_, Error> {
@@ -454,20 +538,31 @@ impl HypPageTable {
         if num_pages > PageSize::num_4k_pages(UMODE_MAPPING_SLOT_SIZE) {
             return Err(Error::OutOfMap);
         }
-        self.sv48
+        self.umode_page_table(service)
+            .sv48
             .unmap_range(vaddr, PageSize::Size4k, num_pages)
             .map_err(Error::UnmapFailed)
     }
 
-    /// Copies `data` into the U-mode Input Region. The structure will be accessible read-only in
-    /// U-mode at address UMODE_INPUT_START.
-    pub fn copy_to_umode_input<T: DataInit>(&self, data: T) -> Result<(), Error> {
-        self.umode_input.borrow_mut().store(data)
+    /// Copies `data` into the U-mode Input Region of `service`. The structure will be accessible
+    /// read-only in U-mode at address UMODE_INPUT_START.
+    pub fn copy_to_umode_input<T: DataInit>(
+        &self,
+        service: UmodeService,
+        data: T,
+    ) -> Result<(), Error> {
+        self.umode_page_table(service)
+            .umode_input
+            .borrow_mut()
+            .store(data)
     }
 
-    /// Clear the U-mode Input Region.
-    pub fn clear_umode_input(&self) {
-        self.umode_input.borrow_mut().clear()
+    /// Clear the U-mode Input Region of `service`.
+    pub fn clear_umode_input(&self, service: UmodeService) {
+        self.umode_page_table(service)
+            .umode_input
+            .borrow_mut()
+            .clear()
     }
 }
 
@@ -507,19 +602,28 @@ impl UmodeSlotMapper<'_> {
 /// A set of global mappings of the hypervisor that can be used to create page tables.
 pub struct HypMap {
     hw_map_regions: HwMapRegionsVec,
-    umode_elf_regions: UmodeElfRegionsVec,
+    umode_elf_regions: ArrayVec<UmodeElfRegionsVec, UMODE_SERVICES>,
 }
 
 impl HypMap {
-    /// Creates a new hypervisor map from a hardware memory mem map and a umode ELF.
-    pub fn init(mem_map: HwMemMap, umode_elf: &ElfMap<'static>) -> Result<(), Error> {
+    /// Creates a new hypervisor map from a hardware memory mem map and the U-mode service ELFs,
+    /// in `UmodeService::ALL` order.
+    pub fn init(mem_map: HwMemMap, umode_elfs: &[ElfMap<'static>]) -> Result<(), Error> {
+        if umode_elfs.len() != UMODE_SERVICES {
+            return Err(Error::UmodeServiceCount(umode_elfs.len()));
+        }
         let hw_map_regions = mem_map
             .regions()
             .filter_map(HwMapRegion::from_hw_mem_region)
             .collect();
-        let umode_elf_regions = umode_elf
-            .segments()
-            .map(UmodeElfRegion::from_umode_elf_segment)
+        let umode_elf_regions = umode_elfs
+            .iter()
+            .map(|umode_elf| {
+                umode_elf
+                    .segments()
+                    .map(UmodeElfRegion::from_umode_elf_segment)
+                    .collect::<Result<_, _>>()
+            })
             .collect::<Result<_, _>>()?;
         let hypmap = HypMap {
             hw_map_regions,
@@ -535,9 +639,9 @@ impl HypMap {
         HYPMAP.get().unwrap()
     }
 
-    // Returns an iterator for the U-mode ELF regions.
-    fn umode_elf_regions(&self) -> impl Iterator<Item = &UmodeElfRegion> {
-        self.umode_elf_regions.iter()
+    // Returns an iterator for the U-mode ELF regions of `service`.
+    fn umode_elf_regions(&self, service: UmodeService) -> impl Iterator<Item = &UmodeElfRegion> {
+        self.umode_elf_regions[service.index()].iter()
     }
 
     /// Returns the virtual address of U-mode mapping slot `slot`.
@@ -590,39 +694,148 @@ impl HypMap {
         hyp_mem: &mut HypPageAlloc,
         stack_pages: SequentialPages<InternalDirty>,
     ) -> Result<HypPageTable, Error> {
-        // Create empty sv48 page table
-        // Unwrap okay: we expect to have at least one page free.
-        let root_page = hyp_mem
-            .take_pages_for_hyp_state(1)
-            .into_iter()
-            .next()
-            .ok_or(Error::InsufficientSpaceForPageTable)?;
-        let sv48: FirstStagePageTable<Sv48> =
-            FirstStagePageTable::new(root_page).map_err(Error::CreateRoot)?;
+        let stack = HypStackRegion::new(stack_pages)?;
+        let umode_page_tables = UmodeService::ALL
+            .iter()
+            .map(|&service| self.new_umode_page_table(hyp_mem, &stack, service))
+            .collect::<Result<_, _>>()?;
+        Ok(HypPageTable { umode_page_tables })
+    }
+
+    // Creates a new page table for U-mode service `service`.
+    fn new_umode_page_table(
+        &self,
+        hyp_mem: &mut HypPageAlloc,
+        stack: &HypStackRegion,
+        service: UmodeService,
+    ) -> Result<UmodePageTable, Error> {
+        let page_table = UmodePageTable::new(self.umode_elf_regions(service), hyp_mem)?;
         // Map hardware map regions
         for r in &self.hw_map_regions {
-            r.map(&sv48, &mut || {
+            r.map(&page_table.sv48, &mut || {
                 hyp_mem.take_pages_for_hyp_state(1).into_iter().next()
             })?;
         }
-        // Map U-mode ELF region.
-        for r in &self.umode_elf_regions {
-            r.map(&sv48, hyp_mem)?;
+        // Map the hypervisor stack for this page-table.
+        stack.map(&page_table.sv48, hyp_mem)?;
+        Ok(page_table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use page_tracking::HwMemMapBuilder;
+    use test_system::*;
+
+    // Size of the memory the test page tables are allocated from.
+    const TEST_MEM_SIZE: usize = 4 * 1024 * 1024;
+
+    #[repr(C, align(2097152))]
+    struct TestMem([u8; TEST_MEM_SIZE]);
+
+    static mut TEST_MEM: TestMem = TestMem([0; TEST_MEM_SIZE]);
+
+    // Returns a hypervisor page allocator over `TEST_MEM`. Must be called at most once.
+    fn test_hyp_mem() -> HypPageAlloc {
+        // Safe because `TEST_MEM` is only used through this allocator, and the test runner runs
+        // with a 1:1 map of physical to virtual addresses.
+        let mut mem_map = unsafe {
+            HwMemMapBuilder::new(PageSize::Size4k as u64)
+                .add_memory_region(
+                    RawAddr::supervisor(core::ptr::addr_of!(TEST_MEM) as u64),
+                    TEST_MEM_SIZE as u64,
+                )
+                .unwrap()
+                .build()
+        };
+        HypPageAlloc::new(&mut mem_map).unwrap()
+    }
+
+    // Unmaps the 4k page at `vaddr` from `page_table`, and returns its physical address if it was
+    // mapped.
+    fn unmap_page(
+        page_table: &UmodePageTable,
+        vaddr: PageAddr<SupervisorVirt>,
+    ) -> Option<SupervisorPageAddr> {
+        page_table
+            .sv48
+            .unmap_range(vaddr, PageSize::Size4k, 1)
+            .ok()?
+            .next()
+    }
+
+    #[test_case]
+    fn UmodeServicesIsolationTest() -> TestResult {
+        static FIRST_DATA: [u8; 8] = [0xa5; 8];
+        static SECOND_DATA: [u8; 8] = [0x5a; 8];
+        let elf_va = PageAddr::new(RawAddr::supervisor_virt(UMODE_BINARY_START)).unwrap();
+        let elf_region = |data: &'static [u8]| UmodeElfRegion {
+            vaddr: elf_va,
+            size: PageSize::Size4k as usize,
+            pte_fields: PteFieldBits::leaf_with_perms(PteLeafPerms::URW),
+            data: Some(data),
+        };
+        let mut hyp_mem = test_hyp_mem();
+        let first = UmodePageTable::new([elf_region(&FIRST_DATA)].iter(), &mut hyp_mem).unwrap();
+        let second = UmodePageTable::new([elf_region(&SECOND_DATA)].iter(), &mut hyp_mem).unwrap();
+
+        // A page mapped in a slot of one service is not mapped in the other one.
+        let slot_va = HypMap::umode_slot_va(UmodeSlotId::A);
+        let page = hyp_mem.take_pages_for_hyp_state(1);
+        {
+            let mapper = first
+                .sv48
+                .map_range(slot_va, PageSize::Size4k, 1, &mut || {
+                    first.pte_pages.borrow_mut().next()
+                })
+                .unwrap();
+            // Safe because the page was just allocated, and is only mapped in U-mode.
+            unsafe {
+                mapper
+                    .map_contiguous(
+                        slot_va,
+                        page.base(),
+                        1,
+                        PteFieldBits::leaf_with_perms(PteLeafPerms::URW),
+                    )
+                    .unwrap();
+            }
         }
-        // Alloc and map the hypervisor stack for this page-table.
-        HypStackRegion::new(stack_pages)?.map(&sv48, hyp_mem)?;
-        // Alloc and map the U-mode Input Region for this page-table.
-        let umode_input = UmodeInputRegion::map(&sv48, hyp_mem)?;
-        // Alloc pte_pages for U-mode mappings.
-        let pte_pages = hyp_mem
-            .take_pages_for_hyp_state(Sv48::max_pte_pages(
-                UMODE_MAPPINGS_SIZE / PageSize::Size4k as u64,
-            ) as usize)
-            .into_iter();
-        Ok(HypPageTable {
-            sv48,
-            umode_input: RefCell::new(umode_input),
-            pte_pages: RefCell::new(pte_pages),
-        })
+        test_result_true!(
+            unmap_page(&second, slot_va).is_none(),
+            "U-mode slot mappings are private"
+        )?;
+        test_result_true!(
+            unmap_page(&first, slot_va) == Some(page.base()),
+            "U-mode slot mapping"
+        )?;
+
+        // Each service has its own copy of its ELF, at the same address.
+        let first_elf = unmap_page(&first, elf_va).unwrap();
+        let second_elf = unmap_page(&second, elf_va).unwrap();
+        // Safe because the test runner runs with a 1:1 map of physical to virtual addresses, and
+        // the ELF pages were populated when the page tables were created.
+        let (first_byte, second_byte) = unsafe {
+            (
+                *(first_elf.bits() as *const u8),
+                *(second_elf.bits() as *const u8),
+            )
+        };
+        test_result_true!(
+            first_elf != second_elf && first_byte == 0xa5 && second_byte == 0x5a,
+            "U-mode ELF mappings are private"
+        )?;
+
+        // And its own U-mode Input Region.
+        let input_va = PageAddr::new(RawAddr::supervisor_virt(UMODE_INPUT_START)).unwrap();
+        test_result_true!(
+            matches!(
+                (unmap_page(&first, input_va), unmap_page(&second, input_va)),
+                (Some(f), Some(s)) if f != s
+            ),
+            "U-mode Input Regions are private"
+        )?;
+        Ok(())
     }
 }
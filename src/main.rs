@@ -39,6 +39,7 @@ mod hyp_layout;
 mod hyp_map;
 mod smp;
 mod trap;
+mod tvm_attestation;
 mod umode;
 mod vm;
 mod vm_cpu;
@@ -47,14 +48,22 @@ mod vm_interrupts;
 mod vm_pages;
 mod vm_pmu;
 
+use arrayvec::ArrayVec;
+use attestation::DiceHandoff;
 use backtrace::backtrace;
 use device_tree::{DeviceTree, DeviceTreeError, Fdt};
 use drivers::{
-    imsic::Imsic, iommu::Iommu, pci::PcieRoot, pmu::PmuInfo, reset::ResetDriver, uart::UartDriver,
+    imsic::{Imsic, ImsicInterruptId},
+    iommu::{CompletionWaiter, Iommu},
+    pci::PcieRoot,
+    pmu::PmuInfo,
+    reset::ResetDriver,
+    uart::UartDriver,
     CpuInfo,
 };
 use host_vm::{HostVm, HostVmLoader, HOST_VM_ALIGN};
 use hyp_alloc::HypAlloc;
+use hyp_layout::{UmodeService, UMODE_SERVICES};
 use hyp_map::HypMap;
 use page_tracking::*;
 use riscv_elf::ElfMap;
@@ -70,6 +79,7 @@ use s_mode_utils::print::*;
 use s_mode_utils::sbi_console::SbiConsoleV01;
 use smp::PerCpu;
 use sync::Once;
+use tvm_attestation::PlatformMeasurements;
 use umode::UmodeTask;
 
 #[panic_handler]
@@ -99,8 +109,29 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 extern "C" {
     static _start: u8;
     static _stack_end: u8;
+    static _image_ro_end: u8;
     static _umode_bin: u8;
     static _umode_bin_len: u8;
+    static _umode_sealing_bin: u8;
+    static _umode_sealing_bin_len: u8;
+}
+
+// Returns the ELF binary of U-mode service `service`.
+fn umode_service_binary(service: UmodeService) -> &'static [u8] {
+    match service {
+        // Safe, because it comes from the Linker
+        UmodeService::Attestation => unsafe {
+            let umode_bin = core::ptr::addr_of!(_umode_bin) as *const u8;
+            let umode_bin_len = core::ptr::addr_of!(_umode_bin_len) as usize;
+            core::slice::from_raw_parts::<u8>(umode_bin, umode_bin_len)
+        },
+        // Safe, because it comes from the Linker
+        UmodeService::Sealing => unsafe {
+            let umode_bin = core::ptr::addr_of!(_umode_sealing_bin) as *const u8;
+            let umode_bin_len = core::ptr::addr_of!(_umode_sealing_bin_len) as usize;
+            core::slice::from_raw_parts::<u8>(umode_bin, umode_bin_len)
+        },
+    }
 }
 
 /// The allocator used for boot-time dynamic memory allocations.
@@ -145,6 +176,27 @@ fn poweroff() -> ! {
 /// The host VM that all CPUs enter at boot.
 static HOST_VM: Once<HostVm<Sv48x4>> = Once::new();
 
+// Lets CPUs sleep while the IOMMU completes commands. The IOMMU wakes up the waiting CPU with a
+// dedicated interrupt once it's done.
+struct IommuCompletionWaiter;
+
+impl CompletionWaiter for IommuCompletionWaiter {
+    fn wakeup_msi(&self) -> (SupervisorPageAddr, u32) {
+        // Unwrap ok: every CPU has a supervisor interrupt file.
+        Imsic::get()
+            .interrupt_msi(PerCpu::this_cpu().cpu_id(), ImsicInterruptId::IommuWakeup)
+            .unwrap()
+    }
+
+    fn wait(&self, timeout_ms: u64) {
+        smp::wfi_timeout(timeout_ms);
+        // Other interrupts that ended the wait are left for their handlers.
+        Imsic::claim_interrupt(ImsicInterruptId::IommuWakeup);
+    }
+}
+
+static IOMMU_COMPLETION_WAITER: IommuCompletionWaiter = IommuCompletionWaiter;
+
 /// Builds the hardware memory map from the device-tree. The kernel & initramfs image regions are
 /// aligned to `HOST_VM_ALIGN` so that they can be mapped directly into the host VM's guest
 /// physical address space.
@@ -394,6 +446,8 @@ enum Error {
     CreateHypervisorMap(hyp_map::Error),
     /// Creating (per CPU) SMP state
     CreateSmpState(smp::Error),
+    /// Invalid DICE handoff from firmware
+    DiceHandoff(attestation::Error),
     /// Problem creating derived device tree
     FdtCreation(DeviceTreeError),
     /// Problem parsing device tree
@@ -430,6 +484,7 @@ impl Display for Error {
             }
             CreateHypervisorMap(e) => write!(f, "Cannot create Hypervisor map: {:?}", e),
             CreateSmpState(e) => write!(f, "Error during (per CPU) SMP setup: {}", e),
+            DiceHandoff(e) => write!(f, "Failed to parse DICE handoff: {:?}", e),
             FdtCreation(e) => write!(f, "Failed to construct device-tree: {}", e),
             FdtParsing(e) => write!(f, "Failed to read FDT: {}", e),
             HeapOutOfSpace => write!(f, "Not enough free memory for hypervisor heap"),
@@ -459,6 +514,26 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
 
     test_declare_pass!("Salus Boot", hart_id);
 
+    // The DICE handoff lives in the FDT, which stays reserved and mapped in the hypervisor.
+    // Initializing the handoff zeroes the CDIs in place, so that the only copies left are the ones
+    // held by the attestation code. Locate it and import it before the FDT is borrowed for the rest
+    // of boot.
+    // Safe because we trust that the firmware passed a valid FDT.
+    let handoff_range = unsafe { Fdt::new_from_raw_pointer(fdt_addr as *const u8) }
+        .map_err(Error::FdtParsing)?
+        .dice_handoff_range();
+    if let Some(range) = handoff_range {
+        // Safety: The FDT is owned by the hypervisor, and `range` is within it. No reference to the
+        // FDT is live yet and the slice is derived from the FDT address, so this is the only
+        // reference to the handoff property while the CDIs are zeroed.
+        let handoff = unsafe {
+            core::slice::from_raw_parts_mut((fdt_addr as *mut u8).add(range.start), range.len())
+        };
+        DiceHandoff::init(handoff).map_err(Error::DiceHandoff)?;
+    } else {
+        println!("No DICE handoff from firmware: unendorsed TVM evidence, no sealing keys");
+    }
+
     // Safe because we trust that the firmware passed a valid FDT.
     let hyp_fdt =
         unsafe { Fdt::new_from_raw_pointer(fdt_addr as *const u8) }.map_err(Error::FdtParsing)?;
@@ -539,27 +614,33 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
     );
     Imsic::setup_this_cpu();
 
-    // Probe for a PCI bus.
+    // Probe for the PCIe root complexes.
     PcieRoot::probe_from(&hyp_dt, &mut mem_map)
         .map_err(|e| Error::RequiredDeviceProbe(RequiredDeviceProbe::Pci(e)))?;
-    let pci = PcieRoot::get();
-    for dev in pci.devices() {
-        let dev = dev.lock();
+    for pci in PcieRoot::roots() {
         println!(
-            "Found func {}; type: {}, MSI: {}, MSI-X: {}, PCIe: {}",
-            dev.info(),
-            dev.info().header_type(),
-            dev.has_msi(),
-            dev.has_msix(),
-            dev.is_pcie(),
+            "PCIe root complex for segment {} at 0x{:08x}",
+            pci.segment(),
+            pci.config_space().base().bits()
         );
-        for bar in dev.bar_info().bars() {
+        for dev in pci.devices() {
+            let dev = dev.lock();
             println!(
-                "BAR{:}: type {:?}, size 0x{:x}",
-                bar.index(),
-                bar.bar_type(),
-                bar.size()
+                "Found func {}; type: {}, MSI: {}, MSI-X: {}, PCIe: {}",
+                dev.info(),
+                dev.info().header_type(),
+                dev.has_msi(),
+                dev.has_msix(),
+                dev.is_pcie(),
             );
+            for bar in dev.bar_info().bars() {
+                println!(
+                    "BAR{:}: type {:?}, size 0x{:x}",
+                    bar.index(),
+                    bar.bar_type(),
+                    bar.size()
+                );
+            }
         }
     }
 
@@ -589,14 +670,29 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
     let guest_phys_size = mem_map.regions().last().unwrap().end().bits()
         - mem_map.regions().next().unwrap().base().bits();
 
-    // Parse the user-mode ELF containing the user-mode task.
-    // Safe, because it comes from the Linker
-    let umode_bytes = unsafe {
-        let umode_bin = core::ptr::addr_of!(_umode_bin) as *const u8;
-        let umode_bin_len = core::ptr::addr_of!(_umode_bin_len) as usize;
-        core::slice::from_raw_parts::<u8>(umode_bin, umode_bin_len)
+    // Parse the user-mode ELFs containing the user-mode services.
+    let umode_binaries: ArrayVec<&[u8], UMODE_SERVICES> = UmodeService::ALL
+        .iter()
+        .map(|&service| umode_service_binary(service))
+        .collect();
+    let umode_elfs = umode_binaries
+        .iter()
+        .map(|&bytes| ElfMap::new(bytes))
+        .collect::<Result<ArrayVec<_, UMODE_SERVICES>, _>>()
+        .map_err(Error::LoadUserMode)?;
+
+    // Measure Salus and the platform configuration, for the VMs platform measurement registers.
+    // Only the read-only part of the image is measured. The data and BSS sections have already been
+    // written to by the time we get here (e.g. by the console setup), so hashing them would give a
+    // different measurement on every boot. Their initial content is still covered by the previous
+    // boot layer, which measures the whole Salus image to derive the CDIs it hands off to us.
+    // Safe, because the linker places the read-only image between `_start` and `_image_ro_end`.
+    let salus_image = unsafe {
+        let start = core::ptr::addr_of!(_start);
+        let end = core::ptr::addr_of!(_image_ro_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
     };
-    let umode_elf = ElfMap::new(umode_bytes).map_err(Error::LoadUserMode)?;
+    PlatformMeasurements::init(salus_image, &umode_binaries, &hyp_dt);
 
     println!("HW memory map:");
     for (i, r) in mem_map.regions().enumerate() {
@@ -609,32 +705,35 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
         );
     }
 
-    println!("umode memory map:");
-    for (i, s) in umode_elf.segments().enumerate() {
-        println!(
-            "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
-            i,
-            s.vaddr(),
-            s.vaddr() + s.size() as u64,
-            s.perms()
-        );
+    for (service, umode_elf) in UmodeService::ALL.iter().zip(umode_elfs.iter()) {
+        println!("umode {:?} memory map:", service);
+        for (i, s) in umode_elf.segments().enumerate() {
+            println!(
+                "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
+                i,
+                s.vaddr(),
+                s.vaddr() + s.size() as u64,
+                s.perms()
+            );
+        }
     }
 
-    // Create the hypervisor mapping from the hardware memory map and the U-mode ELF.
-    HypMap::init(mem_map, &umode_elf).map_err(Error::CreateHypervisorMap)?;
+    // Create the hypervisor mapping from the hardware memory map and the U-mode ELFs.
+    HypMap::init(mem_map, &umode_elfs).map_err(Error::CreateHypervisorMap)?;
 
     // Set up per-CPU memory and prepare the structures for secondary CPUs boot.
     PerCpu::init(hart_id, &mut hyp_mem).map_err(Error::CreateSmpState)?;
 
     // Find and initialize the IOMMU.
-    match Iommu::probe_from(PcieRoot::get(), &mut || {
+    match Iommu::probe_from(&hyp_dt, &mut || {
         hyp_mem.take_pages_for_host_state(1).into_iter().next()
     }) {
         Ok(_) => {
-            println!(
-                "Found RISC-V IOMMU version 0x{:x}",
-                Iommu::get().unwrap().version()
-            );
+            let iommu = Iommu::get().unwrap();
+            println!("Found RISC-V IOMMU version 0x{:x}", iommu.version());
+            if let Err(e) = iommu.enable_completion_interrupts(&IOMMU_COMPLETION_WAITER) {
+                println!("IOMMU command completion interrupts unavailable: {:?}", e);
+            }
         }
         Err(e) => {
             println!("Failed to probe IOMMU: {:?}", e);
@@ -642,7 +741,7 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
     };
 
     // Initialize global Umode state.
-    UmodeTask::init(umode_elf);
+    UmodeTask::init(&umode_elfs);
 
     // Now load the host VM.
     let host = HostVmLoader::new(
@@ -5,7 +5,7 @@
 use core::arch::asm;
 use core::cell::{RefCell, RefMut};
 use core::fmt;
-use drivers::{imsic::Imsic, CpuId, CpuInfo};
+use drivers::{cpu::SupervisorTimerGuard, imsic::Imsic, CpuId, CpuInfo};
 use page_tracking::HypPageAlloc;
 use riscv_pages::{
     InternalDirty, PageAddr, PageSize, RawAddr, SequentialPages, SupervisorPageAddr,
@@ -249,6 +249,16 @@ pub fn wfi() {
     CSR.sstatus.modify(sstatus::sie.val(0));
 }
 
+/// Halts this CPU until an interrupt is pending or `timeout_ms` have passed. Unlike `wfi()`, the
+/// interrupt isn't taken, so one that's already pending ends the wait right away. Interrupts are
+/// left pending on return; the caller claims the ones it was waiting for.
+pub fn wfi_timeout(timeout_ms: u64) {
+    let _timer = SupervisorTimerGuard::arm_ms(timeout_ms);
+    // Safety: WFI behavior is well-defined. SSTATUS.SIE is clear, so the CPU resumes here once an
+    // interrupt is pending without taking it.
+    unsafe { asm!("wfi", options(nomem, nostack)) };
+}
+
 /// Sends an IPI to `cpu`.
 pub fn send_ipi(cpu: CpuId) {
     Imsic::get().send_ipi(cpu).unwrap();
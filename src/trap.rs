@@ -161,8 +161,9 @@ fn handle_interrupt(irq: Interrupt) -> bool {
             let mut handled = false;
             while let Some(id) = Imsic::next_pending_interrupt() {
                 match id {
-                    // For now IPIs just wake up the CPU.
-                    ImsicInterruptId::Ipi => {
+                    // For now IPIs just wake up the CPU. IOMMU wakeups are only of interest to a CPU
+                    // waiting for the IOMMU, which claims them itself.
+                    ImsicInterruptId::Ipi | ImsicInterruptId::IommuWakeup => {
                         handled = true;
                     }
                 }
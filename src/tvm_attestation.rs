@@ -0,0 +1,315 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use arrayvec::ArrayVec;
+use attestation::{AttestationManager, Error, TcgPcrIndex, MSMT_REGISTERS};
+use const_oid::db::rfc5912::{ID_SHA_256, ID_SHA_384, ID_SHA_512};
+use device_tree::{DeviceTree, DeviceTreeNode};
+use digest::{Digest, Output};
+use sbi_rs::{AttestationCapabilities, HashAlgorithm};
+use sync::Once;
+use u_mode_api::cert::{MeasurementRegisterDigest, MAX_DIGEST_LEN};
+
+type Result<T> = core::result::Result<T, Error>;
+
+/// Runs `$body` with `$mgr` bound to the `AttestationManager` wrapped by a
+/// `TvmAttestationManager`, whatever its measurement hash algorithm.
+macro_rules! with_attestation_mgr {
+    ($tvm_mgr:expr, $mgr:ident => $body:expr) => {
+        match $tvm_mgr {
+            $crate::tvm_attestation::TvmAttestationManager::Sha256($mgr) => $body,
+            $crate::tvm_attestation::TvmAttestationManager::Sha384($mgr) => $body,
+            $crate::tvm_attestation::TvmAttestationManager::Sha512($mgr) => $body,
+        }
+    };
+}
+pub(crate) use with_attestation_mgr;
+
+/// The attestation manager of a VM, for the measurement hash algorithm selected when the VM is
+/// created.
+pub enum TvmAttestationManager {
+    /// SHA-256 measurements.
+    Sha256(AttestationManager<sha2::Sha256>),
+    /// SHA-384 measurements.
+    Sha384(AttestationManager<sha2::Sha384>),
+    /// SHA-512 measurements.
+    Sha512(AttestationManager<sha2::Sha512>),
+}
+
+// Digests of a platform component, for every supported measurement hash algorithm.
+struct PlatformDigests {
+    sha256: Output<sha2::Sha256>,
+    sha384: Output<sha2::Sha384>,
+    sha512: Output<sha2::Sha512>,
+}
+
+impl PlatformDigests {
+    // Hashes the bytes `feed` passes to its argument, with every supported algorithm.
+    fn new<F: Fn(&mut dyn FnMut(&[u8]))>(feed: F) -> Self {
+        Self {
+            sha256: Self::digest::<sha2::Sha256, _>(&feed),
+            sha384: Self::digest::<sha2::Sha384, _>(&feed),
+            sha512: Self::digest::<sha2::Sha512, _>(&feed),
+        }
+    }
+
+    fn digest<D: Digest, F: Fn(&mut dyn FnMut(&[u8]))>(feed: &F) -> Output<D> {
+        let mut hasher = D::new();
+        feed(&mut |bytes: &[u8]| hasher.update(bytes));
+        hasher.finalize()
+    }
+
+    // Returns the digest for `hash_algorithm`.
+    fn get(&self, hash_algorithm: HashAlgorithm) -> &[u8] {
+        match hash_algorithm {
+            HashAlgorithm::Sha256 => self.sha256.as_slice(),
+            HashAlgorithm::Sha384 => self.sha384.as_slice(),
+            HashAlgorithm::Sha512 => self.sha512.as_slice(),
+        }
+    }
+}
+
+// Returns true if `node` or one of its ancestors is the '/chosen' node.
+fn is_chosen(dt: &DeviceTree, node: &DeviceTreeNode) -> bool {
+    let mut current = Some(node);
+    while let Some(n) = current {
+        if n.name() == "chosen" && n.parent() == dt.root() {
+            return true;
+        }
+        current = n.parent().and_then(|p| dt.get_node(p));
+    }
+    false
+}
+
+// Returns the depth of `node` in `dt`, the root node being at depth 0.
+fn node_depth(dt: &DeviceTree, node: &DeviceTreeNode) -> u32 {
+    let mut depth = 0;
+    let mut parent = node.parent();
+    while let Some(p) = parent {
+        depth += 1;
+        parent = dt.get_node(p).and_then(|n| n.parent());
+    }
+    depth
+}
+
+// Feeds a length prefixed `bytes` to `update`.
+fn feed_bytes(update: &mut dyn FnMut(&[u8]), bytes: &[u8]) {
+    update(&(bytes.len() as u32).to_le_bytes());
+    update(bytes);
+}
+
+// Feeds the sanitized device tree to `update`, in depth-first order. Every node is encoded as
+// its depth, its name, its number of properties and its properties names and values.
+//
+// The '/chosen' node is skipped: it holds per-boot data (boot arguments, random seeds, the DICE
+// handoff) that is neither part of the platform configuration nor stable across boots.
+fn feed_device_tree(dt: &DeviceTree, update: &mut dyn FnMut(&[u8])) {
+    for node in dt.iter().filter(|n| !is_chosen(dt, n)) {
+        update(&node_depth(dt, node).to_le_bytes());
+        feed_bytes(update, node.name().as_bytes());
+        update(&(node.props().len() as u32).to_le_bytes());
+        for prop in node.props() {
+            feed_bytes(update, prop.name().as_bytes());
+            feed_bytes(update, prop.value_raw());
+        }
+    }
+}
+
+static PLATFORM_MEASUREMENTS: Once<PlatformMeasurements> = Once::new();
+
+/// The measurements of the platform TCB, i.e. of Salus itself and of the platform
+/// configuration, taken at boot. Every VM platform measurement registers are seeded with them:
+///
+/// - `PlatformCode` is extended with the Salus read-only image digest, and with the digest of the
+///   U-mode service ELFs, each one length prefixed so that the ELF boundaries are measured too.
+/// - `PlatformConfiguration` is extended with the sanitized hypervisor device tree digest.
+pub struct PlatformMeasurements {
+    salus_image: PlatformDigests,
+    umode_elf: PlatformDigests,
+    device_tree: PlatformDigests,
+}
+
+impl PlatformMeasurements {
+    /// Measures the Salus image, the U-mode service ELFs and the hypervisor device tree.
+    ///
+    /// `salus_image` is the read-only part of the image, from `_start` to `_image_ro_end`, rather
+    /// than the whole image up to `_stack_end`. The data and BSS sections and the boot stack are
+    /// written to before the measurement is taken, so including them would make the digest differ
+    /// from one boot to the next. The previous boot layer measures the full image as loaded.
+    pub fn init(salus_image: &[u8], umode_elfs: &[&[u8]], hyp_dt: &DeviceTree) {
+        PLATFORM_MEASUREMENTS.call_once(|| Self {
+            salus_image: PlatformDigests::new(|update| update(salus_image)),
+            umode_elf: PlatformDigests::new(|update| {
+                for elf in umode_elfs {
+                    feed_bytes(update, elf);
+                }
+            }),
+            device_tree: PlatformDigests::new(|update| feed_device_tree(hyp_dt, update)),
+        });
+    }
+
+    /// Returns the platform measurements, if Salus was measured.
+    pub fn get() -> Option<&'static PlatformMeasurements> {
+        PLATFORM_MEASUREMENTS.get()
+    }
+
+    // Extends the `tvm_mgr` platform measurement registers.
+    fn extend(&self, tvm_mgr: &TvmAttestationManager, hash_algorithm: HashAlgorithm) -> Result<()> {
+        tvm_mgr.extend_msmt_register(
+            TcgPcrIndex::PlatformCode,
+            self.salus_image.get(hash_algorithm),
+            None,
+            Some("Salus image"),
+        )?;
+        tvm_mgr.extend_msmt_register(
+            TcgPcrIndex::PlatformCode,
+            self.umode_elf.get(hash_algorithm),
+            None,
+            Some("U-mode ELF"),
+        )?;
+        tvm_mgr.extend_msmt_register(
+            TcgPcrIndex::PlatformConfiguration,
+            self.device_tree.get(hash_algorithm),
+            None,
+            Some("Hypervisor device tree"),
+        )
+    }
+}
+
+// Copies `digest` into a zero padded `MeasurementRegisterDigest`.
+fn padded_digest(digest: &[u8]) -> MeasurementRegisterDigest {
+    let mut padded = [0u8; MAX_DIGEST_LEN];
+    padded[..digest.len()].copy_from_slice(digest);
+    padded
+}
+
+impl TvmAttestationManager {
+    /// Creates a new attestation manager measuring with `hash_algorithm`, with its platform
+    /// measurement registers seeded from the `PlatformMeasurements`. See `AttestationManager::new`
+    /// for `dice_cdis`.
+    pub fn new(
+        dice_cdis: Option<(&[u8], &[u8])>,
+        vm_id: u64,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        let tvm_mgr = match hash_algorithm {
+            HashAlgorithm::Sha256 => {
+                Self::Sha256(AttestationManager::new(dice_cdis, vm_id, ID_SHA_256)?)
+            }
+            HashAlgorithm::Sha384 => {
+                Self::Sha384(AttestationManager::new(dice_cdis, vm_id, ID_SHA_384)?)
+            }
+            HashAlgorithm::Sha512 => {
+                Self::Sha512(AttestationManager::new(dice_cdis, vm_id, ID_SHA_512)?)
+            }
+        };
+        if let Some(platform) = PlatformMeasurements::get() {
+            platform.extend(&tvm_mgr, hash_algorithm)?;
+        }
+        Ok(tvm_mgr)
+    }
+
+    /// Returns the length of the measurement digests.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha256(_) => 32,
+            Self::Sha384(_) => 48,
+            Self::Sha512(_) => 64,
+        }
+    }
+
+    /// See `AttestationManager::extend_msmt_register`.
+    pub fn extend_msmt_register(
+        &self,
+        msmt_idx: TcgPcrIndex,
+        digest: &[u8],
+        address: Option<u64>,
+        description: Option<&str>,
+    ) -> Result<()> {
+        with_attestation_mgr!(self, m => {
+            m.extend_msmt_register(msmt_idx, digest, address, description)
+        })
+    }
+
+    /// See `AttestationManager::read_msmt_register`.
+    pub fn read_msmt_register(
+        &self,
+        msmt_idx: TcgPcrIndex,
+    ) -> Result<ArrayVec<u8, MAX_DIGEST_LEN>> {
+        with_attestation_mgr!(self, m => {
+            let digest = m.read_msmt_register(msmt_idx)?;
+            // Unwrap ok: no digest is longer than `MAX_DIGEST_LEN`.
+            Ok(ArrayVec::try_from(digest.as_slice()).unwrap())
+        })
+    }
+
+    /// See `AttestationManager::derive_sealing_key`.
+    pub fn derive_sealing_key(&self, msmt_mask: u64, info: &[u8], key: &mut [u8]) -> Result<()> {
+        with_attestation_mgr!(self, m => m.derive_sealing_key(msmt_mask, info, key))
+    }
+
+    /// See `AttestationManager::endorsed`.
+    pub fn endorsed(&self) -> bool {
+        with_attestation_mgr!(self, m => m.endorsed())
+    }
+
+    /// See `AttestationManager::tvm_certificate`.
+    pub fn tvm_certificate(&self, cert: &mut [u8]) -> Result<usize> {
+        with_attestation_mgr!(self, m => m.tvm_certificate(cert))
+    }
+
+    /// See `AttestationManager::extend_tvm_page`.
+    pub fn extend_tvm_page(&self, bytes: &[u8], address: u64) -> Result<()> {
+        with_attestation_mgr!(self, m => m.extend_tvm_page(bytes, address))
+    }
+
+    /// Returns the measurement registers, zero padded to `MAX_DIGEST_LEN`.
+    pub fn measurement_registers(&self) -> Result<[MeasurementRegisterDigest; MSMT_REGISTERS]> {
+        let mut msmt_regs = [[0u8; MAX_DIGEST_LEN]; MSMT_REGISTERS];
+        with_attestation_mgr!(self, m => {
+            for (i, r) in m.measurement_registers()?.iter().enumerate() {
+                msmt_regs[i] = padded_digest(r.as_slice());
+            }
+        });
+        Ok(msmt_regs)
+    }
+
+    /// Returns the digest of the event log, zero padded to `MAX_DIGEST_LEN`.
+    pub fn event_log_digest(&self) -> Result<MeasurementRegisterDigest> {
+        with_attestation_mgr!(self, m => Ok(padded_digest(m.event_log_digest()?.as_slice())))
+    }
+
+    /// See `AttestationManager::for_each_event_log_record`.
+    pub fn for_each_event_log_record<E, F>(
+        &self,
+        max_len: usize,
+        f: F,
+    ) -> core::result::Result<usize, E>
+    where
+        E: From<Error>,
+        F: FnMut(&[u8]) -> core::result::Result<(), E>,
+    {
+        with_attestation_mgr!(self, m => m.for_each_event_log_record(max_len, f))
+    }
+
+    /// See `AttestationManager::set_epc`.
+    pub fn set_epc(&self, epc: u64) {
+        with_attestation_mgr!(self, m => m.set_epc(epc))
+    }
+
+    /// See `AttestationManager::set_arg`.
+    pub fn set_arg(&self, a1: u64) {
+        with_attestation_mgr!(self, m => m.set_arg(a1))
+    }
+
+    /// See `AttestationManager::finalize`.
+    pub fn finalize(&self) -> Result<()> {
+        with_attestation_mgr!(self, m => m.finalize())
+    }
+
+    /// See `AttestationManager::capabilities`.
+    pub fn capabilities(&self) -> Result<AttestationCapabilities> {
+        with_attestation_mgr!(self, m => m.capabilities())
+    }
+}
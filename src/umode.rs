@@ -2,30 +2,39 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::hyp_layout::{UmodeSlotId, UMODE_INPUT_SIZE, UMODE_INPUT_START};
+use crate::hyp_layout::{
+    UmodeService, UmodeSlotId, UMODE_INPUT_SIZE, UMODE_INPUT_START, UMODE_SERVICES,
+};
 use crate::hyp_map::{Error as HypMapError, HypMap, UmodeSlotPerm};
 use crate::smp::PerCpu;
+use crate::tvm_attestation::{with_attestation_mgr, TvmAttestationManager};
 use crate::vm::FinalizedVm;
 use crate::vm_pages::{Error as VmPagesError, FinalizedVmPages, GuestUmodeMapping};
 
-use attestation::{AttestationManager, Error as AttestationError};
+use arrayvec::ArrayVec;
+use attestation::Error as AttestationError;
 use core::arch::global_asm;
 use core::fmt;
 use core::mem::size_of;
 use core::ops::ControlFlow;
 use data_model::DataInit;
+use drivers::cpu::SupervisorTimerGuard;
 use memoffset::offset_of;
 use rice::cdi::CompoundDeviceIdentifier;
 use riscv_elf::ElfMap;
 use riscv_page_tables::GuestStagePagingMode;
 use riscv_pages::{GuestPhysAddr, PageAddr, PageSize, RawAddr, SupervisorVirt};
-use riscv_regs::{Exception::UserEnvCall, GeneralPurposeRegisters, GprIndex, Readable, Trap, CSR};
+use riscv_regs::{
+    Exception::UserEnvCall, GeneralPurposeRegisters, GprIndex, Interrupt::SupervisorTimer,
+    Readable, Trap, CSR,
+};
 use s_mode_utils::print::*;
 use signature::Signer;
 use sync::Once;
+use u_mode_api::cert::SEALING_KEY_MAX_LEN;
 use u_mode_api::{
-    CdiOp, CdiSel, Error as UmodeApiError, HypCall, OpResult, TryIntoRegisters, UmodeRequest,
-    CDIOP_SIGN_MAXMSG,
+    CdiOp, CdiSel, Error as UmodeApiError, HypCall, IntoRegisters, OpResult, TryIntoRegisters,
+    UmodeRequest, CDIOP_DERIVE_MAXINFO, CDIOP_SIGN_MAXMSG,
 };
 
 /// Host GPR and which must be saved/restored when entering/exiting U-mode.
@@ -263,6 +272,8 @@ pub enum ExecError {
     UnexpectedTrap,
     /// Umode called panic.
     Panic,
+    /// Umode exceeded its execution budget.
+    Timeout,
     /// U-mode API error.
     Api(UmodeApiError),
     /// User address access error
@@ -273,41 +284,88 @@ pub enum ExecError {
     Cdi(rice::Error),
     /// Incorrect size of buffer provided.
     BufferSize(u64, u64),
+    /// Key derivation failed.
+    KeyDerivation,
+    /// The U-mode service is not permitted to issue this hypercall.
+    HypCallNotPermitted(UmodeService),
+}
+
+impl ExecError {
+    // Returns the error reported to U-mode when a hypcall fails with `self`.
+    fn api_error(&self) -> UmodeApiError {
+        match self {
+            ExecError::UnexpectedCdiOp(..) => UmodeApiError::EcallNotSupported,
+            ExecError::BufferSize(..) | ExecError::UmodeAccess(_) => UmodeApiError::InvalidArgument,
+            _ => UmodeApiError::Failed,
+        }
+    }
+}
+
+// Execution budget of a U-mode run, in milliseconds.
+const UMODE_EXEC_BUDGET_MS: u64 = 1000;
+
+// The TVM state a request may access through `HypCall::Cdi`.
+#[derive(Clone, Copy)]
+struct CdiContext<'a> {
+    attestation: &'a TvmAttestationManager,
+    // Mask of the measurement registers bound to sealing keys. Sealing keys can only be derived
+    // for `GetSealingKey` requests, which set it.
+    sealing_msmt_mask: Option<u64>,
 }
 
 struct UmodeExecutionContext<'a, T: DataInit> {
+    service: UmodeService,
     input_data: Option<T>,
     req: UmodeRequest,
-    attestation: Option<&'a AttestationManager<sha2::Sha384>>,
+    cdi: Option<CdiContext<'a>>,
 }
 
-// Entry for umode task.
-static UMODE_ENTRY: Once<u64> = Once::new();
+// Entries for the U-mode services, in `UmodeService::ALL` order.
+static UMODE_ENTRIES: Once<ArrayVec<u64, UMODE_SERVICES>> = Once::new();
 
-/// Represents a per-CPU U-mode task.
-pub struct UmodeTask {
+// The U-mode task of a single U-mode service.
+struct UmodeServiceTask {
+    service: UmodeService,
     arch: UmodeCpuArchState,
 }
 
+/// Represents a per-CPU U-mode task. Each U-mode service runs in its own address space, and
+/// requests are dispatched to the service that handles them.
+pub struct UmodeTask {
+    services: ArrayVec<UmodeServiceTask, UMODE_SERVICES>,
+}
+
 impl UmodeTask {
-    /// Initialize U-mode tasks. Must be called once before `setup_this_cpu()`.
-    pub fn init(umode_elf: ElfMap) {
-        UMODE_ENTRY.call_once(|| umode_elf.entry());
-        // Consumes the ElfMap.
+    /// Initialize U-mode tasks from the U-mode service ELFs, in `UmodeService::ALL` order. Must be
+    /// called once before `setup_this_cpu()`.
+    pub fn init(umode_elfs: &[ElfMap]) {
+        UMODE_ENTRIES.call_once(|| umode_elfs.iter().map(|elf| elf.entry()).collect());
     }
 
     /// Initialize this CPU's U-mode task. Must be called once on each physical CPU.
     pub fn setup_this_cpu() -> Result<(), Error> {
-        let arch = UmodeCpuArchState::default();
-        let mut task = UmodeTask { arch };
-        task.reset()?;
+        let page_table = PerCpu::this_cpu().page_table();
+        let services = UmodeService::ALL
+            .iter()
+            .map(|&service| {
+                let mut task = UmodeServiceTask {
+                    service,
+                    arch: UmodeCpuArchState::default(),
+                };
+                page_table.activate_umode(service);
+                let ret = task.reset();
+                page_table.deactivate_umode();
+                ret.map(|_| task)
+            })
+            .collect::<Result<_, _>>()?;
         // Install umode cpu state in the current cpu.
-        PerCpu::this_cpu().set_umode_task(task);
+        PerCpu::this_cpu().set_umode_task(UmodeTask { services });
         Ok(())
     }
 
     fn map_guest_range_in_umode_slot<T: GuestStagePagingMode>(
         vm_pages: FinalizedVmPages<T>,
+        service: UmodeService,
         addr: GuestPhysAddr,
         len: usize,
         slot: UmodeSlotId,
@@ -319,6 +377,7 @@ impl UmodeTask {
             .ok_or(Error::AddressOverflow)?;
         let umode_mapping = vm_pages
             .map_in_umode_slot(
+                service,
                 slot,
                 base,
                 PageSize::num_4k_pages(end.bits() - base.bits()),
@@ -333,13 +392,18 @@ impl UmodeTask {
         Ok((vaddr, umode_mapping))
     }
 
-    pub fn nop() -> Result<u64, Error> {
-        let ctx = UmodeExecutionContext::<u8> {
-            input_data: None,
-            req: UmodeRequest::Nop,
-            attestation: None,
-        };
-        Self::execute_request(ctx)
+    /// Runs a `Nop` request on every U-mode service.
+    pub fn nop() -> Result<(), Error> {
+        for &service in UmodeService::ALL.iter() {
+            let ctx = UmodeExecutionContext::<u8> {
+                service,
+                input_data: None,
+                req: UmodeRequest::Nop,
+                cdi: None,
+            };
+            Self::execute_request(ctx)?;
+        }
+        Ok(())
     }
 
     pub fn attestation_evidence<T: GuestStagePagingMode>(
@@ -352,6 +416,7 @@ impl UmodeTask {
         // Map input CSR in Slot A as read-only.
         let (csr_vaddr, _csr_mapping) = Self::map_guest_range_in_umode_slot(
             vm.vm_pages(),
+            UmodeService::Attestation,
             csr_gpa,
             csr_len,
             UmodeSlotId::A,
@@ -360,23 +425,28 @@ impl UmodeTask {
         // Map output certificate in Slot B as writable.
         let (certout_vaddr, _certout_mapping) = Self::map_guest_range_in_umode_slot(
             vm.vm_pages(),
+            UmodeService::Attestation,
             certout_gpa,
             certout_len,
             UmodeSlotId::B,
             UmodeSlotPerm::Writable,
         )?;
         let attestation_mgr = vm.attestation_mgr();
-        // Gather measurement registers from the attestation manager and transform it in a array.
-        let msmt_genarray = attestation_mgr
+        // Gather measurement registers from the attestation manager.
+        let msmt_regs = attestation_mgr
             .measurement_registers()
             .map_err(Error::Attestation)?;
-        let zero = [0u8; u_mode_api::cert::SHA384_LEN];
-        let mut msmt_regs = [zero; attestation::MSMT_REGISTERS];
-        for (i, r) in msmt_genarray.iter().enumerate() {
-            msmt_regs[i].copy_from_slice(r.as_slice());
-        }
-        let input_data = u_mode_api::cert::MeasurementRegisters { msmt_regs };
+        let digest_len = attestation_mgr.digest_len() as u64;
+        let event_log_digest = attestation_mgr
+            .event_log_digest()
+            .map_err(Error::Attestation)?;
+        let input_data = u_mode_api::cert::MeasurementRegisters {
+            msmt_regs,
+            event_log_digest,
+            digest_len,
+        };
         let ctx = UmodeExecutionContext {
+            service: UmodeService::Attestation,
             input_data: Some(input_data),
             req: UmodeRequest::GetEvidence {
                 csr_addr: csr_vaddr.bits(),
@@ -384,16 +454,182 @@ impl UmodeTask {
                 certout_addr: certout_vaddr.bits(),
                 certout_len,
             },
-            attestation: Some(attestation_mgr),
+            cdi: Some(CdiContext {
+                attestation: attestation_mgr,
+                sealing_msmt_mask: None,
+            }),
         };
         Self::execute_request(ctx)
     }
 
+    pub fn attestation_eat_evidence<T: GuestStagePagingMode>(
+        vm: &FinalizedVm<T>,
+        request_data: [u8; u_mode_api::cert::REQUEST_DATA_LEN],
+        eatout_gpa: GuestPhysAddr,
+        eatout_len: usize,
+    ) -> Result<u64, Error> {
+        // Map output token in Slot B as writable.
+        let (eatout_vaddr, _eatout_mapping) = Self::map_guest_range_in_umode_slot(
+            vm.vm_pages(),
+            UmodeService::Attestation,
+            eatout_gpa,
+            eatout_len,
+            UmodeSlotId::B,
+            UmodeSlotPerm::Writable,
+        )?;
+        let attestation_mgr = vm.attestation_mgr();
+        // Gather measurement registers from the attestation manager.
+        let msmt_regs = attestation_mgr
+            .measurement_registers()
+            .map_err(Error::Attestation)?;
+        let digest_len = attestation_mgr.digest_len() as u64;
+        let tcb_svn = attestation_mgr
+            .capabilities()
+            .map_err(Error::Attestation)?
+            .tcb_svn;
+        let event_log_digest = attestation_mgr
+            .event_log_digest()
+            .map_err(Error::Attestation)?;
+        let input_data = u_mode_api::cert::EatClaims {
+            msmt_regs,
+            digest_len,
+            tcb_svn,
+            tvm_id: vm.page_owner_id().raw(),
+            event_log_digest,
+            request_data,
+            endorsed: attestation_mgr.endorsed() as u64,
+        };
+        let ctx = UmodeExecutionContext {
+            service: UmodeService::Attestation,
+            input_data: Some(input_data),
+            req: UmodeRequest::GetEatEvidence {
+                eatout_addr: eatout_vaddr.bits(),
+                eatout_len,
+            },
+            cdi: Some(CdiContext {
+                attestation: attestation_mgr,
+                sealing_msmt_mask: None,
+            }),
+        };
+        Self::execute_request(ctx)
+    }
+
+    pub fn attestation_sealing_key<T: GuestStagePagingMode>(
+        vm: &FinalizedVm<T>,
+        msmt_mask: u64,
+        tcb_svn: u64,
+        label: &[u8],
+        keyout_gpa: GuestPhysAddr,
+        keyout_len: usize,
+    ) -> Result<u64, Error> {
+        // Map output key in Slot B as writable.
+        let (keyout_vaddr, _keyout_mapping) = Self::map_guest_range_in_umode_slot(
+            vm.vm_pages(),
+            UmodeService::Sealing,
+            keyout_gpa,
+            keyout_len,
+            UmodeSlotId::B,
+            UmodeSlotPerm::Writable,
+        )?;
+        let attestation_mgr = vm.attestation_mgr();
+        // The registers selected by `msmt_mask` are bound to the key by the hypervisor, when the
+        // service derives it.
+        let mut policy = u_mode_api::cert::SealingKeyPolicy {
+            tcb_svn,
+            label_len: label.len() as u64,
+            label: [0u8; u_mode_api::cert::SEALING_KEY_LABEL_LEN],
+        };
+        policy.label[..label.len()].copy_from_slice(label);
+        let ctx = UmodeExecutionContext {
+            service: UmodeService::Sealing,
+            input_data: Some(policy),
+            req: UmodeRequest::GetSealingKey {
+                keyout_addr: keyout_vaddr.bits(),
+                keyout_len,
+            },
+            cdi: Some(CdiContext {
+                attestation: attestation_mgr,
+                sealing_msmt_mask: Some(msmt_mask),
+            }),
+        };
+        Self::execute_request(ctx)
+    }
+
+    fn execute_request<T: DataInit>(exec_ctx: UmodeExecutionContext<T>) -> Result<u64, Error> {
+        let this_cpu = PerCpu::this_cpu();
+        let page_table = this_cpu.page_table();
+        let service = exec_ctx.service;
+        let mut umode_task = this_cpu.umode_task_mut();
+        let task = &mut umode_task.services[service.index()];
+        // Save request in registers.
+        exec_ctx
+            .req
+            .to_registers(task.arch.umode_regs.gprs.a_regs_mut());
+        if let Some(data) = exec_ctx.input_data {
+            page_table
+                .copy_to_umode_input(service, data)
+                .map_err(Error::HypMap)?;
+        }
+        // Run the service in its own address space.
+        page_table.activate_umode(service);
+        let ret = task.run(exec_ctx.cdi);
+        // Errors encountered while executing the operation (crash or timeout) leave the service
+        // in an unknown state: rebuild it.
+        if ret.is_err() {
+            println!("Umode error ({:?}): {:?}", service, ret);
+            // Panic if we can't restore umode: the hypervisor is in an unrecoverable state.
+            task.recover().expect("Failed to recover U-mode.");
+        }
+        page_table.deactivate_umode();
+        if exec_ctx.input_data.is_some() {
+            page_table.clear_umode_input(service);
+        }
+        let res = ret.map_err(Error::Exec)?;
+        res.map_err(Error::Request)
+    }
+}
+
+// Returns true if U-mode service `service` is permitted to issue `hypcall`. The attestation service
+// can only use the attestation CDI, and the sealing service can only derive keys from the sealing
+// CDI, so that neither service can use the CDI operations of the other.
+fn hypcall_permitted(service: UmodeService, hypcall: &HypCall) -> bool {
+    match hypcall {
+        HypCall::Panic | HypCall::PutChar(_) | HypCall::NextOp(_) => true,
+        HypCall::Cdi { cdi_sel, cdi_op } => match service {
+            UmodeService::Attestation => matches!(
+                (cdi_sel, cdi_op),
+                (
+                    CdiSel::AttestationCurrent,
+                    CdiOp::Id { .. } | CdiOp::Sign { .. } | CdiOp::PublicKey { .. }
+                )
+            ),
+            UmodeService::Sealing => matches!(
+                (cdi_sel, cdi_op),
+                (CdiSel::SealingCurrent, CdiOp::DeriveKey { .. })
+            ),
+        },
+    }
+}
+
+impl UmodeServiceTask {
+    // Rebuilds the service from its pristine ELF image. The page table of the service must be
+    // active.
+    fn recover(&mut self) -> Result<(), Error> {
+        let page_table = PerCpu::this_cpu().page_table();
+        // 1. restore memory to original state
+        page_table.restore_umode(self.service);
+        page_table.clear_umode_input(self.service);
+        // 2. setup umode again, from the ELF entry.
+        self.reset()
+    }
+
+    // Resets the service to its ELF entry and runs it until it is ready for requests. The page
+    // table of the service must be active.
     fn reset(&mut self) -> Result<(), Error> {
         // Initialize umode CPU state to run at ELF entry.
         let mut arch = UmodeCpuArchState::default();
-        // Unwrap okay: this is called after `Self::init()`.
-        arch.umode_regs.sepc = *UMODE_ENTRY.get().unwrap();
+        // Unwrap okay: this is called after `UmodeTask::init()`.
+        arch.umode_regs.sepc = UMODE_ENTRIES.get().unwrap()[self.service.index()];
         // Set cpu id as a0.
         arch.umode_regs
             .gprs
@@ -413,90 +649,141 @@ impl UmodeTask {
         Ok(())
     }
 
-    fn execute_request<T: DataInit>(exec_ctx: UmodeExecutionContext<T>) -> Result<u64, Error> {
-        let this_cpu = PerCpu::this_cpu();
-        let mut task = this_cpu.umode_task_mut();
-        // Save request in registers.
-        exec_ctx
-            .req
-            .to_registers(task.arch.umode_regs.gprs.a_regs_mut());
-        if let Some(data) = exec_ctx.input_data {
-            this_cpu
-                .page_table()
-                .copy_to_umode_input(data)
-                .map_err(Error::HypMap)?;
-        }
-        let ret = task.run(exec_ctx.attestation);
-        // Errors encountered while executing the operation are unrecoverable: reset U-mode.
-        if ret.is_err() {
-            println!("Umode error: {:?}", ret);
-            // 1. restore memory to original state
-            this_cpu.page_table().restore_umode();
-            // 2. setup umode again.
-            // Panic if we can't restore umode: the hypervisor is in an unrecoverable state.
-            task.reset().expect("Failed to recover U-mode.");
-        }
-        if exec_ctx.input_data.is_some() {
-            this_cpu.page_table().clear_umode_input();
+    fn handle_cdi_op(
+        cdi: Option<CdiContext>,
+        cdi_sel: CdiSel,
+        cdi_op: CdiOp,
+    ) -> Result<(), ExecError> {
+        if let Some(cdi) = cdi {
+            let tvm_attmgr = cdi.attestation;
+            // Sealing CDIs are only used for key derivation, and key derivation is only allowed
+            // on sealing CDIs: keys are HKDF outputs of the sealing CDI, which must never be used
+            // to sign evidence.
+            with_attestation_mgr!(tvm_attmgr, attmgr => match (cdi_sel, cdi_op) {
+                (CdiSel::SealingCurrent, CdiOp::DeriveKey { .. }) => match cdi.sealing_msmt_mask {
+                    Some(msmt_mask) => Self::derive_sealing_key(tvm_attmgr, msmt_mask, cdi_op),
+                    None => Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op)),
+                },
+                (_, CdiOp::DeriveKey { .. }) | (CdiSel::SealingCurrent, _) => {
+                    Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op))
+                }
+                (CdiSel::AttestationCurrent, _) => {
+                    Self::cdi_op(attmgr.attestation_current_cdi(), cdi_sel, cdi_op)
+                }
+                _ => Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op)),
+            })
+        } else {
+            Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op))
         }
-        let res = ret.map_err(Error::Exec)?;
-        res.map_err(Error::Request)
     }
 
-    fn handle_cdi_op(
-        attestation: Option<&AttestationManager<sha2::Sha384>>,
+    // Runs `cdi_op` on `cdi`, the CDI selected by `cdi_sel`.
+    fn cdi_op<const N: usize>(
+        cdi: &impl CompoundDeviceIdentifier<N, ed25519_dalek::Signature>,
         cdi_sel: CdiSel,
         cdi_op: CdiOp,
     ) -> Result<(), ExecError> {
-        if let Some(attmgr) = attestation {
-            let cdi = match cdi_sel {
-                CdiSel::AttestationCurrent => Ok(attmgr.attestation_current_cdi()),
-                _ => Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op)),
-            }?;
-            match cdi_op {
-                CdiOp::Id {
-                    idout_addr,
-                    idout_len,
-                } => {
-                    let id = cdi.id().map_err(ExecError::Cdi)?;
-                    if idout_len as usize != id.len() {
-                        return Err(ExecError::BufferSize(idout_len, id.len() as u64));
-                    }
-                    HypMap::copy_to_umode(RawAddr::supervisor_virt(idout_addr), &id)
-                        .map_err(ExecError::UmodeAccess)
+        match cdi_op {
+            CdiOp::Id {
+                idout_addr,
+                idout_len,
+            } => {
+                let id = cdi.id().map_err(ExecError::Cdi)?;
+                if idout_len as usize != id.len() {
+                    return Err(ExecError::BufferSize(idout_len, id.len() as u64));
                 }
-                CdiOp::Sign {
-                    msg_addr,
-                    msg_len,
-                    signout_addr,
-                    signout_len,
-                } => {
-                    let mut msg_buf = [0u8; CDIOP_SIGN_MAXMSG];
-                    if msg_len > CDIOP_SIGN_MAXMSG as u64 {
-                        return Err(ExecError::BufferSize(msg_len, CDIOP_SIGN_MAXMSG as u64));
-                    }
-                    let msg = &mut msg_buf[0..msg_len as usize];
-                    HypMap::copy_from_umode(msg, RawAddr::supervisor_virt(msg_addr))
-                        .map_err(ExecError::UmodeAccess)?;
-                    let signature = cdi.sign(msg).to_bytes();
-                    if signout_len as usize != signature.len() {
-                        return Err(ExecError::BufferSize(signout_len, signature.len() as u64));
-                    }
-                    HypMap::copy_to_umode(RawAddr::supervisor_virt(signout_addr), &signature)
-                        .map_err(ExecError::UmodeAccess)
+                HypMap::copy_to_umode(RawAddr::supervisor_virt(idout_addr), &id)
+                    .map_err(ExecError::UmodeAccess)
+            }
+            CdiOp::Sign {
+                msg_addr,
+                msg_len,
+                signout_addr,
+                signout_len,
+            } => {
+                let mut msg_buf = [0u8; CDIOP_SIGN_MAXMSG];
+                if msg_len > CDIOP_SIGN_MAXMSG as u64 {
+                    return Err(ExecError::BufferSize(msg_len, CDIOP_SIGN_MAXMSG as u64));
+                }
+                let msg = &mut msg_buf[0..msg_len as usize];
+                HypMap::copy_from_umode(msg, RawAddr::supervisor_virt(msg_addr))
+                    .map_err(ExecError::UmodeAccess)?;
+                let signature = cdi.sign(msg).to_bytes();
+                if signout_len as usize != signature.len() {
+                    return Err(ExecError::BufferSize(signout_len, signature.len() as u64));
                 }
+                HypMap::copy_to_umode(RawAddr::supervisor_virt(signout_addr), &signature)
+                    .map_err(ExecError::UmodeAccess)
             }
-        } else {
-            Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op))
+            CdiOp::PublicKey {
+                pkout_addr,
+                pkout_len,
+            } => {
+                let public_key = cdi.public_key();
+                if pkout_len as usize != public_key.len() {
+                    return Err(ExecError::BufferSize(pkout_len, public_key.len() as u64));
+                }
+                HypMap::copy_to_umode(RawAddr::supervisor_virt(pkout_addr), &public_key)
+                    .map_err(ExecError::UmodeAccess)
+            }
+            // Keys are only derived from sealing CDIs, by `derive_sealing_key()`.
+            CdiOp::DeriveKey { .. } => Err(ExecError::UnexpectedCdiOp(cdi_sel, cdi_op)),
+        }
+    }
+
+    // Runs the `DeriveKey` operation `cdi_op` on the sealing CDI of `tvm_attmgr`, binding the
+    // measurement registers selected by `msmt_mask` to the key.
+    fn derive_sealing_key(
+        tvm_attmgr: &TvmAttestationManager,
+        msmt_mask: u64,
+        cdi_op: CdiOp,
+    ) -> Result<(), ExecError> {
+        let CdiOp::DeriveKey {
+            info_addr,
+            info_len,
+            keyout_addr,
+            keyout_len,
+        } = cdi_op
+        else {
+            return Err(ExecError::UnexpectedCdiOp(CdiSel::SealingCurrent, cdi_op));
+        };
+        let mut info_buf = [0u8; CDIOP_DERIVE_MAXINFO];
+        if info_len > CDIOP_DERIVE_MAXINFO as u64 {
+            return Err(ExecError::BufferSize(info_len, CDIOP_DERIVE_MAXINFO as u64));
+        }
+        let mut key_buf = [0u8; SEALING_KEY_MAX_LEN];
+        if keyout_len > SEALING_KEY_MAX_LEN as u64 {
+            return Err(ExecError::BufferSize(
+                keyout_len,
+                SEALING_KEY_MAX_LEN as u64,
+            ));
         }
+        let info = &mut info_buf[0..info_len as usize];
+        HypMap::copy_from_umode(info, RawAddr::supervisor_virt(info_addr))
+            .map_err(ExecError::UmodeAccess)?;
+        let key = &mut key_buf[0..keyout_len as usize];
+        tvm_attmgr
+            .derive_sealing_key(msmt_mask, info, key)
+            .map_err(|_| ExecError::KeyDerivation)?;
+        HypMap::copy_to_umode(RawAddr::supervisor_virt(keyout_addr), key)
+            .map_err(ExecError::UmodeAccess)
+    }
+
+    // Returns true if this service is permitted to issue `hypcall`.
+    fn hypcall_permitted(&self, hypcall: &HypCall) -> bool {
+        hypcall_permitted(self.service, hypcall)
     }
 
     fn handle_ecall(
         &mut self,
-        attestation: Option<&AttestationManager<sha2::Sha384>>,
+        cdi: Option<CdiContext>,
     ) -> ControlFlow<Result<OpResult, ExecError>> {
         let regs = self.arch.umode_regs.gprs.a_regs();
         let cflow = match HypCall::try_from_registers(regs) {
+            Ok(hypercall) if !self.hypcall_permitted(&hypercall) => {
+                println!("U-mode {:?}: hypercall not permitted", self.service);
+                ControlFlow::Break(Err(ExecError::HypCallNotPermitted(self.service)))
+            }
             Ok(hypercall) => match hypercall {
                 HypCall::Panic => {
                     println!("U-mode panic!");
@@ -511,10 +798,16 @@ impl UmodeTask {
                     ControlFlow::Continue(())
                 }
                 HypCall::Cdi { cdi_sel, cdi_op } => {
-                    match Self::handle_cdi_op(attestation, cdi_sel, cdi_op) {
-                        Ok(_) => ControlFlow::Continue(()),
-                        Err(err) => ControlFlow::Break(Err(err)),
-                    }
+                    // Return the outcome of the operation to U-mode, which decides how to fail its
+                    // request.
+                    let res = Self::handle_cdi_op(cdi, cdi_sel, cdi_op)
+                        .map(|_| 0)
+                        .map_err(|err| {
+                            println!("U-mode {:?}: CDI operation failed: {:?}", self.service, err);
+                            err.api_error()
+                        });
+                    res.to_registers(self.arch.umode_regs.gprs.a_regs_mut());
+                    ControlFlow::Continue(())
                 }
                 HypCall::NextOp(result) => ControlFlow::Break(Ok(result)),
             },
@@ -539,18 +832,24 @@ impl UmodeTask {
         self.arch.trap_csrs.stval = CSR.stval.get();
     }
 
-    // Run `umode` until result is returned.
-    fn run(
-        &mut self,
-        attestation: Option<&AttestationManager<sha2::Sha384>>,
-    ) -> Result<OpResult, ExecError> {
+    // Run `umode` until result is returned, or until it exceeds its execution budget.
+    fn run(&mut self, cdi: Option<CdiContext>) -> Result<OpResult, ExecError> {
+        // Bound the run with the supervisor timer, which is restored once we return. Supervisor
+        // interrupts are always taken in U-mode, regardless of SSTATUS.SIE, so a timer interrupt
+        // taken while running U-mode means U-mode exceeded its budget.
+        let _watchdog = SupervisorTimerGuard::arm_ms(UMODE_EXEC_BUDGET_MS);
         loop {
             self.run_to_exit();
             match Trap::from_scause(self.arch.trap_csrs.scause).unwrap() {
-                Trap::Exception(UserEnvCall) => match self.handle_ecall(attestation) {
+                Trap::Exception(UserEnvCall) => match self.handle_ecall(cdi) {
                     ControlFlow::Continue(_) => continue,
                     ControlFlow::Break(res) => break res,
                 },
+                Trap::Interrupt(SupervisorTimer) => {
+                    println!("U-mode {:?} timed out:", self.service);
+                    println!("{}", self.arch);
+                    break Err(ExecError::Timeout);
+                }
                 _ => {
                     println!("Unexpected U-mode Trap:");
                     println!("{}", self.arch);
@@ -560,3 +859,81 @@ impl UmodeTask {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_system::*;
+
+    #[test_case]
+    fn UmodeHypCallPermissionsTest() -> TestResult {
+        let id = CdiOp::Id {
+            idout_addr: 0,
+            idout_len: 0,
+        };
+        let sign = CdiOp::Sign {
+            msg_addr: 0,
+            msg_len: 0,
+            signout_addr: 0,
+            signout_len: 0,
+        };
+        let public_key = CdiOp::PublicKey {
+            pkout_addr: 0,
+            pkout_len: 0,
+        };
+        let derive_key = CdiOp::DeriveKey {
+            info_addr: 0,
+            info_len: 0,
+            keyout_addr: 0,
+            keyout_len: 0,
+        };
+        let permitted = |service, cdi_sel, cdi_op| {
+            hypcall_permitted(service, &HypCall::Cdi { cdi_sel, cdi_op })
+        };
+        use CdiSel::*;
+        use UmodeService::*;
+
+        for service in UmodeService::ALL {
+            test_result_true!(
+                hypcall_permitted(service, &HypCall::PutChar(b'a')),
+                "U-mode services can print"
+            )?;
+        }
+
+        // The attestation service can sign with the attestation CDI, but can't derive sealing
+        // keys.
+        for cdi_op in [id, sign, public_key] {
+            test_result_true!(
+                permitted(Attestation, AttestationCurrent, cdi_op),
+                "Attestation service uses the attestation CDI"
+            )?;
+            test_result_false!(
+                permitted(Attestation, SealingCurrent, cdi_op),
+                "Attestation service can't use the sealing CDI"
+            )?;
+        }
+        test_result_false!(
+            permitted(Attestation, SealingCurrent, derive_key),
+            "Attestation service can't derive sealing keys"
+        )?;
+
+        // The sealing service can derive sealing keys, but can't use the attestation CDI.
+        test_result_true!(
+            permitted(Sealing, SealingCurrent, derive_key),
+            "Sealing service derives sealing keys"
+        )?;
+        for cdi_op in [id, sign, public_key, derive_key] {
+            test_result_false!(
+                permitted(Sealing, AttestationCurrent, cdi_op),
+                "Sealing service can't use the attestation CDI"
+            )?;
+        }
+        for cdi_op in [id, sign, public_key] {
+            test_result_false!(
+                permitted(Sealing, SealingCurrent, cdi_op),
+                "Sealing service can only derive keys"
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -2,9 +2,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use attestation::{AttestationManager, Error as AttestationError, TcgPcrIndex};
+use arrayvec::ArrayVec;
+use attestation::{DiceHandoff, Error as AttestationError, TcgPcrIndex};
 use core::{mem, num::Wrapping, ops::ControlFlow, ops::Neg, slice};
-use drivers::{imsic::*, pmu::PmuInfo};
+use drivers::{
+    imsic::*,
+    iommu::{Iommu, ProcessDirectoryMode, ProcessId, PscId},
+    pci::{Address, PciArenaId, PciDevice, PciError, PcieRoot, PCI_ENDPOINT_BARS},
+    pmu::PmuInfo,
+};
 use page_tracking::collections::PageBox;
 use page_tracking::{LockedPageList, PageList, PageTracker};
 use riscv_page_tables::{GuestStagePageTable, GuestStagePagingMode};
@@ -12,10 +18,12 @@ use riscv_pages::*;
 use riscv_regs::{DecodedInstruction, Exception, GprIndex, Instruction, Interrupt, Trap, CSR};
 use s_mode_utils::print::*;
 use sbi_rs::{salus::*, Error as SbiError, *};
-use sync::Once;
+use sync::{Mutex, Once};
+use u_mode_api::cert::{SEALING_KEY_LABEL_LEN, SEALING_KEY_MAX_LEN};
 use u_mode_api::Error as UmodeApiError;
 
 use crate::guest_tracking::{GuestStateGuard, GuestVm, Guests};
+use crate::tvm_attestation::TvmAttestationManager;
 use crate::umode::{Error as UmodeError, UmodeTask};
 use crate::vm_cpu::{ActiveVmCpu, VmCpu, VmCpuParent, VmCpuStatus, VmCpuTrap, VmCpus, VM_CPUS_MAX};
 use crate::vm_pages::Error as VmPagesError;
@@ -42,6 +50,9 @@ const SBI_IMPL_ID_SALUS: u64 = 7;
 const SBI_SPEC_MAJOR_VERSION_SHIFT: u64 = 24;
 const SBI_SPEC_VERSION: u64 = 1 << SBI_SPEC_MAJOR_VERSION_SHIFT;
 
+// The maximum length of the TVM layer certificate.
+const TVM_CERTIFICATE_MAX_LEN: usize = 4096;
+
 // The number of pages required for `NaclShmem`.
 const NACL_SHMEM_PAGES: u64 =
     PageSize::num_4k_pages(core::mem::size_of::<sbi_rs::NaclShmem>() as u64);
@@ -71,6 +82,17 @@ impl MmioOpcode {
             Load8 | Load8U | Load16 | Load16U | Load32 | Load32U | Load64
         )
     }
+
+    /// Returns the number of bytes accessed by the MMIO operation.
+    pub fn width(&self) -> usize {
+        use MmioOpcode::*;
+        match self {
+            Load8 | Load8U | Store8 => 1,
+            Load16 | Load16U | Store16 => 2,
+            Load32 | Load32U | Store32 => 4,
+            Load64 | Store64 => 8,
+        }
+    }
 }
 
 /// A decoded MMIO operation.
@@ -179,6 +201,10 @@ impl From<AttestationError> for EcallError {
             AttestationError::InvalidMeasurementRegisterDescIndex(_) => {
                 EcallError::Sbi(SbiError::Failed)
             }
+            AttestationError::EventLogBufferTooSmall(_)
+            | AttestationError::CertificateBufferTooSmall(_) => {
+                EcallError::Sbi(SbiError::InsufficientBufferCapacity)
+            }
             // TODO: Map individual error types.
             // InvalidParam may not be the right value for each error.
             _ => EcallError::Sbi(SbiError::InvalidParam),
@@ -204,6 +230,19 @@ impl From<UmodeError> for EcallError {
     }
 }
 
+impl From<PciError> for EcallError {
+    fn from(error: PciError) -> EcallError {
+        match error {
+            PciError::DeviceNotOwned
+            | PciError::DeviceNotFound
+            | PciError::DeviceNotAssignable
+            | PciError::DeviceNotPresent(_) => EcallError::Sbi(SbiError::InvalidParam),
+            PciError::BarNotPageSized(_) => EcallError::Sbi(SbiError::NotSupported),
+            _ => EcallError::Sbi(SbiError::Failed),
+        }
+    }
+}
+
 impl From<SbiError> for EcallError {
     fn from(error: SbiError) -> EcallError {
         EcallError::Sbi(error)
@@ -230,7 +269,9 @@ impl From<EcallResult<u64>> for EcallAction {
                 match pf {
                     // Unhandleable page faults or page faults in MMIO space just result in an
                     // error to the caller.
-                    Unmapped | Mmio | Imsic => Continue(SbiReturn::from(SbiError::InvalidAddress)),
+                    Unmapped | Mmio | Imsic | PciBar => {
+                        Continue(SbiReturn::from(SbiError::InvalidAddress))
+                    }
                     Confidential | Shared => {
                         let addr = PageAddr::with_round_down(addr, PageSize::Size4k);
                         Retry(VmExitCause::PageFault(e, addr))
@@ -241,36 +282,31 @@ impl From<EcallResult<u64>> for EcallAction {
     }
 }
 
-type AttestationSha384 = AttestationManager<sha2::Sha384>;
-
 /// A VM that is being run.
 pub struct Vm<T: GuestStagePagingMode> {
     vcpus: VmCpus,
     vm_pages: VmPages<T>,
     // Only used by Host VM to track guest VMs.
     guests: Option<Guests<T>>,
-    attestation_mgr: AttestationSha384,
+    attestation_mgr: TvmAttestationManager,
     // Latched htimedelta (-CSR_TIME) at the time of first VCPU run.
     htimedelta: Once<u64>,
 }
 
 impl<T: GuestStagePagingMode> Vm<T> {
-    /// Creates a new `Vm` using the given initial page table and vCPU tracking table.
-    pub fn new(vm_pages: VmPages<T>, vcpus: VmCpus) -> Result<Self> {
+    /// Creates a new `Vm` using the given initial page table and vCPU tracking table, measured
+    /// with `hash_algorithm`.
+    pub fn new(vm_pages: VmPages<T>, vcpus: VmCpus, hash_algorithm: HashAlgorithm) -> Result<Self> {
         let vm_id = vm_pages.page_owner_id().raw();
+        // Use the CDIs handed off by firmware. Without them, the VM evidence is unendorsed and it
+        // gets no sealing keys.
+        let dice_cdis = DiceHandoff::get().map(|h| (h.attestation_cdi(), h.sealing_cdi()));
         Ok(Self {
             vcpus,
             vm_pages,
             guests: None,
-            attestation_mgr: AttestationSha384::new(
-                // Fake compound device identifiers (DICE CDI)
-                // TODO Get the CDI from e.g. the TSM driver.
-                b"RANDOMATTESTATIONCDI",
-                b"RANDOMSEALINGCDI",
-                vm_id,
-                const_oid::db::rfc5912::ID_SHA_384,
-            )
-            .map_err(Error::AttestationManagerCreationFailed)?,
+            attestation_mgr: TvmAttestationManager::new(dice_cdis, vm_id, hash_algorithm)
+                .map_err(Error::AttestationManagerCreationFailed)?,
             htimedelta: Once::new(),
         })
     }
@@ -281,7 +317,7 @@ impl<T: GuestStagePagingMode> Vm<T> {
         vcpus: VmCpus,
         guests: Guests<T>,
     ) -> Result<Self> {
-        let mut this = Self::new(vm_pages, vcpus)?;
+        let mut this = Self::new(vm_pages, vcpus, HashAlgorithm::Sha384)?;
         this.guests = Some(guests);
         Ok(this)
     }
@@ -392,7 +428,7 @@ impl<'a, T: GuestStagePagingMode, S> VmRef<'a, T, S> {
     }
 
     /// Returns a reference to this VM's `AttestationManager`.
-    pub fn attestation_mgr(&self) -> &AttestationSha384 {
+    pub fn attestation_mgr(&self) -> &TvmAttestationManager {
         &self.vm().attestation_mgr
     }
 
@@ -624,7 +660,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                                 PageAddr::with_round_down(fault_addr, PageSize::Size4k),
                             );
                         }
-                        Mmio => {
+                        Mmio | PciBar => {
                             // We need the faulting instruction for MMIO faults.
                             use InstructionFetchError::*;
                             let inst = match active_vcpu
@@ -661,6 +697,19 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                                 }
                             };
 
+                            if matches!(pf, PciBar) {
+                                // The only unmapped pages in the BARs of devices assigned to this
+                                // VM hold their virtualized MSI-X tables, which are emulated here
+                                // instead of being forwarded to the host. Accesses to the rest of
+                                // those pages go to the device.
+                                if self.emulate_msix_access(mmio_op, fault_addr, &mut active_vcpu) {
+                                    continue;
+                                }
+                                break VmExitCause::UnhandledTrap(
+                                    Trap::Exception(exception).to_scause(),
+                                );
+                            }
+
                             break VmExitCause::MmioFault(mmio_op, fault_addr);
                         }
                         Unmapped => {
@@ -723,6 +772,55 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         Ok(u64::from(!cause.is_resumable()))
     }
 
+    // Emulates `mmio_op` at `fault_addr` if it accesses a page containing the virtualized MSI-X
+    // table or PBA of a device owned by this VM. Returns false if the access should be forwarded to
+    // the host.
+    fn emulate_msix_access(
+        &self,
+        mmio_op: MmioOperation,
+        fault_addr: GuestPhysAddr,
+        active_vcpu: &mut ActiveVmCpu<T>,
+    ) -> bool {
+        // Device BARs are at their CPU physical addresses in the guest physical address space.
+        let addr = RawAddr::supervisor(fault_addr.bits());
+        let page_addr = PageAddr::with_round_down(addr, PageSize::Size4k);
+        let guest_id = self.page_owner_id();
+        let root = match PcieRoot::roots().find(|r| r.is_msix_table_page(page_addr, guest_id)) {
+            Some(root) => root,
+            None => return false,
+        };
+        let len = mmio_op.opcode().width();
+        let val = if mmio_op.opcode().is_load() {
+            // Loads that can't be emulated read as all-ones, like an access to a missing device.
+            root.emulate_msix_read(addr, len, guest_id).unwrap_or(!0)
+        } else {
+            let vm_pages = self.vm_pages();
+            // If the write failed, just discard it.
+            let _ = root.emulate_msix_write(
+                addr,
+                active_vcpu.mmio_store_value(mmio_op),
+                len,
+                guest_id,
+                |target| vm_pages.is_msi_target(GuestPhysAddr::guest(target, guest_id)),
+            );
+            0
+        };
+        active_vcpu.complete_mmio_op(mmio_op, val);
+        true
+    }
+
+    // Checks the message addresses in the virtualized MSI-X tables of the devices owned by this VM
+    // again, after the interrupt files mapped in its MSI page table changed.
+    fn revalidate_msix_targets(&self) {
+        let guest_id = self.page_owner_id();
+        let vm_pages = self.vm_pages();
+        for root in PcieRoot::roots() {
+            root.revalidate_msix_targets(guest_id, |target| {
+                vm_pages.is_msi_target(GuestPhysAddr::guest(target, guest_id))
+            });
+        }
+    }
+
     // Handles a virtual instruction trap taken due to `inst`.
     fn handle_virtual_instruction(
         &self,
@@ -1157,6 +1255,31 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                 guest_addr,
                 len,
             } => self.guest_remove_pages(guest_id, guest_addr, len).into(),
+            TvmAddMsiTablePages {
+                guest_id,
+                page_addr,
+                num_pages,
+            } => self
+                .guest_add_msi_table_pages(guest_id, page_addr, num_pages)
+                .into(),
+            TvmAssignDevice {
+                guest_id,
+                device_id,
+            } => self.guest_assign_device(guest_id, device_id).into(),
+            IommuSetProcessDirectory {
+                device_id,
+                pdt_mode,
+                pdt_addr,
+            } => self
+                .set_device_process_directory(device_id, pdt_mode, pdt_addr)
+                .into(),
+            IommuInvalidateProcessContext {
+                device_id,
+                process_id,
+            } => self
+                .invalidate_device_process_context(device_id, process_id)
+                .into(),
+            IommuFenceProcess { pscid } => self.iommu_fence_process(pscid).into(),
         }
     }
 
@@ -1219,6 +1342,192 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         Ok(0)
     }
 
+    // Returns the PCIe root complex and arena ID of the PCI device at the PCI address `device_id`,
+    // as enumerated by this VM.
+    fn pci_device_id(&self, device_id: u64) -> EcallResult<(&'static PcieRoot, PciArenaId)> {
+        let address = u32::try_from(device_id)
+            .map(Address::from)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+        let root = PcieRoot::for_segment(address.segment())
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let id = root
+            .device_id_by_virtual_address(address)
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        Ok((root, id))
+    }
+
+    // Returns the PCI device at the PCI address `device_id`, as enumerated by this VM.
+    fn pci_device_by_id(&self, device_id: u64) -> EcallResult<&'static Mutex<PciDevice>> {
+        let (root, id) = self.pci_device_id(device_id)?;
+        // Unwrap ok: `id` was just looked up in `root`.
+        Ok(root.get_device(id).unwrap())
+    }
+
+    // Returns the PCI device at the PCI address `device_id` if it's owned by this VM.
+    fn owned_pci_device_by_id(&self, device_id: u64) -> EcallResult<&'static Mutex<PciDevice>> {
+        let dev = self.pci_device_by_id(device_id)?;
+        if dev.lock().owner() != Some(self.page_owner_id()) {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        Ok(dev)
+    }
+
+    /// Checks every PCIe hotplug slot for changes in occupancy that haven't been picked up by an
+    /// access to the slot's port, and updates DMA translation for the devices that were added or
+    /// removed. This VM must be the host VM.
+    pub fn poll_hotplug_slots(&self) {
+        for root in PcieRoot::roots() {
+            if let Err(err) = root.poll_hotplug_slots() {
+                println!("Failed to update PCIe hotplug slots: {:?}", err);
+            }
+        }
+        self.update_hotplugged_devices();
+    }
+
+    /// Updates DMA translation for the devices that have been added to or removed from PCIe
+    /// hotplug slots since the last call, including those that were removed while assigned to a
+    /// guest. This VM must be the host VM.
+    pub fn update_hotplugged_devices(&self) {
+        for root in PcieRoot::roots() {
+            for id in root.take_hotplug_changes() {
+                if let Err(err) = self.update_hotplugged_device(root, id) {
+                    // Unwrap ok: the changed devices must be in the arena.
+                    let address = root.get_device(id).unwrap().lock().info().address();
+                    println!(
+                        "Failed to update IOMMU for hotplugged device {}: {:?}",
+                        address, err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Completes the page requests sent by devices owned by this VM or its guests, accepting only
+    /// requests for pages that are mapped for DMA in the device owner's address space. This VM
+    /// must be the host VM.
+    pub fn process_iommu_page_requests(&self) {
+        let Some(iommu) = Iommu::get() else {
+            return;
+        };
+        let result = iommu.process_page_requests(|owner, page_addr| {
+            if owner == self.page_owner_id() {
+                self.vm_pages().is_dma_mapped(page_addr)
+            } else {
+                self.guests()
+                    .and_then(|g| g.get(owner))
+                    .is_some_and(|guest| guest.as_any_vm().vm_pages().is_dma_mapped(page_addr))
+            }
+        });
+        if let Err(err) = result {
+            println!("Failed to complete IOMMU page requests: {:?}", err);
+        }
+    }
+
+    // Updates DMA translation for the device with `id` in `root` after it has been added to or
+    // removed from its slot. Empty slot functions belong to the host and are attached to its IOMMU
+    // context, as they are at boot, so removed devices are taken back from the guests they were
+    // assigned to.
+    fn update_hotplugged_device(&self, root: &PcieRoot, id: PciArenaId) -> EcallResult<()> {
+        // Unwrap ok: the changed devices must be in the arena.
+        let dev = root.get_device(id).unwrap();
+        let (present, owner) = {
+            let dev = dev.lock();
+            if !Iommu::get().is_some_and(|iommu| iommu.translates_pci_device(dev.info().address()))
+            {
+                return Ok(());
+            }
+            (dev.is_present(), dev.owner())
+        };
+        if present {
+            let mut dev = dev.lock();
+            if dev.owner() == Some(self.page_owner_id()) {
+                // The device context was set up for the empty function the device replaced, so set
+                // it up again for the device's capabilities.
+                if dev.is_iommu_attached() {
+                    self.vm_pages()
+                        .detach_pci_device(&mut dev)
+                        .map_err(EcallError::from)?;
+                }
+                self.vm_pages()
+                    .attach_pci_device(&mut dev)
+                    .map_err(EcallError::from)?;
+            }
+            return Ok(());
+        }
+
+        if let Some(guest_id) = owner.filter(|&o| o != self.page_owner_id()) {
+            // A guest that has been destroyed has already detached its devices.
+            if let Some(guest) = self.guests().and_then(|g| g.get(guest_id)) {
+                let mut dev = dev.lock();
+                if dev.is_iommu_attached() {
+                    guest
+                        .as_any_vm()
+                        .vm_pages()
+                        .detach_pci_device(&mut dev)
+                        .map_err(EcallError::from)?;
+                }
+            }
+            root.reclaim_removed_device(id, guest_id, self.page_owner_id())?;
+        }
+        let mut dev = dev.lock();
+        if !dev.is_iommu_attached() {
+            self.vm_pages()
+                .attach_pci_device(&mut dev)
+                .map_err(EcallError::from)?;
+        }
+        Ok(())
+    }
+
+    fn set_device_process_directory(
+        &self,
+        device_id: u64,
+        pdt_mode: u64,
+        pdt_addr: u64,
+    ) -> EcallResult<u64> {
+        let dev = self.owned_pci_device_by_id(device_id)?.lock();
+        // A mode of 0 (Bare) turns off first-stage translation for the device.
+        if pdt_mode == 0 {
+            self.vm_pages()
+                .disable_pci_process_directory(&dev)
+                .map_err(EcallError::from)?;
+            return Ok(0);
+        }
+        let mode = ProcessDirectoryMode::from_iommu_mode(pdt_mode)
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let root = self.guest_addr_from_raw(pdt_addr)?;
+        self.vm_pages()
+            .enable_pci_process_directory(&dev, mode, root)
+            .map_err(EcallError::from)?;
+        Ok(0)
+    }
+
+    fn invalidate_device_process_context(
+        &self,
+        device_id: u64,
+        process_id: u64,
+    ) -> EcallResult<u64> {
+        let pid = u32::try_from(process_id)
+            .ok()
+            .and_then(ProcessId::new)
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let dev = self.owned_pci_device_by_id(device_id)?.lock();
+        self.vm_pages()
+            .invalidate_pci_process_context(&dev, pid)
+            .map_err(EcallError::from)?;
+        Ok(0)
+    }
+
+    fn iommu_fence_process(&self, pscid: u64) -> EcallResult<u64> {
+        let pscid = u32::try_from(pscid)
+            .ok()
+            .and_then(PscId::new)
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        self.vm_pages()
+            .fence_iommu_process(pscid)
+            .map_err(EcallError::from)?;
+        Ok(0)
+    }
+
     fn guests(&self) -> Option<&Guests<T>> {
         self.vm().guests.as_ref()
     }
@@ -1268,6 +1577,8 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         // bytes.
         let params: sbi_rs::TvmCreateParams =
             unsafe { core::ptr::read_unaligned(param_bytes.as_slice().as_ptr().cast()) };
+        let hash_algorithm = HashAlgorithm::try_from(params.hash_algorithm)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
 
         // Now claim the pages that the host donated to us.
         let page_root_addr = self.guest_addr_from_raw(params.tvm_page_directory_addr)?;
@@ -1312,6 +1623,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         let vm = Vm::new(
             VmPages::new(guest_root, self.vm_pages().nesting() + 1),
             VmCpus::new(),
+            hash_algorithm,
         )
         .map_err(|_| EcallError::Sbi(SbiError::Failed))?;
 
@@ -1333,6 +1645,30 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         self.guests()
             .and_then(|g| g.remove(guest_id).ok())
             .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+
+        // Dropping the guest detached its devices from its IOMMU context, so take them back.
+        for root in PcieRoot::roots() {
+            for id in root.devices_owned_by(guest_id) {
+                let result = root
+                    .transfer_device(id, guest_id, self.page_owner_id())
+                    .map_err(EcallError::from)
+                    .and_then(|_| {
+                        // Unwrap ok: `id` was returned by `root`.
+                        let mut dev = root.get_device(id).unwrap().lock();
+                        self.vm_pages()
+                            .attach_pci_device(&mut dev)
+                            .map_err(EcallError::from)
+                    });
+                if let Err(err) = result {
+                    // Unwrap ok: `id` was returned by `root`.
+                    let address = root.get_device(id).unwrap().lock().info().address();
+                    println!(
+                        "Failed to reclaim PCI device {} from guest: {:?}",
+                        address, err
+                    );
+                }
+            }
+        }
         Ok(0)
     }
 
@@ -1408,7 +1744,12 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         let guest_vm = guest
             .as_finalized_vm()
             .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-        guest_vm.run_vcpu(vcpu_id, VmCpuParent::HostVm(active_vcpu))
+        let result = guest_vm.run_vcpu(vcpu_id, VmCpuParent::HostVm(active_vcpu));
+        drop(guest_vm);
+        // Devices assigned to the guest stall DMA until their page requests are completed, and the
+        // guest may not exit to the host for a while, so service them on every guest exit too.
+        self.process_iommu_page_requests();
+        result
     }
 
     fn guest_add_page_table_pages(
@@ -1438,6 +1779,172 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         Ok(0)
     }
 
+    // Creates an IOMMU context for the guest VM, using the `num_pages` converted pages at
+    // `page_addr` for its MSI page table. PCI devices can only be assigned to guests with an IOMMU
+    // context.
+    fn guest_add_msi_table_pages(
+        &self,
+        guest_id: u64,
+        page_addr: u64,
+        num_pages: u64,
+    ) -> EcallResult<u64> {
+        let guest = self.guest_by_id(guest_id)?;
+        let guest_vm = guest
+            .as_initializing_vm()
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let from_page_addr = self.guest_addr_from_raw(page_addr)?;
+        let pages = self
+            .vm_pages()
+            .get_converted_pages(from_page_addr, PageSize::Size4k, num_pages)
+            .map_err(EcallError::from)?;
+        if !pages.is_contiguous() {
+            return Err(EcallError::Sbi(SbiError::InvalidAddress));
+        }
+        // Unwrap ok: We checked above that `pages` is contiguous.
+        let msi_table_pages =
+            SequentialPages::from_pages(Self::assign_pages(pages, guest_vm.page_owner_id()))
+                .unwrap();
+        guest_vm
+            .vm_pages()
+            .add_iommu_context(msi_table_pages)
+            .map_err(EcallError::from)?;
+        Ok(0)
+    }
+
+    // Transfers the PCI device at the PCI address `device_id`, as enumerated by this VM, to the
+    // guest VM, and enables DMA translation for it using the guest's IOMMU context.
+    fn guest_assign_device(&self, guest_id: u64, device_id: u64) -> EcallResult<u64> {
+        let guest = self.guest_by_id(guest_id)?;
+        // The device's BARs are added to the guest's address space, which is only possible before
+        // the guest is finalized.
+        let guest_vm = guest
+            .as_initializing_vm()
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        if !guest_vm.vm_pages().has_iommu_context() {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        let guest_id = guest_vm.page_owner_id();
+        let (root, id) = self.pci_device_id(device_id)?;
+        // Unwrap ok: `id` was just looked up in `root`.
+        let dev = root.get_device(id).unwrap();
+
+        let bar_ranges = {
+            let dev = dev.lock();
+            if dev.owner() != Some(self.page_owner_id()) {
+                return Err(EcallError::Sbi(SbiError::InvalidParam));
+            }
+            // The guest can only be isolated from DMA that the IOMMU translates.
+            if !Iommu::get().is_some_and(|iommu| iommu.translates_pci_device(dev.info().address()))
+            {
+                return Err(EcallError::Sbi(SbiError::NotSupported));
+            }
+            root.device_bar_pages(&dev).map_err(EcallError::from)?
+        };
+
+        // The device's BAR pages move from our address space to the guest's along with the device,
+        // so we must have converted them first. Lock them now so that nothing changes under us
+        // while the device is being transferred.
+        let mut bar_pages = ArrayVec::<_, PCI_ENDPOINT_BARS>::new();
+        for range in bar_ranges.iter() {
+            let pages = self
+                .vm_pages()
+                .get_converted_pci_pages(
+                    range.base().as_guest_phys(self.page_owner_id()),
+                    range.num_pages(),
+                )
+                .map_err(EcallError::from)?;
+            bar_pages.push(pages);
+        }
+
+        // Device BARs are at their CPU physical addresses in the guest physical address space.
+        let guest_pages = guest_vm.vm_pages();
+        let remove_bar_regions = |added: &[SupervisorPageRange]| {
+            for range in added {
+                // Unwrap ok: the region was added above and nothing has been mapped into it.
+                guest_pages
+                    .remove_pci_region(range.base().as_guest_phys(guest_id), range.length_bytes())
+                    .unwrap();
+            }
+        };
+        let mut mappers = ArrayVec::<_, PCI_ENDPOINT_BARS>::new();
+        for (i, range) in bar_ranges.iter().enumerate() {
+            let guest_addr = range.base().as_guest_phys(guest_id);
+            if let Err(err) = guest_pages.add_pci_region(guest_addr, range.length_bytes()) {
+                drop(mappers);
+                remove_bar_regions(&bar_ranges[..i]);
+                return Err(err.into());
+            }
+            match guest_pages.map_pci_pages(guest_addr, range.num_pages()) {
+                Ok(mapper) => mappers.push(mapper),
+                Err(err) => {
+                    drop(mappers);
+                    remove_bar_regions(&bar_ranges[..=i]);
+                    return Err(err.into());
+                }
+            }
+        }
+
+        // Devices can only change owners while they're detached from the IOMMU.
+        let was_attached = {
+            let mut dev = dev.lock();
+            let attached = dev.is_iommu_attached();
+            if attached {
+                if let Err(err) = self.vm_pages().detach_pci_device(&mut dev) {
+                    drop(mappers);
+                    remove_bar_regions(&bar_ranges[..]);
+                    return Err(err.into());
+                }
+            }
+            attached
+        };
+        let result = root
+            .transfer_device(id, self.page_owner_id(), guest_id)
+            .map_err(EcallError::from)
+            .and_then(|_| {
+                guest_pages
+                    .attach_pci_device(&mut dev.lock())
+                    .map_err(|err| {
+                        // Take the device back. If that fails too it stays with the guest, and is
+                        // returned to us when the guest is destroyed.
+                        if let Err(e) = root.transfer_device(id, guest_id, self.page_owner_id()) {
+                            println!("Failed to reclaim PCI device from guest: {:?}", e);
+                        }
+                        err.into()
+                    })
+            });
+        if let Err(err) = result {
+            if was_attached {
+                // We still own the device, so give it back its DMA translation.
+                let _ = self.vm_pages().attach_pci_device(&mut dev.lock());
+            }
+            drop(mappers);
+            remove_bar_regions(&bar_ranges[..]);
+            return Err(err);
+        }
+
+        // The guest owns the device now, so hand over its BAR pages. The pages holding the MSI-X
+        // table and PBA are assigned to the guest but left unmapped so that its accesses to them
+        // fault and can be emulated.
+        for (pages, (mapper, range)) in bar_pages
+            .into_iter()
+            .zip(mappers.iter().zip(bar_ranges.iter()))
+        {
+            let guest_addrs = range.base().as_guest_phys(guest_id).iter_from();
+            for (page, guest_addr) in pages.zip(guest_addrs) {
+                let msix_table_page = root.is_msix_table_page(page.addr(), guest_id);
+                // Unwrap ok: the page is converted and locked, and `mapper` covers the whole BAR.
+                let mappable = guest_vm
+                    .page_tracker()
+                    .assign_page_for_mapping(page, guest_id)
+                    .unwrap();
+                if !msix_table_page {
+                    mapper.map_page(guest_addr, mappable).unwrap();
+                }
+            }
+        }
+        Ok(0)
+    }
+
     fn guest_add_memory_region(
         &self,
         guest_id: u64,
@@ -1741,6 +2248,40 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                     evidence_format,
                     cert_addr_out,
                     cert_size as usize,
+                    active_pages,
+                )
+                .into(),
+
+            GetDiceChain {
+                chain_addr_out,
+                chain_size,
+            } => self
+                .guest_get_dice_chain(chain_addr_out, chain_size as usize, active_pages)
+                .into(),
+
+            GetEventLog {
+                log_addr_out,
+                log_size,
+            } => self
+                .guest_get_event_log(log_addr_out, log_size as usize, active_pages)
+                .into(),
+
+            GetSealingKey {
+                msmt_mask,
+                tcb_svn,
+                label_addr,
+                label_size,
+                key_addr_out,
+                key_size,
+            } => self
+                .guest_get_sealing_key(
+                    msmt_mask,
+                    tcb_svn,
+                    label_addr,
+                    label_size as usize,
+                    key_addr_out,
+                    key_size as usize,
+                    active_pages,
                 )
                 .into(),
 
@@ -1802,23 +2343,153 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         Ok(0)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn guest_get_evidence(
         &self,
         csr_guest_addr: u64,
         csr_len: usize,
-        _request_data_addr: u64,
-        _evidence_format: u64,
+        request_data_addr: u64,
+        evidence_format: u64,
         certout_guest_addr: u64,
         certout_len: usize,
+        active_pages: &ActiveVmPages<T>,
     ) -> EcallResult<u64> {
-        let csr_gpa = RawAddr::guest(csr_guest_addr, self.page_owner_id());
+        const DICE_TCB_INFO: u64 = EvidenceFormat::DiceTcbInfo as u64;
+        const EAT: u64 = EvidenceFormat::Eat as u64;
+
         let certout_gpa = RawAddr::guest(certout_guest_addr, self.page_owner_id());
-        Ok(UmodeTask::attestation_evidence(
+        match evidence_format {
+            DICE_TCB_INFO => {
+                let csr_gpa = RawAddr::guest(csr_guest_addr, self.page_owner_id());
+                Ok(UmodeTask::attestation_evidence(
+                    self,
+                    csr_gpa,
+                    csr_len,
+                    certout_gpa,
+                    certout_len,
+                )?)
+            }
+            EAT => {
+                // The token does not certify a key: the CSR is ignored and the request data is
+                // used as the token nonce.
+                let mut request_data = [0u8; sbi_rs::EVIDENCE_DATA_BLOB_SIZE];
+                let request_data_gpa = RawAddr::guest(request_data_addr, self.page_owner_id());
+                active_pages
+                    .copy_from_guest(request_data.as_mut_slice(), request_data_gpa)
+                    .map_err(EcallError::from)?;
+                Ok(UmodeTask::attestation_eat_evidence(
+                    self,
+                    request_data,
+                    certout_gpa,
+                    certout_len,
+                )?)
+            }
+            _ => Err(EcallError::Sbi(SbiError::NotSupported)),
+        }
+    }
+
+    fn guest_get_dice_chain(
+        &self,
+        chain_guest_addr: u64,
+        chain_len: usize,
+        active_pages: &ActiveVmPages<T>,
+    ) -> EcallResult<u64> {
+        // The chain starts with the platform certificates up to the Salus layer, if firmware
+        // provided them, and ends with the TVM layer certificate.
+        let platform_chain = DiceHandoff::get().map_or(&[][..], |h| h.cert_chain());
+        // The TVM layer certificate is issued by the TSM layer CDI, which is shared by every TVM:
+        // build it here, from the TVM measurements, rather than in U-mode.
+        let mut cert = [0u8; TVM_CERTIFICATE_MAX_LEN];
+        let cert_len = self
+            .attestation_mgr()
+            .tvm_certificate(&mut cert)
+            .map_err(EcallError::from)?;
+        if chain_len < platform_chain.len() + cert_len {
+            return Err(EcallError::Sbi(SbiError::InsufficientBufferCapacity));
+        }
+
+        let chain_gpa = RawAddr::guest(chain_guest_addr, self.page_owner_id());
+        active_pages
+            .copy_to_guest(chain_gpa, platform_chain)
+            .map_err(EcallError::from)?;
+        let cert_guest_addr = chain_guest_addr
+            .checked_add(platform_chain.len() as u64)
+            .ok_or(EcallError::Sbi(SbiError::InvalidAddress))?;
+        let cert_gpa = RawAddr::guest(cert_guest_addr, self.page_owner_id());
+        active_pages
+            .copy_to_guest(cert_gpa, &cert[..cert_len])
+            .map_err(EcallError::from)?;
+        Ok((platform_chain.len() + cert_len) as u64)
+    }
+
+    fn guest_get_event_log(
+        &self,
+        log_guest_addr: u64,
+        log_len: usize,
+        active_pages: &ActiveVmPages<T>,
+    ) -> EcallResult<u64> {
+        // Copy the log to the guest one record at a time. The length check and the copy are done
+        // on the same log snapshot.
+        let mut offset = 0;
+        let encoded_len = self.attestation_mgr().for_each_event_log_record(
+            log_len,
+            |record| -> EcallResult<()> {
+                let record_addr = log_guest_addr
+                    .checked_add(offset)
+                    .ok_or(EcallError::Sbi(SbiError::InvalidAddress))?;
+                let record_gpa = RawAddr::guest(record_addr, self.page_owner_id());
+                active_pages
+                    .copy_to_guest(record_gpa, record)
+                    .map_err(EcallError::from)?;
+                offset += record.len() as u64;
+                Ok(())
+            },
+        )?;
+
+        Ok(encoded_len as u64)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn guest_get_sealing_key(
+        &self,
+        msmt_mask: u64,
+        tcb_svn: u64,
+        label_addr: u64,
+        label_size: usize,
+        key_guest_addr: u64,
+        key_size: usize,
+        active_pages: &ActiveVmPages<T>,
+    ) -> EcallResult<u64> {
+        if key_size == 0
+            || key_size > SEALING_KEY_MAX_LEN
+            || label_size > SEALING_KEY_LABEL_LEN
+            || msmt_mask >> attestation::MSMT_REGISTERS != 0
+        {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        // A TVM can derive the keys of previous TCB versions, but not the ones of future versions.
+        let caps = self
+            .attestation_mgr()
+            .capabilities()
+            .map_err(EcallError::from)?;
+        if tcb_svn > caps.tcb_svn {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+
+        let mut label = [0u8; SEALING_KEY_LABEL_LEN];
+        let label_gpa = RawAddr::guest(label_addr, self.page_owner_id());
+        active_pages
+            .copy_from_guest(&mut label[..label_size], label_gpa)
+            .map_err(EcallError::from)?;
+
+        let key_gpa = RawAddr::guest(key_guest_addr, self.page_owner_id());
+        Ok(UmodeTask::attestation_sealing_key(
             self,
-            csr_gpa,
-            csr_len,
-            certout_gpa,
-            certout_len,
+            msmt_mask,
+            tcb_svn,
+            &label[..label_size],
+            key_gpa,
+            key_size,
         )?)
     }
 
@@ -1856,7 +2527,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         // If the passed index is invalid, `extend_msmt_register` will return
         // an error.
         self.attestation_mgr()
-            .extend_msmt_register(msmt_idx, &measurement_data, None)
+            .extend_msmt_register(msmt_idx, &measurement_data[..msmt_size], None, None)
             .map_err(EcallError::from)?;
 
         Ok(0)
@@ -2134,6 +2805,8 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             // Unwrap ok: the address is in range and we haven't mapped it yet.
             mapper.map_page(addr, page).unwrap();
         }
+        // Vectors targeting the interrupt file can now be programmed into the guest's devices.
+        guest_vm.revalidate_msix_targets();
 
         // Unwrap ok: we know the vCPU is already in the "binding" state.
         guest_vm.bind_vcpu_end(vcpu_id).unwrap();
@@ -2225,6 +2898,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             // Unwrap ok: Page was mapped and has just been invalidated.
             guest_vm.vm_pages().block_imsic_page(prev_page).unwrap();
         }
+        guest_vm.revalidate_msix_targets();
 
         Ok(0)
     }
@@ -2308,6 +2982,8 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             .vm_pages()
             .unassign_imsic_begin(guest_addr)
             .unwrap();
+        // The guest's devices may no longer signal the interrupt file.
+        guest_vm.revalidate_msix_targets();
 
         Ok(0)
     }
@@ -2393,6 +3069,26 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             }
             AllowExternalInterrupt { id } => self.allow_ext_interrupt(id, active_vcpu),
             DenyExternalInterrupt { id } => self.deny_ext_interrupt(id, active_vcpu),
+            // A TVM manages first-stage translation for the devices assigned to it on its own; the
+            // host isn't involved.
+            IommuSetProcessDirectory {
+                device_id,
+                pdt_mode,
+                pdt_addr,
+            } => {
+                return self
+                    .set_device_process_directory(device_id, pdt_mode, pdt_addr)
+                    .into()
+            }
+            IommuInvalidateProcessContext {
+                device_id,
+                process_id,
+            } => {
+                return self
+                    .invalidate_device_process_context(device_id, process_id)
+                    .into()
+            }
+            IommuFenceProcess { pscid } => return self.iommu_fence_process(pscid).into(),
         };
 
         // Notify the host if a COVE-Guest call succeeds.
@@ -837,14 +837,7 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
 
                 // The MMIO instruction is transformed as an ordinary load/store to/from A0, so
                 // update A0 with the value the vCPU wants to store.
-                use MmioOpcode::*;
-                let val = match mmio_op.opcode() {
-                    Store8 => self.get_gpr(mmio_op.register()) as u8 as u64,
-                    Store16 => self.get_gpr(mmio_op.register()) as u16 as u64,
-                    Store32 => self.get_gpr(mmio_op.register()) as u32 as u64,
-                    Store64 => self.get_gpr(mmio_op.register()),
-                    _ => 0,
-                };
+                let val = self.mmio_store_value(mmio_op);
                 self.host_context
                     .set_csr(CSR_HTINST, Self::mmio_op_to_htinst(mmio_op));
                 self.host_context.set_guest_gpr(GprIndex::A0, val);
@@ -1105,6 +1098,50 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
         self.arch.shmem_area = None;
     }
 
+    /// Returns the value the vCPU is storing with `mmio_op`, or 0 if it's a load.
+    pub fn mmio_store_value(&self, mmio_op: MmioOperation) -> u64 {
+        use MmioOpcode::*;
+        match mmio_op.opcode() {
+            Store8 => self.get_gpr(mmio_op.register()) as u8 as u64,
+            Store16 => self.get_gpr(mmio_op.register()) as u16 as u64,
+            Store32 => self.get_gpr(mmio_op.register()) as u32 as u64,
+            Store64 => self.get_gpr(mmio_op.register()),
+            _ => 0,
+        }
+    }
+
+    /// Completes `mmio_op`, writing `val` to the destination register if it's a load, and advances
+    /// SEPC past the faulting instruction.
+    pub fn complete_mmio_op(&mut self, mmio_op: MmioOperation, val: u64) {
+        use MmioOpcode::*;
+        // Write the value to the actual destination register.
+        match mmio_op.opcode() {
+            Load8 => {
+                self.set_gpr(mmio_op.register(), val as i8 as u64);
+            }
+            Load8U => {
+                self.set_gpr(mmio_op.register(), val as u8 as u64);
+            }
+            Load16 => {
+                self.set_gpr(mmio_op.register(), val as i16 as u64);
+            }
+            Load16U => {
+                self.set_gpr(mmio_op.register(), val as u16 as u64);
+            }
+            Load32 => {
+                self.set_gpr(mmio_op.register(), val as i32 as u64);
+            }
+            Load32U => {
+                self.set_gpr(mmio_op.register(), val as u32 as u64);
+            }
+            Load64 => {
+                self.set_gpr(mmio_op.register(), val);
+            }
+            _ => (),
+        };
+        self.inc_sepc(mmio_op.len() as u64);
+    }
+
     // Completes any pending MMIO or ECALL result from the host for this vCPU.
     pub fn try_complete_pending_op<F, E>(
         &mut self,
@@ -1118,36 +1155,8 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                 // Complete any pending load operations. The host is expected to have written the
                 // value to complete the load to A0.
                 let val = self.host_context.guest_gpr(GprIndex::A0);
-                use MmioOpcode::*;
-                // Write the value to the actual destination register.
-                match mmio_op.opcode() {
-                    Load8 => {
-                        self.set_gpr(mmio_op.register(), val as i8 as u64);
-                    }
-                    Load8U => {
-                        self.set_gpr(mmio_op.register(), val as u8 as u64);
-                    }
-                    Load16 => {
-                        self.set_gpr(mmio_op.register(), val as i16 as u64);
-                    }
-                    Load16U => {
-                        self.set_gpr(mmio_op.register(), val as u16 as u64);
-                    }
-                    Load32 => {
-                        self.set_gpr(mmio_op.register(), val as i32 as u64);
-                    }
-                    Load32U => {
-                        self.set_gpr(mmio_op.register(), val as u32 as u64);
-                    }
-                    Load64 => {
-                        self.set_gpr(mmio_op.register(), val);
-                    }
-                    _ => (),
-                };
+                self.complete_mmio_op(mmio_op, val);
                 self.host_context.set_guest_gpr(GprIndex::A0, 0);
-
-                // Advance SEPC past the faulting instruction.
-                self.inc_sepc(mmio_op.len() as u64);
             }
             Some(PendingOperation::Ecall(msg)) => {
                 // Forward the SBI call return value from the A0/A1 values provided by the host.
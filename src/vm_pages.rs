@@ -3,7 +3,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use arrayvec::ArrayVec;
-use attestation::AttestationManager;
 use core::marker::PhantomData;
 use drivers::{imsic::*, iommu::*, pci::PciBarPage, pci::PciDevice, pci::PcieRoot};
 use page_tracking::{
@@ -20,9 +19,10 @@ use riscv_regs::{
 };
 use sync::{Mutex, Once, RwLock, RwLockReadGuard};
 
-use crate::hyp_layout::UmodeSlotId;
+use crate::hyp_layout::{UmodeService, UmodeSlotId};
 use crate::hyp_map::{Error as HypMapError, HypMap, UmodeSlotPerm};
 use crate::smp::PerCpu;
+use crate::tvm_attestation::TvmAttestationManager;
 use crate::vm::{VmStateAny, VmStateFinalized, VmStateInitializing};
 use crate::vm_id::VmId;
 
@@ -55,6 +55,9 @@ pub enum Error {
     InvalidImsicLocation,
     MsiTableMapping(IommuError),
     AttachingDevice(IommuError),
+    DetachingDevice(IommuError),
+    ProcessDirectory(IommuError),
+    IommuFence(IommuError),
     PageTracker(PageTrackingError),
     HypMap(HypMapError),
     InsufficientPtePages,
@@ -242,9 +245,11 @@ impl Drop for PinnedPages {
     }
 }
 
-/// A reference to a U-mode slot in the current page-table that is mapped with VM pages in the
-/// shared state. On drop(), the shared reference count is dropped and the slot unmapped.
+/// A reference to a U-mode slot of a U-mode service in the current page-table that is mapped with
+/// VM pages in the shared state. On drop(), the shared reference count is dropped and the slot
+/// unmapped.
 pub struct GuestUmodeMapping {
+    service: UmodeService,
     slot: UmodeSlotId,
     num_pages: u64,
     page_tracker: PageTracker,
@@ -255,12 +260,14 @@ impl GuestUmodeMapping {
     // Safety: the caller must guarantee that the slot maps `num_pages` pages owned by `owner` in
     // state "Shared".
     unsafe fn new(
+        service: UmodeService,
         slot: UmodeSlotId,
         num_pages: u64,
         page_tracker: PageTracker,
         owner: PageOwnerId,
     ) -> Self {
         Self {
+            service,
             slot,
             num_pages,
             page_tracker,
@@ -279,7 +286,7 @@ impl Drop for GuestUmodeMapping {
         // Unwrap okay: the caller guaranteed at construction that slot is mapped with `num_pages` pages.
         let unmapped = PerCpu::this_cpu()
             .page_table()
-            .unmap_umode_slot(self.slot, self.num_pages)
+            .unmap_umode_slot(self.service, self.slot, self.num_pages)
             .unwrap();
         for addr in unmapped {
             // Unwrap ok: the caller guaranteed at construction that the mapped pages are shared and
@@ -647,17 +654,15 @@ pub type MeasuredPagesMapper<'a, T> = VmPagesMapper<'a, T, MeasuredPages>;
 
 impl<'a, T: GuestStagePagingMode> MeasuredPagesMapper<'a, T> {
     /// Maps a page into the guest's address space and measures it.
-    pub fn map_page<S, M, D, H>(
+    pub fn map_page<S, M>(
         &self,
         to_addr: GuestPageAddr,
         page: Page<S>,
-        measurement: &AttestationManager<D, H>,
+        measurement: &TvmAttestationManager,
     ) -> Result<()>
     where
         S: Mappable<M>,
         M: MeasureRequirement,
-        D: digest::Digest,
-        H: hkdf::HmacImpl<D>,
     {
         measurement
             .extend_tvm_page(page.as_bytes(), to_addr.bits())
@@ -751,6 +756,9 @@ pub enum PageFaultType {
     Mmio,
     /// A page fault taken to an IMSIC guest interrupt file page.
     Imsic,
+    /// A page fault taken to an unmapped page of a PCI BAR, which holds the MSI-X table or PBA of
+    /// a device assigned to the VM.
+    PciBar,
     /// A page fault taken when accessing memory outside of any valid region of guest physical
     /// address space. These faults are not resolvable.
     Unmapped,
@@ -904,6 +912,10 @@ impl<'a, T: GuestStagePagingMode> ActiveVmPages<'a, T> {
                 Exception::GuestStorePageFault => Imsic,
                 _ => Unmapped,
             },
+            Some(VmRegionType::Pci) => match exception {
+                Exception::GuestLoadPageFault | Exception::GuestStorePageFault => PciBar,
+                _ => Unmapped,
+            },
             _ => Unmapped,
         }
     }
@@ -973,22 +985,30 @@ impl Drop for VmIommuContext {
 
         // Detach any devices we own from the IOMMU.
         let owner = self.msi_page_table.owner();
-        let pci = PcieRoot::get();
-        for dev in pci.devices() {
+        let mut flushed = true;
+        for dev in PcieRoot::roots().flat_map(|r| r.devices()) {
             let mut dev = dev.lock();
-            if dev.owner() == Some(owner) {
-                // Unwrap ok: `self.gscid` must be valid and match the ownership of the device
-                // to have been attached in the first place.
+            if dev.owner() == Some(owner) && dev.is_iommu_attached() {
+                // `self.gscid` must be valid and match the ownership of the device to have been
+                // attached in the first place, so this only fails if the IOMMU failed to flush
+                // its translation caches.
                 //
                 // Silence buggy clippy warning.
                 #[allow(clippy::explicit_auto_deref)]
-                iommu.detach_pci_device(&mut *dev, self.gscid).unwrap();
+                let result = iommu.detach_pci_device(&mut *dev, self.gscid);
+                if result.is_err() {
+                    flushed = false;
+                }
             }
         }
 
-        // Unwrap ok: `self.gscid` must be valid and freeable since we've detached all devices
-        // using it.
-        iommu.free_gscid(self.gscid).unwrap();
+        // Stale translations tagged with `self.gscid` may remain cached if a flush failed. Leak
+        // the GSCID rather than let another VM pick them up.
+        if flushed {
+            // Unwrap ok: `self.gscid` must be valid and freeable since we've detached all devices
+            // using it.
+            iommu.free_gscid(self.gscid).unwrap();
+        }
     }
 }
 
@@ -1072,6 +1092,117 @@ impl<'a, T: GuestStagePagingMode, S> VmPagesRef<'a, T, S> {
         self.inner.nesting
     }
 
+    /// Returns if this VM has an IOMMU context, allowing PCI devices to be attached to it.
+    pub fn has_iommu_context(&self) -> bool {
+        self.inner.iommu_context.get().is_some()
+    }
+
+    /// Returns if devices attached to this VM may send MSIs to `addr`, that is if `addr` is in an
+    /// interrupt file mapped in this VM's MSI page table.
+    pub fn is_msi_target(&self, addr: GuestPhysAddr) -> bool {
+        self.inner
+            .iommu_context
+            .get()
+            .is_some_and(|iommu_context| iommu_context.msi_page_table.is_mapped(addr))
+    }
+
+    /// Returns if devices attached to this VM may access the page at `page_addr`, that is if it's
+    /// mapped RAM owned by this VM.
+    pub fn is_dma_mapped(&self, page_addr: GuestPageAddr) -> bool {
+        self.inner
+            .root
+            .get_mapped_pages(page_addr, PageSize::Size4k as u64, |addr, ps| {
+                self.inner.page_tracker.is_mapped_page(
+                    addr,
+                    ps,
+                    self.inner.page_owner_id,
+                    MemType::Ram,
+                )
+            })
+            .is_ok()
+    }
+
+    /// Attaches the given PCI device to this VM by enabling DMA translation via the IOMMU using
+    /// this VM's page tables.
+    pub fn attach_pci_device(&self, dev: &mut PciDevice) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .attach_pci_device(
+                dev,
+                &self.inner.root,
+                &iommu_context.msi_page_table,
+                iommu_context.gscid,
+            )
+            .map_err(Error::AttachingDevice)
+    }
+
+    /// Attaches the platform (non-PCI) DMA masters to this VM, which must be the host VM, by enabling
+    /// DMA translation for them via the IOMMU using this VM's page tables.
+    pub fn attach_platform_devices(&self) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .attach_platform_devices(
+                &self.inner.root,
+                &iommu_context.msi_page_table,
+                iommu_context.gscid,
+            )
+            .map_err(Error::AttachingDevice)
+    }
+
+    /// Detaches the given PCI device from this VM by disabling DMA translation for it in the IOMMU.
+    pub fn detach_pci_device(&self, dev: &mut PciDevice) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .detach_pci_device(dev, iommu_context.gscid)
+            .map_err(Error::DetachingDevice)
+    }
+
+    /// Enables first-stage translation of PASID-tagged DMA from the given PCI device, which must be
+    /// attached to this VM, using the process directory table of format `mode` at `root`.
+    pub fn enable_pci_process_directory(
+        &self,
+        dev: &PciDevice,
+        mode: ProcessDirectoryMode,
+        root: GuestPageAddr,
+    ) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .enable_process_directory(dev, iommu_context.gscid, mode, root)
+            .map_err(Error::ProcessDirectory)
+    }
+
+    /// Disables first-stage translation for the given PCI device.
+    pub fn disable_pci_process_directory(&self, dev: &PciDevice) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .disable_process_directory(dev, iommu_context.gscid)
+            .map_err(Error::ProcessDirectory)
+    }
+
+    /// Flushes the IOMMU's cached copy of the process context for `pid` in the process directory
+    /// table of the given PCI device.
+    pub fn invalidate_pci_process_context(&self, dev: &PciDevice, pid: ProcessId) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .invalidate_process_context(dev, iommu_context.gscid, pid)
+            .map_err(Error::ProcessDirectory)
+    }
+
+    /// Flushes the IOMMU's first-stage translations for processes with `pscid` in this VM.
+    pub fn fence_iommu_process(&self, pscid: PscId) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .fence_process(iommu_context.gscid, pscid)
+            .map_err(Error::IommuFence)
+    }
+
     // Returns the address of the root page table for this VM.
     fn root_address(&self) -> SupervisorPageAddr {
         // TODO: Cache this to avoid bouncing off the lock?
@@ -1613,13 +1744,29 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
         Ok(())
     }
 
+    // Returns true if `page_addr` is in a PCI BAR memory region of this VM.
+    fn is_pci_page(&self, page_addr: GuestPageAddr) -> bool {
+        self.inner.regions.read().find(page_addr.into()) == Some(VmRegionType::Pci)
+    }
+
     /// Converts `num_pages` starting at guest physical address `page_addr` to confidential memory.
+    /// Pages in a PCI BAR memory region are converted so that they can be assigned to a child VM
+    /// along with the device that owns them.
     pub fn convert_pages(&self, page_addr: GuestPageAddr, num_pages: u64) -> Result<()> {
-        self.do_convert_pages::<Page<Invalidated>>(page_addr, num_pages)
+        if self.is_pci_page(page_addr) {
+            self.do_convert_pages::<PciBarPage<Invalidated>>(page_addr, num_pages)?;
+        } else {
+            self.do_convert_pages::<Page<Invalidated>>(page_addr, num_pages)?;
+        }
+        // Devices may have cached translations for the pages we just invalidated.
+        self.fence_iommu()
     }
 
     /// Reclaims `num_pages` of confidential memory starting at guest physical address `page_addr`.
     pub fn reclaim_pages(&self, page_addr: GuestPageAddr, num_pages: u64) -> Result<()> {
+        if self.is_pci_page(page_addr) {
+            return self.reclaim_pci_pages(page_addr, num_pages);
+        }
         // TODO: Support reclaim of converted pages that haven't yet been fenced.
         let converted_pages = self.get_converted_pages(page_addr, PageSize::Size4k, num_pages)?;
         // Unwrap ok since the PTE for the page must have previously been invalid and all of
@@ -1635,6 +1782,35 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
         Ok(())
     }
 
+    // Reclaims `num_pages` of converted PCI BAR memory starting at guest physical address
+    // `page_addr`.
+    fn reclaim_pci_pages(&self, page_addr: GuestPageAddr, num_pages: u64) -> Result<()> {
+        let converted_pages = self.get_converted_pci_pages(page_addr, num_pages)?;
+        // Unwrap ok since the PTE for the page must have previously been invalid and all of
+        // the intermediate page-tables must already have been populatd.
+        let mapper = self.map_pci_pages(page_addr, num_pages).unwrap();
+        for (page, addr) in converted_pages.zip(page_addr.iter_from()) {
+            // Unwrap ok since we know that it's a converted page.
+            let mappable = self.inner.page_tracker.reclaim_page(page).unwrap();
+            mapper.map_page(addr, mappable).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Acquries an exclusive reference to the `num_pages` converted PCI BAR pages starting at
+    /// `page_addr`.
+    pub fn get_converted_pci_pages(
+        &self,
+        page_addr: GuestPageAddr,
+        num_pages: u64,
+    ) -> Result<LockedPageList<PciBarPage<ConvertedClean>>> {
+        self.do_get_converted_pages::<PciBarPage<ConvertedClean>>(
+            page_addr,
+            PageSize::Size4k,
+            num_pages,
+        )
+    }
+
     /// Acquries an exclusive reference to the converted IMSIC page at `imsic_addr`.
     pub fn get_converted_imsic(
         &self,
@@ -1671,7 +1847,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
             iommu_context.msi_page_table.unmap(location).unwrap();
         }
 
-        Ok(())
+        self.fence_iommu()
     }
 
     /// Reclaims the confidential guest interrupt file at `imsic_addr`.
@@ -1796,11 +1972,18 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
     /// Initiates a page conversion fence for this `VmPages` by incrementing the TLB version.
     pub fn initiate_fence(&self) -> Result<()> {
         self.inner.tlb_tracker.increment()?;
-        // If we have an IOMMU context then we need to issue a fence there as well as our page
-        // tables may be used for DMA translation.
+        self.fence_iommu()
+    }
+
+    // Flushes the IOMMU's translation caches, and the TLBs of devices using ATS, for this VM if
+    // it has an IOMMU context, since our page tables may be used for DMA translation.
+    fn fence_iommu(&self) -> Result<()> {
         if let Some(iommu_context) = self.inner.iommu_context.get() {
             // Unwrap ok since we must have an IOMMU to have a `VmIommuContext`.
-            Iommu::get().unwrap().fence(iommu_context.gscid, None);
+            Iommu::get()
+                .unwrap()
+                .fence(iommu_context.gscid, None)
+                .map_err(Error::IommuFence)?;
         }
         Ok(())
     }
@@ -1828,7 +2011,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
             self.inner.page_tracker.block_page(page, version).unwrap();
         }
 
-        Ok(())
+        self.fence_iommu()
     }
 
     /// Unblocks previously invalidated page range.
@@ -1949,6 +2132,8 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
             return Err(Error::VmRegionNotRemovable);
         }
 
+        // Make sure no device is still holding a translation for the pages before they're freed.
+        self.fence_iommu()?;
         // Does this need to be TLB current_version instead of TLB min_version?
         let tlb_version = self.inner.tlb_tracker.min_version();
         // Now check each page is removable before it is unmapped.
@@ -2040,10 +2225,11 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
     }
 
     ///  Pins `count` pages starting at `page_addr` as shared pages, and maps them in a U-mode
-    /// slot. Returns a `GuestUmodeMapping` structure that will unmap and release the pin when
-    /// dropped. Used to share memory between a VM and U-mode.
+    /// slot of `service`. Returns a `GuestUmodeMapping` structure that will unmap and release the
+    /// pin when dropped. Used to share memory between a VM and U-mode.
     pub fn map_in_umode_slot(
         &self,
+        service: UmodeService,
         slot: UmodeSlotId,
         page_addr: GuestPageAddr,
         count: u64,
@@ -2053,7 +2239,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
         // TODO: Check that guest request for mapping it writable is consistent with the guest mappings.
         let mapper = PerCpu::this_cpu()
             .page_table()
-            .umode_slot_mapper(slot, count, slot_perm)
+            .umode_slot_mapper(service, slot, count, slot_perm)
             .map_err(Error::HypMap)?;
         let to_page_addr = mapper.vaddr();
 
@@ -2076,6 +2262,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVmPages<'a, T> {
         // Safety: slot is valid and mapped with pages owned by current VM.
         let umode_mapping = unsafe {
             GuestUmodeMapping::new(
+                service,
                 slot,
                 count,
                 self.inner.page_tracker,
@@ -2179,6 +2366,12 @@ impl<'a, T: GuestStagePagingMode> InitializingVmPages<'a, T> {
         self.do_add_region(page_addr, len, VmRegionType::Pci)
     }
 
+    /// Removes the PCI BAR memory region of `len` bytes starting at `page_addr` from this VM's
+    /// address space. The region must not have any pages mapped into it.
+    pub fn remove_pci_region(&self, page_addr: GuestPageAddr, len: u64) -> Result<()> {
+        self.do_remove_region(page_addr, len, VmRegionType::Pci)
+    }
+
     /// Like `map_zero_pages()`, but for measured pages mapped into a region of confidential
     /// memory.
     pub fn map_measured_pages(
@@ -2191,21 +2384,6 @@ impl<'a, T: GuestStagePagingMode> InitializingVmPages<'a, T> {
             r == VmRegionType::Confidential
         })
     }
-
-    /// Attaches the given PCI device to this VM by enabling DMA translation via the IOMMU using
-    /// this VM's page tables.
-    pub fn attach_pci_device(&self, dev: &mut PciDevice) -> Result<()> {
-        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
-        Iommu::get()
-            .unwrap()
-            .attach_pci_device(
-                dev,
-                &self.inner.root,
-                &iommu_context.msi_page_table,
-                iommu_context.gscid,
-            )
-            .map_err(Error::AttachingDevice)
-    }
 }
 
 impl<'a, T: GuestStagePagingMode> From<InitializingVmPages<'a, T>> for AnyVmPages<'a, T> {
@@ -15,7 +15,7 @@ use hex_literal::hex;
 extern crate alloc;
 extern crate test_workloads;
 
-use ::attestation::TcgPcrIndex::{RuntimePcr1, TvmPage};
+use ::attestation::TcgPcrIndex::{PlatformCode, PlatformConfiguration, RuntimePcr1, TvmPage};
 use rice::x509::{
     certificate::Certificate,
     extensions::dice::tcbinfo::{DiceTcbInfo, TCG_DICE_TCB_INFO},
@@ -25,6 +25,7 @@ use riscv_regs::{sie, stopi, Interrupt, Readable, RiscvCsrInterface, Writeable,
 use s_mode_utils::abort::abort;
 use s_mode_utils::{print::*, sbi_console::SbiConsole};
 use sbi_rs::api::{attestation, base, cove_guest, reset};
+use sbi_rs::{ecall_send, CoveGuestFunction, SbiMessage};
 use test_system::*;
 use test_workloads::consts::*;
 
@@ -201,6 +202,14 @@ fn test_attestation() -> TestResult {
         return Err(TestFailure::Fail);
     }
 
+    // This TVM is created with the default measurement hash algorithm.
+    if caps.hash_algorithm != sbi_rs::HashAlgorithm::Sha384
+        || !caps.hash_algorithms.contains(sbi_rs::HashAlgorithm::Sha512)
+    {
+        println!("Unexpected hash algorithms {:?}", caps.hash_algorithms);
+        return Err(TestFailure::Fail);
+    }
+
     // SHA384 for "helloworld"
     let digest = hex!("97982a5b1414b9078103a1c008c4e3526c27b41cdbcf80790560a40f2a9bf2ed4427ab1428789915ed4b3dc07c454bd9");
     attestation::extend_measurement(&digest, RuntimePcr1 as usize)
@@ -211,7 +220,7 @@ fn test_attestation() -> TestResult {
     println!("Runtime PCR #1 measurement: {:x?}", pcr_read.as_slice());
 
     // SHA384 for (RuntimePCR1 || SHA384(b"helloworld"))
-    let expected_pcr = hex!("4d783cdfa8d6bc1293d0f1bfb58f9f0b05a8ef723cb8745d142d60ac3b9d213c51f06aa1e9b92ff09f64e4a2d0c8fe87");
+    let expected_pcr = hex!("96c4ef47b5b70840706372472f80b355673b289d02e219e2b55f2d3727596c9790adda77fa1b53bae481daa1113cc4b7");
     let result = pcr_read.as_slice() != expected_pcr;
     test_assert!(!result, "pcr attestation measurement");
     if pcr_read.as_slice() != expected_pcr {
@@ -219,6 +228,18 @@ fn test_attestation() -> TestResult {
         return Err(TestFailure::Fail);
     }
 
+    // The platform registers are seeded with the Salus and platform configuration measurements.
+    for pcr in [PlatformCode, PlatformConfiguration] {
+        let pcr_read =
+            attestation::read_measurement(pcr as usize).expect("Failed to read platform PCR");
+        let result = pcr_read.as_slice().iter().all(|&b| b == 0);
+        test_assert!(!result, "platform pcr measurement");
+        if result {
+            println!("Platform PCR {} is not measured", pcr as usize);
+            return Err(TestFailure::Fail);
+        }
+    }
+
     if TEST_CSR.len() > MAX_CSR_LEN {
         println!("Test CSR is too large");
         return Err(TestFailure::Fail);
@@ -277,6 +298,95 @@ fn test_attestation() -> TestResult {
         tvm_fwid.digest.as_bytes().len()
     );
 
+    if !caps.evidence_formats.contains(sbi_rs::EvidenceFormat::Eat) {
+        println!("EAT format is not supported");
+        return Err(TestFailure::Fail);
+    }
+
+    let mut eat_request_data = [0u8; sbi_rs::EVIDENCE_DATA_BLOB_SIZE];
+    eat_request_data[0..8].copy_from_slice(&0x5a1a5ea7u64.to_le_bytes());
+    let eat_bytes =
+        match attestation::get_evidence(TEST_CSR, &eat_request_data, sbi_rs::EvidenceFormat::Eat) {
+            Err(e) => {
+                println!("Attestation error {e:?}");
+                println!("Guest EAT evidence call failed");
+                return Err(TestFailure::Fail);
+            }
+            Ok(eat_bytes) => eat_bytes,
+        };
+
+    // A tagged COSE_Sign1 structure starts with CBOR tag 18 (0xd2), followed by a 4 items array.
+    let is_cose_sign1 = eat_bytes.len() > 2 && eat_bytes[0] == 0xd2 && eat_bytes[1] == 0x84;
+    test_assert!(is_cose_sign1, "EAT COSE_Sign1 structure");
+    // The request data is the token nonce.
+    let has_nonce = eat_bytes
+        .windows(eat_request_data.len())
+        .any(|w| w == eat_request_data);
+    test_assert!(has_nonce, "EAT nonce");
+    if !is_cose_sign1 || !has_nonce {
+        println!("Invalid EAT evidence");
+        return Err(TestFailure::Fail);
+    }
+    println!("EAT evidence len {}", eat_bytes.len());
+
+    let chain_bytes = match attestation::get_dice_chain() {
+        Err(e) => {
+            println!("Attestation error {e:?}");
+            println!("Guest DICE chain call failed");
+            return Err(TestFailure::Fail);
+        }
+        Ok(chain_bytes) => chain_bytes,
+    };
+    // The chain is a list of concatenated DER certificates, each one starting with a SEQUENCE.
+    let is_der_chain = !chain_bytes.is_empty() && chain_bytes[0] == 0x30;
+    test_assert!(is_der_chain, "DICE certificate chain");
+    if !is_der_chain {
+        println!("Invalid DICE certificate chain");
+        return Err(TestFailure::Fail);
+    }
+    println!("DICE certificate chain len {}", chain_bytes.len());
+
+    let log_bytes = match attestation::get_event_log() {
+        Err(e) => {
+            println!("Attestation error {e:?}");
+            println!("Guest event log call failed");
+            return Err(TestFailure::Fail);
+        }
+        Ok(log_bytes) => log_bytes,
+    };
+    // The log holds at least the runtime PCR #1 extension, and its first record starts with an
+    // 8 bytes record number TLV.
+    let is_cel = log_bytes.starts_with(&[0, 0, 0, 0, 8]);
+    test_assert!(is_cel, "TCG canonical event log");
+    if !is_cel {
+        println!("Invalid event log");
+        return Err(TestFailure::Fail);
+    }
+    println!("Event log len {}", log_bytes.len());
+
+    // Sealing keys bound to the same policy must match, and differ for a different label.
+    let msmt_mask = 1 << TvmPage as u64;
+    let mut sealing_key = [0u8; 32];
+    let mut sealing_key_again = [0u8; 32];
+    let mut other_sealing_key = [0u8; 32];
+    for (label, key) in [
+        (b"sealing-test", &mut sealing_key),
+        (b"sealing-test", &mut sealing_key_again),
+        (b"sealing-othr", &mut other_sealing_key),
+    ] {
+        if let Err(e) = attestation::get_sealing_key(msmt_mask, caps.tcb_svn, label, key) {
+            println!("Attestation error {e:?}");
+            println!("Guest sealing key call failed");
+            return Err(TestFailure::Fail);
+        }
+    }
+    let sealing_keys_ok = sealing_key == sealing_key_again && sealing_key != other_sealing_key;
+    test_assert!(sealing_keys_ok, "Sealing key derivation");
+    if !sealing_keys_ok {
+        println!("Invalid sealing keys");
+        return Err(TestFailure::Fail);
+    }
+
     Ok(())
 }
 
@@ -355,6 +465,44 @@ fn test_emulated_mmio() -> TestResult {
     Ok(())
 }
 
+fn test_assigned_device_msix(table_addr: u64) -> TestResult {
+    // The MSI-X table of our device isn't mapped; the TSM emulates our accesses to it without
+    // involving the host. Vectors start out masked and read back what we last wrote to them.
+    let msg_data_ptr = (table_addr + 0x8) as *mut u32;
+    let vector_control_ptr = (table_addr + 0xc) as *mut u32;
+    // Safety: For the accesses below, the MSI-X table of the device that's been assigned to us is
+    // at `table_addr`, and both pointers are properly aligned dwords within its first entry.
+    let vector_control = unsafe { core::ptr::read_volatile(vector_control_ptr) };
+    test_result_true!(vector_control == 1, "MSI-X vector masked")?;
+    unsafe { core::ptr::write_volatile(msg_data_ptr, 0x42) };
+    let msg_data = unsafe { core::ptr::read_volatile(msg_data_ptr) };
+    test_result_true!(msg_data == 0x42, "MSI-X message data written")?;
+
+    Ok(())
+}
+
+fn test_iommu_process_directory() -> TestResult {
+    // Function 00:00.0 is the root complex's host bridge, which belongs to the host, so we can't
+    // change DMA translation for it.
+    let msg = SbiMessage::CoveGuest(CoveGuestFunction::IommuSetProcessDirectory {
+        device_id: 0,
+        pdt_mode: 0,
+        pdt_addr: 0,
+    });
+    // Safety: IommuSetProcessDirectory doesn't access our memory.
+    let denied = unsafe { ecall_send::<()>(&msg) }.is_err();
+    test_result_true!(denied, "IommuSetProcessDirectory on a host device")?;
+    let msg = SbiMessage::CoveGuest(CoveGuestFunction::IommuInvalidateProcessContext {
+        device_id: 0,
+        process_id: 1,
+    });
+    // Safety: IommuInvalidateProcessContext doesn't access our memory.
+    let denied = unsafe { ecall_send::<()>(&msg) }.is_err();
+    test_result_true!(denied, "IommuInvalidateProcessContext on a host device")?;
+
+    Ok(())
+}
+
 fn test_interrupts() -> TestResult {
     const INTERRUPT_ID: usize = 3;
 
@@ -495,6 +643,17 @@ extern "C" fn kernel_init(hart_id: u64, boot_args: u64) {
 
     test_runtest!("test emulated mmio", { test_emulated_mmio() });
 
+    let msix_table_addr = boot_args & BOOT_ARG_MSIX_TABLE_MASK;
+    if msix_table_addr != 0 {
+        test_runtest!("test assigned device MSI-X", {
+            test_assigned_device_msix(msix_table_addr)
+        });
+    }
+
+    test_runtest!("test IOMMU process directory", {
+        test_iommu_process_directory()
+    });
+
     test_runtest!("test interrupts", { test_interrupts() });
 
     test_runtest!("test huge pages", { test_huge_pages() });
@@ -451,6 +451,193 @@ fn do_guest_puts(
     Ok(len)
 }
 
+// The PCIe ECAM of the QEMU virt machine, which Salus emulates for us at the same address.
+const PCIE_ECAM_BASE: u64 = 0x3000_0000;
+const PCI_MAX_DEVICES: u64 = 32;
+const PCI_ENDPOINT_BARS: usize = 6;
+const PCI_COMMAND_OFFSET: u64 = 0x4;
+const PCI_COMMAND_MEMORY_ENABLE: u32 = 1 << 1;
+const PCI_STATUS_CAP_LIST: u32 = 1 << 20;
+const PCI_CLASS_OFFSET: u64 = 0x8;
+const PCI_CLASS_IOMMU: u32 = 0x0806;
+const PCI_HEADER_TYPE_OFFSET: u64 = 0xc;
+const PCI_BAR_OFFSET: u64 = 0x10;
+const PCI_CAP_PTR_OFFSET: u64 = 0x34;
+const PCI_CAP_ID_MSIX: u32 = 0x11;
+
+// Reads the config space dword at `offset` of the function with routing ID `rid`.
+fn pci_config_read(rid: u64, offset: u64) -> u32 {
+    let ptr = (PCIE_ECAM_BASE + (rid << 12) + offset) as *const u32;
+    // Safety: `ptr` is an aligned dword in the emulated ECAM, which isn't used for anything else.
+    unsafe { core::ptr::read_volatile(ptr) }
+}
+
+// Writes `val` to the config space dword at `offset` of the function with routing ID `rid`.
+fn pci_config_write(rid: u64, offset: u64, val: u32) {
+    let ptr = (PCIE_ECAM_BASE + (rid << 12) + offset) as *mut u32;
+    // Safety: `ptr` is an aligned dword in the emulated ECAM, which isn't used for anything else.
+    unsafe { core::ptr::write_volatile(ptr, val) };
+}
+
+// A memory BAR of a PCI function.
+#[derive(Clone, Copy)]
+struct PciBar {
+    addr: u64,
+    size: u64,
+}
+
+impl PciBar {
+    fn num_pages(&self) -> u64 {
+        (self.size + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K
+    }
+}
+
+// Returns the memory BARs of the endpoint with routing ID `rid`, indexed by BAR number. Memory
+// space access must be disabled while the BARs are sized.
+fn pci_memory_bars(rid: u64) -> [Option<PciBar>; PCI_ENDPOINT_BARS] {
+    let mut bars = [None; PCI_ENDPOINT_BARS];
+    let mut index = 0;
+    while index < PCI_ENDPOINT_BARS {
+        let offset = PCI_BAR_OFFSET + index as u64 * 4;
+        let bar_index = index;
+        let lo = pci_config_read(rid, offset);
+        index += 1;
+        if lo & 0x1 != 0 {
+            // IO port BAR.
+            continue;
+        }
+        // Write all 1s to find the size of the BAR.
+        pci_config_write(rid, offset, !0);
+        let mask_lo = pci_config_read(rid, offset) & !0xf;
+        pci_config_write(rid, offset, lo);
+        let (hi, mask_hi) = if lo & 0x6 == 0x4 {
+            // The upper half of 64-bit BARs is in the next register.
+            let hi = pci_config_read(rid, offset + 4);
+            pci_config_write(rid, offset + 4, !0);
+            let mask_hi = pci_config_read(rid, offset + 4);
+            pci_config_write(rid, offset + 4, hi);
+            index += 1;
+            (hi, mask_hi)
+        } else {
+            (0, !0)
+        };
+        if mask_lo == 0 && mask_hi == 0 {
+            // Unimplemented BAR.
+            continue;
+        }
+        bars[bar_index] = Some(PciBar {
+            addr: ((hi as u64) << 32) | (lo & !0xf) as u64,
+            size: !(((mask_hi as u64) << 32) | mask_lo as u64) + 1,
+        });
+    }
+    bars
+}
+
+// Returns the address of the MSI-X table of the function with routing ID `rid`, if it has one.
+fn pci_msix_table(rid: u64, bars: &[Option<PciBar>]) -> Option<u64> {
+    if pci_config_read(rid, PCI_COMMAND_OFFSET) & PCI_STATUS_CAP_LIST == 0 {
+        return None;
+    }
+    let mut cap = (pci_config_read(rid, PCI_CAP_PTR_OFFSET) & 0xfc) as u64;
+    while cap != 0 {
+        let header = pci_config_read(rid, cap);
+        if header & 0xff == PCI_CAP_ID_MSIX {
+            let table = pci_config_read(rid, cap + 4);
+            let bar = bars.get((table & 0x7) as usize)?.as_ref()?;
+            return Some(bar.addr + (table & !0x7) as u64);
+        }
+        cap = ((header >> 8) & 0xfc) as u64;
+    }
+    None
+}
+
+// A PCI endpoint assigned to the TVM.
+struct AssignedDevice {
+    rid: u64,
+    command: u32,
+    bars: [Option<PciBar>; PCI_ENDPOINT_BARS],
+    msix_table_addr: u64,
+}
+
+// Assigns the first endpoint on the root bus that has an MSI-X table to the TVM `vmid`, using the
+// page at `msi_table_page` for the TVM's MSI page table. Returns `None` if there's no such endpoint
+// or it can't be assigned.
+fn assign_msix_device(vmid: u64, msi_table_page: u64) -> Option<AssignedDevice> {
+    let device = (0..PCI_MAX_DEVICES).map(|dev| dev << 3).find_map(|rid| {
+        if pci_config_read(rid, 0) & 0xffff == 0xffff
+            || (pci_config_read(rid, PCI_HEADER_TYPE_OFFSET) >> 16) & 0x7f != 0
+            || pci_config_read(rid, PCI_CLASS_OFFSET) >> 16 == PCI_CLASS_IOMMU
+        {
+            return None;
+        }
+        let command = pci_config_read(rid, PCI_COMMAND_OFFSET) & 0xffff;
+        pci_config_write(
+            rid,
+            PCI_COMMAND_OFFSET,
+            command & !PCI_COMMAND_MEMORY_ENABLE,
+        );
+        let bars = pci_memory_bars(rid);
+        match pci_msix_table(rid, &bars) {
+            Some(msix_table_addr) => Some(AssignedDevice {
+                rid,
+                command,
+                bars,
+                msix_table_addr,
+            }),
+            None => {
+                pci_config_write(rid, PCI_COMMAND_OFFSET, command);
+                None
+            }
+        }
+    })?;
+
+    // Safety: The passed-in page is unmapped and we do not access it again until it's reclaimed.
+    unsafe {
+        convert_pages(msi_table_page, 1);
+    }
+    let msg = SbiMessage::CoveHost(sbi_rs::CoveHostFunction::TvmAddMsiTablePages {
+        guest_id: vmid,
+        page_addr: msi_table_page,
+        num_pages: 1,
+    });
+    // Safety: The MSI table page was converted above and isn't otherwise used by this program.
+    if let Err(e) = unsafe { ecall_send::<()>(&msg) } {
+        println!("Tellus - TvmAddMsiTablePages failed: {:?}", e);
+        reclaim_pages(msi_table_page, 1);
+        pci_config_write(device.rid, PCI_COMMAND_OFFSET, device.command);
+        return None;
+    }
+
+    // The device's BARs move to the TVM along with the device.
+    for bar in device.bars.iter().flatten() {
+        cove_host::convert_pages(bar.addr, bar.num_pages()).expect("TsmConvertPages failed");
+    }
+    fence_memory();
+    let msg = SbiMessage::CoveHost(sbi_rs::CoveHostFunction::TvmAssignDevice {
+        guest_id: vmid,
+        device_id: device.rid,
+    });
+    // Safety: TvmAssignDevice doesn't access our memory.
+    if let Err(e) = unsafe { ecall_send::<()>(&msg) } {
+        println!("Tellus - TvmAssignDevice failed: {:?}", e);
+        reclaim_device_bars(&device);
+        return None;
+    }
+    println!(
+        "Tellus - Assigned device {:x} with MSI-X table at 0x{:x}",
+        device.rid, device.msix_table_addr
+    );
+    Some(device)
+}
+
+// Reclaims the BARs of `device` once it's no longer assigned to a TVM.
+fn reclaim_device_bars(device: &AssignedDevice) {
+    for bar in device.bars.iter().flatten() {
+        cove_host::reclaim_pages(bar.addr, bar.num_pages()).expect("TsmReclaimPages failed");
+    }
+    pci_config_write(device.rid, PCI_COMMAND_OFFSET, device.command);
+}
+
 static mut CONSOLE_BUFFER: [u8; 256] = [0; 256];
 
 /// The entry point of the Rust part of the kernel.
@@ -622,6 +809,17 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
         println!("Platform doesn't support COVE AIA extension");
     }
 
+    // Assign a device to the TVM if there's one we can use to check that accesses to its MSI-X
+    // table are emulated. Devices are only isolated from the host with an IOMMU, which requires
+    // IMSIC virtualization.
+    let assigned_device = if has_aia {
+        let msi_table_page = next_page;
+        next_page += PAGE_SIZE_4K;
+        assign_msix_device(vmid, msi_table_page).map(|d| (d, msi_table_page))
+    } else {
+        None
+    };
+
     let guest_image_base = USABLE_RAM_START_ADDRESS + PAGE_SIZE_4K * NUM_TELLUS_IMAGE_PAGES;
     // Safety: Safe to make a slice out of the guest image as it is read-only and not used by this
     // program.
@@ -681,11 +879,27 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
     }
 
     // Tell the guest if we have vector support via its boot argument.
-    let boot_arg = if vector_enabled {
+    let mut boot_arg = if vector_enabled {
         BOOT_ARG_VECTORS_ENABLED
     } else {
         0
     };
+    // Tell the guest where the MSI-X table of its device is.
+    if let Some((ref device, _)) = assigned_device {
+        boot_arg |= device.msix_table_addr & BOOT_ARG_MSIX_TABLE_MASK;
+        // DMA translation for the device is now up to the TVM, so we can't change it.
+        let msg = SbiMessage::CoveHost(sbi_rs::CoveHostFunction::IommuSetProcessDirectory {
+            device_id: device.rid,
+            pdt_mode: 0,
+            pdt_addr: 0,
+        });
+        // Safety: IommuSetProcessDirectory doesn't access our memory.
+        let result = unsafe { ecall_send::<()>(&msg) };
+        assert!(
+            result.is_err(),
+            "Tellus - IommuSetProcessDirectory succeeded for a TVM's device"
+        );
+    }
     // TODO test that access to pages crashes somehow
     cove_host::tvm_finalize(vmid, 0x8020_0000, boot_arg).expect("Tellus - Finalize returned error");
 
@@ -1144,6 +1358,11 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
     if has_aia {
         cove_interrupt::reclaim_imsic(imsic_file_addr).expect("Tellus - TsmReclaimImsic failed");
     }
+    // The device was returned to us when the TVM was destroyed.
+    if let Some((device, msi_table_page)) = assigned_device {
+        reclaim_device_bars(&device);
+        reclaim_pages(msi_table_page, 1);
+    }
     exercise_pmu_functionality();
     nacl::unregister_shmem().expect("SetShmem failed");
 
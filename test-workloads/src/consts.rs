@@ -74,3 +74,6 @@ pub const GUEST_RAM_END_ADDRESS: u64 = 0x1_8000_0000;
 pub const GUEST_SHARE_PING: u64 = 0xBAAD_F00D;
 pub const GUEST_SHARE_PONG: u64 = 0xF00D_BAAD;
 pub const BOOT_ARG_VECTORS_ENABLED: u64 = 0x1;
+// The remaining bits of the boot argument hold the address of the MSI-X table of the device
+// assigned to the guest, or 0 if no device is assigned.
+pub const BOOT_ARG_MSIX_TABLE_MASK: u64 = !0x7;
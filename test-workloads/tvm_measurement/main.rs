@@ -0,0 +1,143 @@
+/* SPDX-FileCopyrightText: 2023 Rivos Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Offline TVM reference measurement calculator.
+//!
+//! Computes the TVM pages (PCR2) and TVM configuration (PCR3) measurement registers that Salus
+//! will report for a TVM, from the TVM image layout. The pages and configuration are measured with
+//! the same `AttestationManager` Salus uses. The layout file lists, one per line:
+//!
+//! ```text
+//! # Measured pages: the file content, zero padded to `num_pages` 4K pages if given, measured
+//! # in order starting at `gpa`.
+//! image <gpa> <path> [num_pages]
+//! # The TVM entry PC and entry argument, as passed to TvmFinalize.
+//! entry_pc <value>
+//! entry_arg <value>
+//! ```
+//!
+//! Numbers are decimal, or hexadecimal with a `0x` prefix. Images are measured in the order
+//! they appear in the layout, which must match the order the host adds the measured pages in.
+//!
+//! Usage: tvm_measurement [--hash sha256|sha384|sha512] <layout>
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use attestation::{AttestationManager, TcgPcrIndex};
+use const_oid::db::rfc5912::{ID_SHA_256, ID_SHA_384, ID_SHA_512};
+use const_oid::ObjectIdentifier;
+use sha2::digest::Digest;
+
+const PAGE_SIZE_4K: usize = 4096;
+
+// A measured image, from the TVM layout.
+struct Image {
+    gpa: u64,
+    path: String,
+    num_pages: Option<usize>,
+}
+
+// The TVM layout.
+#[derive(Default)]
+struct Layout {
+    images: Vec<Image>,
+    entry_pc: u64,
+    entry_arg: u64,
+}
+
+fn parse_number(s: &str) -> u64 {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    parsed.unwrap_or_else(|_| panic!("Invalid number {s}"))
+}
+
+fn parse_layout(path: &str) -> Layout {
+    let text = fs::read_to_string(path).expect("error reading layout");
+    // Image paths are relative to the layout file.
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut layout = Layout::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => {}
+            ["image", gpa, image_path, rest @ ..] if rest.len() <= 1 => {
+                layout.images.push(Image {
+                    gpa: parse_number(gpa),
+                    path: base.join(image_path).to_string_lossy().into_owned(),
+                    num_pages: rest.first().map(|n| parse_number(n) as usize),
+                });
+            }
+            ["entry_pc", pc] => layout.entry_pc = parse_number(pc),
+            ["entry_arg", arg] => layout.entry_arg = parse_number(arg),
+            _ => panic!("Invalid layout line {}: {}", i + 1, line),
+        }
+    }
+    layout
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Computes and prints the TVM measurement registers for `layout`.
+fn measure<D: Digest>(layout: &Layout, hash_algorithm: ObjectIdentifier)
+where
+    hmac::Hmac<D>: hkdf::HmacImpl<D>,
+{
+    // The CDIs don't take part in the TVM pages and configuration measurements.
+    let mgr = AttestationManager::<D>::new(None, 0, hash_algorithm)
+        .expect("error creating the attestation manager");
+    for image in layout.images.iter() {
+        let mut bytes = fs::read(&image.path).expect("error reading image");
+        let num_pages = image
+            .num_pages
+            .unwrap_or((bytes.len() + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K);
+        if bytes.len() > num_pages * PAGE_SIZE_4K {
+            panic!("{} is larger than {} pages", image.path, num_pages);
+        }
+        bytes.resize(num_pages * PAGE_SIZE_4K, 0);
+        for (i, page) in bytes.chunks(PAGE_SIZE_4K).enumerate() {
+            let gpa = image.gpa + (i * PAGE_SIZE_4K) as u64;
+            mgr.extend_tvm_page(page, gpa)
+                .expect("error measuring TVM page");
+        }
+    }
+    // Salus measures the TVM configuration when the TVM is finalized.
+    mgr.set_epc(layout.entry_pc);
+    mgr.set_arg(layout.entry_arg);
+    mgr.finalize().expect("error finalizing the measurements");
+
+    let tvm_pages = mgr.read_msmt_register(TcgPcrIndex::TvmPage).unwrap();
+    let tvm_config = mgr
+        .read_msmt_register(TcgPcrIndex::TvmConfiguration)
+        .unwrap();
+    println!("PCR2 (TVM pages):         {}", to_hex(&tvm_pages));
+    println!("PCR3 (TVM configuration): {}", to_hex(&tvm_config));
+}
+
+fn main() {
+    // Parse Arguments
+    let mut arg_list = env::args().skip(1).peekable();
+
+    let mut hash = String::from("sha384");
+    if arg_list.peek().map(|a| a == "--hash").unwrap_or(false) {
+        arg_list.next();
+        hash = arg_list.next().expect("No hash algorithm");
+    }
+    let layout_path = arg_list.next().expect("No layout path");
+    let layout = parse_layout(&layout_path);
+
+    match hash.as_str() {
+        "sha256" => measure::<sha2::Sha256>(&layout, ID_SHA_256),
+        "sha384" => measure::<sha2::Sha384>(&layout, ID_SHA_384),
+        "sha512" => measure::<sha2::Sha512>(&layout, ID_SHA_512),
+        _ => panic!("Unsupported hash algorithm {hash}"),
+    }
+}
@@ -7,13 +7,20 @@ use data_model::DataInit;
 
 /// CDI ID length.
 pub const CDI_ID_LEN: usize = 20;
-/// Length of a SHA384 hash.
-pub const SHA384_LEN: usize = 48;
+/// Maximum length of a measurement register digest (SHA-512).
+pub const MAX_DIGEST_LEN: usize = 64;
+/// Length of the evidence request data blob.
+pub const REQUEST_DATA_LEN: usize = 64;
+/// Maximum length of a sealing key label.
+pub const SEALING_KEY_LABEL_LEN: usize = 64;
+/// Maximum length of a sealing key.
+pub const SEALING_KEY_MAX_LEN: usize = 64;
 
 /// Compound Device Identifier (CDI) ID type.
 pub type CdiId = [u8; CDI_ID_LEN];
-/// Measurement registers for the Sha384 case.
-pub type MeasurementRegisterSha384 = [u8; SHA384_LEN];
+/// Measurement register digest. Only the first `digest_len` bytes are valid, depending on the
+/// TVM measurement hash algorithm (SHA-256, SHA-384 or SHA-512).
+pub type MeasurementRegisterDigest = [u8; MAX_DIGEST_LEN];
 
 /// Structure passed with `GetEvidence` in the Umode Input Region.
 /// Represents the status of the DICE layer needed to generate a
@@ -21,10 +28,80 @@ pub type MeasurementRegisterSha384 = [u8; SHA384_LEN];
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct MeasurementRegisters {
-    /// Measurement registers in SHA-384. In `fwid` order.
-    pub msmt_regs: [MeasurementRegisterSha384; MSMT_REGISTERS],
+    /// Measurement registers. In `fwid` order.
+    pub msmt_regs: [MeasurementRegisterDigest; MSMT_REGISTERS],
+    /// Digest of the TVM event log.
+    pub event_log_digest: MeasurementRegisterDigest,
+    /// Length of the measurement digests.
+    pub digest_len: u64,
 }
 
 // Safety: `MeasurementRegisters` is a POD struct without implicit padding and therefore can be
 // initialized from a byte array.
 unsafe impl DataInit for MeasurementRegisters {}
+
+// Claim keys of the Entity Attestation Token claims set. Besides the standard EAT nonce, which
+// carries `EatClaims::request_data`, the token uses the following private claim keys for the
+// Salus specific data. Private claim keys must be less than -65536 (RFC 8392).
+
+/// EAT nonce claim key (RFC 9711). The value is `EatClaims::request_data`.
+pub const EAT_NONCE: i64 = 10;
+/// Salus TCB SVN private claim key. The value is `EatClaims::tcb_svn`.
+pub const SALUS_TCB_SVN: i64 = -70000;
+/// Salus TVM identifier private claim key. The value is `EatClaims::tvm_id`.
+pub const SALUS_TVM_ID: i64 = -70001;
+/// Salus measurement registers private claim key. The value is an array of the
+/// `EatClaims::msmt_regs` digests, in `fwid` order.
+pub const SALUS_MSMT_REGS: i64 = -70002;
+/// Salus measurement hash algorithm private claim key. The value is the COSE algorithm identifier
+/// of the hash algorithm matching `EatClaims::digest_len`.
+pub const SALUS_HASH_ALG: i64 = -70003;
+/// Salus event log digest private claim key. The value is `EatClaims::event_log_digest`.
+pub const SALUS_EVENT_LOG_DIGEST: i64 = -70004;
+/// Salus endorsement private claim key. The value is a boolean, false when the token is signed
+/// with a well-known CDI because the firmware handed off no DICE CDI. See `EatClaims::endorsed`.
+pub const SALUS_ENDORSED: i64 = -70005;
+
+/// Structure passed with `GetEatEvidence` in the Umode Input Region.
+/// Contains the claims that U-mode will include in the Entity Attestation
+/// Token.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct EatClaims {
+    /// Measurement registers. In `fwid` order.
+    pub msmt_regs: [MeasurementRegisterDigest; MSMT_REGISTERS],
+    /// Length of the measurement digests.
+    pub digest_len: u64,
+    /// TCB security version number.
+    pub tcb_svn: u64,
+    /// TVM identifier.
+    pub tvm_id: u64,
+    /// Digest of the TVM event log.
+    pub event_log_digest: MeasurementRegisterDigest,
+    /// Caller provided request data, used as the token nonce.
+    pub request_data: [u8; REQUEST_DATA_LEN],
+    /// Non-zero if the attestation CDI is endorsed by the platform root of trust.
+    pub endorsed: u64,
+}
+
+// Safety: `EatClaims` is a POD struct without implicit padding and therefore can be initialized
+// from a byte array.
+unsafe impl DataInit for EatClaims {}
+
+/// Structure passed with `GetSealingKey` in the Umode Input Region.
+/// Contains the caller selected policy the sealing key is bound to. The measurement registers the
+/// caller selected are bound by the hypervisor when it derives the key.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SealingKeyPolicy {
+    /// TCB security version number the key is bound to.
+    pub tcb_svn: u64,
+    /// Length of the key label.
+    pub label_len: u64,
+    /// Caller chosen key label.
+    pub label: [u8; SEALING_KEY_LABEL_LEN],
+}
+
+// Safety: `SealingKeyPolicy` is a POD struct without implicit padding and therefore can be
+// initialized from a byte array.
+unsafe impl DataInit for SealingKeyPolicy {}
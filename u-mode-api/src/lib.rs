@@ -139,11 +139,31 @@ pub enum UmodeRequest {
         /// size of the output Certificate.
         certout_len: usize,
     },
+    /// Get Attestation Evidence as a CBOR Web Token (COSE_Sign1) Entity Attestation Token.
+    ///
+    /// Umode Input Region: contains `EatClaims`.
+    GetEatEvidence {
+        /// starting address of the output token.
+        eatout_addr: u64,
+        /// size of the output token.
+        eatout_len: usize,
+    },
+    /// Derive a sealing key from the TVM sealing CDI.
+    ///
+    /// Umode Input Region: contains `SealingKeyPolicy`.
+    GetSealingKey {
+        /// starting address of the output key.
+        keyout_addr: u64,
+        /// size of the output key.
+        keyout_len: usize,
+    },
 }
 
 // Mappings of A0 register to U-mode operation.
 const UMOP_NOP: u64 = 0;
 const UMOP_GET_EVIDENCE: u64 = 1;
+const UMOP_GET_EAT_EVIDENCE: u64 = 2;
+const UMOP_GET_SEALING_KEY: u64 = 3;
 
 impl TryIntoRegisters for UmodeRequest {
     fn try_from_registers(regs: &[u64]) -> Result<UmodeRequest, Error> {
@@ -155,6 +175,14 @@ impl TryIntoRegisters for UmodeRequest {
                 certout_addr: regs[3],
                 certout_len: regs[3] as usize,
             }),
+            UMOP_GET_EAT_EVIDENCE => Ok(UmodeRequest::GetEatEvidence {
+                eatout_addr: regs[1],
+                eatout_len: regs[2] as usize,
+            }),
+            UMOP_GET_SEALING_KEY => Ok(UmodeRequest::GetSealingKey {
+                keyout_addr: regs[1],
+                keyout_len: regs[2] as usize,
+            }),
             _ => Err(Error::RequestNotSupported),
         }
     }
@@ -176,6 +204,22 @@ impl TryIntoRegisters for UmodeRequest {
                 regs[3] = certout_addr;
                 regs[4] = certout_len as u64;
             }
+            UmodeRequest::GetEatEvidence {
+                eatout_addr,
+                eatout_len,
+            } => {
+                regs[0] = UMOP_GET_EAT_EVIDENCE;
+                regs[1] = eatout_addr;
+                regs[2] = eatout_len as u64;
+            }
+            UmodeRequest::GetSealingKey {
+                keyout_addr,
+                keyout_len,
+            } => {
+                regs[0] = UMOP_GET_SEALING_KEY;
+                regs[1] = keyout_addr;
+                regs[2] = keyout_len as u64;
+            }
         }
     }
 }
@@ -215,6 +259,8 @@ impl TryFrom<u64> for CdiSel {
 
 /// Maximum size of a message to sign.
 pub const CDIOP_SIGN_MAXMSG: usize = 2048;
+/// Maximum size of a key derivation context.
+pub const CDIOP_DERIVE_MAXINFO: usize = 1024;
 
 /// CDI Operation
 #[derive(Debug, Clone, Copy)]
@@ -237,10 +283,30 @@ pub enum CdiOp {
         /// Length of the buffer for storing the signature.
         signout_len: u64,
     },
+    /// Public key of the CDI.
+    PublicKey {
+        /// Address where the public key will be stored.
+        pkout_addr: u64,
+        /// Length of the buffer for storing the public key.
+        pkout_len: u64,
+    },
+    /// Derive a key from the CDI and a derivation context. Only valid for sealing CDIs.
+    DeriveKey {
+        /// Address where the derivation context starts.
+        info_addr: u64,
+        /// Length of the derivation context.
+        info_len: u64,
+        /// Address where the derived key will be stored.
+        keyout_addr: u64,
+        /// Length of the key to derive.
+        keyout_len: u64,
+    },
 }
 
 const HYPC_CDI_ID: u64 = 0;
 const HYPC_CDI_SIGN: u64 = 1;
+const HYPC_CDI_PUBLIC_KEY: u64 = 2;
+const HYPC_CDI_DERIVE_KEY: u64 = 3;
 
 impl TryIntoRegisters for CdiOp {
     fn try_from_registers(regs: &[u64]) -> Result<Self, Error> {
@@ -255,6 +321,16 @@ impl TryIntoRegisters for CdiOp {
                 signout_addr: regs[3],
                 signout_len: regs[4],
             }),
+            HYPC_CDI_PUBLIC_KEY => Ok(CdiOp::PublicKey {
+                pkout_addr: regs[1],
+                pkout_len: regs[2],
+            }),
+            HYPC_CDI_DERIVE_KEY => Ok(CdiOp::DeriveKey {
+                info_addr: regs[1],
+                info_len: regs[2],
+                keyout_addr: regs[3],
+                keyout_len: regs[4],
+            }),
             _ => Err(Error::EcallNotSupported),
         }
     }
@@ -281,6 +357,26 @@ impl TryIntoRegisters for CdiOp {
                 regs[3] = signout_addr;
                 regs[4] = signout_len;
             }
+            CdiOp::PublicKey {
+                pkout_addr,
+                pkout_len,
+            } => {
+                regs[0] = HYPC_CDI_PUBLIC_KEY;
+                regs[1] = pkout_addr;
+                regs[2] = pkout_len;
+            }
+            CdiOp::DeriveKey {
+                info_addr,
+                info_len,
+                keyout_addr,
+                keyout_len,
+            } => {
+                regs[0] = HYPC_CDI_DERIVE_KEY;
+                regs[1] = info_addr;
+                regs[2] = info_len;
+                regs[3] = keyout_addr;
+                regs[4] = keyout_len;
+            }
         }
     }
 }
@@ -294,7 +390,8 @@ pub enum HypCall {
     PutChar(u8),
     /// Return result of previous request and wait for next operation.
     NextOp(OpResult),
-    /// Attestation Manager CDI operations.
+    /// Attestation Manager CDI operations. The result of the operation is returned in the
+    /// A-registers as a `Result<u64, Error>`.
     Cdi {
         /// CDI Selector.
         cdi_sel: CdiSel,
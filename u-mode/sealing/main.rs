@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_std]
+#![no_main]
+
+//! # Salus U-mode sealing service.
+//!
+//! This is Salus U-mode code. It is used to offload the derivation
+//! of TVM sealing keys from the hypervisor to user mode. It is linked
+//! and runs separately from the attestation service, so that neither
+//! service can reach the CDI operations of the other. There's a copy
+//! of this task in each CPU.
+//!
+//! The task it's based on a request loop. This task can be reset at
+//! any time by the hypervisor, so it shouldn't hold non-recoverable
+//! state.
+
+extern crate libuser;
+
+use data_model::{VolatileMemory, VolatileSlice};
+use libuser::*;
+use test_system::*;
+use u_mode_api::{Error as UmodeApiError, UmodeRequest};
+
+mod sealing;
+
+struct UmodeTask {
+    vslice: VolatileSlice<'static>,
+}
+
+impl UmodeTask {
+    // Derive a sealing key from the TVM sealing CDI.
+    // This function returns the length of the derived key.
+    //
+    // Arguments:
+    //   keyout_addr: starting address of the output key.
+    //   keyout_len: size of the output key.
+    //
+    // U-mode Input Region: contains an instance of `SealingKeyPolicy`.
+    fn op_get_sealing_key(
+        &self,
+        keyout_addr: u64,
+        keyout_len: usize,
+    ) -> Result<u64, UmodeApiError> {
+        // Safety: we trust the hypervisor to have mapped at `keyout_addr` `keyout_len` bytes valid
+        // for reading and writing.
+        let keyout = unsafe {
+            &mut *core::ptr::slice_from_raw_parts_mut(keyout_addr as *mut u8, keyout_len)
+        };
+        let policy = self
+            .vslice
+            .get_ref(0)
+            .map_err(|_| UmodeApiError::Failed)?
+            .load();
+        sealing::get_sealing_key(policy, keyout).map_err(|e| {
+            println!("get_sealing_key failed: {:?}", e);
+            match e {
+                sealing::Error::Cdi(err) => err,
+                _ => UmodeApiError::InvalidArgument,
+            }
+        })
+    }
+
+    // Run the main loop, receiving requests from the hypervisor and executing them.
+    fn run_loop(&self) -> ! {
+        let mut res = Ok(0);
+        loop {
+            // Return result and wait for next operation.
+            let req = hyp_nextop(res);
+            res = match req {
+                Ok(req) => match req {
+                    UmodeRequest::Nop => Ok(0),
+                    UmodeRequest::GetSealingKey {
+                        keyout_addr,
+                        keyout_len,
+                    } => self.op_get_sealing_key(keyout_addr, keyout_len),
+                    // Evidence is generated by the attestation service.
+                    UmodeRequest::GetEvidence { .. } | UmodeRequest::GetEatEvidence { .. } => {
+                        Err(UmodeApiError::RequestNotSupported)
+                    }
+                },
+                Err(err) => Err(err),
+            };
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn task_main(cpuid: u64, input_addr: u64, input_size: u64) -> ! {
+    // Safety: we trust the hypervisor to have mapped an area of memory starting at `input_addr`
+    // valid for at least `input_size` bytes.
+    let vslice =
+        unsafe { VolatileSlice::from_raw_parts(input_addr as *mut u8, input_size as usize) };
+    let task = UmodeTask { vslice };
+    println!(
+        "umode-sealing/#{}: U-mode Input Region: {:016x} - {} bytes",
+        cpuid, input_addr, input_size
+    );
+    test_declare_pass!("umode sealing start", cpuid);
+    task.run_loop()
+}
@@ -0,0 +1,64 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+extern crate libuser;
+use libuser::*;
+
+use u_mode_api::cert::*;
+use u_mode_api::{CdiSel, Error as UmodeApiError, CDIOP_DERIVE_MAXINFO};
+
+// Sealing key derivation.
+//
+// Sealing keys are derived from the TVM sealing CDI and a derivation context built from the
+// caller policy. The hypervisor binds the key to the TVM image and configuration, so that no other
+// TVM can derive the same keys, and to the values of the measurement registers the caller selected.
+// The context further binds the key to the selected TCB SVN and the caller chosen label, so that a
+// TVM gets the same key across restarts as long as none of those changes.
+
+// Derivation context domain separator.
+const SEALING_KEY_CONTEXT: &[u8] = b"Salus TVM sealing key";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid key length.
+    InvalidKeyLength(usize),
+    /// Invalid label length.
+    InvalidLabelLength(u64),
+    /// The key derivation failed.
+    Cdi(UmodeApiError),
+}
+
+// Appends `bytes` to the derivation context.
+fn put(info: &mut [u8], pos: &mut usize, bytes: &[u8]) {
+    info[*pos..*pos + bytes.len()].copy_from_slice(bytes);
+    *pos += bytes.len();
+}
+
+/// Derives a sealing key bound to `policy` from the TVM sealing CDI and writes it to
+/// `key_output`. The key length is the length of `key_output`.
+/// Returns the length of the key.
+pub fn get_sealing_key(policy: SealingKeyPolicy, key_output: &mut [u8]) -> Result<u64, Error> {
+    if key_output.is_empty() || key_output.len() > SEALING_KEY_MAX_LEN {
+        return Err(Error::InvalidKeyLength(key_output.len()));
+    }
+    if policy.label_len > SEALING_KEY_LABEL_LEN as u64 {
+        return Err(Error::InvalidLabelLength(policy.label_len));
+    }
+
+    // The context is: domain || tcb_svn || label_len || label.
+    // Its maximum length is well below `CDIOP_DERIVE_MAXINFO`.
+    let mut info = [0u8; CDIOP_DERIVE_MAXINFO];
+    let mut pos = 0;
+    put(&mut info, &mut pos, SEALING_KEY_CONTEXT);
+    put(&mut info, &mut pos, &policy.tcb_svn.to_le_bytes());
+    put(&mut info, &mut pos, &policy.label_len.to_le_bytes());
+    put(
+        &mut info,
+        &mut pos,
+        &policy.label[..policy.label_len as usize],
+    );
+
+    hyp_cdi_derive_key(CdiSel::SealingCurrent, &info[..pos], key_output).map_err(Error::Cdi)?;
+    Ok(key_output.len() as u64)
+}
@@ -5,6 +5,9 @@
 extern crate libuser;
 use libuser::*;
 
+use alloc::vec;
+use const_oid::db::rfc5912::{ID_SHA_256, ID_SHA_384, ID_SHA_512};
+use const_oid::ObjectIdentifier;
 use der::Decode;
 use ed25519_dalek::{Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
 use generic_array::GenericArray;
@@ -14,6 +17,7 @@ use rice::layer::Layer;
 use rice::x509::extensions::dice::tcbinfo::DiceTcbInfo;
 use rice::x509::request::CertReq;
 use rice::x509::MAX_CSR_LEN;
+use sha2::Digest;
 use signature::{Error as SignatureError, Signer};
 use u_mode_api::cert::*;
 use u_mode_api::CdiSel;
@@ -29,6 +33,8 @@ pub enum Error {
     CsrVerificationFailed(rice::Error),
     /// Cannot add FWID extension.
     FwidAddFailed(rice::Error),
+    /// Unsupported measurement digest length.
+    InvalidDigestLength(u64),
     /// Could not create TcbInfo Extensions.
     TcbInfoFailed(rice::Error),
     /// Cannot create Certificate.
@@ -50,7 +56,8 @@ impl Zeroize for UmodeCdi {
 impl CompoundDeviceIdentifier<PUBLIC_KEY_LENGTH, Signature> for UmodeCdi {
     fn id(&self) -> Result<[u8; CDI_ID_LEN], rice::Error> {
         let mut id = [0u8; CDI_ID_LEN];
-        hyp_cdi_id(self.cdi, &mut id);
+        // A failed CDI operation aborts the request.
+        hyp_cdi_id(self.cdi, &mut id).unwrap_or_else(|_| hyp_panic());
         Ok(id)
     }
 
@@ -59,14 +66,16 @@ impl CompoundDeviceIdentifier<PUBLIC_KEY_LENGTH, Signature> for UmodeCdi {
     }
 
     fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
-        todo!();
+        let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+        hyp_cdi_public_key(self.cdi, &mut public_key).unwrap_or_else(|_| hyp_panic());
+        public_key
     }
 }
 
 impl Signer<Signature> for UmodeCdi {
     fn try_sign(&self, msg: &[u8]) -> Result<Signature, SignatureError> {
         let mut signature = [0u8; SIGNATURE_LENGTH];
-        hyp_cdi_sign(self.cdi, msg, &mut signature);
+        hyp_cdi_sign(self.cdi, msg, &mut signature).map_err(|_| SignatureError::new())?;
         Signature::from_bytes(&signature)
     }
 }
@@ -79,7 +88,37 @@ const ATTESTATION_NEXT_CDI: UmodeCdi = UmodeCdi {
     cdi: CdiSel::AttestationNext,
 };
 
-pub fn get_certificate_sha384(
+// Adds the measurement registers and the event log digest from `evidence` to `tcb_info`, as
+// `digest_len` long FWIDs. The event log digest comes last, after the measurement registers.
+fn add_fwids_digest<D: Digest>(
+    tcb_info: &mut DiceTcbInfo,
+    hash_algorithm: ObjectIdentifier,
+    evidence: &MeasurementRegisters,
+) -> Result<(), Error> {
+    let digest_len = <D as Digest>::output_size();
+    for m in evidence
+        .msmt_regs
+        .iter()
+        .chain(core::iter::once(&evidence.event_log_digest))
+    {
+        tcb_info
+            .add_fwid::<D>(hash_algorithm, GenericArray::from_slice(&m[..digest_len]))
+            .map_err(Error::FwidAddFailed)?;
+    }
+    Ok(())
+}
+
+// Adds the `evidence` FWIDs to `tcb_info`, for the TVM measurement hash algorithm.
+fn add_fwids(tcb_info: &mut DiceTcbInfo, evidence: &MeasurementRegisters) -> Result<(), Error> {
+    match evidence.digest_len {
+        32 => add_fwids_digest::<sha2::Sha256>(tcb_info, ID_SHA_256, evidence),
+        48 => add_fwids_digest::<sha2::Sha384>(tcb_info, ID_SHA_384, evidence),
+        64 => add_fwids_digest::<sha2::Sha512>(tcb_info, ID_SHA_512, evidence),
+        len => Err(Error::InvalidDigestLength(len)),
+    }
+}
+
+pub fn get_certificate(
     csr_input: &[u8],
     evidence: MeasurementRegisters,
     cert_output: &mut [u8],
@@ -89,14 +128,12 @@ pub fn get_certificate_sha384(
     if csr_len > MAX_CSR_LEN {
         return Err(Error::CsrBufferTooSmall(csr_len, MAX_CSR_LEN));
     }
-    let mut csr_bytes = [0u8; MAX_CSR_LEN];
-    csr_bytes[0..csr_len].copy_from_slice(csr_input);
+    let csr_bytes = csr_input.to_vec();
 
-    let mut tcb_info_bytes = [0u8; 4096];
+    let mut tcb_info_bytes = vec![0u8; 4096];
     let mut tcb_info = DiceTcbInfo::new();
-    let hash_algorithm = const_oid::db::rfc5912::ID_SHA_384;
 
-    let csr = CertReq::from_der(&csr_bytes[0..csr_len]).map_err(Error::CsrParseFailed)?;
+    let csr = CertReq::from_der(&csr_bytes).map_err(Error::CsrParseFailed)?;
 
     println!(
         "U-mode CSR version {:?} Signature algorithm {:?}",
@@ -105,11 +142,7 @@ pub fn get_certificate_sha384(
 
     csr.verify().map_err(Error::CsrVerificationFailed)?;
 
-    for m in evidence.msmt_regs.iter() {
-        tcb_info
-            .add_fwid::<sha2::Sha384>(hash_algorithm, GenericArray::from_slice(m.as_slice()))
-            .map_err(Error::FwidAddFailed)?;
-    }
+    add_fwids(&mut tcb_info, &evidence)?;
 
     let tcb_info_extn = tcb_info
         .to_extension(&mut tcb_info_bytes)
@@ -0,0 +1,246 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+extern crate libuser;
+use libuser::*;
+
+use ed25519_dalek::SIGNATURE_LENGTH;
+use u_mode_api::cert::*;
+use u_mode_api::{CdiSel, Error as UmodeApiError, CDIOP_SIGN_MAXMSG};
+
+// Entity Attestation Token (EAT) generation.
+//
+// The token is a CBOR Web Token (CWT) protected by a COSE_Sign1 structure (RFC 9052), signed
+// with the TVM attestation CDI. The claims set carries the standard EAT nonce claim, plus a set
+// of private claims for the Salus specific data. The claim keys are defined in `u_mode_api::cert`.
+
+// COSE header labels and values (RFC 9053).
+const COSE_HDR_ALG: i64 = 1;
+const COSE_HDR_KID: i64 = 4;
+const COSE_ALG_EDDSA: i64 = -8;
+const COSE_ALG_SHA256: i64 = -16;
+const COSE_ALG_SHA384: i64 = -43;
+const COSE_ALG_SHA512: i64 = -44;
+// CBOR tag for a COSE_Sign1 structure.
+const COSE_SIGN1_TAG: u64 = 18;
+// Signature structure context for COSE_Sign1.
+const SIGN1_CONTEXT: &str = "Signature1";
+
+// Maximum size of the encoded claims set.
+const MAX_PAYLOAD_LEN: usize = 1024;
+// Maximum size of the encoded protected header.
+const MAX_PROTECTED_LEN: usize = 16;
+// Maximum size of the encoded COSE_Sign1 token.
+const MAX_TOKEN_LEN: usize = MAX_PAYLOAD_LEN + 256;
+
+#[derive(Debug)]
+pub enum Error {
+    /// CBOR encoding buffer too small.
+    EncodingBufferTooSmall,
+    /// The to-be-signed structure exceeds the CDI signing limit.
+    SignedDataTooLarge(usize, usize),
+    /// Output token buffer too small.
+    TokenBufferTooSmall(usize, usize),
+    /// Unsupported measurement digest length.
+    InvalidDigestLength(u64),
+    /// A CDI operation failed.
+    Cdi(UmodeApiError),
+}
+
+// CBOR major types (RFC 8949).
+const CBOR_UINT: u8 = 0;
+const CBOR_NINT: u8 = 1;
+const CBOR_BSTR: u8 = 2;
+const CBOR_TSTR: u8 = 3;
+const CBOR_ARRAY: u8 = 4;
+const CBOR_MAP: u8 = 5;
+const CBOR_TAG: u8 = 6;
+const CBOR_SIMPLE: u8 = 7;
+// CBOR simple values (RFC 8949).
+const CBOR_FALSE: u64 = 20;
+const CBOR_TRUE: u64 = 21;
+
+// A heapless, definite length only, CBOR encoder.
+struct CborWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> CborWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self
+            .pos
+            .checked_add(bytes.len())
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(Error::EncodingBufferTooSmall)?;
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    // Writes a data item head with the shortest argument encoding.
+    fn head(&mut self, major: u8, arg: u64) -> Result<(), Error> {
+        let mt = major << 5;
+        if arg < 24 {
+            self.put(&[mt | arg as u8])
+        } else if arg <= u8::MAX as u64 {
+            self.put(&[mt | 24, arg as u8])
+        } else if arg <= u16::MAX as u64 {
+            self.put(&[mt | 25])?;
+            self.put(&(arg as u16).to_be_bytes())
+        } else if arg <= u32::MAX as u64 {
+            self.put(&[mt | 26])?;
+            self.put(&(arg as u32).to_be_bytes())
+        } else {
+            self.put(&[mt | 27])?;
+            self.put(&arg.to_be_bytes())
+        }
+    }
+
+    fn uint(&mut self, val: u64) -> Result<(), Error> {
+        self.head(CBOR_UINT, val)
+    }
+
+    fn int(&mut self, val: i64) -> Result<(), Error> {
+        if val >= 0 {
+            self.head(CBOR_UINT, val as u64)
+        } else {
+            // Negative integers are encoded as -1 - val.
+            self.head(CBOR_NINT, !(val as u64))
+        }
+    }
+
+    fn bstr(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.head(CBOR_BSTR, bytes.len() as u64)?;
+        self.put(bytes)
+    }
+
+    fn tstr(&mut self, s: &str) -> Result<(), Error> {
+        self.head(CBOR_TSTR, s.len() as u64)?;
+        self.put(s.as_bytes())
+    }
+
+    fn array(&mut self, len: usize) -> Result<(), Error> {
+        self.head(CBOR_ARRAY, len as u64)
+    }
+
+    fn map(&mut self, len: usize) -> Result<(), Error> {
+        self.head(CBOR_MAP, len as u64)
+    }
+
+    fn tag(&mut self, tag: u64) -> Result<(), Error> {
+        self.head(CBOR_TAG, tag)
+    }
+
+    fn bool(&mut self, val: bool) -> Result<(), Error> {
+        self.head(CBOR_SIMPLE, if val { CBOR_TRUE } else { CBOR_FALSE })
+    }
+
+    fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+// Encodes the claims set into `buf` and returns its length.
+fn encode_claims(claims: &EatClaims, buf: &mut [u8]) -> Result<usize, Error> {
+    let hash_alg = match claims.digest_len {
+        32 => COSE_ALG_SHA256,
+        48 => COSE_ALG_SHA384,
+        64 => COSE_ALG_SHA512,
+        len => return Err(Error::InvalidDigestLength(len)),
+    };
+    let digest_len = claims.digest_len as usize;
+    let mut w = CborWriter::new(buf);
+    w.map(7)?;
+    w.int(EAT_NONCE)?;
+    w.bstr(&claims.request_data)?;
+    w.int(SALUS_TCB_SVN)?;
+    w.uint(claims.tcb_svn)?;
+    w.int(SALUS_TVM_ID)?;
+    w.uint(claims.tvm_id)?;
+    w.int(SALUS_HASH_ALG)?;
+    w.int(hash_alg)?;
+    w.int(SALUS_EVENT_LOG_DIGEST)?;
+    w.bstr(&claims.event_log_digest[..digest_len])?;
+    w.int(SALUS_ENDORSED)?;
+    w.bool(claims.endorsed != 0)?;
+    w.int(SALUS_MSMT_REGS)?;
+    w.array(claims.msmt_regs.len())?;
+    for m in claims.msmt_regs.iter() {
+        w.bstr(&m[..digest_len])?;
+    }
+    Ok(w.len())
+}
+
+// Encodes the COSE protected header into `buf` and returns its length.
+fn encode_protected_header(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut w = CborWriter::new(buf);
+    w.map(1)?;
+    w.int(COSE_HDR_ALG)?;
+    w.int(COSE_ALG_EDDSA)?;
+    Ok(w.len())
+}
+
+// Encodes the COSE_Sign1 `Sig_structure` into `buf` and returns its length.
+fn encode_sig_structure(protected: &[u8], payload: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+    let mut w = CborWriter::new(buf);
+    w.array(4)?;
+    w.tstr(SIGN1_CONTEXT)?;
+    w.bstr(protected)?;
+    // No external AAD.
+    w.bstr(&[])?;
+    w.bstr(payload)?;
+    Ok(w.len())
+}
+
+/// Builds an Entity Attestation Token from `claims`, signs it with the TVM attestation CDI and
+/// writes it to `eat_output`.
+/// Returns the length of the token.
+pub fn get_eat(claims: EatClaims, eat_output: &mut [u8]) -> Result<u64, Error> {
+    let mut payload_bytes = [0u8; MAX_PAYLOAD_LEN];
+    let payload_len = encode_claims(&claims, &mut payload_bytes)?;
+    let payload = &payload_bytes[..payload_len];
+
+    let mut protected_bytes = [0u8; MAX_PROTECTED_LEN];
+    let protected_len = encode_protected_header(&mut protected_bytes)?;
+    let protected = &protected_bytes[..protected_len];
+
+    let mut tbs_bytes = [0u8; CDIOP_SIGN_MAXMSG];
+    let tbs_len = encode_sig_structure(protected, payload, &mut tbs_bytes)
+        .map_err(|_| Error::SignedDataTooLarge(payload_len, CDIOP_SIGN_MAXMSG))?;
+
+    let mut signature = [0u8; SIGNATURE_LENGTH];
+    hyp_cdi_sign(
+        CdiSel::AttestationCurrent,
+        &tbs_bytes[..tbs_len],
+        &mut signature,
+    )
+    .map_err(Error::Cdi)?;
+    // The key identifier lets relying parties match the token with the attestation CDI
+    // certificate.
+    let mut kid = [0u8; CDI_ID_LEN];
+    hyp_cdi_id(CdiSel::AttestationCurrent, &mut kid).map_err(Error::Cdi)?;
+
+    let mut token_bytes = [0u8; MAX_TOKEN_LEN];
+    let mut w = CborWriter::new(&mut token_bytes);
+    w.tag(COSE_SIGN1_TAG)?;
+    w.array(4)?;
+    w.bstr(protected)?;
+    w.map(1)?;
+    w.int(COSE_HDR_KID)?;
+    w.bstr(&kid)?;
+    w.bstr(payload)?;
+    w.bstr(&signature)?;
+    let token_len = w.len();
+    if eat_output.len() < token_len {
+        return Err(Error::TokenBufferTooSmall(eat_output.len(), token_len));
+    }
+    // Copy token to output.
+    eat_output[0..token_len].copy_from_slice(&token_bytes[0..token_len]);
+    Ok(token_len as u64)
+}
@@ -5,16 +5,17 @@
 #![no_std]
 #![no_main]
 
-//! # Salus U-mode binary.
+//! # Salus U-mode attestation service.
 //!
-//! This is Salus U-mode code. It is used to offload functionalities
-//! of the hypervisor in user mode. There's a copy of this task in
-//! each CPU.
+//! This is Salus U-mode code. It is used to offload the generation
+//! of attestation evidence from the hypervisor to user mode. There's
+//! a copy of this task in each CPU.
 //!
 //! The task it's based on a request loop. This task can be reset at
 //! any time by the hypervisor, so it shouldn't hold non-recoverable
 //! state.
 
+extern crate alloc;
 extern crate libuser;
 
 use data_model::{VolatileMemory, VolatileSlice};
@@ -23,23 +24,7 @@ use test_system::*;
 use u_mode_api::{Error as UmodeApiError, UmodeRequest};
 
 mod cert;
-
-// Dummy global allocator - panic if anything tries to do an allocation.
-struct GeneralGlobalAlloc;
-
-unsafe impl core::alloc::GlobalAlloc for GeneralGlobalAlloc {
-    unsafe fn alloc(&self, _layout: core::alloc::Layout) -> *mut u8 {
-        panic!("alloc called!");
-    }
-
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
-        panic!("dealloc called!");
-    }
-}
-
-// Global allocator linking (not usage) required by rice dependencies.
-#[global_allocator]
-static GENERAL_ALLOCATOR: GeneralGlobalAlloc = GeneralGlobalAlloc;
+mod eat;
 
 struct UmodeTask {
     vslice: VolatileSlice<'static>,
@@ -76,7 +61,7 @@ impl UmodeTask {
             .get_ref(0)
             .map_err(|_| UmodeApiError::Failed)?
             .load();
-        cert::get_certificate_sha384(csr, input_data, certout).map_err(|e| {
+        cert::get_certificate(csr, input_data, certout).map_err(|e| {
             println!("get_certificate failed: {:?}", e);
             use cert::Error::*;
             match e {
@@ -89,6 +74,41 @@ impl UmodeTask {
         })
     }
 
+    // Get an attestation evidence as an Entity Attestation Token.
+    // This function returns a CBOR Web Token, protected by a COSE_Sign1 structure signed with
+    // the TVM attestation CDI.
+    //
+    // Arguments:
+    //   eatout_addr: starting address of the output token.
+    //   eatout_len: size of the output token.
+    //
+    // U-mode Input Region: contains an instance of `EatClaims`.
+    fn op_get_eat_evidence(
+        &self,
+        eatout_addr: u64,
+        eatout_len: usize,
+    ) -> Result<u64, UmodeApiError> {
+        // Safety: we trust the hypervisor to have mapped at `eatout_addr` `eatout_len` bytes valid
+        // for reading and writing.
+        let eatout = unsafe {
+            &mut *core::ptr::slice_from_raw_parts_mut(eatout_addr as *mut u8, eatout_len)
+        };
+        let claims = self
+            .vslice
+            .get_ref(0)
+            .map_err(|_| UmodeApiError::Failed)?
+            .load();
+        eat::get_eat(claims, eatout).map_err(|e| {
+            println!("get_eat failed: {:?}", e);
+            use eat::Error::*;
+            match e {
+                TokenBufferTooSmall(_, _) => UmodeApiError::InvalidArgument,
+                Cdi(err) => err,
+                _ => UmodeApiError::Failed,
+            }
+        })
+    }
+
     // Run the main loop, receiving requests from the hypervisor and executing them.
     fn run_loop(&self) -> ! {
         let mut res = Ok(0);
@@ -104,6 +124,12 @@ impl UmodeTask {
                         certout_addr,
                         certout_len,
                     } => self.op_get_evidence(csr_addr, csr_len, certout_addr, certout_len),
+                    UmodeRequest::GetEatEvidence {
+                        eatout_addr,
+                        eatout_len,
+                    } => self.op_get_eat_evidence(eatout_addr, eatout_len),
+                    // Sealing keys are derived by the sealing service.
+                    UmodeRequest::GetSealingKey { .. } => Err(UmodeApiError::RequestNotSupported),
                 },
                 Err(err) => Err(err),
             };